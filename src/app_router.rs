@@ -1,30 +1,56 @@
 use crate::{
     config::CONFIG,
     controller::{
+        account::{user_delete_post, user_export},
         admin::{admin, admin_gallery, admin_post, admin_view},
+        api,
+        automod::mod_automod_post,
+        block::{user_block, user_mute},
+        bookmark::{
+            bookmark_edit_post, bookmark_post, bookmark_token_post, bookmarks, bookmarks_feed,
+        },
+        email::{
+            email_verify, reset_email, reset_email_post, reset_token, reset_token_post,
+            user_email_post,
+        },
         feed::{feed, feed_add, feed_add_post, feed_read, feed_star, feed_subscribe, feed_update},
         inn::{
-            comment_delete, comment_downvote, comment_hide, comment_post, comment_upvote,
-            edit_post, edit_post_post, inn, inn_feed, inn_join, inn_list, mod_feed_post, mod_inn,
-            mod_inn_post, post, post_delete, post_downvote, post_hide, post_lock, post_pin,
-            post_upvote, preview, tag,
+            comment_delete, comment_downvote, comment_edit, comment_edit_post, comment_hide,
+            comment_history, comment_post, comment_upvote, edit_post, edit_post_post, inn,
+            inn_feed, inn_join, inn_list, mod_feed_post, mod_inn, mod_inn_post, mod_rules_post,
+            post, post_delete, post_downvote, post_hide, post_history, post_lock, post_move,
+            post_move_post, post_pin, post_restore, post_upvote, preview, tag,
         },
         message::{inbox, key, key_post, message, message_post},
         meta_handler::{favicon, handler_404, home, robots, serve_embedded_js, style},
-        notification::notification,
+        mod_log::{admin_mod_log, inn_mod_log},
+        notification::{notification, notification_post},
+        poll::{poll_create, poll_create_post, poll_vote},
+        premod::{mod_premod_post, mod_queue, mod_queue_post},
+        registration::{admin_signups, admin_signups_post, invite, invite_post},
+        report::{mod_report_post, mod_reports, report, report_post},
+        shadowban::shadowban_post,
         solo::{solo, solo_delete, solo_like, solo_list, solo_post},
         tantivy::search,
+        token::{bearer_auth, token_post, token_revoke},
         upload::{gallery, image_delete, upload, upload_pic_post, upload_post},
         user::{
-            remove_session, reset, reset_post, role_post, signin, signin_post, signout, signup,
-            signup_post, user, user_follow, user_list, user_password_post, user_recovery_code,
-            user_setting, user_setting_post,
+            remove_session, reset, reset_post, role_post, signin, signin_post, signin_totp_post,
+            signout, signup, signup_post, user, user_follow, user_list, user_password_post,
+            user_recovery_code, user_setting, user_setting_post, user_totp_disable_post,
+            user_totp_post,
         },
+        webhook::{mod_webhook_delete, mod_webhook_log, mod_webhooks, mod_webhooks_post},
     },
 };
 use axum::{
-    BoxError, Router, error_handling::HandleErrorLayer, extract::DefaultBodyLimit,
-    handler::Handler, http::StatusCode, routing::get,
+    BoxError, Router,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    http::StatusCode,
+    middleware,
+    routing::{self, get},
 };
 use include_dir::{Dir, include_dir};
 use std::time::Duration;
@@ -50,53 +76,136 @@ pub async fn router() -> Router {
             TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().level(Level::INFO)),
         );
 
+    let router_api = Router::new()
+        .route("/openapi.json", get(api::openapi))
+        .route("/inn/list", get(api::inn_list))
+        .route("/inn/{i}", get(api::inn))
+        .route("/post/{iid}/{pid}", get(api::post).post(api::comment))
+        .route("/post/edit/{pid}", routing::post(api::post_edit))
+        .route("/solo/user/{u}", get(api::solo_list).post(api::solo_create))
+        .route("/solo/{sid}", get(api::solo))
+        .route("/user/{u}", get(api::user))
+        .route("/notification", get(api::notification))
+        .route("/feed/{uid}", get(api::feed))
+        .route("/search", get(api::search))
+        .fallback(api::handler_404);
+
     let router_db = Router::new()
         .route("/", get(home))
         .route("/signup", get(signup).post(signup_post))
         .route("/signin", get(signin).post(signin_post))
-        .route("/signout", get(signout))
+        .route("/signin/totp", get(signin).post(signin_totp_post))
+        .route("/signout", routing::post(signout))
         .route("/user/{u}", get(user))
-        .route("/user/{u}/follow", get(user_follow))
+        .route("/user/{u}/follow", routing::post(user_follow))
+        .route("/user/{u}/block", routing::post(user_block))
+        .route("/user/{u}/mute", routing::post(user_mute))
         .route("/user/setting", get(user_setting).post(user_setting_post))
         .route("/user/avatar", get(user_setting).post(upload_pic_post))
         .route("/user/password", get(user_setting).post(user_password_post))
         .route("/user/recovery", get(user_setting).post(user_recovery_code))
+        .route("/user/totp", get(user_setting).post(user_totp_post))
+        .route(
+            "/user/totp/disable",
+            get(user_setting).post(user_totp_disable_post),
+        )
         .route("/user/reset", get(reset).post(reset_post))
+        .route("/user/reset/email", get(reset_email).post(reset_email_post))
+        .route(
+            "/user/reset/email/{token}",
+            get(reset_token).post(reset_token_post),
+        )
+        .route("/user/email", routing::post(user_email_post))
+        .route("/user/export", get(user_export))
+        .route("/user/delete", routing::post(user_delete_post))
+        .route("/user/email/verify/{token}", get(email_verify))
         .route("/user/list", get(user_list))
-        .route("/user/remove/{session_id}", get(remove_session))
+        .route("/user/bookmarks", get(bookmarks))
+        .route("/user/bookmarks/token", routing::post(bookmark_token_post))
+        .route("/user/bookmarks/{bid}", routing::post(bookmark_edit_post))
+        .route("/user/{u}/bookmarks/atom.xml", get(bookmarks_feed))
+        .route("/user/remove/{session_id}", routing::post(remove_session))
+        .route("/user/tokens", routing::post(token_post))
+        .route("/user/tokens/{tid}/revoke", routing::post(token_revoke))
         .route("/role/{id}/{uid}", get(user_list).post(role_post))
-        .route("/notification", get(notification))
+        .route("/notification", get(notification).post(notification_post))
         .route("/admin", get(admin).post(admin_post))
         .route("/admin/view", get(admin_view))
+        .route("/admin/mod_log", get(admin_mod_log))
         .route("/admin/gallery", get(admin_gallery))
-        .route("/image/delete/{uid}/{img_id}", get(image_delete))
+        .route("/admin/shadowban/{uid}", routing::post(shadowban_post))
+        .route("/admin/signups", get(admin_signups))
+        .route("/admin/signups/{uid}", routing::post(admin_signups_post))
+        .route("/invite", get(invite).post(invite_post))
+        .route("/image/delete/{uid}/{img_id}", routing::post(image_delete))
         .route("/mod/{iid}", get(mod_inn).post(mod_inn_post))
         .route("/mod/feed/{iid}", get(mod_inn).post(mod_feed_post))
+        .route("/mod/rules/{iid}", get(mod_inn).post(mod_rules_post))
+        .route("/mod/automod/{iid}", get(mod_inn).post(mod_automod_post))
+        .route("/mod/premod/{iid}", get(mod_inn).post(mod_premod_post))
         .route(
             "/mod/inn_icon",
             get(mod_inn).post(upload_pic_post.layer(DefaultBodyLimit::max(UPLOAD_LIMIT))),
         )
-        .route("/mod/{iid}/{pid}/lock", get(post_lock))
-        .route("/mod/{iid}/{pid}/hide", get(post_hide))
-        .route("/mod/{iid}/{pid}/pin", get(post_pin))
+        .route("/mod/{iid}/log", get(inn_mod_log))
+        .route("/mod/{iid}/reports", get(mod_reports))
+        .route("/mod/{iid}/reports/{rid}", routing::post(mod_report_post))
+        .route("/mod/{iid}/queue", get(mod_queue))
+        .route("/mod/{iid}/queue/{pid}", routing::post(mod_queue_post))
+        .route(
+            "/mod/{iid}/webhooks",
+            get(mod_webhooks).post(mod_webhooks_post),
+        )
+        .route("/mod/{iid}/webhooks/{whid}", get(mod_webhook_log))
+        .route(
+            "/mod/{iid}/webhooks/{whid}/delete",
+            routing::post(mod_webhook_delete),
+        )
+        .route("/mod/{iid}/{pid}/lock", routing::post(post_lock))
+        .route("/mod/{iid}/{pid}/hide", routing::post(post_hide))
+        .route("/mod/{iid}/{pid}/pin", routing::post(post_pin))
+        .route("/mod/{iid}/{pid}/move", get(post_move).post(post_move_post))
+        .route(
+            "/mod/{iid}/{pid}/restore/{rev}",
+            routing::post(post_restore),
+        )
         .route("/inn/list", get(inn_list))
         .route("/inn/tag/{tag}", get(tag))
         .route("/inn/{i}", get(inn))
-        .route("/inn/{i}/join", get(inn_join))
+        .route("/inn/{i}/join", routing::post(inn_join))
         .route("/inn/{i}/atom.xml", get(inn_feed))
         .route("/post/{iid}/{pid}", get(post).post(comment_post))
-        .route("/post/{iid}/{pid}/{cid}/delete", get(comment_delete))
-        .route("/post/{iid}/{pid}/{cid}/hide", get(comment_hide))
+        .route(
+            "/post/{iid}/{pid}/{cid}/delete",
+            routing::post(comment_delete),
+        )
+        .route("/post/{iid}/{pid}/{cid}/hide", routing::post(comment_hide))
+        .route(
+            "/post/{iid}/{pid}/{cid}/edit",
+            get(comment_edit).post(comment_edit_post),
+        )
+        .route("/post/{iid}/{pid}/{cid}/history", get(comment_history))
         .route("/post/edit/{pid}", get(edit_post).post(edit_post_post))
-        .route("/post/{iid}/{pid}/upvote", get(post_upvote))
-        .route("/post/{iid}/{pid}/downvote", get(post_downvote))
-        .route("/post/{iid}/{pid}/delete", get(post_delete))
-        .route("/post/{iid}/{pid}/{cid}/upvote", get(comment_upvote))
-        .route("/post/{iid}/{pid}/{cid}/downvote", get(comment_downvote))
+        .route("/post/{iid}/{pid}/upvote", routing::post(post_upvote))
+        .route("/post/{iid}/{pid}/downvote", routing::post(post_downvote))
+        .route("/post/{iid}/{pid}/delete", routing::post(post_delete))
+        .route("/post/{iid}/{pid}/history", get(post_history))
+        .route(
+            "/post/{iid}/{pid}/{cid}/upvote",
+            routing::post(comment_upvote),
+        )
+        .route(
+            "/post/{iid}/{pid}/{cid}/downvote",
+            routing::post(comment_downvote),
+        )
         .route("/preview", get(post).post(preview))
+        .route("/report", get(report).post(report_post))
+        .route("/bookmark", routing::post(bookmark_post))
+        .route("/poll/new", get(poll_create).post(poll_create_post))
+        .route("/poll/vote", routing::post(poll_vote))
         .route("/solo/user/{u}", get(solo_list).post(solo_post))
-        .route("/solo/{sid}/like", get(solo_like))
-        .route("/solo/{sid}/delete", get(solo_delete))
+        .route("/solo/{sid}/like", routing::post(solo_like))
+        .route("/solo/{sid}/delete", routing::post(solo_delete))
         .route("/solo/{sid}", get(solo))
         .route(
             "/upload",
@@ -105,14 +214,19 @@ pub async fn router() -> Router {
         .route("/gallery/{uid}", get(gallery))
         .route("/feed/{uid}", get(feed))
         .route("/feed/add", get(feed_add).post(feed_add_post))
-        .route("/feed/update", get(feed_update))
-        .route("/feed/star/{item_id}", get(feed_star))
-        .route("/feed/subscribe/{uid}/{item_id}", get(feed_subscribe))
+        .route("/feed/update", routing::post(feed_update))
+        .route("/feed/star/{item_id}", routing::post(feed_star))
+        .route(
+            "/feed/subscribe/{uid}/{item_id}",
+            routing::post(feed_subscribe),
+        )
         .route("/feed/read/{item_id}", get(feed_read))
         .route("/search", get(search))
         .route("/message/{uid}", get(message).post(message_post))
         .route("/key", get(key).post(key_post))
-        .route("/inbox/{mid}", get(inbox));
+        .route("/inbox/{mid}", get(inbox))
+        .nest("/api/v1", router_api)
+        .layer(middleware::from_fn(bearer_auth));
 
     let router_static = Router::new()
         .route("/static/style.css", get(style))
@@ -19,6 +19,25 @@ pub struct Config {
     pub(crate) podcast_path: String,
     pub snapshots_path: String,
     pub(crate) proxy: String,
+    /// Header set by the reverse proxy with the client ip, e.g. `X-Real-IP`.
+    /// Only set it if freedit is always behind a proxy, otherwise clients can spoof their ip.
+    pub(crate) real_ip_header: Option<String>,
+    /// Outgoing email. Leave it out to disable email.
+    pub(crate) smtp: Option<SmtpConfig>,
+}
+
+/// SMTP server to send email through. For a local sink like mailpit in development, use
+/// port 1025 with `security = "none"`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SmtpConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    /// Sender of all emails, e.g. `freedit <noreply@example.com>`
+    pub(crate) from: String,
+    /// `tls`, `starttls` or `none`
+    pub(crate) security: String,
 }
 
 impl Config {
@@ -62,6 +81,8 @@ impl Default for Config {
             tantivy_path: "data/tantivy".into(),
             snapshots_path: "data/snapshots".into(),
             proxy: "".into(),
+            real_ip_header: None,
+            smtp: None,
         }
     }
 }
@@ -0,0 +1,497 @@
+//! ## Account
+//!
+//! Users can download their data as a tar archive of json files and uploaded images, and delete
+//! their account.
+//!
+//! A deleted account is kept as a banned user named `deleted_{uid}`, since its uid is referred to
+//! all over the db. Its private data is removed and it leaves the search index. Deleting can
+//! either anonymise the account, keeping its posts, comments and solos, or purge them too.
+
+use super::{
+    Claim, Comment, Feed, Item, Post, SiteConfig, Solo, User, UserEmail,
+    db_utils::{get_one, set_one, u8_slice_to_i64, u8_slice_to_u32, u32_to_ivec},
+    inn::{comment_rm, post_rm},
+    solo::solo_rm,
+    token::user_tokens,
+    user::{InnRole, Role, check_password, generate_password_hash, generate_salt},
+};
+use crate::{CONFIG, DB, error::AppError};
+use axum::{
+    Form,
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use fjall::{Batch, TransactionalKeyspace};
+use http::{HeaderMap, header};
+use identicon::Identicon;
+use jiff::Timestamp;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io::ErrorKind};
+use tokio::fs::{read, remove_file};
+
+#[derive(Serialize)]
+struct ExportUser {
+    uid: u32,
+    username: String,
+    created_at: i64,
+    role: u8,
+    url: String,
+    about: String,
+    lang: Option<String>,
+    pub_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportFeed {
+    folder: String,
+    title: String,
+    link: String,
+    is_public: bool,
+}
+
+#[derive(Serialize)]
+struct ExportStar {
+    title: String,
+    link: String,
+    starred_at: i64,
+}
+
+#[derive(Serialize)]
+struct ExportMessage {
+    mid: u32,
+    sender: u32,
+    /// the ciphertext as it was sent, only the private key of the user can decrypt it
+    message: String,
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, AppError> {
+    serde_json::to_vec_pretty(value).map_err(|e| AppError::Custom(e.to_string()))
+}
+
+fn append(tar: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) -> Result<(), AppError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Timestamp::now().as_second() as u64);
+    tar.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+/// `GET /user/export` download the data of the user as a tar archive
+pub(crate) async fn user_export(
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    let uid = claim.uid;
+    let uid_ivec = u32_to_ivec(uid);
+    let mut tar = tar::Builder::new(Vec::new());
+
+    let user: User = get_one(&DB, "users", uid)?;
+    let export_user = ExportUser {
+        uid,
+        username: user.username,
+        created_at: user.created_at,
+        role: user.role,
+        url: user.url,
+        about: user.about,
+        lang: user.lang,
+        pub_key: user.pub_key,
+    };
+    append(&mut tar, "user.json", &to_json(&export_user)?)?;
+
+    let mut posts = vec![];
+    for i in DB
+        .open_partition("user_posts", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (k, _) = i?;
+        let post: Post = get_one(&DB, "posts", u8_slice_to_u32(&k[4..8]))?;
+        posts.push(post);
+    }
+    append(&mut tar, "posts.json", &to_json(&posts)?)?;
+
+    let mut comments = vec![];
+    let comments_ks = DB.open_partition("post_comments", Default::default())?;
+    for i in DB
+        .open_partition("user_comments", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (k, _) = i?;
+        if let Some(v) = comments_ks.get(&k[4..12])? {
+            let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+            comments.push(comment);
+        }
+    }
+    append(&mut tar, "comments.json", &to_json(&comments)?)?;
+
+    let mut solos = vec![];
+    for i in DB
+        .open_partition("user_solos", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (k, _) = i?;
+        let solo: Solo = get_one(&DB, "solos", u8_slice_to_u32(&k[4..8]))?;
+        solos.push(solo);
+    }
+    append(&mut tar, "solos.json", &to_json(&solos)?)?;
+
+    let mut feeds = vec![];
+    for i in DB
+        .open_partition("user_folders", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (k, v) = i?;
+        let feed_id = u8_slice_to_u32(&k[k.len() - 4..]);
+        let feed: Feed = get_one(&DB, "feeds", feed_id)?;
+        feeds.push(ExportFeed {
+            folder: String::from_utf8_lossy(&k[4..k.len() - 4]).to_string(),
+            title: feed.title,
+            link: feed.link,
+            is_public: v[0] == 1,
+        });
+    }
+    append(&mut tar, "feeds.json", &to_json(&feeds)?)?;
+
+    let mut stars = vec![];
+    for i in DB
+        .open_partition("star", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (k, v) = i?;
+        let item: Item = get_one(&DB, "items", u8_slice_to_u32(&k[4..8]))?;
+        stars.push(ExportStar {
+            title: item.title,
+            link: item.link,
+            starred_at: u8_slice_to_i64(&v),
+        });
+    }
+    append(&mut tar, "stars.json", &to_json(&stars)?)?;
+
+    let mut messages = vec![];
+    let messages_ks = DB.open_partition("messages", Default::default())?;
+    for i in DB
+        .open_partition("user_message", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (k, _) = i?;
+        if let Some(v) = messages_ks.get(&k[4..8])? {
+            messages.push(ExportMessage {
+                mid: u8_slice_to_u32(&k[4..8]),
+                sender: u8_slice_to_u32(&v[4..8]),
+                message: String::from_utf8_lossy(&v[8..]).to_string(),
+            });
+        }
+    }
+    append(&mut tar, "messages.json", &to_json(&messages)?)?;
+
+    for i in DB
+        .open_partition("user_uploads", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+    {
+        let (_, v) = i?;
+        let img = String::from_utf8_lossy(&v);
+        if let Ok(data) = read(format!("{}/{}", CONFIG.upload_path, img)).await {
+            append(&mut tar, &format!("uploads/{img}"), &data)?;
+        }
+    }
+
+    let body = tar.into_inner()?;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "application/x-tar".parse().unwrap());
+    let disposition = format!("attachment; filename=\"freedit-{uid}.tar\"");
+    headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+    Ok((headers, body))
+}
+
+/// Form data: `/user/delete`
+#[derive(Deserialize)]
+pub(crate) struct FormDeleteAccount {
+    csrf_token: String,
+    password: String,
+    /// `anonymise` or `purge`
+    mode: String,
+}
+
+/// `POST /user/delete` delete the account of the user
+pub(crate) async fn user_delete_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(input): Form<FormDeleteAccount>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    let user: User = get_one(&DB, "users", claim.uid)?;
+    if !check_password(&input.password, &user.password_hash) {
+        return Err(AppError::WrongPassword);
+    }
+    if Role::from(user.role) == Role::Admin {
+        return Err(AppError::Custom(
+            "Admins must hand over the role before deleting their account".into(),
+        ));
+    }
+    let purge = match input.mode.as_str() {
+        "anonymise" => false,
+        "purge" => true,
+        _ => return Err(AppError::NotFound),
+    };
+
+    delete_account(&DB, user, purge).await?;
+
+    Ok(Redirect::to("/"))
+}
+
+/// remove all keys with the prefix in the batch
+fn remove_prefix(
+    db: &TransactionalKeyspace,
+    batch: &mut Batch,
+    tree: &str,
+    prefix: &[u8],
+) -> Result<(), AppError> {
+    let ks = db.inner().open_partition(tree, Default::default())?;
+    for i in ks.prefix(prefix) {
+        let (k, _) = i?;
+        batch.remove(&ks, k);
+    }
+    Ok(())
+}
+
+async fn delete_account(
+    db: &TransactionalKeyspace,
+    mut user: User,
+    purge: bool,
+) -> Result<(), AppError> {
+    let uid = user.uid;
+    let uid_ivec = u32_to_ivec(uid);
+
+    if purge {
+        // comments first, removing them puts their posts back in the timeline
+        for i in db
+            .open_partition("user_comments", Default::default())?
+            .inner()
+            .prefix(&uid_ivec)
+        {
+            let (k, _) = i?;
+            let pid = u8_slice_to_u32(&k[4..8]);
+            let cid = u8_slice_to_u32(&k[8..12]);
+            let post: Post = get_one(db, "posts", pid)?;
+            comment_rm(db, post.iid, pid, cid)?;
+        }
+
+        for i in db
+            .open_partition("user_posts", Default::default())?
+            .inner()
+            .prefix(&uid_ivec)
+        {
+            let (k, _) = i?;
+            let mut post: Post = get_one(db, "posts", u8_slice_to_u32(&k[4..8]))?;
+            post.title = "Deleted".into();
+            post_rm(db, &mut post)?;
+        }
+
+        for i in db
+            .open_partition("user_solos", Default::default())?
+            .inner()
+            .prefix(&uid_ivec)
+        {
+            let (k, _) = i?;
+            let solo: Solo = get_one(db, "solos", u8_slice_to_u32(&k[4..8]))?;
+            solo_rm(db, &solo)?;
+        }
+    }
+
+    // The rest of the account goes at once, files are removed after that.
+    let mut batch = db.inner().batch();
+
+    // When the same pictures uploaded, only one will be saved. So the file is only removed
+    // when no one else has uploaded it.
+    let uploads_ks = db
+        .inner()
+        .open_partition("user_uploads", Default::default())?;
+    let mut imgs = HashSet::new();
+    for i in uploads_ks.prefix(&uid_ivec) {
+        let (k, img) = i?;
+        batch.remove(&uploads_ks, k);
+        imgs.insert(img);
+    }
+    if purge && !imgs.is_empty() {
+        for i in uploads_ks.iter() {
+            let (k, img) = i?;
+            if !k.starts_with(&uid_ivec) {
+                imgs.remove(&img);
+            }
+        }
+    } else {
+        imgs.clear();
+    }
+
+    let following_ks = db
+        .inner()
+        .open_partition("user_following", Default::default())?;
+    let followers_ks = db
+        .inner()
+        .open_partition("user_followers", Default::default())?;
+    for i in following_ks.prefix(&uid_ivec) {
+        let (k, _) = i?;
+        batch.remove(&followers_ks, [&k[4..8], &uid_ivec].concat());
+        batch.remove(&following_ks, k);
+    }
+    for i in followers_ks.prefix(&uid_ivec) {
+        let (k, _) = i?;
+        batch.remove(&following_ks, [&k[4..8], &uid_ivec].concat());
+        batch.remove(&followers_ks, k);
+    }
+
+    // memberships of inns the user does not moderate
+    let user_inns_ks = db.inner().open_partition("user_inns", Default::default())?;
+    let inn_users_ks = db.inner().open_partition("inn_users", Default::default())?;
+    let inn_apply_ks = db.inner().open_partition("inn_apply", Default::default())?;
+    for i in user_inns_ks.prefix(&uid_ivec) {
+        let (k, _) = i?;
+        let iid = u8_slice_to_u32(&k[4..8]);
+        if InnRole::get(db, iid, uid)?.is_some_and(|role| role >= InnRole::Mod) {
+            continue;
+        }
+        let inn_users_k = [&k[4..8], &uid_ivec].concat();
+        batch.remove(&inn_users_ks, &*inn_users_k);
+        batch.remove(&inn_apply_ks, inn_users_k);
+        batch.remove(&user_inns_ks, k);
+    }
+
+    // the sessions of tokens go with the other sessions
+    let tokens_ks = db
+        .inner()
+        .open_partition("api_tokens", Default::default())?;
+    for token in user_tokens(db, uid)? {
+        batch.remove(&tokens_ks, token.tid);
+    }
+    let sessions_ks = db.inner().open_partition("sessions", Default::default())?;
+    for i in sessions_ks.iter() {
+        let (k, v) = i?;
+        if let Ok((claim, _)) = bincode::decode_from_slice::<Claim, _>(&v, standard())
+            && claim.uid == uid
+        {
+            batch.remove(&sessions_ks, k);
+        }
+    }
+
+    let bookmarks_ks = db.inner().open_partition("bookmarks", Default::default())?;
+    for i in db
+        .inner()
+        .open_partition("user_bookmarks", Default::default())?
+        .prefix(&uid_ivec)
+    {
+        let (k, _) = i?;
+        batch.remove(&bookmarks_ks, &k[4..8]);
+    }
+
+    let messages_ks = db.inner().open_partition("messages", Default::default())?;
+    for i in db
+        .inner()
+        .open_partition("user_message", Default::default())?
+        .prefix(&uid_ivec)
+    {
+        let (k, _) = i?;
+        batch.remove(&messages_ks, &k[4..8]);
+    }
+
+    let user_emails_ks = db
+        .inner()
+        .open_partition("user_emails", Default::default())?;
+    if let Some(v) = user_emails_ks.get(&uid_ivec)? {
+        let (user_email, _): (UserEmail, usize) = bincode::decode_from_slice(&v, standard())?;
+        let email_uids_ks = db
+            .inner()
+            .open_partition("email_uids", Default::default())?;
+        if email_uids_ks
+            .get(&user_email.address)?
+            .is_some_and(|v| v[..] == uid_ivec[..])
+        {
+            batch.remove(&email_uids_ks, user_email.address);
+        }
+    }
+
+    for tree in [
+        "user_bookmarks",
+        "user_bookmark_targets",
+        "user_message",
+        "user_folders",
+        "read",
+        "star",
+        "notifications",
+        "user_blocks",
+        "user_mutes",
+        "drafts",
+    ] {
+        remove_prefix(db, &mut batch, tree, &uid_ivec)?;
+    }
+    for tree in [
+        "user_emails",
+        "user_totp",
+        "bookmark_tokens",
+        "pending_signups",
+    ] {
+        let ks = db.inner().open_partition(tree, Default::default())?;
+        batch.remove(&ks, &*uid_ivec);
+    }
+
+    let usernames_ks = db.inner().open_partition("usernames", Default::default())?;
+    batch.remove(
+        &usernames_ks,
+        user.username.replace(' ', "_").to_lowercase(),
+    );
+    user.username = format!("deleted_{uid}");
+    batch.insert(&usernames_ks, &*user.username, &*uid_ivec);
+
+    user.password_hash = generate_password_hash(&nanoid!());
+    user.recovery_hash = None;
+    user.role = Role::Banned as u8;
+    user.url = String::new();
+    user.about = String::new();
+    user.lang = None;
+    user.pub_key = None;
+    let users_ks = db.inner().open_partition("users", Default::default())?;
+    batch.insert(
+        &users_ks,
+        &*uid_ivec,
+        bincode::encode_to_vec(&user, standard())?,
+    );
+
+    // search leaves out deleted users at once, their docs are deleted when the search index is
+    // opened next time
+    let deleted_ks = db
+        .inner()
+        .open_partition("deleted_users", Default::default())?;
+    batch.insert(&deleted_ks, &*uid_ivec, [purge as u8]);
+    let tan_deleted_ks = db
+        .inner()
+        .open_partition("tan_deleted_users", Default::default())?;
+    batch.insert(&tan_deleted_ks, &*uid_ivec, []);
+    batch.commit()?;
+
+    for img in imgs {
+        let img = String::from_utf8_lossy(&img);
+        if let Err(e) = remove_file(format!("{}/{}", CONFIG.upload_path, img)).await
+            && e.kind() != ErrorKind::NotFound
+        {
+            return Err(e.into());
+        }
+    }
+
+    let avatar = format!("{}/{}.png", CONFIG.avatars_path, uid);
+    Identicon::new(&generate_salt()).image().save(avatar)?;
+
+    Ok(())
+}
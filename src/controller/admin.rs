@@ -1,10 +1,10 @@
 use super::{
-    Claim, Comment, Feed, FormPost, Inn, Item, Post, SiteConfig, Solo, User,
+    Claim, Comment, Feed, FormPost, Inn, Item, Post, Shadowban, SiteConfig, Solo, User,
     db_utils::{IterType, get_range, ivec_to_u32, set_one_with_key, u8_slice_to_u32},
     filters,
     fmt::{clean_html, ts_to_date},
     inn::ParamsTag,
-    meta_handler::{PageData, ParamsPage, ValidatedForm, into_response},
+    meta_handler::{CsrfForm, PageData, ParamsPage, into_response},
     user::Role,
 };
 use crate::{DB, error::AppError};
@@ -225,6 +225,15 @@ pub(crate) async fn admin_view(
                         .unwrap();
                     ones.push(format!("timestamp: {time_stamp}"));
                 }
+                "login_failures" => {
+                    let mut k_str = std::str::from_utf8(&k)?.splitn(3, '_');
+                    let timestamp = i64::from_str_radix(k_str.next().unwrap(), 16).unwrap();
+                    let date = ts_to_date(timestamp);
+                    let scope = k_str.next().unwrap();
+                    let id = k_str.next().unwrap();
+                    let count = ivec_to_u32(&v);
+                    ones.push(format!("{date} - {scope} - {id} - {count}"));
+                }
                 "post_timeline" => {
                     let timestamp = i64::from(u8_slice_to_u32(&k[0..4]));
                     let date = ts_to_date(timestamp);
@@ -252,6 +261,16 @@ pub(crate) async fn admin_view(
                     let one_fmt = unescape(&format!("{one:?}")).unwrap();
                     ones.push(format!("{key}: {one_fmt}"));
                 }
+                "shadowbans" => {
+                    let key = ivec_to_u32(&k);
+                    let (one, _): (Shadowban, usize) = bincode::decode_from_slice(&v, standard())?;
+                    ones.push(format!("{key}: {one:?}"));
+                }
+                "shadowban_posts" => {
+                    let pid = u8_slice_to_u32(&k);
+                    let uid = u8_slice_to_u32(&v);
+                    ones.push(format!("{pid}: {uid}"));
+                }
                 "feed_errs" => {
                     let id = ivec_to_u32(&k);
                     let msg = String::from_utf8_lossy(&v);
@@ -259,7 +278,7 @@ pub(crate) async fn admin_view(
                 }
                 "drafts" => {
                     let uid = u8_slice_to_u32(&k[0..4]);
-                    let (draft, _): (FormPost, usize) = bincode::decode_from_slice(&v, standard())?;
+                    let draft = FormPost::decode_draft(&v)?;
                     ones.push(format!("{uid}: {draft:?}"));
                 }
                 "items" => {
@@ -329,10 +348,11 @@ pub(crate) async fn admin(
 /// `POST /admin`
 pub(crate) async fn admin_post(
     cookie: Option<TypedHeader<Cookie>>,
-    ValidatedForm(input): ValidatedForm<SiteConfig>,
+    CsrfForm(csrf_token, input): CsrfForm<SiteConfig>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let claim = Claim::get(&DB, &cookie, &input).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&csrf_token)?;
     if Role::from(claim.role) != Role::Admin {
         return Err(AppError::Unauthorized);
     }
@@ -382,6 +402,9 @@ impl Default for SiteConfig {
             tos_link: "https://en.wikipedia.org/wiki/WTFPL".into(),
             custom_footer_code: None,
             login_captcha: false,
+            spam_threshold: 0,
+            registration: 0,
+            invite_role: 255,
         }
     }
 }
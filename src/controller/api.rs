@@ -0,0 +1,460 @@
+//! ## JSON API
+//!
+//! `/api/v1` mirrors the routes of the pages: the same paths answer with JSON instead of HTML,
+//! e.g. `GET /api/v1/post/1/2` is the post page `/post/1/2`. Responses are built from the same
+//! `Out*` data and go through the same permission checks as the pages.
+//!
+//! Requests are signed in by the session cookie or by a personal API token, see
+//! [super::token]. Write endpoints take a JSON body with the fields of the page form and answer
+//! with the location of the new content. Errors are `{"error": "..."}` with the status of the
+//! error page.
+//!
+//! The OpenAPI document is served at `/api/v1/openapi.json`.
+
+use super::{
+    Claim, FormPost, SiteConfig, User,
+    db_utils::{get_id_by_name, get_one},
+    feed::{FeedView, ParamsFeed, get_feed_view},
+    inn::{
+        FormComment, OutInnList, OutPostList, ParamsInn, ParamsInnList, ParamsPost, PostLookup,
+        PostView, comment_post, edit_post_post, get_inn_posts, get_out_inn_list, get_post_view,
+    },
+    meta_handler::{CsrfForm, ParamsPage},
+    notification::{Notification, get_notifications},
+    solo::{FormSolo, OutSolo, ParamsSolo, SoloView, get_out_solos, get_solo_view, solo_post},
+    tantivy::{OutSearch, ParamsSearch, get_out_searches},
+    user::OutUser,
+};
+use crate::{DB, error::AppError};
+use axum::{
+    Json,
+    extract::{Path, Query},
+    http::{StatusCode, header::LOCATION},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::{
+    IntoParams, Modify, OpenApi, ToSchema,
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+use validator::Validate;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "freedit", description = "JSON API of freedit"),
+    paths(
+        inn_list,
+        inn,
+        post,
+        post_edit,
+        comment,
+        solo_list,
+        solo,
+        solo_create,
+        user,
+        notification,
+        feed,
+        search,
+    ),
+    components(schemas(ApiErrorBody, Created)),
+    modifiers(&BearerToken),
+    security(("token" = []))
+)]
+struct ApiDoc;
+
+struct BearerToken;
+
+impl Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+/// `GET /api/v1/openapi.json`
+pub(crate) async fn openapi() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiErrorBody {
+    error: String,
+}
+
+/// [AppError] answered as JSON
+pub(crate) struct ApiError(AppError);
+
+impl<E: Into<AppError>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        ApiError(e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.0.status_code();
+        error!("{}, {}", status, self.0);
+        let body = ApiErrorBody {
+            error: self.0.to_string(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+pub(crate) async fn handler_404() -> ApiError {
+    ApiError(AppError::NotFound)
+}
+
+/// Where the written content can be found
+#[derive(Serialize, ToSchema)]
+struct Created {
+    location: String,
+}
+
+/// The form handlers redirect to the written content, turn it into [Created]. The status is 201
+/// only if `is_new`, edits and drafts are answered with 200.
+fn created(res: Result<impl IntoResponse, AppError>, is_new: bool) -> Result<Response, ApiError> {
+    let res = res?.into_response();
+    let location = res
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let status = if is_new {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(Created { location })).into_response())
+}
+
+fn claim(cookie: Option<&TypedHeader<Cookie>>) -> Result<Option<Claim>, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    Ok(cookie.and_then(|cookie| Claim::get(&DB, cookie, &site_config)))
+}
+
+/// Other sites can not send JSON bodies without CORS, so write endpoints pass the CSRF token of
+/// the session on to the page handlers.
+fn session_csrf(cookie: Option<&TypedHeader<Cookie>>) -> Result<String, AppError> {
+    Ok(claim(cookie)?
+        .map(|claim| claim.csrf_token())
+        .unwrap_or_default())
+}
+
+fn page_params(anchor: Option<usize>, is_desc: Option<bool>) -> Result<ParamsPage, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    Ok(ParamsPage {
+        anchor: anchor.unwrap_or(0),
+        n: site_config.per_page,
+        is_desc: is_desc.unwrap_or(true),
+    })
+}
+
+/// inn or user, by id or by name
+fn id_by_name(tree: &str, name: &str) -> Result<u32, AppError> {
+    match name.parse::<u32>() {
+        Ok(id) => Ok(id),
+        Err(_) => get_id_by_name(&DB, tree, name)?.ok_or(AppError::NotFound),
+    }
+}
+
+/// List inns
+#[utoipa::path(
+    get,
+    path = "/api/v1/inn/list",
+    params(ParamsInnList),
+    responses((status = 200, body = Vec<OutInnList>))
+)]
+pub(crate) async fn inn_list(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsInnList>,
+) -> Result<Json<Vec<OutInnList>>, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    let page_params = page_params(params.anchor, params.is_desc)?;
+    let inns = get_out_inn_list(
+        claim.as_ref(),
+        params.topic.as_deref(),
+        params.filter.as_deref(),
+        &page_params,
+    )?;
+    Ok(Json(inns))
+}
+
+/// Posts of an inn, of all inns if `i` is 0
+#[utoipa::path(
+    get,
+    path = "/api/v1/inn/{i}",
+    params(("i" = String, Path, description = "iid or inn name"), ParamsInn),
+    responses((status = 200, body = Vec<OutPostList>), (status = 404, body = ApiErrorBody))
+)]
+pub(crate) async fn inn(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(i): Path<String>,
+    Query(params): Query<ParamsInn>,
+) -> Result<Json<Vec<OutPostList>>, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    let iid = id_by_name("inn_names", &i)?;
+    let page_params = page_params(params.anchor, params.is_desc)?;
+    let posts = get_inn_posts(claim.as_ref(), iid, params.filter.as_deref(), &page_params)?;
+    Ok(Json(posts.posts))
+}
+
+/// A post with a page of its comments. Moved posts are redirected to their new place.
+#[utoipa::path(
+    get,
+    path = "/api/v1/post/{iid}/{pid}",
+    params(("iid" = u32, Path), ("pid" = u32, Path), ParamsPost),
+    responses(
+        (status = 200, body = PostView),
+        (status = 303, description = "the post has been moved"),
+        (status = 404, body = ApiErrorBody)
+    )
+)]
+pub(crate) async fn post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Query(params): Query<ParamsPost>,
+) -> Result<Response, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    let n = SiteConfig::get(&DB)?.per_page;
+    match get_post_view(claim.as_ref(), iid, pid, &params, n)? {
+        PostLookup::Moved(target) => Ok(Redirect::to(&format!("/api/v1{target}")).into_response()),
+        PostLookup::Found(view) => Ok(Json(view).into_response()),
+    }
+}
+
+/// Create a post if `pid` is 0, or edit it
+#[utoipa::path(
+    post,
+    path = "/api/v1/post/edit/{pid}",
+    params(("pid" = u32, Path)),
+    request_body = FormPost,
+    responses(
+        (status = 201, body = Created),
+        (status = 200, body = Created, description = "the post is edited, or the draft is saved or deleted"),
+        (status = 400, body = ApiErrorBody)
+    )
+)]
+pub(crate) async fn post_edit(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(pid): Path<u32>,
+    Json(input): Json<FormPost>,
+) -> Result<Response, ApiError> {
+    input.validate()?;
+    let is_new = pid == 0 && input.is_draft != Some(true) && input.delete_draft != Some(true);
+    let csrf_token = session_csrf(cookie.as_ref())?;
+    created(
+        edit_post_post(cookie, Path(pid), CsrfForm(csrf_token, input)).await,
+        is_new,
+    )
+}
+
+/// Comment on a post
+#[utoipa::path(
+    post,
+    path = "/api/v1/post/{iid}/{pid}",
+    params(("iid" = u32, Path), ("pid" = u32, Path)),
+    request_body = FormComment,
+    responses((status = 201, body = Created), (status = 400, body = ApiErrorBody))
+)]
+pub(crate) async fn comment(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Json(input): Json<FormComment>,
+) -> Result<Response, ApiError> {
+    input.validate()?;
+    let csrf_token = session_csrf(cookie.as_ref())?;
+    created(
+        comment_post(cookie, Path((iid, pid)), CsrfForm(csrf_token, input)).await,
+        true,
+    )
+}
+
+/// Solos of a user, of all users if `u` is 0
+#[utoipa::path(
+    get,
+    path = "/api/v1/solo/user/{u}",
+    params(("u" = String, Path, description = "uid or username"), ParamsSolo),
+    responses((status = 200, body = Vec<OutSolo>))
+)]
+pub(crate) async fn solo_list(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(u): Path<String>,
+    Query(params): Query<ParamsSolo>,
+) -> Result<Json<Vec<OutSolo>>, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    let uid = id_by_name("usernames", &u)?;
+    let page_params = page_params(params.anchor, params.is_desc)?;
+    let solos = get_out_solos(
+        claim.as_ref(),
+        uid,
+        params.filter.as_deref(),
+        params.hashtag.as_deref(),
+        &page_params,
+    )?;
+    Ok(Json(solos))
+}
+
+/// A solo with its replies
+#[utoipa::path(
+    get,
+    path = "/api/v1/solo/{sid}",
+    params(("sid" = u32, Path)),
+    responses((status = 200, body = SoloView), (status = 404, body = ApiErrorBody))
+)]
+pub(crate) async fn solo(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(sid): Path<u32>,
+) -> Result<Json<SoloView>, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    Ok(Json(get_solo_view(claim.as_ref(), sid)?))
+}
+
+/// Post a solo, or reply to one by `reply_to`. It is always posted by the signed in user.
+#[utoipa::path(
+    post,
+    path = "/api/v1/solo/user/{u}",
+    params(("u" = String, Path, description = "uid or username")),
+    request_body = FormSolo,
+    responses((status = 201, body = Created), (status = 400, body = ApiErrorBody))
+)]
+pub(crate) async fn solo_create(
+    cookie: Option<TypedHeader<Cookie>>,
+    Json(input): Json<FormSolo>,
+) -> Result<Response, ApiError> {
+    input.validate()?;
+    let csrf_token = session_csrf(cookie.as_ref())?;
+    created(solo_post(cookie, CsrfForm(csrf_token, input)).await, true)
+}
+
+/// Profile of a user
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{u}",
+    params(("u" = String, Path, description = "uid or username")),
+    responses((status = 200, body = OutUser), (status = 404, body = ApiErrorBody))
+)]
+pub(crate) async fn user(Path(u): Path<String>) -> Result<Json<OutUser>, ApiError> {
+    let uid = id_by_name("usernames", &u)?;
+    let user: User = get_one(&DB, "users", uid)?;
+    Ok(Json(OutUser::from(user)))
+}
+
+/// url params: `/api/v1/notification`
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ParamsNotification {
+    anchor: Option<usize>,
+}
+
+/// Notifications of the signed in user, newest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/notification",
+    params(ParamsNotification),
+    responses((status = 200, body = Vec<Notification>), (status = 401, body = ApiErrorBody))
+)]
+pub(crate) async fn notification(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsNotification>,
+) -> Result<Json<Vec<Notification>>, ApiError> {
+    let claim = claim(cookie.as_ref())?.ok_or(AppError::NonLogin)?;
+    let n = SiteConfig::get(&DB)?.per_page;
+    let notifications = get_notifications(claim.uid, params.anchor.unwrap_or(0), n)?;
+    Ok(Json(notifications))
+}
+
+/// Feeds of a user with a page of their items
+#[utoipa::path(
+    get,
+    path = "/api/v1/feed/{uid}",
+    params(("uid" = u32, Path), ParamsFeed),
+    responses((status = 200, body = FeedView), (status = 404, body = ApiErrorBody))
+)]
+pub(crate) async fn feed(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(uid): Path<u32>,
+    Query(params): Query<ParamsFeed>,
+) -> Result<Json<FeedView>, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    let n = SiteConfig::get(&DB)?.per_page;
+    Ok(Json(get_feed_view(claim.as_ref(), uid, &params, n)?))
+}
+
+/// Full-text search
+#[utoipa::path(
+    get,
+    path = "/api/v1/search",
+    params(ParamsSearch),
+    responses((status = 200, body = Vec<OutSearch>))
+)]
+pub(crate) async fn search(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsSearch>,
+) -> Result<Json<Vec<OutSearch>>, ApiError> {
+    let claim = claim(cookie.as_ref())?;
+    Ok(Json(get_out_searches(claim.as_ref(), &params)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::token::{required_scope, scope_bit};
+    use axum::http::Method;
+
+    #[test]
+    fn test_openapi() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        assert_eq!(doc["paths"].as_object().unwrap().len(), 10);
+        assert!(doc["components"]["securitySchemes"]["token"].is_object());
+        assert!(doc["paths"]["/api/v1/post/edit/{pid}"]["post"]["responses"]["201"].is_object());
+    }
+
+    #[test]
+    fn test_api_scopes() {
+        let mut scopes = Vec::new();
+        for (path, item) in &ApiDoc::openapi().paths.paths {
+            // path parameters are ids or names
+            let path: Vec<&str> = path
+                .split('/')
+                .map(|s| if s.starts_with('{') { "1" } else { s })
+                .collect();
+            let path = path.join("/");
+            for (method, op) in [(Method::GET, &item.get), (Method::POST, &item.post)] {
+                if op.is_some() {
+                    scopes.push((
+                        method.to_string(),
+                        path.clone(),
+                        required_scope(&method, &path),
+                    ));
+                }
+            }
+        }
+        scopes.sort();
+
+        let mut expected = [
+            ("GET", "/api/v1/inn/list", "read"),
+            ("GET", "/api/v1/inn/1", "read"),
+            ("GET", "/api/v1/post/1/1", "read"),
+            ("POST", "/api/v1/post/1/1", "comment"),
+            ("POST", "/api/v1/post/edit/1", "post"),
+            ("GET", "/api/v1/solo/user/1", "read"),
+            ("POST", "/api/v1/solo/user/1", "solo"),
+            ("GET", "/api/v1/solo/1", "read"),
+            ("GET", "/api/v1/user/1", "read"),
+            ("GET", "/api/v1/notification", "read"),
+            ("GET", "/api/v1/feed/1", "read"),
+            ("GET", "/api/v1/search", "read"),
+        ]
+        .map(|(method, path, scope)| (method.to_owned(), path.to_owned(), Some(scope_bit(scope))));
+        expected.sort();
+        assert_eq!(scopes, expected);
+    }
+}
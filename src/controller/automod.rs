@@ -0,0 +1,321 @@
+//! ## Automod
+//!
+//! Mods can set up automod rules for their inn, which are checked in order whenever a post or a
+//! comment is written or edited. The first matching rule decides what happens to it. Every action
+//! but `Reject` is recorded in the mod log under the name "automod", with uid 0.
+//!
+//! Compiled regexes of automod rules, inn rules and the site spam regex are cached, so they are
+//! not rebuilt on each request.
+
+use super::{
+    AutomodAction, AutomodRule, Claim, ModAction, ModLog, ModTarget, Report, ReportTarget,
+    SiteConfig, User,
+    db_utils::{set_one, u8_slice_to_u32, u32_to_ivec},
+    meta_handler::ValidatedForm,
+    notification::{NtType, add_notification},
+    user::{InnRole, Role},
+};
+use crate::{DB, error::AppError};
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use cached::cached;
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::BTreeSet, sync::LazyLock};
+use validator::Validate;
+
+static LINK_HOST: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)https?://([a-z0-9.-]+)").unwrap());
+
+/// `None` if the pattern is not a valid regex
+#[cached(size = 256)]
+pub(super) fn cached_regex(pattern: String) -> Option<Regex> {
+    Regex::new(&pattern).ok()
+}
+
+fn is_match(pattern: &str, text: &str) -> bool {
+    cached_regex(pattern.to_owned()).is_some_and(|re| re.is_match(text))
+}
+
+/// Reject the texts if any of them matches the site spam regex
+pub(super) fn check_spam(site_config: &SiteConfig, texts: &[&str]) -> Result<(), AppError> {
+    if let Some(ref spam_regex) = site_config.spam_regex
+        && texts.iter().any(|text| is_match(spam_regex, text))
+    {
+        return Err(AppError::Custom("Spam detected".into()));
+    }
+    Ok(())
+}
+
+/// What is written, checked against the automod rules
+pub(super) struct AutomodInput<'a> {
+    /// `None` for comments
+    pub(super) title: Option<&'a str>,
+    pub(super) content: &'a str,
+    pub(super) tags: &'a BTreeSet<String>,
+}
+
+impl AutomodRule {
+    pub(super) fn get_all(db: &TransactionalKeyspace, iid: u32) -> Result<Vec<Self>, AppError> {
+        match db
+            .open_partition("inn_automod", Default::default())?
+            .get(u32_to_ivec(iid))?
+        {
+            Some(v) => {
+                let (rules, _): (Vec<Self>, usize) = bincode::decode_from_slice(&v, standard())?;
+                Ok(rules)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn matches(&self, input: &AutomodInput, user: &User, inn_role: &InnRole, now: i64) -> bool {
+        if !self.title.is_empty() && !input.title.is_some_and(|t| is_match(&self.title, t)) {
+            return false;
+        }
+        if !self.body.is_empty() && !is_match(&self.body, input.content) {
+            return false;
+        }
+        if !self.tags.is_empty() && !input.tags.iter().any(|t| is_match(&self.tags, t)) {
+            return false;
+        }
+        if !self.domains.is_empty() {
+            let has_domain = LINK_HOST.captures_iter(input.content).any(|cap| {
+                let host = cap[1].to_lowercase();
+                self.domains
+                    .iter()
+                    .any(|d| host == *d || host.ends_with(&format!(".{d}")))
+            });
+            if !has_domain {
+                return false;
+            }
+        }
+        if self.account_days > 0 && now - user.created_at >= self.account_days as i64 * 24 * 3600 {
+            return false;
+        }
+        if self.below_role > 0 && *inn_role >= InnRole::from(self.below_role) {
+            return false;
+        }
+        true
+    }
+
+    /// a short description of the conditions for `inn_edit.html`
+    pub(super) fn desc(&self) -> String {
+        let mut conds = vec![];
+        if !self.title.is_empty() {
+            conds.push(format!("title ~ {}", self.title));
+        }
+        if !self.body.is_empty() {
+            conds.push(format!("body ~ {}", self.body));
+        }
+        if !self.tags.is_empty() {
+            conds.push(format!("tag ~ {}", self.tags));
+        }
+        if !self.domains.is_empty() {
+            conds.push(format!("links to {}", self.domains.join(", ")));
+        }
+        if self.account_days > 0 {
+            conds.push(format!("account < {} days", self.account_days));
+        }
+        if self.below_role > 0 {
+            conds.push(format!("role < {}", InnRole::from(self.below_role)));
+        }
+        conds.join(" & ")
+    }
+}
+
+/// Check what is written against the automod rules of the inn. Returns the number of the first
+/// matching rule, starting from 1, and its action. `Reject` is returned as an error.
+///
+/// `Lock` rules only apply to posts.
+pub(super) fn automod_check(
+    iid: u32,
+    input: &AutomodInput,
+    user: &User,
+    inn_role: &InnRole,
+) -> Result<Option<(usize, AutomodAction)>, AppError> {
+    let now = Timestamp::now().as_second();
+    for (idx, rule) in AutomodRule::get_all(&DB, iid)?.iter().enumerate() {
+        if rule.action == AutomodAction::Lock && input.title.is_none() {
+            continue;
+        }
+        if rule.matches(input, user, inn_role, now) {
+            let n = idx + 1;
+            if rule.action == AutomodAction::Reject {
+                return Err(AppError::Custom(format!(
+                    "Rejected by the automod of this inn (rule {n})"
+                )));
+            }
+            return Ok(Some((n, rule.action)));
+        }
+    }
+    Ok(None)
+}
+
+/// Record what automod did with a post (cid is 0) or a comment, after it has been saved with the
+/// status the action asks for. New posts held for review are in the queue and need no record.
+pub(super) fn automod_apply(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    pid: u32,
+    cid: u32,
+    rule: usize,
+    action: AutomodAction,
+) -> Result<(), AppError> {
+    let reason = format!("Automod rule {rule}");
+    let (mod_target, report_target) = if cid == 0 {
+        (ModTarget::Post(pid), ReportTarget::Post(pid))
+    } else {
+        (
+            ModTarget::Comment(pid, cid),
+            ReportTarget::Comment(pid, cid),
+        )
+    };
+
+    match action {
+        AutomodAction::Reject => {}
+        AutomodAction::Hold => {
+            ModLog::add(
+                db,
+                0,
+                iid,
+                mod_target,
+                ModAction::Hide,
+                Some(reason.clone()),
+            )?;
+            let reason = format!("{reason}: held for review");
+            Report::add(db, 0, iid, report_target, reason)?;
+        }
+        AutomodAction::Hide => {
+            ModLog::add(db, 0, iid, mod_target, ModAction::Hide, Some(reason))?;
+        }
+        AutomodAction::Lock => {
+            ModLog::add(db, 0, iid, mod_target, ModAction::Lock, Some(reason))?;
+        }
+        AutomodAction::NotifyMods => {
+            for i in db
+                .open_partition("inn_users", Default::default())?
+                .inner()
+                .prefix(u32_to_ivec(iid))
+            {
+                let (k, v) = i?;
+                if v[0] >= InnRole::Mod as u8 {
+                    let uid = u8_slice_to_u32(&k[4..8]);
+                    add_notification(db, uid, NtType::Automod, pid, cid)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Form data: `/mod/automod/:iid` add or delete an automod rule
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormAutomod {
+    csrf_token: String,
+    /// number of the rule to delete, starting from 1
+    delete: Option<usize>,
+    #[validate(length(max = 256))]
+    title: Option<String>,
+    #[validate(length(max = 256))]
+    body: Option<String>,
+    #[validate(length(max = 256))]
+    tags: Option<String>,
+    /// separated by spaces or commas
+    #[validate(length(max = 1024))]
+    domains: Option<String>,
+    account_days: Option<u32>,
+    below_role: Option<u8>,
+    action: Option<String>,
+}
+
+/// `POST /mod/automod/:iid` add or delete an automod rule
+pub(crate) async fn mod_automod_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    ValidatedForm(input): ValidatedForm<FormAutomod>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+    if !DB
+        .open_partition("inns", Default::default())?
+        .contains_key(u32_to_ivec(iid))?
+    {
+        return Err(AppError::NotFound);
+    }
+
+    let mut rules = AutomodRule::get_all(&DB, iid)?;
+    if let Some(n) = input.delete {
+        if n == 0 || n > rules.len() {
+            return Err(AppError::NotFound);
+        }
+        rules.remove(n - 1);
+    } else {
+        if rules.len() >= 50 {
+            return Err(AppError::Custom(
+                "An inn can have at most 50 automod rules".into(),
+            ));
+        }
+
+        let action = match input.action.as_deref() {
+            Some("reject") => AutomodAction::Reject,
+            Some("hold") => AutomodAction::Hold,
+            Some("hide") => AutomodAction::Hide,
+            Some("lock") => AutomodAction::Lock,
+            Some("notify") => AutomodAction::NotifyMods,
+            _ => return Err(AppError::Custom("Invalid automod action".into())),
+        };
+        let below_role = input.below_role.unwrap_or_default();
+        if ![0, 4, 5, 7].contains(&below_role) {
+            return Err(AppError::Custom("Invalid inn role".into()));
+        }
+
+        let title = input.title.unwrap_or_default().trim().to_owned();
+        let body = input.body.unwrap_or_default().trim().to_owned();
+        let tags = input.tags.unwrap_or_default().trim().to_owned();
+        for pattern in [&title, &body, &tags] {
+            if let Err(e) = Regex::new(pattern) {
+                return Err(AppError::Custom(e.to_string()));
+            }
+        }
+        let domains: Vec<String> = input
+            .domains
+            .unwrap_or_default()
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .map(|d| d.trim_start_matches("*.").trim_matches('.').to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+
+        let rule = AutomodRule {
+            title,
+            body,
+            tags,
+            domains,
+            account_days: input.account_days.unwrap_or_default(),
+            below_role,
+            action,
+        };
+        if rule.desc().is_empty() {
+            return Err(AppError::Custom(
+                "An automod rule needs at least one condition".into(),
+            ));
+        }
+        rules.push(rule);
+    }
+    set_one(&DB, "inn_automod", iid, &rules)?;
+
+    let target = format!("/mod/{iid}");
+    Ok(Redirect::to(&target))
+}
@@ -0,0 +1,105 @@
+//! ## Block and mute
+//!
+//! Users can block or mute anyone but themselves. A blocked user can not send messages to the
+//! blocker, reply to their solos, or notify them by @mentions. Content of muted users is still
+//! listed, but collapsed in timelines and on post pages.
+//!
+//! Both lists are private, the user sees them at `/user/list?filter=blocked` and
+//! `/user/list?filter=muted`.
+
+use super::{
+    Claim, SiteConfig, User,
+    db_utils::{get_id_by_name, get_ids_by_prefix, get_one, u32_to_ivec},
+    meta_handler::FormCsrf,
+};
+use crate::{DB, error::AppError};
+use axum::{
+    Form,
+    extract::Path,
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use fjall::TransactionalKeyspace;
+use std::collections::HashSet;
+
+/// whether `uid` is blocked by `by`
+pub(super) fn is_blocked(db: &TransactionalKeyspace, by: u32, uid: u32) -> Result<bool, AppError> {
+    let k = [u32_to_ivec(by), u32_to_ivec(uid)].concat();
+    Ok(db
+        .open_partition("user_blocks", Default::default())?
+        .contains_key(k)?)
+}
+
+pub(super) fn is_muted(db: &TransactionalKeyspace, by: u32, uid: u32) -> Result<bool, AppError> {
+    let k = [u32_to_ivec(by), u32_to_ivec(uid)].concat();
+    Ok(db
+        .open_partition("user_mutes", Default::default())?
+        .contains_key(k)?)
+}
+
+/// Users muted by the viewer, none for guests
+pub(super) fn muted_uids(
+    db: &TransactionalKeyspace,
+    claim: Option<&Claim>,
+) -> Result<HashSet<u32>, AppError> {
+    let Some(claim) = claim else {
+        return Ok(HashSet::new());
+    };
+    Ok(
+        get_ids_by_prefix(db, "user_mutes", u32_to_ivec(claim.uid), None)?
+            .into_iter()
+            .collect(),
+    )
+}
+
+/// add the user `u`, a uid or username, to the list of the user or remove it. Returns the uid.
+fn toggle(tree: &str, claim: &Claim, u: &str) -> Result<u32, AppError> {
+    let uid = match u.parse::<u32>() {
+        Ok(uid) => uid,
+        Err(_) => get_id_by_name(&DB, "usernames", u)?.ok_or(AppError::NotFound)?,
+    };
+    if uid == claim.uid {
+        return Err(AppError::Custom(
+            "You can not block or mute yourself".into(),
+        ));
+    }
+    let _: User = get_one(&DB, "users", uid)?;
+    let ks = DB.open_partition(tree, Default::default())?;
+    let k = [u32_to_ivec(claim.uid), u32_to_ivec(uid)].concat();
+    if ks.contains_key(&k)? {
+        ks.remove(k)?;
+    } else {
+        ks.insert(k, [])?;
+    }
+    Ok(uid)
+}
+
+/// `POST /user/:u/block` block a user or unblock it
+pub(crate) async fn user_block(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(u): Path<String>,
+    Form(form): Form<FormCsrf>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    let uid = toggle("user_blocks", &claim, &u)?;
+    Ok(Redirect::to(&format!("/user/{uid}")))
+}
+
+/// `POST /user/:u/mute` mute a user or unmute it
+pub(crate) async fn user_mute(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(u): Path<String>,
+    Form(form): Form<FormCsrf>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    let uid = toggle("user_mutes", &claim, &u)?;
+    Ok(Redirect::to(&format!("/user/{uid}")))
+}
@@ -0,0 +1,512 @@
+//! ## Bookmark
+//!
+//! Members can bookmark posts, comments and solos, and sort them into their own collections.
+//! Bookmarks are private. They can be followed with an Atom feed whose url contains a secret
+//! token, which can be regenerated at any time.
+
+use super::{
+    Bookmark, Claim, Inn, InnType, Post, PostStatus, ReportTarget, SiteConfig, Solo, User,
+    db_utils::{get_ids_by_prefix, get_one, incr_id, set_one, u8_slice_to_u32, u32_to_ivec},
+    filters,
+    fmt::ts_to_date,
+    inn::{is_pending_for, is_scheduled_for},
+    meta_handler::{
+        FormCsrf, PageData, ParamsPage, ValidatedForm, get_referer, into_response,
+        into_response_with_content_type,
+    },
+    report::get_comment,
+    solo::can_visit_solo_by,
+};
+use crate::{DB, error::AppError};
+use askama::{
+    Template,
+    filters::{Html, escape},
+};
+use axum::{
+    Form,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Cookie, Referer},
+};
+use bincode::config::standard;
+use jiff::Timestamp;
+use nanoid::nanoid;
+use ring::constant_time::verify_slices_are_equal;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use validator::Validate;
+
+/// What a bookmark shows, see [`ReportTarget::resolve`]
+struct Resolved {
+    link: String,
+    /// escaped html
+    title: String,
+    content: String,
+    uid: u32,
+}
+
+impl ReportTarget {
+    /// key of "user_bookmark_targets"
+    fn key(&self, uid: u32) -> Result<Vec<u8>, AppError> {
+        Ok([u32_to_ivec(uid), bincode::encode_to_vec(self, standard())?].concat())
+    }
+
+    /// Whether `uid` has bookmarked the target
+    pub(super) fn is_bookmarked(&self, uid: u32) -> Result<bool, AppError> {
+        Ok(DB
+            .open_partition("user_bookmark_targets", Default::default())?
+            .contains_key(self.key(uid)?)?)
+    }
+
+    const fn kind(&self) -> &'static str {
+        match self {
+            Self::Post(_) => "post",
+            Self::Comment(_, _) => "comment",
+            Self::Solo(_) => "solo",
+        }
+    }
+
+    /// Returns `None` if the target has been deleted or hidden, or `uid` can not see it any more.
+    fn resolve(&self, uid: u32) -> Result<Option<Resolved>, AppError> {
+        let pid = match *self {
+            Self::Post(pid) | Self::Comment(pid, _) => pid,
+            Self::Solo(sid) => {
+                let Ok(solo) = get_one::<Solo>(&DB, "solos", sid) else {
+                    return Ok(None);
+                };
+                if !can_visit_solo_by(&DB, &solo, Some(uid))? {
+                    return Ok(None);
+                }
+                let summary = ammonia::Builder::empty().clean(&solo.content).to_string();
+                return Ok(Some(Resolved {
+                    link: format!("/solo/{sid}"),
+                    title: summary.trim().chars().take(100).collect(),
+                    content: solo.content,
+                    uid: solo.uid,
+                }));
+            }
+        };
+
+        let Ok(post) = get_one::<Post>(&DB, "posts", pid) else {
+            return Ok(None);
+        };
+        if matches!(
+            post.status,
+            PostStatus::HiddenByUser | PostStatus::HiddenByMod
+        ) || is_scheduled_for(&DB, &post, Some(uid))?
+            || is_pending_for(&DB, &post, Some(uid))?
+        {
+            return Ok(None);
+        }
+        let inn: Inn = get_one(&DB, "inns", post.iid)?;
+        if InnType::from(inn.inn_type) == InnType::Private && !User::is_admin(&DB, uid)? {
+            let k = [u32_to_ivec(uid), u32_to_ivec(post.iid)].concat();
+            if !DB
+                .open_partition("user_inns", Default::default())?
+                .contains_key(k)?
+            {
+                return Ok(None);
+            }
+        }
+
+        match *self {
+            Self::Comment(pid, cid) => {
+                let Ok(comment) = get_comment(pid, cid) else {
+                    return Ok(None);
+                };
+                if comment.is_hidden {
+                    return Ok(None);
+                }
+                Ok(Some(Resolved {
+                    link: format!("/post/{}/{pid}?view=flat#{cid}", post.iid),
+                    title: format!("#{cid} {}", escape(&post.title, Html).unwrap()),
+                    content: comment.content,
+                    uid: comment.uid,
+                }))
+            }
+            _ => Ok(Some(Resolved {
+                link: format!("/post/{}/{pid}", post.iid),
+                title: escape(&post.title, Html).unwrap().to_string(),
+                content: post.content.to_html(&DB)?,
+                uid: post.uid,
+            })),
+        }
+    }
+}
+
+/// Form data: `/bookmark` bookmark toggle
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormBookmark {
+    csrf_token: String,
+    pid: Option<u32>,
+    cid: Option<u32>,
+    sid: Option<u32>,
+    #[validate(length(max = 64))]
+    collection: Option<String>,
+}
+
+/// `POST /bookmark` bookmark toggle
+///
+/// Adds the bookmark, or removes it if the target has been bookmarked before.
+pub(crate) async fn bookmark_post(
+    referer: Option<TypedHeader<Referer>>,
+    cookie: Option<TypedHeader<Cookie>>,
+    ValidatedForm(input): ValidatedForm<FormBookmark>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    let target = ReportTarget::new(input.pid, input.cid, input.sid)?;
+    let k = target.key(claim.uid)?;
+    let targets_tree = DB.open_partition("user_bookmark_targets", Default::default())?;
+    let link;
+    if let Some(v) = targets_tree.get(&k)? {
+        let bid = u8_slice_to_u32(&v);
+        remove_bookmark(claim.uid, bid)?;
+        link = "/user/bookmarks".to_owned();
+    } else {
+        let resolved = target.resolve(claim.uid)?.ok_or(AppError::NotFound)?;
+        link = resolved.link;
+
+        let bid = incr_id(&DB, "bookmarks_count")?;
+        let bookmark = Bookmark {
+            uid: claim.uid,
+            target,
+            collection: input.collection.unwrap_or_default().trim().to_owned(),
+            created_at: Timestamp::now().as_second(),
+        };
+        set_one(&DB, "bookmarks", bid, &bookmark)?;
+        targets_tree.insert(k, u32_to_ivec(bid))?;
+        DB.open_partition("user_bookmarks", Default::default())?
+            .insert(
+                [u32_to_ivec(claim.uid), u32_to_ivec(bid)].concat(),
+                bookmark.collection.as_bytes(),
+            )?;
+    }
+
+    let target = get_referer(referer).unwrap_or(link);
+    Ok(Redirect::to(&target))
+}
+
+fn remove_bookmark(uid: u32, bid: u32) -> Result<(), AppError> {
+    let bookmark: Bookmark = get_one(&DB, "bookmarks", bid)?;
+    if bookmark.uid != uid {
+        return Err(AppError::Unauthorized);
+    }
+    DB.open_partition("user_bookmark_targets", Default::default())?
+        .remove(bookmark.target.key(uid)?)?;
+    DB.open_partition("user_bookmarks", Default::default())?
+        .remove([u32_to_ivec(uid), u32_to_ivec(bid)].concat())?;
+    DB.open_partition("bookmarks", Default::default())?
+        .remove(u32_to_ivec(bid))?;
+    Ok(())
+}
+
+/// Point the bookmarks of a merged post and its comments to where they live now in the
+/// canonical post, see [`super::report::retarget_reports`].
+pub(super) fn retarget_bookmarks(
+    dup: u32,
+    canonical: u32,
+    cids: &HashMap<u32, u32>,
+) -> Result<(), AppError> {
+    let bookmarks_tree = DB.open_partition("bookmarks", Default::default())?;
+    let targets_tree = DB.open_partition("user_bookmark_targets", Default::default())?;
+    for i in bookmarks_tree.inner().iter() {
+        let (k, v) = i?;
+        let (mut bookmark, _): (Bookmark, usize) = bincode::decode_from_slice(&v, standard())?;
+        let cid = match bookmark.target {
+            ReportTarget::Post(pid) if pid == dup => 0,
+            ReportTarget::Comment(pid, cid) if pid == dup => cid,
+            _ => continue,
+        };
+        let Some(&new_cid) = cids.get(&cid) else {
+            continue;
+        };
+
+        targets_tree.remove(bookmark.target.key(bookmark.uid)?)?;
+        bookmark.target = ReportTarget::Comment(canonical, new_cid);
+        targets_tree.insert(bookmark.target.key(bookmark.uid)?, &*k)?;
+        bookmarks_tree.insert(k, bincode::encode_to_vec(&bookmark, standard())?)?;
+    }
+    Ok(())
+}
+
+/// Form data: `/user/bookmarks/:bid` bookmark edit
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormBookmarkEdit {
+    csrf_token: String,
+    #[validate(length(max = 64))]
+    collection: String,
+    delete: Option<bool>,
+}
+
+/// `POST /user/bookmarks/:bid` move a bookmark to another collection, or delete it
+pub(crate) async fn bookmark_edit_post(
+    referer: Option<TypedHeader<Referer>>,
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(bid): Path<u32>,
+    ValidatedForm(input): ValidatedForm<FormBookmarkEdit>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    if input.delete == Some(true) {
+        remove_bookmark(claim.uid, bid)?;
+    } else {
+        let mut bookmark: Bookmark = get_one(&DB, "bookmarks", bid)?;
+        if bookmark.uid != claim.uid {
+            return Err(AppError::Unauthorized);
+        }
+        bookmark.collection = input.collection.trim().to_owned();
+        set_one(&DB, "bookmarks", bid, &bookmark)?;
+        DB.open_partition("user_bookmarks", Default::default())?
+            .insert(
+                [u32_to_ivec(claim.uid), u32_to_ivec(bid)].concat(),
+                bookmark.collection.as_bytes(),
+            )?;
+    }
+
+    let target = get_referer(referer).unwrap_or_else(|| "/user/bookmarks".to_owned());
+    Ok(Redirect::to(&target))
+}
+
+/// url params: `bookmarks.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsBookmarks {
+    anchor: Option<usize>,
+    is_desc: Option<bool>,
+    collection: Option<String>,
+}
+
+/// Vec data: bookmark
+struct OutBookmark {
+    bid: u32,
+    kind: &'static str,
+    link: String,
+    title: String,
+    collection: String,
+    created_at: String,
+}
+
+/// Page data: `bookmarks.html`
+#[derive(Template)]
+#[template(path = "bookmarks.html")]
+struct PageBookmarks<'a> {
+    page_data: PageData<'a>,
+    bookmarks: Vec<OutBookmark>,
+    /// collection name and the number of bookmarks in it
+    collections: Vec<(String, usize)>,
+    collection: Option<String>,
+    feed_url: Option<String>,
+    anchor: usize,
+    n: usize,
+    is_desc: bool,
+    has_next: bool,
+}
+
+/// `GET /user/bookmarks` bookmarks page
+pub(crate) async fn bookmarks(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsBookmarks>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let n = site_config.per_page;
+    let collection = params.collection.filter(|c| !c.is_empty());
+
+    // the collections are kept in "user_bookmarks", so only the bookmarks of the page are read
+    let prefix = u32_to_ivec(claim.uid);
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut collection_bids = Vec::new();
+    let mut total = 0;
+    for i in DB
+        .open_partition("user_bookmarks", Default::default())?
+        .inner()
+        .prefix(&prefix)
+    {
+        let (k, v) = i?;
+        let c = String::from_utf8_lossy(&v);
+        if collection.as_deref() == Some(&*c) {
+            collection_bids.push(u8_slice_to_u32(&k[4..8]));
+        }
+        *counts.entry(c.into_owned()).or_default() += 1;
+        total += 1;
+    }
+
+    let page_params = ParamsPage { anchor, n, is_desc };
+    let bids = if collection.is_some() {
+        total = collection_bids.len();
+        if is_desc {
+            collection_bids.reverse();
+        }
+        collection_bids.into_iter().skip(anchor).take(n).collect()
+    } else {
+        get_ids_by_prefix(&DB, "user_bookmarks", prefix, Some(&page_params))?
+    };
+
+    let mut bookmarks = Vec::with_capacity(n);
+    for bid in bids {
+        let bookmark: Bookmark = get_one(&DB, "bookmarks", bid)?;
+        let (link, title) = match bookmark.target.resolve(claim.uid)? {
+            Some(resolved) => (resolved.link, resolved.title),
+            None => (String::new(), String::new()),
+        };
+        bookmarks.push(OutBookmark {
+            bid,
+            kind: bookmark.target.kind(),
+            link,
+            title,
+            collection: bookmark.collection,
+            created_at: ts_to_date(bookmark.created_at),
+        });
+    }
+    let has_next = total > anchor + n;
+    counts.remove("");
+
+    let feed_url = DB
+        .open_partition("bookmark_tokens", Default::default())?
+        .get(u32_to_ivec(claim.uid))?
+        .map(|token| {
+            format!(
+                "{}/user/{}/bookmarks/atom.xml?token={}",
+                site_config.domain,
+                claim.uid,
+                String::from_utf8_lossy(&token)
+            )
+        });
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("bookmarks", &site_config, Some(claim), has_unread);
+    let page_bookmarks = PageBookmarks {
+        page_data,
+        bookmarks,
+        collections: counts.into_iter().collect(),
+        collection,
+        feed_url,
+        anchor,
+        n,
+        is_desc,
+        has_next,
+    };
+
+    Ok(into_response(&page_bookmarks))
+}
+
+/// `POST /user/bookmarks/token` create or regenerate the secret token of the bookmark feed
+pub(crate) async fn bookmark_token_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(form): Form<FormCsrf>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    DB.open_partition("bookmark_tokens", Default::default())?
+        .insert(u32_to_ivec(claim.uid), nanoid!().as_bytes())?;
+
+    Ok(Redirect::to("/user/bookmarks"))
+}
+
+/// Page data: `bookmarks_atom.xml`
+#[derive(Template)]
+#[template(path = "bookmarks_atom.xml")]
+struct PageBookmarksAtom {
+    domain: String,
+    title: String,
+    uid: u32,
+    token: String,
+    updated: String,
+    entries: Vec<BookmarkEntry>,
+}
+
+struct BookmarkEntry {
+    bid: u32,
+    title: String,
+    link: String,
+    updated: String,
+    author: (String, u32),
+    content: String,
+}
+
+/// url params: `bookmarks_atom.xml`
+#[derive(Deserialize)]
+pub(crate) struct ParamsBookmarksAtom {
+    token: String,
+}
+
+/// `GET /user/:uid/bookmarks/atom.xml` private bookmarks feed
+pub(crate) async fn bookmarks_feed(
+    Path(uid): Path<u32>,
+    Query(params): Query<ParamsBookmarksAtom>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = DB
+        .open_partition("bookmark_tokens", Default::default())?
+        .get(u32_to_ivec(uid))?
+        .ok_or(AppError::NotFound)?;
+    if verify_slices_are_equal(&token, params.token.as_bytes()).is_err() {
+        return Err(AppError::NotFound);
+    }
+
+    let site_config = SiteConfig::get(&DB)?;
+    let user: User = get_one(&DB, "users", uid)?;
+
+    let page_params = ParamsPage {
+        anchor: 0,
+        n: 20,
+        is_desc: true,
+    };
+    let bids = get_ids_by_prefix(&DB, "user_bookmarks", u32_to_ivec(uid), Some(&page_params))?;
+
+    let mut entries = Vec::with_capacity(20);
+    for bid in bids {
+        let bookmark: Bookmark = get_one(&DB, "bookmarks", bid)?;
+        let Some(resolved) = bookmark.target.resolve(uid)? else {
+            continue;
+        };
+        let author: User = get_one(&DB, "users", resolved.uid)?;
+        let updated = Timestamp::from_second(bookmark.created_at)
+            .unwrap()
+            .strftime("%Y-%m-%dT%H:%M:%SZ")
+            .to_string();
+        entries.push(BookmarkEntry {
+            bid,
+            title: resolved.title,
+            link: resolved.link,
+            updated,
+            author: (author.username, author.uid),
+            content: resolved.content,
+        });
+    }
+
+    let updated = entries
+        .first()
+        .map(|e| e.updated.clone())
+        .unwrap_or_default();
+    let page_atom = PageBookmarksAtom {
+        domain: site_config.domain,
+        title: format!("{} - {}", user.username, site_config.site_name),
+        uid,
+        token: params.token,
+        updated,
+        entries,
+    };
+
+    Ok(into_response_with_content_type(
+        &page_atom,
+        "application/atom+xml",
+    ))
+}
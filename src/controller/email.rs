@@ -0,0 +1,524 @@
+//! ## Email
+//!
+//! Email is optional: it is enabled by the `smtp` section of the config. Emails are not sent
+//! during requests, they are put in the "email_queue" partition and sent by a cron job, which
+//! retries failed ones with backoff.
+//!
+//! Users can add an email address in their settings. Once verified, it can be used to reset
+//! the password, and to receive digests of unread notifications.
+
+use super::{
+    Claim, QueuedEmail, SiteConfig, User, UserEmail,
+    db_utils::{
+        generate_nanoid_ttl, get_id_by_name, get_one, incr_id, ivec_to_u32, set_one,
+        u8_slice_to_u32, u32_to_ivec,
+    },
+    meta_handler::{PageData, ValidatedForm, into_response},
+    user::{CaptchaSession, generate_password_hash},
+};
+use crate::{CONFIG, DB, error::AppError};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::header::ContentType,
+    transport::smtp::authentication::Credentials,
+};
+use serde::Deserialize;
+use std::sync::LazyLock;
+use tracing::{error, warn};
+use validator::{Validate, ValidateEmail};
+
+/// failed emails are dropped after this many attempts
+const MAX_ATTEMPTS: u8 = 6;
+/// how many unexpired links of a kind a user can have, to keep the queue from being flooded
+const TOKENS_MAX: usize = 3;
+
+const TOKEN_VERIFY: u8 = 1;
+const TOKEN_RESET: u8 = 2;
+
+static MAILER: LazyLock<Option<AsyncSmtpTransport<Tokio1Executor>>> = LazyLock::new(|| {
+    let smtp = CONFIG.smtp.as_ref()?;
+    let builder = match smtp.security.as_str() {
+        "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host),
+        "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host),
+        _ => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+            &smtp.host,
+        )),
+    };
+    let mut builder = match builder {
+        Ok(builder) => builder.port(smtp.port),
+        Err(e) => {
+            error!("invalid smtp config: {e}");
+            return None;
+        }
+    };
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    Some(builder.build())
+});
+
+/// whether email is configured
+pub(super) fn is_enabled() -> bool {
+    CONFIG.smtp.is_some()
+}
+
+impl UserEmail {
+    pub(super) fn get(db: &TransactionalKeyspace, uid: u32) -> Result<Option<Self>, AppError> {
+        let Some(v) = db
+            .open_partition("user_emails", Default::default())?
+            .get(u32_to_ivec(uid))?
+        else {
+            return Ok(None);
+        };
+        let (user_email, _): (UserEmail, _) = bincode::decode_from_slice(&v, standard())?;
+        Ok(Some(user_email))
+    }
+}
+
+/// Put an email in the queue, it is sent by [cron_send_emails]
+fn queue_email(
+    db: &TransactionalKeyspace,
+    to: &str,
+    subject: String,
+    body: String,
+) -> Result<(), AppError> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let eid = incr_id(db, "emails_count")?;
+    let email = QueuedEmail {
+        to: to.to_owned(),
+        subject,
+        body,
+        attempts: 0,
+        next_try: 0,
+    };
+    set_one(db, "email_queue", eid, &email)
+}
+
+async fn send(
+    mailer: &AsyncSmtpTransport<Tokio1Executor>,
+    email: &QueuedEmail,
+) -> Result<(), String> {
+    let from = CONFIG.smtp.as_ref().map(|s| s.from.as_str()).unwrap_or("");
+    let message = Message::builder()
+        .from(from.parse().map_err(|e| format!("invalid sender: {e}"))?)
+        .to(email
+            .to
+            .parse()
+            .map_err(|e| format!("invalid address: {e}"))?)
+        .subject(&email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())
+        .map_err(|e| e.to_string())?;
+    mailer.send(message).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Send the emails in the queue, after queueing the digests which are due
+pub async fn cron_send_emails(db: &TransactionalKeyspace) -> Result<(), AppError> {
+    let Some(mailer) = MAILER.as_ref() else {
+        return Ok(());
+    };
+    queue_digests(db)?;
+
+    let now = Timestamp::now().as_second();
+    let queue = db.open_partition("email_queue", Default::default())?;
+    for i in queue.inner().iter() {
+        let (k, v) = i?;
+        let (mut email, _): (QueuedEmail, _) = bincode::decode_from_slice(&v, standard())?;
+        if email.next_try > now {
+            continue;
+        }
+
+        match send(mailer, &email).await {
+            Ok(()) => {
+                queue.remove(k)?;
+            }
+            Err(e) => {
+                email.attempts += 1;
+                if email.attempts >= MAX_ATTEMPTS {
+                    warn!(
+                        "dropping email to {} after {} attempts: {e}",
+                        email.to, email.attempts
+                    );
+                    queue.remove(k)?;
+                } else {
+                    warn!("failed to send email to {}: {e}", email.to);
+                    email.next_try = now + (300 << email.attempts);
+                    set_one(db, "email_queue", ivec_to_u32(&k), &email)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Queue a digest for each verified address whose digest is due and who has new unread
+/// notifications
+fn queue_digests(db: &TransactionalKeyspace) -> Result<(), AppError> {
+    let now = Timestamp::now().as_second();
+    let site_config = SiteConfig::get(db)?;
+    let notifications = db.open_partition("notifications", Default::default())?;
+    for i in db
+        .open_partition("user_emails", Default::default())?
+        .inner()
+        .iter()
+    {
+        let (k, v) = i?;
+        let (mut user_email, _): (UserEmail, _) = bincode::decode_from_slice(&v, standard())?;
+        if !user_email.verified
+            || user_email.digest_days == 0
+            || now - user_email.digest_at < i64::from(user_email.digest_days) * 24 * 3600
+        {
+            continue;
+        }
+
+        let mut count = 0;
+        let mut last_nid = user_email.digest_nid;
+        for i in notifications.inner().prefix(&k) {
+            let (k, v) = i?;
+            let nid = u8_slice_to_u32(&k[4..8]);
+            if nid > user_email.digest_nid && v[8] == 0 {
+                count += 1;
+                last_nid = last_nid.max(nid);
+            }
+        }
+
+        if count > 0 {
+            let subject = format!("{count} unread notifications on {}", site_config.site_name);
+            let body = format!(
+                "You have {count} new unread notifications on {}:\n\n{}/notification\n\nTo stop these emails, change the digest in {}/user/setting",
+                site_config.site_name, site_config.domain, site_config.domain
+            );
+            queue_email(db, &user_email.address, subject, body)?;
+        }
+        user_email.digest_nid = last_nid;
+        user_email.digest_at = now;
+        set_one(db, "user_emails", ivec_to_u32(&k), &user_email)?;
+    }
+    Ok(())
+}
+
+/// unexpired link tokens of a kind for a user
+fn token_count(db: &TransactionalKeyspace, uid: u32, kind: u8) -> Result<usize, AppError> {
+    let now = Timestamp::now().as_second();
+    let mut count = 0;
+    for i in db
+        .open_partition("email_tokens", Default::default())?
+        .inner()
+        .iter()
+    {
+        let (k, v) = i?;
+        if token_expires_at(&k).is_some_and(|exp| exp > now)
+            && u8_slice_to_u32(&v[0..4]) == uid
+            && v[4] == kind
+        {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Create a link token, at most [TOKENS_MAX] unexpired ones of a kind for a user
+fn create_token(
+    db: &TransactionalKeyspace,
+    uid: u32,
+    kind: u8,
+    address: &str,
+    seconds: i64,
+) -> Result<String, AppError> {
+    if token_count(db, uid, kind)? >= TOKENS_MAX {
+        return Err(AppError::Custom(
+            "Too many emails have been sent, please check your inbox or try again later".into(),
+        ));
+    }
+
+    let token = generate_nanoid_ttl(seconds);
+    let v = [&u32_to_ivec(uid), &[kind][..], address.as_bytes()].concat();
+    db.open_partition("email_tokens", Default::default())?
+        .insert(&token, v)?;
+    Ok(token)
+}
+
+fn token_expires_at(k: &[u8]) -> Option<i64> {
+    let token = std::str::from_utf8(k).ok()?;
+    token
+        .split_once('_')
+        .and_then(|s| i64::from_str_radix(s.0, 16).ok())
+}
+
+/// Use up a link token, returns the uid and the address it was sent to
+fn take_token(
+    db: &TransactionalKeyspace,
+    token: &str,
+    kind: u8,
+) -> Result<(u32, String), AppError> {
+    let invalid = || AppError::Custom("Invalid or expired link".into());
+    let v = db
+        .open_partition("email_tokens", Default::default())?
+        .take(token)?
+        .ok_or_else(invalid)?;
+    let now = Timestamp::now().as_second();
+    if v.len() < 5 || v[4] != kind || !token_expires_at(token.as_bytes()).is_some_and(|e| e > now) {
+        return Err(invalid());
+    }
+    let address = String::from_utf8_lossy(&v[5..]).to_string();
+    Ok((u8_slice_to_u32(&v[0..4]), address))
+}
+
+fn queue_verification(
+    db: &TransactionalKeyspace,
+    site_config: &SiteConfig,
+    uid: u32,
+    address: &str,
+) -> Result<(), AppError> {
+    let token = create_token(db, uid, TOKEN_VERIFY, address, 24 * 3600)?;
+    let subject = format!("Verify your email on {}", site_config.site_name);
+    let body = format!(
+        "Open this link within 24 hours to verify your email:\n\n{}/user/email/verify/{token}\n\nIf you did not add this address, ignore this email.",
+        site_config.domain
+    );
+    queue_email(db, address, subject, body)
+}
+
+/// Form data: `/user/email` email address and digest
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormUserEmail {
+    csrf_token: String,
+    /// empty to remove the address
+    #[validate(length(max = 256))]
+    address: String,
+    digest_days: u8,
+    /// send the verification email again
+    resend: Option<String>,
+}
+
+/// `POST /user/email` email address and digest
+pub(crate) async fn user_email_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    ValidatedForm(input): ValidatedForm<FormUserEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if !is_enabled() {
+        return Err(AppError::NotFound);
+    }
+    if ![0, 1, 7].contains(&input.digest_days) {
+        return Err(AppError::Custom("Invalid digest".into()));
+    }
+
+    let address = input.address.trim().to_lowercase();
+    let old = UserEmail::get(&DB, claim.uid)?;
+    if let Some(ref old) = old
+        && old.verified
+        && old.address != address
+    {
+        DB.open_partition("email_uids", Default::default())?
+            .remove(&old.address)?;
+    }
+
+    if address.is_empty() {
+        DB.open_partition("user_emails", Default::default())?
+            .remove(u32_to_ivec(claim.uid))?;
+        return Ok(Redirect::to("/user/setting"));
+    }
+    if !address.validate_email() {
+        return Err(AppError::Custom("Invalid email address".into()));
+    }
+
+    let user_email = match old {
+        Some(mut old) if old.address == address => {
+            if !old.verified && input.resend.is_some() {
+                queue_verification(&DB, &site_config, claim.uid, &address)?;
+            }
+            old.digest_days = input.digest_days;
+            old
+        }
+        _ => {
+            queue_verification(&DB, &site_config, claim.uid, &address)?;
+            UserEmail {
+                address,
+                verified: false,
+                digest_days: input.digest_days,
+                digest_nid: 0,
+                digest_at: Timestamp::now().as_second(),
+            }
+        }
+    };
+    set_one(&DB, "user_emails", claim.uid, &user_email)?;
+
+    Ok(Redirect::to("/user/setting"))
+}
+
+/// `GET /user/email/verify/:token` verify an email address
+pub(crate) async fn email_verify(Path(token): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let (uid, address) = take_token(&DB, &token, TOKEN_VERIFY)?;
+    let mut user_email = UserEmail::get(&DB, uid)?.ok_or(AppError::NotFound)?;
+    if user_email.address != address {
+        return Err(AppError::Custom("Invalid or expired link".into()));
+    }
+
+    let email_uids = DB.open_partition("email_uids", Default::default())?;
+    if let Some(v) = email_uids.get(&address)?
+        && ivec_to_u32(&v) != uid
+    {
+        return Err(AppError::Custom("This email is already in use".into()));
+    }
+    email_uids.insert(&address, u32_to_ivec(uid))?;
+    user_email.verified = true;
+    set_one(&DB, "user_emails", uid, &user_email)?;
+
+    Ok(Redirect::to("/user/setting"))
+}
+
+/// Page data: `reset_email.html`
+#[derive(Template)]
+#[template(path = "reset_email.html")]
+struct PageResetEmail<'a> {
+    page_data: PageData<'a>,
+    captcha_id: String,
+    captcha_image: String,
+    sent: bool,
+}
+
+/// url params: `reset_email.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsResetEmail {
+    sent: Option<bool>,
+}
+
+/// `GET /user/reset/email` ask for a password reset link
+pub(crate) async fn reset_email(
+    Query(params): Query<ParamsResetEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    if !is_enabled() {
+        return Err(AppError::NotFound);
+    }
+    let site_config = SiteConfig::get(&DB)?;
+    let captcha_session = CaptchaSession::create(&site_config)?;
+    let page_data = PageData::new("Forgot password", &site_config, None, false);
+    let page_reset_email = PageResetEmail {
+        page_data,
+        captcha_id: captcha_session.id,
+        captcha_image: captcha_session.image,
+        sent: params.sent.unwrap_or_default(),
+    };
+    Ok(into_response(&page_reset_email))
+}
+
+/// Form data: `/user/reset/email`
+#[derive(Deserialize)]
+pub(crate) struct FormResetEmail {
+    captcha_id: String,
+    captcha_value: String,
+    /// username, uid or email address
+    username: String,
+}
+
+/// `POST /user/reset/email` send a password reset link to the verified email of the user
+///
+/// It answers the same whether or not a link was sent, also when too many have been sent, so it
+/// does not tell which addresses are in use.
+pub(crate) async fn reset_email_post(
+    Form(input): Form<FormResetEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let captcha_char = DB
+        .open_partition("captcha", Default::default())?
+        .take(&input.captcha_id)?
+        .ok_or(AppError::CaptchaError)?;
+    if captcha_char != input.captcha_value {
+        return Err(AppError::CaptchaError);
+    }
+    if !is_enabled() {
+        return Err(AppError::NotFound);
+    }
+
+    let name = input.username.trim();
+    let uid = if name.contains('@') {
+        DB.open_partition("email_uids", Default::default())?
+            .get(name.to_lowercase())?
+            .map(|v| ivec_to_u32(&v))
+    } else {
+        match name.parse::<u32>() {
+            Ok(uid) => Some(uid),
+            Err(_) => get_id_by_name(&DB, "usernames", name)?,
+        }
+    };
+
+    if let Some(uid) = uid
+        && let Some(user_email) = UserEmail::get(&DB, uid)?
+        && user_email.verified
+        && token_count(&DB, uid, TOKEN_RESET)? < TOKENS_MAX
+    {
+        let site_config = SiteConfig::get(&DB)?;
+        let user: User = get_one(&DB, "users", uid)?;
+        let token = create_token(&DB, uid, TOKEN_RESET, &user_email.address, 3600)?;
+        let subject = format!("Reset your password on {}", site_config.site_name);
+        let body = format!(
+            "Hi {}, open this link within an hour to reset your password:\n\n{}/user/reset/email/{token}\n\nIf you did not ask for it, ignore this email.",
+            user.username, site_config.domain
+        );
+        queue_email(&DB, &user_email.address, subject, body)?;
+    }
+
+    Ok(Redirect::to("/user/reset/email?sent=true"))
+}
+
+/// Page data: `reset_token.html`
+#[derive(Template)]
+#[template(path = "reset_token.html")]
+struct PageResetToken<'a> {
+    page_data: PageData<'a>,
+    token: String,
+}
+
+/// `GET /user/reset/email/:token` set a new password
+pub(crate) async fn reset_token(Path(token): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let page_data = PageData::new("Forgot password", &site_config, None, false);
+    let page_reset_token = PageResetToken { page_data, token };
+    Ok(into_response(&page_reset_token))
+}
+
+/// Form data: `/user/reset/email/:token`
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormResetToken {
+    password: String,
+    #[validate(length(min = 7))]
+    password2: String,
+}
+
+/// `POST /user/reset/email/:token` set a new password
+pub(crate) async fn reset_token_post(
+    Path(token): Path<String>,
+    ValidatedForm(input): ValidatedForm<FormResetToken>,
+) -> Result<impl IntoResponse, AppError> {
+    if input.password != input.password2 {
+        return Err(AppError::Custom("Passwords do not match".to_string()));
+    }
+
+    let (uid, address) = take_token(&DB, &token, TOKEN_RESET)?;
+    if !UserEmail::get(&DB, uid)?.is_some_and(|e| e.verified && e.address == address) {
+        return Err(AppError::Custom("Invalid or expired link".into()));
+    }
+
+    let mut user: User = get_one(&DB, "users", uid)?;
+    user.password_hash = generate_password_hash(&input.password);
+    set_one(&DB, "users", uid, &user)?;
+    // whoever knew the old password is signed out
+    Claim::revoke_all(&DB, uid)?;
+
+    Ok(Redirect::to("/signin"))
+}
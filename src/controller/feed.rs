@@ -8,7 +8,8 @@ use super::{
     fmt::{clean_html, ts_to_date},
     incr_id,
     inn::inn_add_index,
-    meta_handler::{PageData, ParamsPage, get_referer, into_response},
+    meta_handler::{FormCsrf, PageData, ParamsPage, get_referer, into_response},
+    shadowban::index_post,
 };
 use crate::{DB, config::CONFIG, error::AppError};
 use askama::Template;
@@ -24,14 +25,15 @@ use axum_extra::{
 use fjall::TransactionalKeyspace;
 use infer::is_audio;
 use jiff::{Timestamp, fmt::rfc2822};
-use reqwest::Client;
-use serde::Deserialize;
+use reqwest::{Client, ClientBuilder};
+use serde::{Deserialize, Serialize};
 use std::{collections::HashSet, time::Duration};
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::LazyLock,
+    sync::{LazyLock, Once},
 };
 use tracing::{error, info, warn};
+use utoipa::{IntoParams, ToSchema};
 use validator::Validate;
 
 struct SourceItem {
@@ -161,6 +163,7 @@ struct PageFeed<'a> {
     active_feed: u32,
 }
 
+#[derive(Serialize, ToSchema)]
 struct OutFeed {
     feed_id: u32,
     title: String,
@@ -184,6 +187,7 @@ impl OutFeed {
     }
 }
 
+#[derive(Serialize, ToSchema)]
 struct OutItem {
     item_id: u32,
     title: String,
@@ -197,7 +201,8 @@ struct OutItem {
 }
 
 /// url params: `feed.html`
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub(crate) struct ParamsFeed {
     anchor: Option<usize>,
     is_desc: Option<bool>,
@@ -212,17 +217,26 @@ struct Folder {
     is_public: bool,
 }
 
-/// `GET /feed`
-pub(crate) async fn feed(
-    cookie: Option<TypedHeader<Cookie>>,
-    Path(uid): Path<u32>,
-    Query(params): Query<ParamsFeed>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+/// Folders and items of the feed page
+#[derive(Serialize, ToSchema)]
+pub(super) struct FeedView {
+    /// the owner, `None` if it is the viewer
+    username: Option<String>,
+    folders: BTreeMap<String, Vec<OutFeed>>,
+    items: Vec<OutItem>,
+    active_folder: String,
+}
+
+/// Feeds of `uid` and a page of their items. Private feeds are left out for other users.
+pub(super) fn get_feed_view(
+    claim: Option<&Claim>,
+    uid: u32,
+    params: &ParamsFeed,
+    n: usize,
+) -> Result<FeedView, AppError> {
     let mut read = false;
     let username = match claim {
-        Some(ref claim) if claim.uid == uid => None,
+        Some(claim) if claim.uid == uid => None,
         _ => {
             read = true;
             let user: User = get_one(&DB, "users", uid)?;
@@ -252,7 +266,7 @@ pub(crate) async fn feed(
         })
     }
 
-    let mut active_folder = params.active_folder;
+    let mut active_folder = params.active_folder.clone();
 
     for feed in folders {
         if username.is_some() && !feed.is_public {
@@ -291,7 +305,7 @@ pub(crate) async fn feed(
     let mut read_ids = HashSet::new();
     let mut star_ids = vec![];
     let mut star_ids_set = HashSet::new();
-    if let Some(ref claim) = claim {
+    if let Some(claim) = claim {
         star_ids = get_item_ids_and_ts(&DB, "star", claim.uid)?;
         star_ids_set = star_ids.iter().map(|(i, _)| *i).collect();
 
@@ -315,7 +329,6 @@ pub(crate) async fn feed(
     item_ids.sort_unstable_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
     item_ids.dedup_by(|a, b| a.0 == b.0);
 
-    let n = site_config.per_page;
     let anchor = params.anchor.unwrap_or(0);
     let is_desc = params.is_desc.unwrap_or(true);
     let page_params = ParamsPage { anchor, n, is_desc };
@@ -355,6 +368,27 @@ pub(crate) async fn feed(
         items.push(out_item);
     }
 
+    Ok(FeedView {
+        username,
+        folders: map,
+        items,
+        active_folder: active_folder.unwrap_or_default(),
+    })
+}
+
+/// `GET /feed`
+pub(crate) async fn feed(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(uid): Path<u32>,
+    Query(params): Query<ParamsFeed>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let view = get_feed_view(claim.as_ref(), uid, &params, n)?;
+
     let has_unread = if let Some(ref claim) = claim {
         User::has_unread(&DB, claim.uid)?
     } else {
@@ -363,16 +397,16 @@ pub(crate) async fn feed(
     let page_data = PageData::new("Feed", &site_config, claim, has_unread);
     let page_feed = PageFeed {
         page_data,
-        folders: map,
-        items,
+        folders: view.folders,
+        items: view.items,
         filter: params.filter,
         n,
         anchor,
         is_desc,
         uid,
-        username,
+        username: view.username,
         active_feed: params.active_feed.unwrap_or_default(),
-        active_folder: active_folder.unwrap_or_default(),
+        active_folder: view.active_folder,
     };
 
     Ok(into_response(&page_feed))
@@ -533,6 +567,7 @@ pub(crate) async fn feed_add(
 /// Form data: `/feed/add`
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormFeedAdd {
+    csrf_token: String,
     #[validate(length(max = 256))]
     url: String,
     #[validate(length(max = 256))]
@@ -545,13 +580,20 @@ pub(crate) struct FormFeedAdd {
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
+/// A client builder with the user agent of the app. The crypto provider of rustls is installed
+/// by the first call.
+pub(super) fn client_builder() -> ClientBuilder {
+    static PROVIDER: Once = Once::new();
+    PROVIDER.call_once(|| {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .expect("Failed to install rustls crypto provider");
+    });
+    Client::builder().user_agent(APP_USER_AGENT)
+}
+
 static CLIENT: LazyLock<Client> = LazyLock::new(|| {
-    rustls::crypto::ring::default_provider()
-        .install_default()
-        .expect("Failed to install rustls crypto provider");
-    let mut client = reqwest::Client::builder()
-        .user_agent(APP_USER_AGENT)
-        .timeout(Duration::from_secs(60));
+    let mut client = client_builder().timeout(Duration::from_secs(60));
     if !CONFIG.proxy.is_empty() {
         let proxy = reqwest::Proxy::all(&CONFIG.proxy).unwrap();
         client = client.proxy(proxy);
@@ -567,6 +609,7 @@ pub(crate) async fn feed_add_post(
     let site_config = SiteConfig::get(&DB)?;
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let (feed, item_ids) = update(&form.url, &DB, 30).await?;
     let feed_links_tree = DB.open_partition("feed_links", Default::default())?;
@@ -616,13 +659,15 @@ pub(crate) async fn feed_add_post(
     Ok(Redirect::to(&format!("/feed/{}", claim.uid)))
 }
 
-/// `GET /feed/update`
+/// `POST /feed/update`
 pub(crate) async fn feed_update(
     cookie: Option<TypedHeader<Cookie>>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let feed_items_tree = DB.open_partition("feed_items", Default::default())?;
     let mut handers = vec![];
@@ -933,6 +978,7 @@ pub(super) fn inn_feed_to_post(
             v.push(inn.inn_type);
             db.open_partition("user_posts", Default::default())?
                 .insert(k, v)?;
+            index_post(db, uid, pid)?;
 
             inn_items_tree.insert(inn_item_k, [])?;
         }
@@ -941,15 +987,17 @@ pub(super) fn inn_feed_to_post(
     Ok(())
 }
 
-/// `GET /feed/star`
+/// `POST /feed/star`
 pub(crate) async fn feed_star(
     referer: Option<TypedHeader<Referer>>,
     cookie: Option<TypedHeader<Cookie>>,
     Path(item_id): Path<u32>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let item_id_ivec = u32_to_ivec(item_id);
     if DB
@@ -974,14 +1022,16 @@ pub(crate) async fn feed_star(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /feed/subscribe`
+/// `POST /feed/subscribe`
 pub(crate) async fn feed_subscribe(
     cookie: Option<TypedHeader<Cookie>>,
     Path((uid, feed_id)): Path<(u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let user_folder_tree = DB.open_partition("user_folders", Default::default())?;
 
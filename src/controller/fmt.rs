@@ -1,5 +1,6 @@
 use std::sync::LazyLock;
 
+use askama::filters::{Html, escape};
 use jiff::Timestamp;
 use pulldown_cmark::{CodeBlockKind, Event, Options, Tag, html};
 use syntect::{highlighting::ThemeSet, html::highlighted_html_for_string, parsing::SyntaxSet};
@@ -12,6 +13,14 @@ pub(super) fn ts_to_date(timestamp: i64) -> String {
         .to_string()
 }
 
+/// convert a `i64` timestamp to a date and time [`String`] in UTC
+pub(super) fn ts_to_datetime(timestamp: i64) -> String {
+    Timestamp::from_second(timestamp)
+        .unwrap()
+        .strftime("%Y-%m-%d %H:%M UTC")
+        .to_string()
+}
+
 // list of mathml tags obtained from
 // <https://www.tutorialspoint.com/mathml/mathml_all_elements.htm>
 const MATHML_TAGS: [&str; 31] = [
@@ -153,3 +162,186 @@ fn code_highlighter(code: &str, lang: &str) -> String {
     )
     .unwrap_or_else(|e| e.to_string())
 }
+
+/// Beyond this many word pairs, the diff falls back to lines, and beyond this many line pairs
+/// to replacing the whole changed part. It bounds the table of the diff to 1 MB.
+const DIFF_LIMIT: usize = 250_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiffOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Split text into words, runs of whitespace and single punctuation marks.
+/// CJK characters are not separated by spaces, so each of them is a word.
+fn split_words(s: &str) -> Vec<&str> {
+    fn is_cjk(c: char) -> bool {
+        matches!(
+            c,
+            '\u{2E80}'..='\u{9FFF}'
+                | '\u{AC00}'..='\u{D7AF}'
+                | '\u{F900}'..='\u{FAFF}'
+                | '\u{FF00}'..='\u{FFEF}'
+        )
+    }
+
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut last_class = None;
+    for (i, c) in s.char_indices() {
+        let class = if c.is_whitespace() {
+            0
+        } else if (c.is_alphanumeric() || c == '_') && !is_cjk(c) {
+            1
+        } else {
+            // each punctuation or cjk character is a word of its own
+            2
+        };
+        if i > 0 && (class == 2 || last_class != Some(class)) {
+            words.push(&s[start..i]);
+            start = i;
+        }
+        last_class = Some(class);
+    }
+    if start < s.len() {
+        words.push(&s[start..]);
+    }
+    words
+}
+
+/// Diff of two sequences by their longest common subsequence, `None` if they are too long
+fn lcs_diff<'a>(a: &[&'a str], b: &[&'a str]) -> Option<Vec<(DiffOp, &'a str)>> {
+    if a.len() * b.len() > DIFF_LIMIT {
+        return None;
+    }
+
+    // lcs[i][j] is for a[i..] and b[j..]
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i * width + j] = if a[i] == b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            ops.push((DiffOp::Equal, a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+            ops.push((DiffOp::Delete, a[i]));
+            i += 1;
+        } else {
+            ops.push((DiffOp::Insert, b[j]));
+            j += 1;
+        }
+    }
+    ops.extend(a[i..].iter().map(|w| (DiffOp::Delete, *w)));
+    ops.extend(b[j..].iter().map(|w| (DiffOp::Insert, *w)));
+    Some(ops)
+}
+
+/// word-level diff of two texts, rendered as escaped html with changes in `<del>` and `<ins>`
+pub(super) fn diff_html(old_text: &str, new_text: &str) -> String {
+    let old = split_words(old_text);
+    let new = split_words(new_text);
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(DiffOp, &str)> = old[..prefix].iter().map(|w| (DiffOp::Equal, *w)).collect();
+    if let Some(changes) = lcs_diff(a, b) {
+        ops.extend(changes);
+    } else {
+        // the changed parts are contiguous slices of the texts, so they can be split by lines
+        let len = |words: &[&str]| words.iter().map(|w| w.len()).sum::<usize>();
+        let (a_start, b_start) = (len(&old[..prefix]), len(&new[..prefix]));
+        let a_text = &old_text[a_start..a_start + len(a)];
+        let b_text = &new_text[b_start..b_start + len(b)];
+        let a_lines: Vec<&str> = a_text.split_inclusive('\n').collect();
+        let b_lines: Vec<&str> = b_text.split_inclusive('\n').collect();
+        match lcs_diff(&a_lines, &b_lines) {
+            Some(changes) => ops.extend(changes),
+            None => {
+                ops.push((DiffOp::Delete, a_text));
+                ops.push((DiffOp::Insert, b_text));
+            }
+        }
+    }
+    ops.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|w| (DiffOp::Equal, *w)),
+    );
+
+    let mut out = String::with_capacity(old.len() * 8);
+    let mut current = DiffOp::Equal;
+    for (op, word) in ops {
+        if op != current {
+            match current {
+                DiffOp::Delete => out.push_str("</del>"),
+                DiffOp::Insert => out.push_str("</ins>"),
+                DiffOp::Equal => (),
+            }
+            match op {
+                DiffOp::Delete => out.push_str("<del>"),
+                DiffOp::Insert => out.push_str("<ins>"),
+                DiffOp::Equal => (),
+            }
+            current = op;
+        }
+        out.push_str(&escape(word, Html).unwrap().to_string());
+    }
+    match current {
+        DiffOp::Delete => out.push_str("</del>"),
+        DiffOp::Insert => out.push_str("</ins>"),
+        DiffOp::Equal => (),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_html() {
+        assert_eq!(diff_html("hello world", "hello world"), "hello world");
+        assert_eq!(
+            diff_html("the quick fox", "the slow fox"),
+            "the <del>quick</del><ins>slow</ins> fox"
+        );
+        assert_eq!(diff_html("", "one two"), "<ins>one two</ins>");
+        assert_eq!(
+            diff_html("你好世界", "你好朋友"),
+            "你好<del>世界</del><ins>朋友</ins>"
+        );
+
+        // too many words, diffed by lines
+        let old: String = (0..300).map(|i| format!("line {i} a\n")).collect();
+        let new: String = (0..300)
+            .map(|i| format!("line {i} {}\n", if i % 10 == 5 { "b" } else { "a" }))
+            .collect();
+        let diff = diff_html(&old, &new);
+        assert!(diff.starts_with("line 0 a\n"));
+        assert!(
+            diff.contains("line 14 a\n<del>line 15 a\n</del><ins>line 15 b\n</ins>line 16 a\n")
+        );
+    }
+}
@@ -12,21 +12,32 @@
 //! | Super   | ✅      | ✅   | ✅              | ✅        | ✅        | ✅        |                  |
 
 use super::{
-    Claim, Comment, Feed, FormPost, Inn, InnType, Post, PostContent, PostStatus, SiteConfig, User,
+    AutomodAction, AutomodRule, Claim, Comment, CommentRevision, Feed, FormPost, Inn, InnRules,
+    InnType, ModAction, ModLog, ModTarget, Post, PostContent, PostRevision, PostStatus,
+    PreModeration, ReportTarget, Shadowban, SiteConfig, User,
+    automod::{AutomodInput, automod_apply, automod_check, cached_regex, check_spam},
+    block::{is_blocked, muted_uids},
+    bookmark::retarget_bookmarks,
     db_utils::{
         IterType, extract_element, get_batch, get_count, get_count_by_prefix, get_id_by_name,
         get_ids_by_prefix, get_ids_by_tag, get_one, get_range, is_valid_name, ivec_to_u32,
-        ks_incr_id, set_one, set_one_with_key, u8_slice_to_u32, u32_to_ivec,
+        ks_incr_id, set_one, set_one_with_key, u8_slice_to_i64, u8_slice_to_u32, u32_to_ivec,
     },
     feed::{inn_feed_to_post, update},
     filters,
-    fmt::{clean_html, md2html, ts_to_date},
+    fmt::{clean_html, diff_html, md2html, ts_to_date, ts_to_datetime},
     incr_id,
     meta_handler::{
-        PageData, ParamsPage, ValidatedForm, into_response, into_response_with_content_type,
+        CsrfForm, FormCsrf, FormModAction, PageData, ParamsPage, ValidatedForm, into_response,
+        into_response_with_content_type,
     },
     notification::{NtType, add_notification, mark_read},
+    poll::{OutPoll, PollTarget, get_out_poll, poll_delete, poll_move},
+    report::retarget_reports,
+    shadowban::{hidden_pids, hidden_uids, index_post},
+    spam::{spam_check, spam_hold, train_comment, train_post},
     user::{InnRole, Role},
+    webhook::{WebhookEvent, emit_comment, emit_post, emit_user},
 };
 use crate::{DB, error::AppError};
 use askama::{
@@ -42,9 +53,10 @@ use axum_extra::{TypedHeader, headers::Cookie};
 use bincode::config::standard;
 use cached::cached;
 use fjall::TransactionalKeyspace;
-use jiff::Timestamp;
-use serde::Deserialize;
+use jiff::{Timestamp, civil::DateTime, tz::TimeZone};
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet, HashMap, HashSet};
+use utoipa::{IntoParams, ToSchema};
 use validator::Validate;
 
 /// Page data: `inn_create.html`
@@ -61,6 +73,9 @@ struct PageInnEdit<'a> {
     page_data: PageData<'a>,
     inn: Inn,
     inn_feeds: Vec<Feed>,
+    rules: InnRules,
+    automod: Vec<AutomodRule>,
+    premod: PreModeration,
 }
 
 /// `GET /mod/:iid` inn create/edit page
@@ -113,6 +128,9 @@ pub(crate) async fn mod_inn(
             page_data,
             inn,
             inn_feeds,
+            rules: InnRules::get(&DB, iid)?,
+            automod: AutomodRule::get_all(&DB, iid)?,
+            premod: PreModeration::get(&DB, iid)?,
         };
         Ok(into_response(&page_inn_edit))
     }
@@ -121,6 +139,7 @@ pub(crate) async fn mod_inn(
 /// Form data: `/mod/:iid` inn create/edit page
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormInn {
+    csrf_token: String,
     #[validate(length(min = 1, max = 64))]
     inn_name: String,
     #[validate(length(min = 1, max = 512))]
@@ -155,6 +174,7 @@ pub(crate) async fn mod_inn_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
     if Role::from(claim.role) < Role::Senior && Role::from(claim.role) != Role::Admin {
         return Err(AppError::Unauthorized);
     }
@@ -331,6 +351,7 @@ pub(crate) async fn mod_inn_post(
 /// Form data: `/mod/feed/:iid` inn feed page
 #[derive(Deserialize)]
 pub(crate) struct FormInnFeed {
+    csrf_token: String,
     url: String,
 }
 
@@ -343,6 +364,7 @@ pub(crate) async fn mod_feed_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
     if Role::from(claim.role) < Role::Senior {
         return Err(AppError::Unauthorized);
     }
@@ -382,17 +404,187 @@ pub(crate) async fn mod_feed_post(
     Ok(Redirect::to(&target))
 }
 
+impl InnRules {
+    pub(super) fn get(db: &TransactionalKeyspace, iid: u32) -> Result<Self, AppError> {
+        match db
+            .open_partition("inn_rules", Default::default())?
+            .get(u32_to_ivec(iid))?
+        {
+            Some(v) => {
+                let (rules, _): (InnRules, usize) = bincode::decode_from_slice(&v, standard())?;
+                Ok(rules)
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Prefix the reason of a report or a mod action with the cited rule, unknown rules are ignored
+    pub(super) fn cite(
+        db: &TransactionalKeyspace,
+        iid: u32,
+        rule: Option<usize>,
+        reason: Option<String>,
+    ) -> Result<Option<String>, AppError> {
+        let Some(n) = rule.filter(|n| *n > 0) else {
+            return Ok(reason);
+        };
+        let rules = Self::get(db, iid)?;
+        let Some(text) = rules.rules.get(n - 1) else {
+            return Ok(reason);
+        };
+        let reason = reason.unwrap_or_default();
+        Ok(Some(format!("Rule {n} ({text}) {}", reason.trim())))
+    }
+
+    fn check_account_age(&self, user: &User, now: i64) -> Result<(), AppError> {
+        let days = self.min_account_days as i64;
+        if days > 0 && now - user.created_at < days * 24 * 3600 {
+            return Err(AppError::Custom(format!(
+                "Your account must be at least {days} days old to write in this inn"
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_post(
+        &self,
+        inn_role: &InnRole,
+        is_new: bool,
+        title: &str,
+        tags: &BTreeSet<String>,
+    ) -> Result<(), AppError> {
+        if is_new && self.min_role_to_post > 0 {
+            let min_role = InnRole::from(self.min_role_to_post);
+            if *inn_role < min_role {
+                return Err(AppError::Custom(format!(
+                    "Only {min_role} or above can start threads in this inn"
+                )));
+            }
+        }
+
+        if !self.tags.is_empty() {
+            if tags.is_empty() {
+                return Err(AppError::Custom(format!(
+                    "Posts in this inn need one of these tags: {}",
+                    self.tags.join(", ")
+                )));
+            }
+            if let Some(tag) = tags.iter().find(|t| !self.tags.contains(t)) {
+                return Err(AppError::Custom(format!(
+                    "Tag {tag} is not allowed in this inn, use one of: {}",
+                    self.tags.join(", ")
+                )));
+            }
+        }
+
+        if !self.title_regex.is_empty() {
+            let re = cached_regex(self.title_regex.clone())
+                .ok_or_else(|| AppError::Custom("Invalid title format".into()))?;
+            if !re.is_match(title) {
+                return Err(AppError::Custom(format!(
+                    "The title must match the format of this inn: {}",
+                    self.title_regex
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Form data: `/mod/rules/:iid` inn rules
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormInnRules {
+    csrf_token: String,
+    /// one rule per line
+    #[validate(length(max = 10000))]
+    rules: String,
+    min_account_days: u32,
+    min_role_to_post: u8,
+    /// separated by `#`
+    #[validate(length(max = 1024))]
+    tags: String,
+    #[validate(length(max = 256))]
+    title_regex: String,
+}
+
+/// `POST /mod/rules/:iid` inn rules
+pub(crate) async fn mod_rules_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    ValidatedForm(input): ValidatedForm<FormInnRules>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+    if !DB
+        .open_partition("inns", Default::default())?
+        .contains_key(u32_to_ivec(iid))?
+    {
+        return Err(AppError::NotFound);
+    }
+
+    if ![0, 4, 5, 7].contains(&input.min_role_to_post) {
+        return Err(AppError::Custom("Invalid inn role".into()));
+    }
+    let title_regex = input.title_regex.trim().to_owned();
+    if let Err(e) = regex::Regex::new(&title_regex) {
+        return Err(AppError::Custom(e.to_string()));
+    }
+
+    let rules = InnRules {
+        rules: input
+            .rules
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        min_account_days: input.min_account_days,
+        min_role_to_post: input.min_role_to_post,
+        tags: parse_tags(&input.tags).into_iter().collect(),
+        title_regex,
+    };
+    set_one(&DB, "inn_rules", iid, &rules)?;
+
+    let target = format!("/mod/{iid}");
+    Ok(Redirect::to(&target))
+}
+
+/// rules of the inn for templates which do not escape
+fn escaped_rules(db: &TransactionalKeyspace, iid: u32) -> Result<Vec<String>, AppError> {
+    Ok(InnRules::get(db, iid)?
+        .rules
+        .iter()
+        .map(|r| escape(r, Html).unwrap().to_string())
+        .collect())
+}
+
+/// split the `#` separated tags of a post
+fn parse_tags(tags: &str) -> BTreeSet<String> {
+    tags.split('#')
+        .map(clean_html)
+        .map(|s| s.replace('/', "-").trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 /// url params: `inn_list.html`
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub(crate) struct ParamsInnList {
-    anchor: Option<usize>,
-    is_desc: Option<bool>,
-    topic: Option<String>,
-    filter: Option<String>,
+    pub(super) anchor: Option<usize>,
+    pub(super) is_desc: Option<bool>,
+    pub(super) topic: Option<String>,
+    pub(super) filter: Option<String>,
 }
 
 /// Vec data: inn
-struct OutInnList {
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutInnList {
     iid: u32,
     inn_name: String,
     about: String,
@@ -412,38 +604,33 @@ struct PageInnList<'a> {
     topic: Option<String>,
 }
 
-/// `GET /inn/list` inns list page
-pub(crate) async fn inn_list(
-    cookie: Option<TypedHeader<Cookie>>,
-    Query(params): Query<ParamsInnList>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
-    let n = site_config.per_page;
-    let anchor = params.anchor.unwrap_or(0);
-    let is_desc = params.is_desc.unwrap_or(true);
-    let page_params = ParamsPage { anchor, n, is_desc };
-
-    let mut inns: Vec<Inn> = Vec::with_capacity(n);
+/// inns of the list page, names are prefixed by the icon of the inn type
+pub(super) fn get_out_inn_list(
+    claim: Option<&Claim>,
+    topic: Option<&str>,
+    filter: Option<&str>,
+    page_params: &ParamsPage,
+) -> Result<Vec<OutInnList>, AppError> {
+    let mut inns: Vec<Inn> = Vec::with_capacity(page_params.n);
 
-    if let Some(topic) = &params.topic {
-        for i in get_ids_by_tag(&DB, "topics", topic, Some(&page_params))? {
+    if let Some(topic) = topic {
+        for i in get_ids_by_tag(&DB, "topics", topic, Some(page_params))? {
             if let Ok(inn) = get_one::<Inn>(&DB, "inns", i)
                 && !inn.is_closed()
             {
                 inns.push(inn);
             }
         }
-    } else if let Some(claim) = &claim {
+    } else if let Some(claim) = claim {
         let uid_ivec = u32_to_ivec(claim.uid);
-        if params.filter.as_deref() == Some("mod") {
-            for i in get_ids_by_prefix(&DB, "mod_inns", uid_ivec, Some(&page_params))? {
+        if filter == Some("mod") {
+            for i in get_ids_by_prefix(&DB, "mod_inns", uid_ivec, Some(page_params))? {
                 if let Ok(inn) = get_one(&DB, "inns", i) {
                     inns.push(inn);
                 }
             }
-        } else if params.filter.as_deref() == Some("joined") {
-            for i in get_ids_by_prefix(&DB, "user_inns", uid_ivec, Some(&page_params))? {
+        } else if filter == Some("joined") {
+            for i in get_ids_by_prefix(&DB, "user_inns", uid_ivec, Some(page_params))? {
                 if let Ok(inn) = get_one::<Inn>(&DB, "inns", i)
                     && !inn.is_closed()
                 {
@@ -451,10 +638,10 @@ pub(crate) async fn inn_list(
                 }
             }
         } else {
-            inns = get_batch(&DB, "default", "inns_count", "inns", &page_params)?;
+            inns = get_batch(&DB, "default", "inns_count", "inns", page_params)?;
         }
     } else {
-        inns = get_batch(&DB, "default", "inns_count", "inns", &page_params)?;
+        inns = get_batch(&DB, "default", "inns_count", "inns", page_params)?;
     }
 
     let mut out_inns = Vec::with_capacity(inns.len());
@@ -474,6 +661,27 @@ pub(crate) async fn inn_list(
         };
         out_inns.push(out_inn);
     }
+    Ok(out_inns)
+}
+
+/// `GET /inn/list` inns list page
+pub(crate) async fn inn_list(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsInnList>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let page_params = ParamsPage { anchor, n, is_desc };
+
+    let out_inns = get_out_inn_list(
+        claim.as_ref(),
+        params.topic.as_deref(),
+        params.filter.as_deref(),
+        &page_params,
+    )?;
 
     let filter = if claim.is_none() { None } else { params.filter };
     let has_unread = if let Some(ref claim) = claim {
@@ -504,6 +712,8 @@ struct PagePostCreate<'a> {
     selected_iid: u32,
     draft: FormPost,
     draft_titles: Vec<String>,
+    /// iid, pid, title and publish time
+    scheduled: Vec<(u32, u32, String, String)>,
 }
 
 /// Page data: `post_edit.html`
@@ -512,6 +722,8 @@ struct PagePostCreate<'a> {
 struct PagePostEdit<'a> {
     page_data: PageData<'a>,
     post: Post,
+    /// empty if the post has been published
+    publish_at: String,
 }
 
 /// url params: `post_create.html`
@@ -569,11 +781,25 @@ pub(crate) async fn edit_post(
         if let Some(from_draft) = params.from_draft {
             let k: Vec<u8> = [&u32_to_ivec(claim.uid), from_draft.as_bytes()].concat();
             if let Some(v) = DB.open_partition("drafts", Default::default())?.get(k)? {
-                (draft, _) = bincode::decode_from_slice(&v, standard())?;
+                draft = FormPost::decode_draft(&v)?;
             };
             selected_iid = draft.iid;
         };
 
+        let mut scheduled = vec![];
+        for i in DB
+            .open_partition("scheduled_posts", Default::default())?
+            .inner()
+            .iter()
+        {
+            let (k, v) = i?;
+            let post: Post = get_one(&DB, "posts", u8_slice_to_u32(&k))?;
+            if post.uid == claim.uid {
+                let publish_at = ts_to_datetime(u8_slice_to_i64(&v[0..8]));
+                scheduled.push((post.iid, post.pid, post.title, publish_at));
+            }
+        }
+
         let page_data = PageData::new("new post", &site_config, Some(claim), has_unread);
         let page_post_create = PagePostCreate {
             page_data,
@@ -581,6 +807,7 @@ pub(crate) async fn edit_post(
             draft,
             selected_iid,
             draft_titles,
+            scheduled,
         };
 
         Ok(into_response(&page_post_create))
@@ -606,8 +833,21 @@ pub(crate) async fn edit_post(
             post.tags.push("private".into());
         }
 
+        let publish_at = post_publish_at(&DB, pid)?
+            .map(|ts| {
+                Timestamp::from_second(ts)
+                    .unwrap()
+                    .strftime("%Y-%m-%dT%H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+
         let page_data = PageData::new("edit post", &site_config, Some(claim), has_unread);
-        let page_post_edit = PagePostEdit { page_data, post };
+        let page_post_edit = PagePostEdit {
+            page_data,
+            post,
+            publish_at,
+        };
 
         Ok(into_response(&page_post_edit))
     }
@@ -664,29 +904,151 @@ fn inn_rm_index(db: &TransactionalKeyspace, iid: u32, pid: u32) -> Result<u8, Ap
     Ok(inn_type)
 }
 
+/// publish time of a scheduled post, `None` if it has been published
+fn post_publish_at(db: &TransactionalKeyspace, pid: u32) -> Result<Option<i64>, AppError> {
+    Ok(db
+        .open_partition("scheduled_posts", Default::default())?
+        .get(u32_to_ivec(pid))?
+        .map(|v| u8_slice_to_i64(&v[0..8])))
+}
+
+/// Whether the post is scheduled and `viewer` is not its author, the only one who can see it
+/// before it is published
+pub(super) fn is_scheduled_for(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    viewer: Option<u32>,
+) -> Result<bool, AppError> {
+    Ok(viewer != Some(post.uid) && post_publish_at(db, post.pid)?.is_some())
+}
+
+/// Whether the post is pending review and `viewer` is neither its author nor a mod
+pub(super) fn is_pending_for(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    viewer: Option<u32>,
+) -> Result<bool, AppError> {
+    if post.status != PostStatus::Pending {
+        return Ok(false);
+    }
+    let Some(uid) = viewer else {
+        return Ok(true);
+    };
+    Ok(uid != post.uid && !User::is_mod(db, uid, post.iid)? && !User::is_admin(db, uid)?)
+}
+
+/// parse `YYYY-MM-DDTHH:MM` in UTC, as sent by `<input type="datetime-local">`
+fn parse_publish_at(s: &str) -> Result<i64, AppError> {
+    let err = || AppError::Custom("Invalid publish time".into());
+    let datetime: DateTime = s.parse().map_err(|_| err())?;
+    let zoned = datetime.to_zoned(TimeZone::UTC).map_err(|_| err())?;
+    Ok(zoned.timestamp().as_second())
+}
+
+/// Publish scheduled posts which are due: index them and notify the mentioned users.
+///
+/// Posts still pending review are queued for mods instead.
+pub async fn cron_publish_posts(db: &TransactionalKeyspace) -> Result<(), AppError> {
+    let now = Timestamp::now().as_second();
+    let scheduled_ks = db.open_partition("scheduled_posts", Default::default())?;
+    for i in scheduled_ks.inner().iter() {
+        let (k, v) = i?;
+        if u8_slice_to_i64(&v[0..8]) > now {
+            continue;
+        }
+
+        let pid = u8_slice_to_u32(&k);
+        let post: Post = get_one(db, "posts", pid)?;
+        if post.status == PostStatus::Pending {
+            let k = [u32_to_ivec(post.iid), u32_to_ivec(pid)].concat();
+            db.open_partition("inn_pending", Default::default())?
+                .insert(k, &v[8..])?;
+        } else {
+            publish_post(db, &post, v[8..].chunks_exact(4).map(u8_slice_to_u32))?;
+        }
+
+        scheduled_ks.remove(k)?;
+    }
+    Ok(())
+}
+
+/// Index a post which was scheduled or pending review, and notify the mentioned users.
+pub(super) fn publish_post(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    mentions: impl Iterator<Item = u32>,
+) -> Result<(), AppError> {
+    let pid = post.pid;
+    let pid_ivec = u32_to_ivec(pid);
+    let inn: Inn = get_one(db, "inns", post.iid)?;
+
+    let mut batch = db.inner().batch();
+    if inn.is_open_access() {
+        let tags_ks = db.inner().open_partition("tags", Default::default())?;
+        for tag in &post.tags {
+            let k = [tag.as_bytes(), &pid_ivec].concat();
+            batch.insert(&tags_ks, k, []);
+        }
+        let tan_ks = db.inner().open_partition("tan", Default::default())?;
+        batch.insert(&tan_ks, format!("post{pid}"), []);
+    }
+
+    let inn_posts_ks = db.inner().open_partition("inn_posts", Default::default())?;
+    batch.insert(
+        &inn_posts_ks,
+        [u32_to_ivec(post.iid), pid_ivec.clone()].concat(),
+        [],
+    );
+
+    let mut user_posts_v = post.iid.to_be_bytes().to_vec();
+    user_posts_v.push(inn.inn_type);
+    let user_posts_ks = db
+        .inner()
+        .open_partition("user_posts", Default::default())?;
+    batch.insert(
+        &user_posts_ks,
+        [u32_to_ivec(post.uid), pid_ivec].concat(),
+        user_posts_v,
+    );
+    batch.commit()?;
+    index_post(db, post.uid, pid)?;
+
+    if post.status != PostStatus::HiddenByMod {
+        inn_add_index(db, post.iid, pid, post.created_at as u32, inn.inn_type)?;
+    }
+    User::update_stats(db, post.uid, "post")?;
+    if !Shadowban::is_on(db, post.uid)? {
+        for uid in mentions {
+            if !is_blocked(db, uid, post.uid)? {
+                add_notification(db, uid, NtType::PostMention, pid, 0)?;
+            }
+        }
+        if post.status != PostStatus::HiddenByMod {
+            emit_post(db, WebhookEvent::NewPost, post, post.uid);
+        }
+    }
+    Ok(())
+}
+
 /// `POST /post/edit/:pid` post create/edit page
 ///
 /// if pid is 0, then create a new post
 pub(crate) async fn edit_post_post(
     cookie: Option<TypedHeader<Cookie>>,
     Path(old_pid): Path<u32>,
-    ValidatedForm(input): ValidatedForm<FormPost>,
+    CsrfForm(csrf_token, input): CsrfForm<FormPost>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&csrf_token)?;
 
     let is_draft = input.is_draft.unwrap_or_default();
     let delete_draft = input.delete_draft.unwrap_or_default();
 
     let k: Vec<u8> = [&u32_to_ivec(claim.uid), input.title.as_bytes()].concat();
 
-    if let Some(spam_regex) = &site_config.spam_regex {
-        let re = regex::Regex::new(spam_regex).unwrap();
-        if re.is_match(&input.title) || re.is_match(&input.content) || re.is_match(&input.tags) {
-            return Err(AppError::Custom("Spam detected".into()));
-        }
-    }
+    check_spam(&site_config, &[&input.title, &input.content, &input.tags])?;
 
     if delete_draft {
         DB.open_partition("drafts", Default::default())?
@@ -704,7 +1066,8 @@ pub(crate) async fn edit_post_post(
         return Err(AppError::Unauthorized);
     }
 
-    let mut created_at = Timestamp::now().as_second();
+    let now = Timestamp::now().as_second();
+    let mut created_at = now;
     if created_at - claim.last_write < site_config.post_interval {
         return Err(AppError::WriteInterval);
     }
@@ -714,6 +1077,35 @@ pub(crate) async fn edit_post_post(
         return Err(AppError::LockedOrHidden);
     }
 
+    let rules = InnRules::get(&DB, iid)?;
+    let tags_set = parse_tags(&input.tags);
+    rules.check_post(&inn_role, old_pid == 0, &input.title, &tags_set)?;
+    let user: User = get_one(&DB, "users", claim.uid)?;
+    if old_pid == 0 {
+        rules.check_account_age(&user, now)?;
+    }
+    let automod_input = AutomodInput {
+        title: Some(&input.title),
+        content: &input.content,
+        tags: &tags_set,
+    };
+    let automod = automod_check(iid, &automod_input, &user, &inn_role)?;
+    let spam_score = spam_check(&DB, &site_config, &[&input.title, &input.content])?;
+    // edits can not go back to the queue, so they are hidden like comments
+    let status = match automod {
+        Some((_, AutomodAction::Hide)) => PostStatus::HiddenByMod,
+        Some((_, AutomodAction::Hold)) if old_pid > 0 => PostStatus::HiddenByMod,
+        Some((_, AutomodAction::Hold)) => PostStatus::Pending,
+        _ if old_pid == 0 && PreModeration::get(&DB, iid)?.applies(&inn_role, &user, now) => {
+            PostStatus::Pending
+        }
+        _ if spam_score.is_some() && old_pid > 0 => PostStatus::HiddenByMod,
+        _ if spam_score.is_some() => PostStatus::Pending,
+        Some((_, AutomodAction::Lock)) => PostStatus::LockedByMod,
+        _ => PostStatus::Normal,
+    };
+    let is_pending = status == PostStatus::Pending;
+
     let pid = if old_pid == 0 {
         incr_id(&DB, "posts_count")?
     } else {
@@ -721,49 +1113,63 @@ pub(crate) async fn edit_post_post(
     };
     let pid_ivec = u32_to_ivec(pid);
 
+    let old_post: Option<Post> = if old_pid > 0 {
+        let post: Post = get_one(&DB, "posts", old_pid)?;
+        if post.uid != claim.uid {
+            return Err(AppError::Unauthorized);
+        }
+
+        if post.status != PostStatus::Normal {
+            return Err(AppError::LockedOrHidden);
+        }
+
+        if post.iid != iid {
+            return Err(AppError::NotFound);
+        }
+
+        created_at = post.created_at;
+        Some(post)
+    } else {
+        None
+    };
+
+    // only new or still scheduled posts can be (re)scheduled
+    let was_scheduled = old_pid > 0 && post_publish_at(&DB, pid)?.is_some();
+    let mut publish_at = None;
+    if old_pid == 0 || was_scheduled {
+        if let Some(ts) = input.publish_at.as_deref().filter(|s| !s.is_empty()) {
+            let ts = parse_publish_at(ts)?;
+            if ts > now {
+                publish_at = Some(ts);
+            }
+        }
+        created_at = publish_at.unwrap_or(now);
+    }
+
     let mut tags = vec![];
     let mut batch = DB.inner().batch();
     if inn.is_open_access() {
-        let tags_set: BTreeSet<String> = input
-            .tags
-            .split('#')
-            .map(clean_html)
-            .map(|s| s.replace('/', "-").trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
-
         tags = tags_set.into_iter().collect();
         tags.truncate(5);
 
         let tags_ks = DB.inner().open_partition("tags", Default::default())?;
-        if old_pid > 0 {
-            let post: Post = get_one(&DB, "posts", old_pid)?;
-            if post.uid != claim.uid {
-                return Err(AppError::Unauthorized);
-            }
-
-            if post.status != PostStatus::Normal {
-                return Err(AppError::LockedOrHidden);
-            }
-
-            if post.iid != iid {
-                return Err(AppError::NotFound);
-            }
-
-            created_at = post.created_at;
+        if let Some(ref post) = old_post {
             for old_tag in &post.tags {
                 let k = [old_tag.as_bytes(), &u32_to_ivec(old_pid)].concat();
                 batch.remove(&tags_ks, k);
             }
         }
 
-        for tag in &tags {
-            let k = [tag.as_bytes(), &pid_ivec].concat();
-            batch.insert(&tags_ks, k, []);
+        if publish_at.is_none() && !is_pending {
+            for tag in &tags {
+                let k = [tag.as_bytes(), &pid_ivec].concat();
+                batch.insert(&tags_ks, k, []);
+            }
         }
     }
 
     let mut content = input.content;
+    let mut mentions = vec![];
     // extract @username or @uid notification
     let notifications = extract_element(&content, 5, '@');
     for notification in &notifications {
@@ -792,8 +1198,8 @@ pub(crate) async fn edit_post_post(
 
         // notify user to be mentioned
         // prevent duplicate notifications
-        if uid != claim.uid {
-            add_notification(&DB, uid, NtType::PostMention, pid, 0)?;
+        if uid != claim.uid && !mentions.contains(&uid) {
+            mentions.push(uid);
         }
     }
 
@@ -805,13 +1211,78 @@ pub(crate) async fn edit_post_post(
         tags,
         content: PostContent::Markdown(content),
         created_at,
-        status: PostStatus::Normal,
+        status,
     };
 
     set_one(&DB, "posts", pid, &post)?;
+    // posts held for review are in the queue instead
+    if let Some((rule, action)) = automod
+        && !is_pending
+    {
+        automod_apply(&DB, iid, pid, 0, rule, action)?;
+    } else if automod.is_none()
+        && status == PostStatus::HiddenByMod
+        && let Some(score) = spam_score
+    {
+        spam_hold(&DB, iid, ReportTarget::Post(pid), score)?;
+    }
+
+    // posts edited before revisions were recorded have no history, so save the old version first
+    let mut rev = post_last_revision(&DB, pid)?;
+    if rev == 0
+        && let Some(ref old_post) = old_post
+    {
+        rev += 1;
+        post_save_revision(&DB, old_post, rev, old_post.uid, old_post.created_at)?;
+    }
+    post_save_revision(&DB, &post, rev + 1, claim.uid, now)?;
+
+    let scheduled_ks = DB
+        .inner()
+        .open_partition("scheduled_posts", Default::default())?;
+    if let Some(publish_at) = publish_at {
+        // indexed and announced by `cron_publish_posts` when it is due
+        let mut v = publish_at.to_be_bytes().to_vec();
+        for uid in &mentions {
+            v.extend_from_slice(&u32_to_ivec(*uid));
+        }
+        batch.insert(&scheduled_ks, &pid_ivec, v);
+        batch.commit()?;
+        claim.update_last_write(&DB)?;
+
+        let target = format!("/post/{iid}/{pid}");
+        return Ok(Redirect::to(&target));
+    }
+    if was_scheduled {
+        batch.remove(&scheduled_ks, &pid_ivec);
+    }
+    if is_pending {
+        // indexed and announced by `publish_post` when mods approve it
+        let mut v = vec![];
+        for uid in &mentions {
+            v.extend_from_slice(&u32_to_ivec(*uid));
+        }
+        let pending_ks = DB
+            .inner()
+            .open_partition("inn_pending", Default::default())?;
+        batch.insert(&pending_ks, [u32_to_ivec(iid), pid_ivec].concat(), v);
+        batch.commit()?;
+        claim.update_last_write(&DB)?;
+
+        let target = format!("/post/{iid}/{pid}");
+        return Ok(Redirect::to(&target));
+    }
+
+    if !Shadowban::is_on(&DB, claim.uid)? {
+        for uid in mentions {
+            if !is_blocked(&DB, uid, claim.uid)? {
+                add_notification(&DB, uid, NtType::PostMention, pid, 0)?;
+            }
+        }
+    }
 
     let iid_ivec = u32_to_ivec(iid);
-    if old_pid == 0 {
+    if old_pid == 0 || was_scheduled {
         let k = [iid_ivec, pid_ivec.clone()].concat();
         let inn_posts_ks = DB.inner().open_partition("inn_posts", Default::default())?;
         batch.insert(&inn_posts_ks, &k, []);
@@ -825,11 +1296,13 @@ pub(crate) async fn edit_post_post(
         batch.insert(&user_posts_ks, &k, v);
     }
 
-    if old_pid > 0 {
+    if old_pid > 0 && !was_scheduled {
         inn_rm_index(&DB, iid, pid)?;
     }
 
-    inn_add_index(&DB, iid, pid, created_at as u32, inn.inn_type)?;
+    if post.status != PostStatus::HiddenByMod {
+        inn_add_index(&DB, iid, pid, created_at as u32, inn.inn_type)?;
+    }
     User::update_stats(&DB, claim.uid, "post")?;
     claim.update_last_write(&DB)?;
 
@@ -838,42 +1311,734 @@ pub(crate) async fn edit_post_post(
         batch.insert(&tan_ks, format!("post{pid}"), []);
     }
     batch.commit()?;
+    if old_pid == 0 || was_scheduled {
+        index_post(&DB, claim.uid, pid)?;
+    }
+
+    if (old_pid == 0 || was_scheduled)
+        && post.status != PostStatus::HiddenByMod
+        && !Shadowban::is_on(&DB, claim.uid)?
+    {
+        emit_post(&DB, WebhookEvent::NewPost, &post, claim.uid);
+    }
 
     let target = format!("/post/{iid}/{pid}");
     Ok(Redirect::to(&target))
 }
 
-/// Vec data: post list
-struct OutPostList {
+/// the latest revision number of a post, 0 if it has no revisions
+fn post_last_revision(db: &TransactionalKeyspace, pid: u32) -> Result<u32, AppError> {
+    let tree = db.open_partition("post_revisions", Default::default())?;
+    match tree.inner().prefix(u32_to_ivec(pid)).next_back() {
+        Some(i) => {
+            let (k, _) = i?;
+            Ok(u8_slice_to_u32(&k[4..8]))
+        }
+        None => Ok(0),
+    }
+}
+
+/// save a post as revision `rev`. Posts generated from feeds are not saved.
+fn post_save_revision(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    rev: u32,
+    uid: u32,
+    created_at: i64,
+) -> Result<(), AppError> {
+    let PostContent::Markdown(ref content) = post.content else {
+        return Ok(());
+    };
+    let revision = PostRevision {
+        uid,
+        title: post.title.clone(),
+        tags: post.tags.clone(),
+        content: content.clone(),
+        created_at,
+    };
+    let k = [u32_to_ivec(post.pid), u32_to_ivec(rev)].concat();
+    set_one_with_key(db, "post_revisions", k, &revision)
+}
+
+fn get_post_revision(
+    db: &TransactionalKeyspace,
     pid: u32,
-    iid: u32,
-    inn_name: String,
+    rev: u32,
+) -> Result<PostRevision, AppError> {
+    let k = [u32_to_ivec(pid), u32_to_ivec(rev)].concat();
+    let v = db
+        .open_partition("post_revisions", Default::default())?
+        .get(k)?
+        .ok_or(AppError::NotFound)?;
+    let (revision, _): (PostRevision, usize) = bincode::decode_from_slice(&v, standard())?;
+    Ok(revision)
+}
+
+/// Vec data: post revision
+struct OutRevision {
+    rev: u32,
     uid: u32,
     username: String,
-    title: String,
     created_at: String,
-    comment_count: u32,
-    last_reply: Option<(u32, String)>,
-    is_pinned: bool,
 }
 
-/// Page data: `tag.html`
+/// Page data: `post_history.html`
 #[derive(Template)]
-#[template(path = "tag.html")]
-struct PageTag<'a> {
+#[template(path = "post_history.html")]
+struct PagePostHistory<'a> {
     page_data: PageData<'a>,
-    posts: Vec<OutPostList>,
-    anchor: usize,
-    n: usize,
-    is_desc: bool,
-    tag: String,
+    iid: u32,
+    pid: u32,
+    title: String,
+    revisions: Vec<OutRevision>,
+    from: u32,
+    to: u32,
+    diff_title: String,
+    diff_tags: String,
+    diff_content: String,
+    is_mod: bool,
 }
 
-/// url params: `tag.html`
+/// url params: `post_history.html`
 #[derive(Deserialize)]
-pub(crate) struct ParamsTag {
-    pub(crate) anchor: Option<usize>,
-    pub(crate) is_desc: Option<bool>,
+pub(crate) struct ParamsHistory {
+    from: Option<u32>,
+    to: Option<u32>,
+}
+
+/// `GET /post/:iid/:pid/history` post revision history
+///
+/// Shows a word-level diff between revision `from` and revision `to`, by default
+/// the last edit of the post.
+pub(crate) async fn post_history(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Query(params): Query<ParamsHistory>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let post: Post = get_one(&DB, "posts", pid)?;
+    if post.iid != iid {
+        return Err(AppError::NotFound);
+    }
+    let inn: Inn = get_one(&DB, "inns", iid)?;
+    if inn.is_closed() {
+        return Err(AppError::LockedOrHidden);
+    }
+
+    let mut is_mod = false;
+    let mut has_joined = false;
+    let mut has_unread = false;
+    if let Some(ref claim) = claim {
+        has_unread = User::has_unread(&DB, claim.uid)?;
+        is_mod = User::is_mod(&DB, claim.uid, iid)? || Role::from(claim.role) == Role::Admin;
+        let k = [u32_to_ivec(claim.uid), u32_to_ivec(iid)].concat();
+        has_joined = DB
+            .open_partition("user_inns", Default::default())?
+            .contains_key(k)?;
+    }
+
+    if InnType::from(inn.inn_type) == InnType::Private && !has_joined && !is_mod {
+        return Err(AppError::NotFound);
+    }
+    let viewer = claim.as_ref().map(|claim| claim.uid);
+    if is_scheduled_for(&DB, &post, viewer)? || is_pending_for(&DB, &post, viewer)? {
+        return Err(AppError::NotFound);
+    }
+
+    if !is_mod
+        && (post.status == PostStatus::HiddenByMod
+            || post.status == PostStatus::HiddenByUser
+            || is_deleted(&post))
+    {
+        return Err(AppError::LockedOrHidden);
+    }
+
+    let mut revisions = vec![];
+    let mut username_map = HashMap::new();
+    let tree = DB.open_partition("post_revisions", Default::default())?;
+    for i in tree.inner().prefix(u32_to_ivec(pid)) {
+        let (k, v) = i?;
+        let (revision, _): (PostRevision, usize) = bincode::decode_from_slice(&v, standard())?;
+        if !username_map.contains_key(&revision.uid) {
+            let user: User = get_one(&DB, "users", revision.uid)?;
+            username_map.insert(revision.uid, user.username);
+        }
+        revisions.push(OutRevision {
+            rev: u8_slice_to_u32(&k[4..8]),
+            uid: revision.uid,
+            username: username_map[&revision.uid].clone(),
+            created_at: ts_to_date(revision.created_at),
+        });
+    }
+
+    let last = revisions.last().map(|r| r.rev).unwrap_or_default();
+    let to = params.to.unwrap_or(last).min(last);
+    let from = params.from.unwrap_or(to.saturating_sub(1)).min(to);
+
+    let mut diff_title = String::new();
+    let mut diff_tags = String::new();
+    let mut diff_content = String::new();
+    if to > 0 {
+        let new = get_post_revision(&DB, pid, to)?;
+        let old = if from > 0 && from != to {
+            get_post_revision(&DB, pid, from)?
+        } else {
+            PostRevision {
+                uid: new.uid,
+                title: String::new(),
+                tags: vec![],
+                content: String::new(),
+                created_at: new.created_at,
+            }
+        };
+        diff_title = diff_html(&old.title, &new.title);
+        diff_tags = diff_html(&old.tags.join(" "), &new.tags.join(" "));
+        diff_content = diff_html(&old.content, &new.content);
+    }
+
+    let page_data = PageData::new("post history", &site_config, claim, has_unread);
+    let page_post_history = PagePostHistory {
+        page_data,
+        iid,
+        pid,
+        title: post.title,
+        revisions,
+        from,
+        to,
+        diff_title,
+        diff_tags,
+        diff_content,
+        is_mod,
+    };
+
+    Ok(into_response(&page_post_history))
+}
+
+/// `POST /mod/:iid/:pid/restore/:rev` restore an old revision of a post
+///
+/// The restored version is saved as a new revision, so nothing is lost.
+pub(crate) async fn post_restore(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid, rev)): Path<(u32, u32, u32)>,
+    Form(form): Form<FormModAction>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let mut post: Post = get_one(&DB, "posts", pid)?;
+    if post.iid != iid {
+        return Err(AppError::NotFound);
+    }
+    if is_deleted(&post) || post.status == PostStatus::HiddenByUser {
+        return Err(AppError::LockedOrHidden);
+    }
+    let inn: Inn = get_one(&DB, "inns", iid)?;
+    let revision = get_post_revision(&DB, pid, rev)?;
+
+    // the restored version is checked like an edit of the author
+    check_spam(
+        &site_config,
+        &[&revision.title, &revision.content, &revision.tags.join("#")],
+    )?;
+    let author: User = get_one(&DB, "users", post.uid)?;
+    let inn_role = InnRole::get(&DB, iid, post.uid)?.unwrap_or(InnRole::Pending);
+    let tags_set: BTreeSet<String> = revision.tags.iter().cloned().collect();
+    InnRules::get(&DB, iid)?.check_post(&inn_role, false, &revision.title, &tags_set)?;
+    let automod_input = AutomodInput {
+        title: Some(&revision.title),
+        content: &revision.content,
+        tags: &tags_set,
+    };
+    let automod = automod_check(iid, &automod_input, &author, &inn_role)?;
+    let spam_score = spam_check(&DB, &site_config, &[&revision.title, &revision.content])?;
+    let is_visible = matches!(
+        post.status,
+        PostStatus::Normal | PostStatus::LockedByUser | PostStatus::LockedByMod
+    );
+    let status = match automod {
+        Some((_, AutomodAction::Hide | AutomodAction::Hold)) if is_visible => {
+            PostStatus::HiddenByMod
+        }
+        _ if spam_score.is_some() && is_visible => PostStatus::HiddenByMod,
+        Some((_, AutomodAction::Lock)) if post.status == PostStatus::Normal => {
+            PostStatus::LockedByMod
+        }
+        _ => post.status.clone(),
+    };
+
+    let mut batch = DB.inner().batch();
+    let pid_ivec = u32_to_ivec(pid);
+    if inn.is_open_access() {
+        let tags_ks = DB.inner().open_partition("tags", Default::default())?;
+        for old_tag in &post.tags {
+            let k = [old_tag.as_bytes(), &pid_ivec].concat();
+            batch.remove(&tags_ks, k);
+        }
+        for tag in &revision.tags {
+            let k = [tag.as_bytes(), &pid_ivec].concat();
+            batch.insert(&tags_ks, k, []);
+        }
+
+        let tan_ks = DB.inner().open_partition("tan", Default::default())?;
+        batch.insert(&tan_ks, format!("post{pid}"), []);
+    }
+
+    post.title = revision.title;
+    post.tags = revision.tags;
+    post.content = PostContent::Markdown(revision.content);
+    let status_changed = status != post.status;
+    post.status = status;
+    set_one(&DB, "posts", pid, &post)?;
+    if let Some((rule, action)) = automod {
+        automod_apply(&DB, iid, pid, 0, rule, action)?;
+    } else if status_changed && let Some(score) = spam_score {
+        spam_hold(&DB, iid, ReportTarget::Post(pid), score)?;
+    }
+
+    let last = post_last_revision(&DB, pid)?;
+    post_save_revision(
+        &DB,
+        &post,
+        last + 1,
+        claim.uid,
+        Timestamp::now().as_second(),
+    )?;
+    batch.commit()?;
+    ModLog::add(
+        &DB,
+        claim.uid,
+        iid,
+        ModTarget::Post(pid),
+        ModAction::Restore(rev),
+        InnRules::cite(&DB, iid, form.rule, form.reason)?,
+    )?;
+
+    let target = format!("/post/{iid}/{pid}/history");
+    Ok(Redirect::to(&target))
+}
+
+/// Page data: `post_move.html`
+#[derive(Template)]
+#[template(path = "post_move.html")]
+struct PagePostMove<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    pid: u32,
+    title: String,
+    inns: Vec<(u32, String)>,
+}
+
+/// Mods can move posts between the inns they moderate, admins between any inns.
+///
+/// Posts of private inns can only go to private inns, so members-only content never becomes
+/// public, and the author must have joined a private target to keep access to the post.
+fn check_move(claim: &Claim, from: &Inn, to: &Inn, author: u32) -> Result<(), AppError> {
+    if Role::from(claim.role) != Role::Admin
+        && (!User::is_mod(&DB, claim.uid, from.iid)? || !User::is_mod(&DB, claim.uid, to.iid)?)
+    {
+        return Err(AppError::Unauthorized);
+    }
+    if to.is_closed() {
+        return Err(AppError::LockedOrHidden);
+    }
+    if from.is_private() && !to.is_private() {
+        return Err(AppError::Custom("Inn type err".into()));
+    }
+    if to.is_private() {
+        let k = [u32_to_ivec(author), u32_to_ivec(to.iid)].concat();
+        if !DB
+            .open_partition("user_inns", Default::default())?
+            .contains_key(k)?
+        {
+            return Err(AppError::Custom("The author has not joined the inn".into()));
+        }
+    }
+    Ok(())
+}
+
+/// `GET /mod/:iid/:pid/move` move or merge post page
+pub(crate) async fn post_move(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+    let post: Post = get_one(&DB, "posts", pid)?;
+    if post.iid != iid {
+        return Err(AppError::NotFound);
+    }
+    let from: Inn = get_one(&DB, "inns", iid)?;
+
+    let iids = if Role::from(claim.role) == Role::Admin {
+        let mut iids = Vec::new();
+        for i in DB
+            .open_partition("inns", Default::default())?
+            .inner()
+            .iter()
+        {
+            let (k, _) = i?;
+            iids.push(u8_slice_to_u32(&k));
+        }
+        iids
+    } else {
+        get_ids_by_prefix(&DB, "mod_inns", u32_to_ivec(claim.uid), None)?
+    };
+    let mut inns = Vec::with_capacity(iids.len());
+    for i in iids {
+        if i == iid {
+            continue;
+        }
+        let to: Inn = get_one(&DB, "inns", i)?;
+        if check_move(&claim, &from, &to, post.uid).is_ok() {
+            inns.push((to.iid, to.inn_name));
+        }
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("move", &site_config, Some(claim), has_unread);
+    let page_post_move = PagePostMove {
+        page_data,
+        iid,
+        pid,
+        title: post.title,
+        inns,
+    };
+
+    Ok(into_response(&page_post_move))
+}
+
+/// Form data: `/mod/:iid/:pid/move` move or merge post
+#[derive(Deserialize)]
+pub(crate) struct FormPostMove {
+    csrf_token: String,
+    /// the inn to move the post to
+    to: Option<u32>,
+    /// the canonical post to merge the comments into
+    into: Option<u32>,
+    reason: Option<String>,
+    rule: Option<usize>,
+}
+
+/// `POST /mod/:iid/:pid/move` move a post with its comments to another inn, or merge it into
+/// another post
+///
+/// The old url redirects to the new place.
+pub(crate) async fn post_move_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormPostMove>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    let mut post: Post = get_one(&DB, "posts", pid)?;
+    let redirects = DB.open_partition("post_redirects", Default::default())?;
+    if post.iid != iid || redirects.contains_key(u32_to_ivec(pid))? {
+        return Err(AppError::NotFound);
+    }
+    if post.status == PostStatus::Pending {
+        return Err(AppError::Custom(
+            "Posts pending review can not be moved".into(),
+        ));
+    }
+    let from: Inn = get_one(&DB, "inns", iid)?;
+
+    let (target, action) = match (form.to, form.into) {
+        (Some(to), None) if to != iid => {
+            let to: Inn = get_one(&DB, "inns", to)?;
+            check_move(&claim, &from, &to, post.uid)?;
+            move_post(&mut post, &to)?;
+            (format!("/post/{}/{pid}", to.iid), ModAction::Move(to.iid))
+        }
+        (None, Some(into)) if into != pid && !redirects.contains_key(u32_to_ivec(into))? => {
+            let canonical: Post = get_one(&DB, "posts", into)?;
+            if canonical.status == PostStatus::Pending {
+                return Err(AppError::NotFound);
+            }
+            let to: Inn = get_one(&DB, "inns", canonical.iid)?;
+            if to.iid != iid {
+                check_move(&claim, &from, &to, post.uid)?;
+            } else if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
+                return Err(AppError::Unauthorized);
+            }
+            if post_publish_at(&DB, pid)?.is_some() || post_publish_at(&DB, into)?.is_some() {
+                return Err(AppError::Custom("Scheduled posts can not be merged".into()));
+            }
+            merge_post(&post, &canonical, &to)?;
+            (format!("/post/{}/{into}", to.iid), ModAction::Merge(into))
+        }
+        _ => return Err(AppError::NotFound),
+    };
+
+    ModLog::add(
+        &DB,
+        claim.uid,
+        iid,
+        ModTarget::Post(pid),
+        action,
+        InnRules::cite(&DB, iid, form.rule, form.reason)?,
+    )?;
+
+    Ok(Redirect::to(&target))
+}
+
+/// Move the post to the inn, rewriting the indexes under the type of the new inn.
+/// Comments are keyed by pid, so they move along.
+fn move_post(post: &mut Post, to: &Inn) -> Result<(), AppError> {
+    let pid = post.pid;
+    let pid_ivec = u32_to_ivec(pid);
+    let old_k = [u32_to_ivec(post.iid), pid_ivec.clone()].concat();
+    let new_k = [u32_to_ivec(to.iid), pid_ivec.clone()].concat();
+    let is_scheduled = post_publish_at(&DB, pid)?.is_some();
+
+    let mut batch = DB.inner().batch();
+    let pins_ks = DB.inner().open_partition("post_pins", Default::default())?;
+    batch.remove(&pins_ks, &*old_k);
+
+    // scheduled posts are indexed by `cron_publish_posts` under their inn at the time
+    if !is_scheduled {
+        let inn_posts_ks = DB.inner().open_partition("inn_posts", Default::default())?;
+        batch.remove(&inn_posts_ks, &*old_k);
+        batch.insert(&inn_posts_ks, &*new_k, []);
+
+        let k = [u32_to_ivec(post.uid), pid_ivec.clone()].concat();
+        let mut v = to.iid.to_be_bytes().to_vec();
+        v.push(to.inn_type);
+        let user_posts_ks = DB
+            .inner()
+            .open_partition("user_posts", Default::default())?;
+        batch.insert(&user_posts_ks, k, v);
+    }
+
+    let tags_ks = DB.inner().open_partition("tags", Default::default())?;
+    if !to.is_open_access() {
+        for tag in &post.tags {
+            let k = [tag.as_bytes(), &pid_ivec].concat();
+            batch.remove(&tags_ks, k);
+        }
+        post.tags.clear();
+    }
+
+    let is_visible =
+        post.status != PostStatus::HiddenByMod && post.status != PostStatus::HiddenByUser;
+    // docs already indexed are left out of the results by `OutSearch::get`, drop the queued ones
+    let is_indexed = to.is_open_access() && is_visible && !is_scheduled;
+    let tan_ks = DB.inner().open_partition("tan", Default::default())?;
+    if is_indexed {
+        batch.insert(&tan_ks, format!("post{pid}"), []);
+    } else {
+        batch.remove(&tan_ks, format!("post{pid}"));
+    }
+    for i in DB
+        .inner()
+        .open_partition("post_comments", Default::default())?
+        .prefix(&pid_ivec)
+    {
+        let (_, v) = i?;
+        let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+        let id = format!("comt{pid}/{}", comment.cid);
+        if is_indexed && !comment.is_hidden {
+            batch.insert(&tan_ks, id, []);
+        } else {
+            batch.remove(&tan_ks, id);
+        }
+    }
+    batch.commit()?;
+
+    // hidden posts are not on the timeline, keep the last active time of the others
+    if let Some(v) = DB
+        .open_partition("post_timeline_idx", Default::default())?
+        .get(&old_k)?
+    {
+        let timestamp = u8_slice_to_u32(&v[0..4]);
+        inn_rm_index(&DB, post.iid, pid)?;
+        inn_add_index(&DB, to.iid, pid, timestamp, to.inn_type)?;
+    }
+
+    post.iid = to.iid;
+    set_one(&DB, "posts", pid, post)?;
+    Ok(())
+}
+
+/// Fold a duplicate post into the canonical one. The body of the duplicate becomes a comment of
+/// the canonical post, followed by the comments of the duplicate as its replies. The duplicate
+/// is taken off all lists and its url redirects to the canonical post.
+fn merge_post(dup: &Post, canonical: &Post, canonical_inn: &Inn) -> Result<(), AppError> {
+    let dup_ivec = u32_to_ivec(dup.pid);
+    let canonical_ivec = u32_to_ivec(canonical.pid);
+    let comments_count = DB.open_partition("post_comments_count", Default::default())?;
+    let index_comments = canonical_inn.is_open_access()
+        && canonical.status != PostStatus::HiddenByMod
+        && canonical.status != PostStatus::HiddenByUser;
+
+    let mut comments = vec![Comment {
+        cid: 0,
+        pid: dup.pid,
+        uid: dup.uid,
+        reply_to: None,
+        content: dup.content.to_html(&DB)?,
+        created_at: dup.created_at,
+        is_hidden: false,
+    }];
+    for i in DB
+        .open_partition("post_comments", Default::default())?
+        .inner()
+        .prefix(&dup_ivec)
+    {
+        let (_, v) = i?;
+        let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+        comments.push(comment);
+    }
+
+    index_old_replies(&DB, canonical.pid)?;
+    let mut batch = DB.inner().batch();
+    let replies_ks = DB
+        .inner()
+        .open_partition("comment_replies", Default::default())?;
+    for i in replies_ks.prefix(&dup_ivec) {
+        let (k, _) = i?;
+        batch.remove(&replies_ks, k);
+    }
+    let post_comments_ks = DB
+        .inner()
+        .open_partition("post_comments", Default::default())?;
+    let user_comments_ks = DB
+        .inner()
+        .open_partition("user_comments", Default::default())?;
+    let tan_ks = DB.inner().open_partition("tan", Default::default())?;
+    let mut cids = HashMap::with_capacity(comments.len());
+    for mut comment in comments {
+        let old_cid = comment.cid;
+        let cid = ks_incr_id(&comments_count, &canonical_ivec)?;
+        cids.insert(old_cid, cid);
+
+        if old_cid > 0 {
+            let old_prefix = [dup_ivec.clone(), u32_to_ivec(old_cid)].concat();
+            let new_prefix = [canonical_ivec.clone(), u32_to_ivec(cid)].concat();
+            batch.remove(&post_comments_ks, &*old_prefix);
+            for tree in ["comment_upvotes", "comment_downvotes", "comment_revisions"] {
+                let ks = DB.inner().open_partition(tree, Default::default())?;
+                for i in ks.prefix(&old_prefix) {
+                    let (k, v) = i?;
+                    let new_k = [&new_prefix, &k[old_prefix.len()..]].concat();
+                    batch.insert(&ks, new_k, v);
+                    batch.remove(&ks, k);
+                }
+            }
+            let k = [u32_to_ivec(comment.uid), old_prefix].concat();
+            batch.remove(&user_comments_ks, k);
+            batch.remove(&tan_ks, format!("comt{}/{old_cid}", dup.pid));
+        }
+
+        // the duplicate's own comments reply to its body at least
+        comment.reply_to = comment.reply_to.and_then(|c| cids.get(&c).copied());
+        if old_cid > 0 && comment.reply_to.is_none() {
+            comment.reply_to = cids.get(&0).copied();
+        }
+        comment.pid = canonical.pid;
+        comment.cid = cid;
+
+        let k = [canonical_ivec.clone(), u32_to_ivec(cid)].concat();
+        batch.insert(
+            &post_comments_ks,
+            &*k,
+            bincode::encode_to_vec(&comment, standard())?,
+        );
+        batch.insert(&replies_ks, reply_key(canonical.pid, &comment), []);
+        let k = [u32_to_ivec(comment.uid), k].concat();
+        batch.insert(&user_comments_ks, k, []);
+        if index_comments && !comment.is_hidden {
+            batch.insert(&tan_ks, format!("comt{}/{cid}", canonical.pid), []);
+        }
+    }
+
+    let dup_k = [u32_to_ivec(dup.iid), dup_ivec.clone()].concat();
+    let inn_posts_ks = DB.inner().open_partition("inn_posts", Default::default())?;
+    batch.remove(&inn_posts_ks, &*dup_k);
+    let pins_ks = DB.inner().open_partition("post_pins", Default::default())?;
+    batch.remove(&pins_ks, &*dup_k);
+    let user_posts_ks = DB
+        .inner()
+        .open_partition("user_posts", Default::default())?;
+    batch.remove(
+        &user_posts_ks,
+        [u32_to_ivec(dup.uid), dup_ivec.clone()].concat(),
+    );
+    let shadowban_posts_ks = DB
+        .inner()
+        .open_partition("shadowban_posts", Default::default())?;
+    batch.remove(&shadowban_posts_ks, &*dup_ivec);
+    let tags_ks = DB.inner().open_partition("tags", Default::default())?;
+    for tag in &dup.tags {
+        batch.remove(&tags_ks, [tag.as_bytes(), &dup_ivec].concat());
+    }
+    batch.remove(&tan_ks, format!("post{}", dup.pid));
+    let redirects_ks = DB
+        .inner()
+        .open_partition("post_redirects", Default::default())?;
+    batch.insert(&redirects_ks, &*dup_ivec, &*canonical_ivec);
+    batch.commit()?;
+
+    inn_rm_index(&DB, dup.iid, dup.pid)?;
+    poll_move(PollTarget::Post(dup.pid), PollTarget::Post(canonical.pid))?;
+    retarget_reports(dup.pid, canonical.pid, canonical_inn.iid, &cids)?;
+    retarget_bookmarks(dup.pid, canonical.pid, &cids)?;
+    Ok(())
+}
+
+/// Vec data: post list
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutPostList {
+    pid: u32,
+    iid: u32,
+    inn_name: String,
+    uid: u32,
+    username: String,
+    title: String,
+    created_at: String,
+    comment_count: u32,
+    /// uid and username
+    #[schema(value_type = Option<Vec<Value>>)]
+    last_reply: Option<(u32, String)>,
+    is_pinned: bool,
+    /// the author is muted by the viewer
+    is_muted: bool,
+}
+
+/// Page data: `tag.html`
+#[derive(Template)]
+#[template(path = "tag.html")]
+struct PageTag<'a> {
+    page_data: PageData<'a>,
+    posts: Vec<OutPostList>,
+    anchor: usize,
+    n: usize,
+    is_desc: bool,
+    tag: String,
+}
+
+/// url params: `tag.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsTag {
+    pub(crate) anchor: Option<usize>,
+    pub(crate) is_desc: Option<bool>,
 }
 
 /// `GET /inn/tag/:tag` tag page
@@ -891,7 +2056,9 @@ pub(crate) async fn tag(
     let page_params = ParamsPage { anchor, n, is_desc };
 
     let index = get_ids_by_tag(&DB, "tags", &tag, Some(&page_params))?;
-    let out_post_list = get_out_post_list(&DB, &index)?;
+    let hidden_uids = hidden_uids(&DB, claim.as_ref())?;
+    let muted_uids = muted_uids(&DB, claim.as_ref())?;
+    let out_post_list = get_out_post_list(&DB, &index, &hidden_uids, &muted_uids)?;
 
     let has_unread = if let Some(ref claim) = claim {
         User::has_unread(&DB, claim.uid)?
@@ -921,6 +2088,7 @@ struct PageInn<'a> {
     inn_name: String,
     about: String,
     description: String,
+    rules: Vec<String>,
     anchor: usize,
     n: usize,
     is_desc: bool,
@@ -935,64 +2103,58 @@ struct PageInn<'a> {
 }
 
 /// url params: `inn.html`
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub(crate) struct ParamsInn {
-    anchor: Option<usize>,
-    is_desc: Option<bool>,
-    filter: Option<String>,
+    pub(super) anchor: Option<usize>,
+    pub(super) is_desc: Option<bool>,
+    pub(super) filter: Option<String>,
 }
 
-/// `GET /inn/:iid` inn page
-pub(crate) async fn inn(
-    cookie: Option<TypedHeader<Cookie>>,
-    Path(i): Path<String>,
-    Query(params): Query<ParamsInn>,
-) -> Result<impl IntoResponse, AppError> {
-    let iid = match i.parse::<u32>() {
-        Ok(iid) => iid,
-        Err(_) => get_id_by_name(&DB, "inn_names", &i)?.ok_or(AppError::NotFound)?,
-    };
-
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
-
-    let n = site_config.per_page;
-    let anchor = params.anchor.unwrap_or(0);
-    let is_desc = params.is_desc.unwrap_or(true);
-    let page_params = ParamsPage { anchor, n, is_desc };
+/// Posts of the inn page, and what is read about the viewer to find them
+pub(super) struct InnPosts {
+    pub(super) posts: Vec<OutPostList>,
+    /// set if the posts are filtered by a user
+    pub(super) username: Option<String>,
+    pub(super) joined_inns: Vec<u32>,
+    pub(super) is_site_admin: bool,
+}
 
-    let mut index = Vec::with_capacity(n);
-    let mut joined_inns = &Vec::new();
-    let mut user_iins: Result<Vec<u32>, AppError> = Err(AppError::NotFound);
+/// Posts of the inn page, all inns if `iid` is 0. `filter` is `joined`, `following` or a uid.
+pub(super) fn get_inn_posts(
+    claim: Option<&Claim>,
+    iid: u32,
+    filter: Option<&str>,
+    page_params: &ParamsPage,
+) -> Result<InnPosts, AppError> {
+    let mut index = Vec::with_capacity(page_params.n);
+    let mut joined_inns = Vec::new();
     let mut username: Option<String> = None;
-    let mut is_mod = false;
     let mut is_site_admin = false;
-    if let Some(ref claim) = claim {
-        is_mod = User::is_mod(&DB, claim.uid, iid)?;
+    let hidden_uids = hidden_uids(&DB, claim)?;
+    let hidden_pids = hidden_pids(&DB, &hidden_uids)?;
+    if let Some(claim) = claim {
         is_site_admin = Role::from(claim.role) == Role::Admin;
-        user_iins = get_ids_by_prefix(&DB, "user_inns", u32_to_ivec(claim.uid), None);
-        if let Ok(ref user_iins) = user_iins {
-            joined_inns = user_iins;
-        }
+        joined_inns =
+            get_ids_by_prefix(&DB, "user_inns", u32_to_ivec(claim.uid), None).unwrap_or_default();
     }
 
-    match params.filter.as_deref() {
+    match filter {
         Some("joined") if claim.is_some() => {
-            if let Ok(ref iids) = user_iins {
-                index = get_pids_by_iids(&DB, iids, &page_params)?;
-            };
+            index = get_pids_by_iids(&DB, &joined_inns, page_params, &hidden_pids)?;
         }
         Some("following") => {
-            if let Some(ref claim) = claim {
+            if let Some(claim) = claim {
                 let user_following: Vec<u32> =
                     get_ids_by_prefix(&DB, "user_following", u32_to_ivec(claim.uid), None)
                         .unwrap_or_default();
                 index = get_pids_by_uids(
                     &DB,
                     &user_following,
-                    joined_inns,
-                    &page_params,
+                    &joined_inns,
+                    page_params,
                     is_site_admin,
+                    &hidden_pids,
                 )?;
             }
         }
@@ -1000,20 +2162,27 @@ pub(crate) async fn inn(
             if let Ok(uid) = uid.parse::<u32>() {
                 let user: User = get_one(&DB, "users", uid)?;
                 username = Some(user.username);
-                index = get_pids_by_uids(&DB, &[uid], joined_inns, &page_params, is_site_admin)?;
+                index = get_pids_by_uids(
+                    &DB,
+                    &[uid],
+                    &joined_inns,
+                    page_params,
+                    is_site_admin,
+                    &hidden_pids,
+                )?;
             };
         }
         _ => {
             if iid == 0 {
-                index = get_pids_all(&DB, joined_inns, &page_params, is_site_admin)?;
+                index = get_pids_all(&DB, &joined_inns, page_params, is_site_admin, &hidden_pids)?;
             } else {
                 let inn: Inn = get_one(&DB, "inns", iid)?;
                 if inn.is_private() {
                     if joined_inns.contains(&iid) || is_site_admin {
-                        index = get_pids_by_iids(&DB, &[iid], &page_params)?;
+                        index = get_pids_by_iids(&DB, &[iid], page_params, &hidden_pids)?;
                     }
                 } else {
-                    index = get_pids_by_iids(&DB, &[iid], &page_params)?;
+                    index = get_pids_by_iids(&DB, &[iid], page_params, &hidden_pids)?;
                 }
 
                 // add pinned posts
@@ -1024,7 +2193,46 @@ pub(crate) async fn inn(
         }
     }
 
-    let out_post_list = get_out_post_list(&DB, &index)?;
+    let muted_uids = muted_uids(&DB, claim)?;
+    let posts = get_out_post_list(&DB, &index, &hidden_uids, &muted_uids)?;
+    Ok(InnPosts {
+        posts,
+        username,
+        joined_inns,
+        is_site_admin,
+    })
+}
+
+/// `GET /inn/:iid` inn page
+pub(crate) async fn inn(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(i): Path<String>,
+    Query(params): Query<ParamsInn>,
+) -> Result<impl IntoResponse, AppError> {
+    let iid = match i.parse::<u32>() {
+        Ok(iid) => iid,
+        Err(_) => get_id_by_name(&DB, "inn_names", &i)?.ok_or(AppError::NotFound)?,
+    };
+
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let page_params = ParamsPage { anchor, n, is_desc };
+
+    let mut is_mod = false;
+    if let Some(ref claim) = claim {
+        is_mod = User::is_mod(&DB, claim.uid, iid)?;
+    }
+
+    let InnPosts {
+        posts: out_post_list,
+        username,
+        joined_inns,
+        is_site_admin,
+    } = get_inn_posts(claim.as_ref(), iid, params.filter.as_deref(), &page_params)?;
     let mut inn_role = 0;
     if let Some(ref claim) = claim
         && iid > 0
@@ -1061,6 +2269,7 @@ pub(crate) async fn inn(
     let inn_name;
     let about;
     let description;
+    let mut rules = vec![];
 
     if iid > 0 {
         let inn: Inn = get_one(&DB, "inns", iid)?;
@@ -1073,6 +2282,7 @@ pub(crate) async fn inn(
         inn_name = format!("{} {}", icon, inn.inn_name);
         about = inn.about;
         description = md2html(&inn.description);
+        rules = escaped_rules(&DB, iid)?;
     } else {
         inn_name = "No post".into();
         about = "".into();
@@ -1086,6 +2296,7 @@ pub(crate) async fn inn(
         inn_name,
         about,
         description,
+        rules,
         posts: out_post_list,
         anchor,
         iid,
@@ -1226,16 +2437,17 @@ pub(crate) async fn inn_feed(Path(i): Path<String>) -> Result<impl IntoResponse,
     let title;
     let description;
     let mut categories = Vec::new();
+    let hidden_pids = hidden_pids(&DB, &hidden_uids(&DB, None)?)?;
 
     if iid == 0 {
-        index = get_pids_all(&DB, &[], &page_params, false)?;
+        index = get_pids_all(&DB, &[], &page_params, false, &hidden_pids)?;
         title = site_config.site_name;
         description = md2html(&site_config.description);
     } else {
         let inn: Inn = get_one(&DB, "inns", iid)?;
         description = md2html(&inn.about);
         if inn.is_open_access() {
-            index = get_pids_by_iids(&DB, &[iid], &page_params)?;
+            index = get_pids_by_iids(&DB, &[iid], &page_params, &hidden_pids)?;
         }
         title = inn.inn_name;
         for i in inn.topics {
@@ -1284,34 +2496,44 @@ pub(crate) async fn inn_feed(Path(i): Path<String>) -> Result<impl IntoResponse,
     ))
 }
 
-/// get [OutPostList] from pids
+/// get [OutPostList] from pids, leaving out posts and replies of `hidden_uids`, posts of
+/// `muted_uids` are collapsed
 fn get_out_post_list(
     db: &TransactionalKeyspace,
     index: &[u32],
+    hidden_uids: &HashSet<u32>,
+    muted_uids: &HashSet<u32>,
 ) -> Result<Vec<OutPostList>, AppError> {
     let mut post_lists = Vec::with_capacity(index.len());
     if !index.is_empty() {
         for pid in index {
             let post: Post = get_one(db, "posts", *pid)?;
+            if hidden_uids.contains(&post.uid) {
+                continue;
+            }
             let user: User = get_one(db, "users", post.uid)?;
             let date = ts_to_date(post.created_at);
             let inn: Inn = get_one(db, "inns", post.iid)?;
-            let comment_count =
-                get_count_by_prefix(db, "post_comments", &u32_to_ivec(*pid))? as u32;
-
-            let last_reply = if let Some(i) = db
+            // comments of hidden users are neither counted nor shown as the last reply
+            let mut comment_count = 0;
+            let mut last_reply = None;
+            for i in db
                 .open_partition("post_comments", Default::default())?
                 .inner()
                 .prefix(u32_to_ivec(*pid))
-                .last()
+                .rev()
             {
                 let (_, v) = i?;
                 let (one, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
-                let user: User = get_one(db, "users", one.uid)?;
-                Some((user.uid, user.username))
-            } else {
-                None
-            };
+                if hidden_uids.contains(&one.uid) {
+                    continue;
+                }
+                comment_count += 1;
+                if last_reply.is_none() {
+                    let user: User = get_one(db, "users", one.uid)?;
+                    last_reply = Some((user.uid, user.username));
+                }
+            }
 
             let k = [u32_to_ivec(post.iid), u32_to_ivec(post.pid)].concat();
             let is_pinned = db
@@ -1329,6 +2551,7 @@ fn get_out_post_list(
                 comment_count,
                 last_reply,
                 is_pinned,
+                is_muted: muted_uids.contains(&post.uid),
             };
             post_lists.push(post_list);
         }
@@ -1342,6 +2565,7 @@ fn get_pids_all(
     joined_inns: &[u32],
     page_params: &ParamsPage,
     is_site_admin: bool,
+    hidden_pids: &HashSet<u32>,
 ) -> Result<Vec<u32>, AppError> {
     let tree = db.open_partition("post_timeline", Default::default())?;
     let mut count: usize = 0;
@@ -1360,6 +2584,9 @@ fn get_pids_all(
         let (k, v) = i?;
         let id = u8_slice_to_u32(&k[4..8]);
         let out_id = u8_slice_to_u32(&k[8..12]);
+        if hidden_pids.contains(&out_id) {
+            continue;
+        }
         let inn_type = InnType::from(v[0]);
         if inn_type == InnType::Public
             || inn_type == InnType::Apply
@@ -1385,6 +2612,7 @@ fn get_pids_by_iids(
     db: &TransactionalKeyspace,
     iids: &[u32],
     page_params: &ParamsPage,
+    hidden_pids: &HashSet<u32>,
 ) -> Result<Vec<u32>, AppError> {
     let mut pids = Vec::with_capacity(page_params.n);
     let mut pairs = Vec::new();
@@ -1400,7 +2628,10 @@ fn get_pids_by_iids(
             let pid = u8_slice_to_u32(&k[4..8]);
             let timestamp = u8_slice_to_u32(&v[0..4]);
             let inn_type = InnType::from(v[4]);
-            if inn_type != InnType::Hidden && inn_type != InnType::PrivateHidden {
+            if inn_type != InnType::Hidden
+                && inn_type != InnType::PrivateHidden
+                && !hidden_pids.contains(&pid)
+            {
                 pairs.push((pid, timestamp));
             }
         }
@@ -1422,6 +2653,7 @@ fn get_pids_by_uids(
     joined_inns: &[u32],
     page_params: &ParamsPage,
     is_site_admin: bool,
+    hidden_pids: &HashSet<u32>,
 ) -> Result<Vec<u32>, AppError> {
     let mut pids = Vec::with_capacity(page_params.n);
     for uid in uids {
@@ -1434,6 +2666,9 @@ fn get_pids_by_uids(
         {
             let (k, v) = i?;
             let pid = u8_slice_to_u32(&k[4..8]);
+            if hidden_pids.contains(&pid) {
+                continue;
+            }
             let iid = u8_slice_to_u32(&v[0..4]);
             let inn_type = InnType::from(v[4]);
             if inn_type == InnType::Public
@@ -1452,14 +2687,16 @@ fn get_pids_by_uids(
     Ok(pids)
 }
 
-/// `GET /inn/:iid/join` join inn
+/// `POST /inn/:iid/join` join inn
 pub(crate) async fn inn_join(
     cookie: Option<TypedHeader<Cookie>>,
     Path(i): Path<String>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let iid = match i.parse::<u32>() {
         Ok(iid) => iid,
@@ -1485,6 +2722,13 @@ pub(crate) async fn inn_join(
                 // 1: applied, but pending
                 inn_users_tree.insert(&inn_users_k, [1])?;
                 inn_apply_tree.insert(&inn_users_k, [])?;
+                emit_user(
+                    &DB,
+                    iid,
+                    WebhookEvent::MemberApplied,
+                    claim.uid,
+                    &claim.username,
+                );
             } else {
                 user_inns_tree.insert(&user_inns_k, [])?;
                 let count = get_count_by_prefix(&DB, "inn_users", &u32_to_ivec(iid))? as u32;
@@ -1493,6 +2737,13 @@ pub(crate) async fn inn_join(
                 } else {
                     inn_users_tree.insert(&inn_users_k, [4])?;
                 }
+                emit_user(
+                    &DB,
+                    iid,
+                    WebhookEvent::MemberJoined,
+                    claim.uid,
+                    &claim.username,
+                );
             }
         }
         Some(_) => {
@@ -1507,6 +2758,7 @@ pub(crate) async fn inn_join(
 }
 
 /// Vec data: post
+#[derive(Serialize, ToSchema)]
 struct OutPost {
     pid: u32,
     iid: u32,
@@ -1541,12 +2793,24 @@ struct PagePost<'a> {
     has_joined: bool,
     is_mod: bool,
     is_author: bool,
+    is_bookmarked: bool,
     can_delete: bool,
+    is_flat: bool,
+    has_next: bool,
+    poll: Option<OutPoll>,
+    rules: Vec<String>,
 }
 
 /// Vec data: Comment
+#[derive(Serialize, ToSchema)]
 struct OutComment {
     cid: u32,
+    /// number of direct replies
+    replies: usize,
+    /// whether the replies are shown in a collapsible subthread below this comment
+    opens_thread: bool,
+    /// number of subthreads which end after this comment
+    close_threads: usize,
     uid: u32,
     username: String,
     content: String,
@@ -1555,27 +2819,244 @@ struct OutComment {
     downvotes: usize,
     is_upvoted: bool,
     is_downvoted: bool,
+    is_bookmarked: bool,
     is_hidden: bool,
+    /// the author is muted by the viewer
+    is_muted: bool,
+    can_edit: bool,
+    is_edited: bool,
 }
 
 /// url params: `post.html`
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub(crate) struct ParamsPost {
     anchor: Option<usize>,
     is_desc: Option<bool>,
     nid: Option<u32>,
+    /// `flat` to list comments in order, otherwise they are shown as reply trees
+    view: Option<String>,
 }
 
-/// `GET /inn/:iid/:pid` post page
-pub(crate) async fn post(
-    cookie: Option<TypedHeader<Cookie>>,
-    Path((iid, pid)): Path<(u32, u32)>,
-    Query(params): Query<ParamsPost>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+/// Replies deeper than this are not nested any further.
+const THREAD_MAX_DEPTH: usize = 5;
+
+/// Order comments `(cid, reply_to)` as reply trees, top-level comments are paginated.
+///
+/// Returns `(cid, depth, replies, close_threads)` in display order and whether there
+/// is a next page.
+fn thread_comments(
+    comments: &[(u32, Option<u32>)],
+    page_params: &ParamsPage,
+) -> (Vec<(u32, usize, usize, usize)>, bool) {
+    let cids: HashSet<u32> = comments.iter().map(|(cid, _)| *cid).collect();
+    let mut roots = Vec::new();
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (cid, reply_to) in comments {
+        match reply_to {
+            Some(parent) if parent < cid && cids.contains(parent) => {
+                children.entry(*parent).or_default().push(*cid)
+            }
+            _ => roots.push(*cid),
+        }
+    }
+    if page_params.is_desc {
+        roots.reverse();
+    }
+    let has_next = roots.len() > page_params.anchor + page_params.n;
+
+    let mut out = Vec::new();
+    for root in roots
+        .into_iter()
+        .skip(page_params.anchor)
+        .take(page_params.n)
+    {
+        let mut stack = vec![(root, 0)];
+        while let Some((cid, depth)) = stack.pop() {
+            let replies = children.get(&cid).map_or(&[][..], Vec::as_slice);
+            out.push((cid, depth, replies.len(), 0));
+            let child_depth = (depth + 1).min(THREAD_MAX_DEPTH);
+            for reply in replies.iter().rev() {
+                stack.push((*reply, child_depth));
+            }
+        }
+    }
+
+    // a subthread ends before the next comment which is not deeper than its parent
+    let mut open_depths: Vec<usize> = Vec::new();
+    for i in 0..out.len() {
+        let (_, depth, replies, _) = out[i];
+        while open_depths.last().is_some_and(|d| *d >= depth) {
+            open_depths.pop();
+            out[i - 1].3 += 1;
+        }
+        if replies > 0 && depth < THREAD_MAX_DEPTH {
+            open_depths.push(depth);
+        }
+    }
+    if let Some(last) = out.last_mut() {
+        last.3 += open_depths.len();
+    }
+
+    (out, has_next)
+}
+
+/// key of "comment_replies", top-level comments are replies to cid 0
+fn reply_key(pid: u32, comment: &Comment) -> Vec<u8> {
+    let parent = comment.reply_to.filter(|p| *p < comment.cid).unwrap_or(0);
+    [
+        u32_to_ivec(pid),
+        u32_to_ivec(parent),
+        u32_to_ivec(comment.cid),
+    ]
+    .concat()
+}
+
+/// Index the comments of a post from before "comment_replies" was kept
+fn index_old_replies(db: &TransactionalKeyspace, pid: u32) -> Result<(), AppError> {
+    let replies_ks = db.open_partition("comment_replies", Default::default())?;
+    if replies_ks.inner().prefix(u32_to_ivec(pid)).next().is_some() {
+        return Ok(());
+    }
+    for i in db
+        .open_partition("post_comments", Default::default())?
+        .inner()
+        .prefix(u32_to_ivec(pid))
+    {
+        let (_, v) = i?;
+        let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+        replies_ks.insert(reply_key(pid, &comment), [])?;
+    }
+    Ok(())
+}
+
+/// Add a comment to "comment_replies"
+fn index_reply(db: &TransactionalKeyspace, pid: u32, comment: &Comment) -> Result<(), AppError> {
+    index_old_replies(db, pid)?;
+    db.open_partition("comment_replies", Default::default())?
+        .insert(reply_key(pid, comment), [])?;
+    Ok(())
+}
+
+/// The top-level comments of the page and their replies as `(cid, reply_to)` for
+/// [`thread_comments`], so long posts do not load all comments. Replies to hidden or deleted
+/// comments move up to the nearest shown comment, or start their own threads.
+///
+/// Returns the comments, their order and whether there is a next page.
+#[allow(clippy::type_complexity)]
+fn get_comment_page(
+    db: &TransactionalKeyspace,
+    pid: u32,
+    hidden_uids: &HashSet<u32>,
+    page_params: &ParamsPage,
+) -> Result<(HashMap<u32, Comment>, Vec<(u32, Option<u32>)>, bool), AppError> {
+    index_old_replies(db, pid)?;
+    let comments_ks = db.open_partition("post_comments", Default::default())?;
+    let pid_ivec = u32_to_ivec(pid);
+    let replies = |cid: u32| {
+        let prefix = [pid_ivec.clone(), u32_to_ivec(cid)].concat();
+        get_ids_by_prefix(db, "comment_replies", prefix, None)
+    };
+    let shown = |cid: u32| -> Result<Option<Comment>, AppError> {
+        let k = [pid_ivec.clone(), u32_to_ivec(cid)].concat();
+        let Some(v) = comments_ks.get(k)? else {
+            return Ok(None);
+        };
+        let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+        Ok((!hidden_uids.contains(&comment.uid)).then_some(comment))
+    };
+
+    let mut top = replies(0)?;
+    if page_params.is_desc {
+        top.reverse();
+    }
+    let mut comments = HashMap::new();
+    let mut roots = Vec::with_capacity(page_params.n);
+    let mut skipped = 0;
+    let mut has_next = false;
+    let mut stack: Vec<u32> = top.into_iter().rev().collect();
+    while let Some(cid) = stack.pop() {
+        if let Some(comment) = shown(cid)? {
+            if skipped < page_params.anchor {
+                skipped += 1;
+                continue;
+            }
+            if roots.len() == page_params.n {
+                has_next = true;
+                break;
+            }
+            roots.push(cid);
+            comments.insert(cid, comment);
+        } else {
+            let mut children = replies(cid)?;
+            if page_params.is_desc {
+                children.reverse();
+            }
+            stack.extend(children.into_iter().rev());
+        }
+    }
+
+    let mut order: Vec<(u32, Option<u32>)> = roots.iter().map(|cid| (*cid, None)).collect();
+    // (cid, the shown comment its replies go to)
+    let mut stack: Vec<(u32, u32)> = roots.iter().map(|cid| (*cid, *cid)).collect();
+    while let Some((cid, parent)) = stack.pop() {
+        for reply in replies(cid)? {
+            if let Some(comment) = shown(reply)? {
+                order.push((reply, Some(parent)));
+                comments.insert(reply, comment);
+                stack.push((reply, reply));
+            } else {
+                stack.push((reply, parent));
+            }
+        }
+    }
+    order[roots.len()..].sort_unstable_by_key(|(cid, _)| *cid);
+
+    Ok((comments, order, has_next))
+}
+
+/// A post with a page of its comments, as the viewer sees them
+#[derive(Serialize, ToSchema)]
+pub(super) struct PostView {
+    post: OutPost,
+    comments: Vec<OutComment>,
+    has_joined: bool,
+    is_mod: bool,
+    is_author: bool,
+    is_bookmarked: bool,
+    can_delete: bool,
+    has_next: bool,
+    poll: Option<OutPoll>,
+}
+
+pub(super) enum PostLookup {
+    /// the post has been moved or merged, the path of its new place
+    Moved(String),
+    Found(PostView),
+}
 
+/// The post with the same checks as the post page
+pub(super) fn get_post_view(
+    claim: Option<&Claim>,
+    iid: u32,
+    pid: u32,
+    params: &ParamsPost,
+    n: usize,
+) -> Result<PostLookup, AppError> {
+    // moved and merged posts redirect to their new place
+    if let Some(v) = DB
+        .open_partition("post_redirects", Default::default())?
+        .get(u32_to_ivec(pid))?
+    {
+        let canonical: Post = get_one(&DB, "posts", u8_slice_to_u32(&v))?;
+        let target = format!("/post/{}/{}", canonical.iid, canonical.pid);
+        return Ok(PostLookup::Moved(target));
+    }
     let post: Post = get_one(&DB, "posts", pid)?;
+    if post.iid != iid {
+        let target = format!("/post/{}/{pid}", post.iid);
+        return Ok(PostLookup::Moved(target));
+    }
     let user: User = get_one(&DB, "users", post.uid)?;
     let date = ts_to_date(post.created_at);
     let inn: Inn = get_one(&DB, "inns", post.iid)?;
@@ -1584,7 +3065,7 @@ pub(crate) async fn post(
     }
 
     if InnType::from(inn.inn_type) == InnType::Private {
-        match claim.as_ref() {
+        match claim {
             Some(claim) => {
                 let k = [u32_to_ivec(claim.uid), u32_to_ivec(iid)].concat();
                 if !DB
@@ -1599,7 +3080,15 @@ pub(crate) async fn post(
         }
     }
 
-    if post.iid != iid {
+    if is_scheduled_for(&DB, &post, claim.map(|claim| claim.uid))? {
+        return Err(AppError::NotFound);
+    }
+    let publish_at = post_publish_at(&DB, pid)?;
+    if is_pending_for(&DB, &post, claim.map(|claim| claim.uid))? {
+        return Err(AppError::NotFound);
+    }
+    let hidden_uids = hidden_uids(&DB, claim)?;
+    if hidden_uids.contains(&post.uid) {
         return Err(AppError::NotFound);
     }
 
@@ -1608,12 +3097,13 @@ pub(crate) async fn post(
     let mut is_downvoted = false;
     let mut is_mod = false;
     let mut is_author = false;
+    let mut is_bookmarked = false;
     let mut can_edit = false;
     let mut can_delete = false;
     let upvotes = get_count_by_prefix(&DB, "post_upvotes", &u32_to_ivec(pid)).unwrap_or_default();
     let downvotes =
         get_count_by_prefix(&DB, "post_downvotes", &u32_to_ivec(pid)).unwrap_or_default();
-    if let Some(ref claim) = claim {
+    if let Some(claim) = claim {
         if post.uid == claim.uid {
             is_author = true;
         }
@@ -1631,6 +3121,7 @@ pub(crate) async fn post(
         {
             is_downvoted = true;
         }
+        is_bookmarked = ReportTarget::Post(pid).is_bookmarked(claim.uid)?;
 
         if is_author
             && (inn.limit_edit_seconds == 0
@@ -1702,6 +3193,24 @@ pub(crate) async fn post(
             }
         }
     };
+    let content = if post.status == PostStatus::Pending {
+        format!(
+            r#"<article class="message is-warning">
+                <div class="message-body">This post is waiting for the review of mods.</div>
+            </article>{content}"#
+        )
+    } else {
+        content
+    };
+    let content = match publish_at {
+        Some(publish_at) => format!(
+            r#"<article class="message is-info">
+                <div class="message-body">This post is scheduled to be published at <b>{}</b>.</div>
+            </article>{content}"#,
+            ts_to_datetime(publish_at)
+        ),
+        None => content,
+    };
 
     let k = [u32_to_ivec(iid), u32_to_ivec(pid)].concat();
     let is_pinned = DB
@@ -1728,66 +3237,119 @@ pub(crate) async fn post(
         is_pinned,
     };
 
-    let n = site_config.per_page;
     let anchor = params.anchor.unwrap_or(0);
     let is_desc = params.is_desc.unwrap_or(false);
     let page_params = ParamsPage { anchor, n, is_desc };
 
+    let is_flat = params.view.as_deref() == Some("flat");
+    let mut has_next = false;
+
     let mut out_comments = Vec::with_capacity(n);
+    let muted_uids = muted_uids(&DB, claim)?;
     let max_id = get_count(&DB, "post_comments_count", u32_to_ivec(pid))?;
     if max_id > 0 {
-        let (start, _) = get_range(max_id, &page_params);
         let post_comments_tree = DB.open_partition("post_comments", Default::default())?;
-        let comment_upvotes_tree = DB.open_partition("comment_upvotes", Default::default())?;
-        let comment_downvotes_tree = DB.open_partition("comment_downvotes", Default::default())?;
-        for i in start..=max_id {
-            let k = [u32_to_ivec(pid), u32_to_ivec(i as u32)].concat();
-            let v = &post_comments_tree.get(k)?;
-            if let Some(v) = v {
-                let (comment, _): (Comment, usize) = bincode::decode_from_slice(v, standard())?;
-                let user: User = get_one(&DB, "users", comment.uid)?;
-                let date = ts_to_date(comment.created_at);
-
-                let mut is_upvoted = false;
-                let mut is_downvoted = false;
-
-                if let Some(ref claim) = claim {
-                    let k = [
-                        u32_to_ivec(pid),
-                        u32_to_ivec(comment.cid),
-                        u32_to_ivec(claim.uid),
-                    ]
-                    .concat();
-                    is_upvoted = comment_upvotes_tree.contains_key(&k)?;
-                    is_downvoted = comment_downvotes_tree.contains_key(&k)?;
+        // (comment, depth, replies, close_threads)
+        let mut comments = Vec::with_capacity(n);
+        if is_flat {
+            let (start, _) = get_range(max_id, &page_params);
+            for i in start..=max_id {
+                let k = [u32_to_ivec(pid), u32_to_ivec(i as u32)].concat();
+                if let Some(v) = post_comments_tree.get(k)? {
+                    let (comment, _): (Comment, usize) =
+                        bincode::decode_from_slice(&v, standard())?;
+                    if hidden_uids.contains(&comment.uid) {
+                        continue;
+                    }
+                    comments.push((comment, 0, 0, 0));
+                    if comments.len() >= n {
+                        break;
+                    }
                 }
-
-                let prefix = [u32_to_ivec(pid), u32_to_ivec(comment.cid)].concat();
-                let upvotes =
-                    get_count_by_prefix(&DB, "comment_upvotes", &prefix).unwrap_or_default();
-                let downvotes =
-                    get_count_by_prefix(&DB, "comment_downvotes", &prefix).unwrap_or_default();
-
-                let out_comment = OutComment {
-                    cid: comment.cid,
-                    uid: comment.uid,
-                    username: user.username,
-                    content: comment.content,
-                    created_at: date,
-                    upvotes,
-                    downvotes,
-                    is_upvoted,
-                    is_downvoted,
-                    is_hidden: comment.is_hidden,
-                };
-                out_comments.push(out_comment);
-                if out_comments.len() >= n {
-                    break;
+            }
+            if is_desc {
+                comments.reverse();
+            }
+            has_next = comments.len() >= n;
+        } else {
+            let (mut all_comments, reply_tos, next) =
+                get_comment_page(&DB, pid, &hidden_uids, &page_params)?;
+            has_next = next;
+            // the page is already cut, and the roots are in display order
+            let page = ParamsPage {
+                anchor: 0,
+                n: reply_tos.len(),
+                is_desc: false,
+            };
+            let (threaded, _) = thread_comments(&reply_tos, &page);
+            for (cid, depth, replies, close_threads) in threaded {
+                if let Some(comment) = all_comments.remove(&cid) {
+                    comments.push((comment, depth, replies, close_threads));
                 }
             }
         }
-        if is_desc {
-            out_comments.reverse();
+
+        let comment_upvotes_tree = DB.open_partition("comment_upvotes", Default::default())?;
+        let comment_downvotes_tree = DB.open_partition("comment_downvotes", Default::default())?;
+        let comment_revisions_tree = DB.open_partition("comment_revisions", Default::default())?;
+        let now = Timestamp::now().as_second();
+        for (comment, depth, replies, close_threads) in comments {
+            let user: User = get_one(&DB, "users", comment.uid)?;
+            let date = ts_to_date(comment.created_at);
+
+            let mut is_upvoted = false;
+            let mut is_downvoted = false;
+            let mut is_bookmarked = false;
+            let mut can_edit = false;
+
+            if let Some(claim) = claim {
+                let k = [
+                    u32_to_ivec(pid),
+                    u32_to_ivec(comment.cid),
+                    u32_to_ivec(claim.uid),
+                ]
+                .concat();
+                is_upvoted = comment_upvotes_tree.contains_key(&k)?;
+                is_downvoted = comment_downvotes_tree.contains_key(&k)?;
+                is_bookmarked = ReportTarget::Comment(pid, comment.cid).is_bookmarked(claim.uid)?;
+
+                can_edit = comment.uid == claim.uid
+                    && !comment.is_hidden
+                    && post.status == PostStatus::Normal
+                    && (inn.limit_edit_seconds == 0
+                        || comment.created_at + (inn.limit_edit_seconds as i64) >= now);
+            }
+
+            let prefix = [u32_to_ivec(pid), u32_to_ivec(comment.cid)].concat();
+            let is_edited = comment_revisions_tree
+                .inner()
+                .prefix(&prefix)
+                .nth(1)
+                .is_some();
+            let upvotes = get_count_by_prefix(&DB, "comment_upvotes", &prefix).unwrap_or_default();
+            let downvotes =
+                get_count_by_prefix(&DB, "comment_downvotes", &prefix).unwrap_or_default();
+
+            let out_comment = OutComment {
+                cid: comment.cid,
+                replies,
+                opens_thread: replies > 0 && depth < THREAD_MAX_DEPTH,
+                close_threads,
+                uid: comment.uid,
+                username: user.username,
+                content: comment.content,
+                created_at: date,
+                upvotes,
+                downvotes,
+                is_upvoted,
+                is_downvoted,
+                is_bookmarked,
+                is_hidden: comment.is_hidden,
+                is_muted: muted_uids.contains(&comment.uid),
+                can_edit,
+                is_edited,
+            };
+            out_comments.push(out_comment);
         }
     }
 
@@ -1796,6 +3358,43 @@ pub(crate) async fn post(
         can_delete = true;
     }
 
+    let poll = get_out_poll(
+        PollTarget::Post(pid),
+        claim.map(|c| c.uid),
+        post.status == PostStatus::Normal,
+    )?;
+
+    Ok(PostLookup::Found(PostView {
+        post: out_post,
+        comments: out_comments,
+        has_joined,
+        is_mod,
+        is_author,
+        is_bookmarked,
+        can_delete,
+        has_next,
+        poll,
+    }))
+}
+
+/// `GET /inn/:iid/:pid` post page
+pub(crate) async fn post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Query(params): Query<ParamsPost>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let n = site_config.per_page;
+    let view = match get_post_view(claim.as_ref(), iid, pid, &params, n)? {
+        PostLookup::Moved(target) => return Ok(Redirect::to(&target).into_response()),
+        PostLookup::Found(view) => view,
+    };
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(false);
+    let is_flat = params.view.as_deref() == Some("flat");
+
     let pageview = ks_incr_id(
         &DB.open_partition("post_pageviews", Default::default())?,
         u32_to_ivec(pid),
@@ -1806,27 +3405,32 @@ pub(crate) async fn post(
         false
     };
 
-    let title = out_post.title.clone();
+    let title = view.post.title.clone();
     let page_data = PageData::new(&title, &site_config, claim, has_unread);
     let page_post = PagePost {
         page_data,
-        post: out_post,
-        comments: out_comments,
+        post: view.post,
+        comments: view.comments,
         pageview,
         anchor,
         n,
         is_desc,
-        has_joined,
-        is_mod,
-        is_author,
-        can_delete,
+        has_joined: view.has_joined,
+        is_mod: view.is_mod,
+        is_author: view.is_author,
+        is_bookmarked: view.is_bookmarked,
+        can_delete: view.can_delete,
+        is_flat,
+        has_next: view.has_next,
+        poll: view.poll,
+        rules: escaped_rules(&DB, iid)?,
     };
 
     Ok(into_response(&page_post))
 }
 
 /// Form data: `/inn/:iid/:pid/` comment create
-#[derive(Deserialize, Validate)]
+#[derive(Deserialize, Validate, ToSchema)]
 pub(crate) struct FormComment {
     #[validate(length(min = 1, max = 10000))]
     content: String,
@@ -1836,19 +3440,15 @@ pub(crate) struct FormComment {
 pub(crate) async fn comment_post(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
-    ValidatedForm(input): ValidatedForm<FormComment>,
+    CsrfForm(csrf_token, input): CsrfForm<FormComment>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&csrf_token)?;
 
-    if let Some(spam_regex) = &site_config.spam_regex {
-        let re = regex::Regex::new(spam_regex).unwrap();
-        if re.is_match(&input.content) {
-            return Err(AppError::Custom("Spam detected".into()));
-        }
-    }
+    check_spam(&site_config, &[&input.content])?;
 
     let inn: Inn = get_one(&DB, "inns", iid)?;
     if inn.is_closed() {
@@ -1872,6 +3472,16 @@ pub(crate) async fn comment_post(
         return Err(AppError::WriteInterval);
     }
 
+    let user: User = get_one(&DB, "users", claim.uid)?;
+    InnRules::get(&DB, iid)?.check_account_age(&user, created_at)?;
+    let automod_input = AutomodInput {
+        title: None,
+        content: &input.content,
+        tags: &BTreeSet::new(),
+    };
+    let automod = automod_check(iid, &automod_input, &user, &inn_role)?;
+    let spam_score = spam_check(&DB, &site_config, &[&input.content])?;
+
     let post: Post = get_one(&DB, "posts", pid)?;
     if post.iid != iid {
         return Err(AppError::NotFound);
@@ -1880,13 +3490,85 @@ pub(crate) async fn comment_post(
         return Err(AppError::LockedOrHidden);
     }
 
-    let pid_ivec = u32_to_ivec(pid);
-    let cid = ks_incr_id(
-        &DB.open_partition("post_comments_count", Default::default())?,
-        &pid_ivec,
-    )?;
+    let pid_ivec = u32_to_ivec(pid);
+    let cid = ks_incr_id(
+        &DB.open_partition("post_comments_count", Default::default())?,
+        &pid_ivec,
+    )?;
+
+    let is_shadowbanned = Shadowban::is_on(&DB, claim.uid)?;
+    let (content, mentions, reply_to_cid) = comment_links(&input.content, iid, pid, cid)?;
+    for uid in mentions {
+        // notify user to be mentioned in comment
+        // prevent duplicate notifications
+        if uid != post.uid && !is_shadowbanned && !is_blocked(&DB, uid, claim.uid)? {
+            add_notification(&DB, uid, NtType::CommentMention, pid, cid)?;
+        }
+    }
+
+    let comment = Comment {
+        cid,
+        pid,
+        uid: claim.uid,
+        reply_to: reply_to_cid,
+        content: md2html(&content),
+        created_at,
+        is_hidden: spam_score.is_some()
+            || matches!(
+                automod,
+                Some((_, AutomodAction::Hold | AutomodAction::Hide))
+            ),
+    };
+    let k = [pid_ivec.clone(), u32_to_ivec(cid)].concat();
+    set_one_with_key(&DB, "post_comments", k, &comment)?;
+    index_reply(&DB, pid, &comment)?;
+    comment_save_revision(&DB, pid, cid, 1, input.content, created_at)?;
+    if let Some((rule, action)) = automod {
+        automod_apply(&DB, iid, pid, cid, rule, action)?;
+    } else if let Some(score) = spam_score {
+        spam_hold(&DB, iid, ReportTarget::Comment(pid, cid), score)?;
+    }
+
+    let k = [u32_to_ivec(claim.uid), pid_ivec, u32_to_ivec(cid)].concat();
+    DB.open_partition("user_comments", Default::default())?
+        .insert(k, [])?;
+
+    // only the fellow could update the timeline by adding comment
+    if inn_role >= InnRole::Fellow && !comment.is_hidden && !is_shadowbanned {
+        let inn_type = inn_rm_index(&DB, iid, pid)?;
+        inn_add_index(&DB, iid, pid, created_at as u32, inn_type)?;
+    }
+
+    // notify post author
+    if post.uid != claim.uid && !comment.is_hidden && !is_shadowbanned {
+        add_notification(&DB, post.uid, NtType::PostComment, pid, cid)?;
+    }
+    if !comment.is_hidden && !is_shadowbanned {
+        emit_comment(&DB, &post, cid, claim.uid, &claim.username);
+    }
+
+    User::update_stats(&DB, claim.uid, "comment")?;
+    claim.update_last_write(&DB)?;
+
+    if inn.is_open_access() {
+        DB.open_partition("tan", Default::default())?
+            .insert(format!("comt{pid}/{cid}"), [])?;
+    }
+
+    let target = format!("/post/{iid}/{pid}");
+    Ok(Redirect::to(&target))
+}
 
-    let mut content = input.content;
+/// Replace `@username` or `@uid` with links to the users, and `#cid` with a link to the replied
+/// comment. Returns the new content, the mentioned users and the replied comment.
+fn comment_links(
+    content: &str,
+    iid: u32,
+    pid: u32,
+    cid: u32,
+) -> Result<(String, Vec<u32>, Option<u32>), AppError> {
+    let mut content = content.to_owned();
+    let mut mentions = vec![];
 
     // extract @username or @uid notification
     let notifications = extract_element(&content, 5, '@');
@@ -1913,12 +3595,7 @@ pub(crate) async fn comment_post(
         let from = format!("@{notification}");
         let to = format!("@{notification_link}");
         content = content.replace(&from, &to);
-
-        // notify user to be mentioned in comment
-        // prevent duplicate notifications
-        if uid != post.uid {
-            add_notification(&DB, uid, NtType::CommentMention, pid, cid)?;
-        }
+        mentions.push(uid);
     }
 
     let reply_to = extract_element(&content, 5, '#');
@@ -1934,37 +3611,237 @@ pub(crate) async fn comment_post(
         reply_to_cid = Some(reply_cid);
     }
 
-    let comment = Comment {
-        cid,
-        pid,
-        uid: claim.uid,
-        reply_to: reply_to_cid,
-        content: md2html(&content),
+    Ok((content, mentions, reply_to_cid))
+}
+
+/// the latest revision of a comment, with its number. `None` if the comment has no revisions.
+fn comment_last_revision(
+    db: &TransactionalKeyspace,
+    pid: u32,
+    cid: u32,
+) -> Result<Option<(u32, CommentRevision)>, AppError> {
+    let prefix = [u32_to_ivec(pid), u32_to_ivec(cid)].concat();
+    let tree = db.open_partition("comment_revisions", Default::default())?;
+    match tree.inner().prefix(prefix).next_back() {
+        Some(i) => {
+            let (k, v) = i?;
+            let (revision, _): (CommentRevision, usize) =
+                bincode::decode_from_slice(&v, standard())?;
+            Ok(Some((u8_slice_to_u32(&k[8..12]), revision)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn comment_save_revision(
+    db: &TransactionalKeyspace,
+    pid: u32,
+    cid: u32,
+    rev: u32,
+    content: String,
+    created_at: i64,
+) -> Result<(), AppError> {
+    let k = [u32_to_ivec(pid), u32_to_ivec(cid), u32_to_ivec(rev)].concat();
+    let revision = CommentRevision {
+        content,
         created_at,
-        is_hidden: false,
     };
-    let k = [pid_ivec.clone(), u32_to_ivec(cid)].concat();
-    set_one_with_key(&DB, "post_comments", k, &comment)?;
+    set_one_with_key(db, "comment_revisions", k, &revision)
+}
 
-    let k = [u32_to_ivec(claim.uid), pid_ivec, u32_to_ivec(cid)].concat();
-    DB.open_partition("user_comments", Default::default())?
-        .insert(k, [])?;
+/// Comments written before revisions were recorded only have the rendered html,
+/// so the markdown is approximated by the text of it.
+fn comment_source(db: &TransactionalKeyspace, comment: &Comment) -> Result<String, AppError> {
+    match comment_last_revision(db, comment.pid, comment.cid)? {
+        Some((_, revision)) => Ok(revision.content),
+        None => Ok(ammonia::Builder::empty()
+            .clean(&comment.content)
+            .to_string()
+            .trim()
+            .to_owned()),
+    }
+}
 
-    // only the fellow could update the timeline by adding comment
-    if inn_role >= InnRole::Fellow {
-        let inn_type = inn_rm_index(&DB, iid, pid)?;
-        inn_add_index(&DB, iid, pid, created_at as u32, inn_type)?;
+/// Page data: `comment_edit.html`
+#[derive(Template)]
+#[template(path = "comment_edit.html")]
+struct PageCommentEdit<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    pid: u32,
+    cid: u32,
+    content: String,
+}
+
+/// check if the user can still edit the comment, returns the comment
+fn comment_can_edit(
+    db: &TransactionalKeyspace,
+    claim: &Claim,
+    iid: u32,
+    pid: u32,
+    cid: u32,
+) -> Result<Comment, AppError> {
+    let inn: Inn = get_one(db, "inns", iid)?;
+    if inn.is_closed() {
+        return Err(AppError::LockedOrHidden);
     }
 
-    // notify post author
-    if post.uid != claim.uid {
-        add_notification(&DB, post.uid, NtType::PostComment, pid, cid)?;
+    let post: Post = get_one(db, "posts", pid)?;
+    if post.iid != iid {
+        return Err(AppError::NotFound);
+    }
+    if post.status != PostStatus::Normal {
+        return Err(AppError::LockedOrHidden);
     }
 
-    User::update_stats(&DB, claim.uid, "comment")?;
-    claim.update_last_write(&DB)?;
+    let k = [u32_to_ivec(pid), u32_to_ivec(cid)].concat();
+    let v = db
+        .open_partition("post_comments", Default::default())?
+        .get(k)?
+        .ok_or(AppError::NotFound)?;
+    let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+    if comment.uid != claim.uid {
+        return Err(AppError::Unauthorized);
+    }
+    if comment.is_hidden {
+        return Err(AppError::LockedOrHidden);
+    }
 
-    if inn.is_open_access() {
+    if inn.limit_edit_seconds > 0
+        && comment.created_at + (inn.limit_edit_seconds as i64) < Timestamp::now().as_second()
+    {
+        return Err(AppError::Custom(format!(
+            "Comments can only be edited within {} seconds",
+            inn.limit_edit_seconds
+        )));
+    }
+
+    Ok(comment)
+}
+
+/// `GET /post/:iid/:pid/:cid/edit` comment edit page
+pub(crate) async fn comment_edit(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let comment = comment_can_edit(&DB, &claim, iid, pid, cid)?;
+    let content = comment_source(&DB, &comment)?;
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("edit comment", &site_config, Some(claim), has_unread);
+    let page_comment_edit = PageCommentEdit {
+        page_data,
+        iid,
+        pid,
+        cid,
+        content,
+    };
+
+    Ok(into_response(&page_comment_edit))
+}
+
+/// Form data: `/post/:iid/:pid/:cid/edit` comment edit
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormCommentEdit {
+    csrf_token: String,
+    #[validate(length(min = 1, max = 10000))]
+    content: String,
+}
+
+/// `POST /post/:iid/:pid/:cid/edit` comment edit
+///
+/// The previous versions are kept in `comment_revisions`. Only users newly mentioned are notified.
+pub(crate) async fn comment_edit_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+    ValidatedForm(input): ValidatedForm<FormCommentEdit>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    check_spam(&site_config, &[&input.content])?;
+
+    let inn_role = InnRole::get(&DB, iid, claim.uid)?.ok_or(AppError::Unauthorized)?;
+    if inn_role < InnRole::Limited {
+        return Err(AppError::Unauthorized);
+    }
+
+    let mut comment = comment_can_edit(&DB, &claim, iid, pid, cid)?;
+    let user: User = get_one(&DB, "users", claim.uid)?;
+    let automod_input = AutomodInput {
+        title: None,
+        content: &input.content,
+        tags: &BTreeSet::new(),
+    };
+    let automod = automod_check(iid, &automod_input, &user, &inn_role)?;
+    let spam_score = spam_check(&DB, &site_config, &[&input.content])?;
+
+    let (rev, old_source) = match comment_last_revision(&DB, pid, cid)? {
+        Some((rev, revision)) => (rev, revision.content),
+        None => {
+            let content = comment_source(&DB, &comment)?;
+            comment_save_revision(&DB, pid, cid, 1, content.clone(), comment.created_at)?;
+            (1, content)
+        }
+    };
+
+    if old_source == input.content {
+        let target = format!("/post/{iid}/{pid}");
+        return Ok(Redirect::to(&target));
+    }
+    let (_, old_mentions, _) = comment_links(&old_source, iid, pid, cid)?;
+
+    let post: Post = get_one(&DB, "posts", pid)?;
+    let (content, mentions, reply_to_cid) = comment_links(&input.content, iid, pid, cid)?;
+    let is_shadowbanned = Shadowban::is_on(&DB, claim.uid)?;
+    for uid in mentions {
+        if uid != post.uid
+            && !old_mentions.contains(&uid)
+            && !is_shadowbanned
+            && !is_blocked(&DB, uid, claim.uid)?
+        {
+            add_notification(&DB, uid, NtType::CommentMention, pid, cid)?;
+        }
+    }
+
+    comment.content = md2html(&content);
+    DB.open_partition("comment_replies", Default::default())?
+        .remove(reply_key(pid, &comment))?;
+    comment.reply_to = reply_to_cid;
+    if spam_score.is_some()
+        || matches!(
+            automod,
+            Some((_, AutomodAction::Hold | AutomodAction::Hide))
+        )
+    {
+        comment.is_hidden = true;
+    }
+    let k = [u32_to_ivec(pid), u32_to_ivec(cid)].concat();
+    set_one_with_key(&DB, "post_comments", k, &comment)?;
+    index_reply(&DB, pid, &comment)?;
+    if let Some((rule, action)) = automod {
+        automod_apply(&DB, iid, pid, cid, rule, action)?;
+    } else if let Some(score) = spam_score {
+        spam_hold(&DB, iid, ReportTarget::Comment(pid, cid), score)?;
+    }
+    comment_save_revision(
+        &DB,
+        pid,
+        cid,
+        rev + 1,
+        input.content,
+        Timestamp::now().as_second(),
+    )?;
+
+    let inn: Inn = get_one(&DB, "inns", iid)?;
+    if inn.is_open_access() && !comment.is_hidden {
         DB.open_partition("tan", Default::default())?
             .insert(format!("comt{pid}/{cid}"), [])?;
     }
@@ -1973,6 +3850,89 @@ pub(crate) async fn comment_post(
     Ok(Redirect::to(&target))
 }
 
+/// Vec data: comment revision
+struct OutCommentRevision {
+    rev: u32,
+    created_at: String,
+    diff: String,
+}
+
+/// Page data: `comment_history.html`
+#[derive(Template)]
+#[template(path = "comment_history.html")]
+struct PageCommentHistory<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    pid: u32,
+    cid: u32,
+    revisions: Vec<OutCommentRevision>,
+}
+
+/// `GET /post/:iid/:pid/:cid/history` comment revisions, for mods and the author
+///
+/// Every revision is shown as a diff against the one before it. Revisions of deleted comments
+/// are kept for mods.
+pub(crate) async fn comment_history(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let post: Post = get_one(&DB, "posts", pid)?;
+    if post.iid != iid {
+        return Err(AppError::NotFound);
+    }
+    let k = [u32_to_ivec(pid), u32_to_ivec(cid)].concat();
+    let author = match DB
+        .open_partition("post_comments", Default::default())?
+        .get(&k)?
+    {
+        Some(v) => {
+            let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+            Some(comment.uid)
+        }
+        None => None,
+    };
+    if author != Some(claim.uid)
+        && !User::is_mod(&DB, claim.uid, iid)?
+        && Role::from(claim.role) != Role::Admin
+    {
+        return Err(AppError::Unauthorized);
+    }
+
+    let mut revisions = vec![];
+    let mut previous = String::new();
+    let tree = DB.open_partition("comment_revisions", Default::default())?;
+    for i in tree.inner().prefix(k) {
+        let (k, v) = i?;
+        let (revision, _): (CommentRevision, usize) = bincode::decode_from_slice(&v, standard())?;
+        revisions.push(OutCommentRevision {
+            rev: u8_slice_to_u32(&k[8..12]),
+            created_at: ts_to_date(revision.created_at),
+            diff: diff_html(&previous, &revision.content),
+        });
+        previous = revision.content;
+    }
+    if revisions.is_empty() && author.is_none() {
+        return Err(AppError::NotFound);
+    }
+    revisions.reverse();
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("comment history", &site_config, Some(claim), has_unread);
+    let page_comment_history = PageCommentHistory {
+        page_data,
+        iid,
+        pid,
+        cid,
+        revisions,
+    };
+
+    Ok(into_response(&page_comment_history))
+}
+
 /// Page data: `preview.html`
 #[derive(Template)]
 #[template(path = "preview.html", escape = "none")]
@@ -1996,15 +3956,17 @@ pub(crate) async fn preview(
     Ok(into_response(&page_preview))
 }
 
-/// `GET /post/:iid/:pid/:cid/delete` comment delete
+/// `POST /post/:iid/:pid/:cid/delete` comment delete
 pub(crate) async fn comment_delete(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let k = [u32_to_ivec(claim.uid), u32_to_ivec(pid), u32_to_ivec(cid)].concat();
     if !DB
@@ -2014,40 +3976,65 @@ pub(crate) async fn comment_delete(
         return Err(AppError::Unauthorized);
     }
 
+    comment_rm(&DB, iid, pid, cid)?;
+
+    let target = format!("/post/{iid}/{pid}");
+    Ok(Redirect::to(&target))
+}
+
+/// Remove a comment deleted by its author. Its versions are kept for mods, see
+/// [`comment_history`].
+pub(super) fn comment_rm(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    pid: u32,
+    cid: u32,
+) -> Result<(), AppError> {
     let k = [u32_to_ivec(pid), u32_to_ivec(cid)].concat();
-    DB.open_partition("post_comments", Default::default())?
-        .remove(k)?;
+    let Some(v) = db
+        .open_partition("post_comments", Default::default())?
+        .take(&k)?
+    else {
+        return Ok(());
+    };
 
-    let inn_type = inn_rm_index(&DB, iid, pid)?;
-    let ks = DB.open_partition("post_comments", Default::default())?;
+    // comments never edited have no revisions yet
+    let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+    if comment_last_revision(db, pid, cid)?.is_none() {
+        let content = comment_source(db, &comment)?;
+        comment_save_revision(db, pid, cid, 1, content, comment.created_at)?;
+    }
+
+    let inn_type = inn_rm_index(db, iid, pid)?;
+    let ks = db.open_partition("post_comments", Default::default())?;
     let latest_id = ks.inner().prefix(u32_to_ivec(pid)).last();
 
     let timestamp = if let Some(Ok((_, v))) = latest_id {
         let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
         comment.created_at
     } else {
-        let post: Post = get_one(&DB, "posts", pid)?;
+        let post: Post = get_one(db, "posts", pid)?;
         post.created_at
     };
 
-    inn_add_index(&DB, iid, pid, timestamp as u32, inn_type)?;
+    inn_add_index(db, iid, pid, timestamp as u32, inn_type)?;
 
-    DB.open_partition("tan", Default::default())?
+    db.open_partition("tan", Default::default())?
         .remove(format!("comt{pid}/{cid}"))?;
-
-    let target = format!("/post/{iid}/{pid}");
-    Ok(Redirect::to(&target))
+    Ok(())
 }
 
-/// `GET /post/:iid/:pid/:cid/hide` comment hide
+/// `POST /post/:iid/:pid/:cid/hide` comment hide
 pub(crate) async fn comment_hide(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+    Form(form): Form<FormModAction>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let k = [u32_to_ivec(claim.uid), u32_to_ivec(iid)].concat();
     if !DB
@@ -2067,6 +4054,20 @@ pub(crate) async fn comment_hide(
     comment.is_hidden = !comment.is_hidden;
 
     set_one_with_key(&DB, "post_comments", k, &comment)?;
+    train_comment(&DB, &comment, comment.is_hidden)?;
+    let action = if comment.is_hidden {
+        ModAction::Hide
+    } else {
+        ModAction::Unhide
+    };
+    ModLog::add(
+        &DB,
+        claim.uid,
+        iid,
+        ModTarget::Comment(pid, cid),
+        action,
+        InnRules::cite(&DB, iid, form.rule, form.reason)?,
+    )?;
     add_notification(
         &DB,
         comment.uid,
@@ -2079,15 +4080,17 @@ pub(crate) async fn comment_hide(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/upvote` post upvote
+/// `POST /inn/:iid/:pid/upvote` post upvote
 pub(crate) async fn post_upvote(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let inn: Inn = get_one(&DB, "inns", iid)?;
     if inn.is_closed() {
@@ -2106,15 +4109,17 @@ pub(crate) async fn post_upvote(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/:cid/upvote` comment upvote
+/// `POST /inn/:iid/:pid/:cid/upvote` comment upvote
 pub(crate) async fn comment_upvote(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
     let k = [u32_to_ivec(pid), u32_to_ivec(cid), u32_to_ivec(claim.uid)].concat();
 
     let inn: Inn = get_one(&DB, "inns", iid)?;
@@ -2133,15 +4138,17 @@ pub(crate) async fn comment_upvote(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/downvote` post downvote
+/// `POST /inn/:iid/:pid/downvote` post downvote
 pub(crate) async fn post_downvote(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
     let inn: Inn = get_one(&DB, "inns", iid)?;
     if inn.is_closed() {
         return Err(AppError::LockedOrHidden);
@@ -2159,47 +4166,79 @@ pub(crate) async fn post_downvote(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/delete` post delete
+/// `POST /inn/:iid/:pid/delete` post delete
 pub(crate) async fn post_delete(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
     let mut post: Post = get_one(&DB, "posts", pid)?;
     let count = get_count_by_prefix(&DB, "post_comments", &u32_to_ivec(pid))?;
 
     if count == 0 && post.uid == claim.uid {
-        post.content = PostContent::Markdown("*Post deleted by author.*".into());
-        set_one(&DB, "posts", pid, &post)?;
-
-        // remove this post from inn timeline
-        inn_rm_index(&DB, iid, pid)?;
-        // delete tags
-        let mut batch = DB.inner().batch();
-        let tags_ks = DB.inner().open_partition("tags", Default::default())?;
-        for tag in post.tags {
-            let k = [tag.as_bytes(), &u32_to_ivec(post.pid)].concat();
-            batch.remove(&tags_ks, k);
-        }
-        batch.commit()?;
+        post_rm(&DB, &mut post)?;
     }
 
     let target = format!("/post/{iid}/{pid}");
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/:cid/downvote` comment downvote
+const POST_DELETED: &str = "*Post deleted by author.*";
+
+/// Whether the post has been deleted by its author, see [`post_rm`]
+fn is_deleted(post: &Post) -> bool {
+    matches!(post.content, PostContent::Markdown(ref content) if content == POST_DELETED)
+}
+
+/// Replace the content of a post deleted by its author, and take it out of the inn timeline,
+/// tags, schedule and review queue. Its revisions are kept for mods, see [`post_history`].
+pub(super) fn post_rm(db: &TransactionalKeyspace, post: &mut Post) -> Result<(), AppError> {
+    let (iid, pid) = (post.iid, post.pid);
+    // posts never edited have no revisions yet
+    if post_last_revision(db, pid)? == 0 {
+        post_save_revision(db, post, 1, post.uid, post.created_at)?;
+    }
+    post.content = PostContent::Markdown(POST_DELETED.into());
+    set_one(db, "posts", pid, &*post)?;
+
+    // remove this post from inn timeline
+    inn_rm_index(db, iid, pid)?;
+    // delete tags
+    let mut batch = db.inner().batch();
+    let tags_ks = db.inner().open_partition("tags", Default::default())?;
+    for tag in &post.tags {
+        let k = [tag.as_bytes(), &u32_to_ivec(pid)].concat();
+        batch.remove(&tags_ks, k);
+    }
+    let scheduled_ks = db
+        .inner()
+        .open_partition("scheduled_posts", Default::default())?;
+    batch.remove(&scheduled_ks, u32_to_ivec(pid));
+    let pending_ks = db
+        .inner()
+        .open_partition("inn_pending", Default::default())?;
+    batch.remove(&pending_ks, [u32_to_ivec(iid), u32_to_ivec(pid)].concat());
+    batch.commit()?;
+
+    poll_delete(PollTarget::Post(pid))
+}
+
+/// `POST /inn/:iid/:pid/:cid/downvote` comment downvote
 pub(crate) async fn comment_downvote(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid, cid)): Path<(u32, u32, u32)>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
     let k = [u32_to_ivec(pid), u32_to_ivec(cid), u32_to_ivec(claim.uid)].concat();
 
     let inn: Inn = get_one(&DB, "inns", iid)?;
@@ -2218,25 +4257,41 @@ pub(crate) async fn comment_downvote(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/lock` post lock
+/// `POST /inn/:iid/:pid/lock` post lock
 pub(crate) async fn post_lock(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormModAction>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let mut post: Post = get_one(&DB, "posts", pid)?;
+    if post.status == PostStatus::Pending {
+        return Err(AppError::LockedOrHidden);
+    }
 
     if User::is_mod(&DB, claim.uid, iid)? || Role::from(claim.role) == Role::Admin {
-        if post.status != PostStatus::LockedByMod {
+        let action = if post.status != PostStatus::LockedByMod {
             add_notification(&DB, post.uid, NtType::PostLock, claim.uid, post.pid)?;
-            post.status = PostStatus::LockedByMod
-        } else if post.status == PostStatus::LockedByMod {
-            post.status = PostStatus::Normal
-        }
+            post.status = PostStatus::LockedByMod;
+            emit_post(&DB, WebhookEvent::PostLocked, &post, claim.uid);
+            ModAction::Lock
+        } else {
+            post.status = PostStatus::Normal;
+            ModAction::Unlock
+        };
+        ModLog::add(
+            &DB,
+            claim.uid,
+            iid,
+            ModTarget::Post(pid),
+            action,
+            InnRules::cite(&DB, iid, form.rule, form.reason)?,
+        )?;
     } else if post.uid == claim.uid {
         if post.status == PostStatus::Normal {
             post.status = PostStatus::LockedByUser
@@ -2251,26 +4306,43 @@ pub(crate) async fn post_lock(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/hide` post hide
+/// `POST /inn/:iid/:pid/hide` post hide
 pub(crate) async fn post_hide(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormModAction>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let mut post: Post = get_one(&DB, "posts", pid)?;
+    if post.status == PostStatus::Pending {
+        return Err(AppError::LockedOrHidden);
+    }
     let old_status = post.status.clone();
 
     if User::is_mod(&DB, claim.uid, iid)? || Role::from(claim.role) == Role::Admin {
-        if post.status != PostStatus::HiddenByMod {
+        let action = if post.status != PostStatus::HiddenByMod {
             add_notification(&DB, post.uid, NtType::PostHide, claim.uid, post.pid)?;
-            post.status = PostStatus::HiddenByMod
-        } else if post.status == PostStatus::HiddenByMod {
-            post.status = PostStatus::Normal
-        }
+            post.status = PostStatus::HiddenByMod;
+            emit_post(&DB, WebhookEvent::PostHidden, &post, claim.uid);
+            ModAction::Hide
+        } else {
+            post.status = PostStatus::Normal;
+            ModAction::Unhide
+        };
+        train_post(&DB, &post, post.status == PostStatus::HiddenByMod)?;
+        ModLog::add(
+            &DB,
+            claim.uid,
+            iid,
+            ModTarget::Post(pid),
+            action,
+            InnRules::cite(&DB, iid, form.rule, form.reason)?,
+        )?;
     } else if post.uid == claim.uid {
         if post.status < PostStatus::HiddenByUser {
             post.status = PostStatus::HiddenByUser
@@ -2302,15 +4374,17 @@ pub(crate) async fn post_hide(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /inn/:iid/:pid/pin` post pin
+/// `POST /inn/:iid/:pid/pin` post pin
 pub(crate) async fn post_pin(
     cookie: Option<TypedHeader<Cookie>>,
     Path((iid, pid)): Path<(u32, u32)>,
+    Form(form): Form<FormModAction>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
         return Err(AppError::Unauthorized);
@@ -2318,11 +4392,21 @@ pub(crate) async fn post_pin(
 
     let k = [u32_to_ivec(iid), u32_to_ivec(pid)].concat();
     let tree = DB.open_partition("post_pins", Default::default())?;
-    if tree.contains_key(&k)? {
+    let action = if tree.contains_key(&k)? {
         tree.remove(&k)?;
+        ModAction::Unpin
     } else {
         tree.insert(&k, [])?;
-    }
+        ModAction::Pin
+    };
+    ModLog::add(
+        &DB,
+        claim.uid,
+        iid,
+        ModTarget::Post(pid),
+        action,
+        InnRules::cite(&DB, iid, form.rule, form.reason)?,
+    )?;
 
     let target = format!("/post/{iid}/{pid}");
     Ok(Redirect::to(&target))
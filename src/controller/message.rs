@@ -13,6 +13,7 @@ use crate::{controller::filters, set_one};
 
 use super::{
     Claim, SiteConfig, User,
+    block::is_blocked,
     db_utils::{get_one, incr_id, u8_slice_to_u32, u32_to_ivec},
     fmt::clean_html,
     meta_handler::{PageData, into_response},
@@ -54,6 +55,7 @@ pub(crate) async fn message(
 /// Form data: `/message/:uid`
 #[derive(Deserialize)]
 pub(crate) struct FormMessage {
+    csrf_token: String,
     message: String,
 }
 
@@ -66,6 +68,10 @@ pub(crate) async fn message_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if is_blocked(&DB, uid, claim.uid)? {
+        return Err(AppError::Blocked);
+    }
 
     let mid = incr_id(&DB, "messages_count")?;
     let message = clean_html(&input.message);
@@ -117,6 +123,7 @@ pub(crate) async fn key(
 /// Form data: `/key`
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormKey {
+    csrf_token: String,
     #[validate(length(max = 4096))]
     pub_key: String,
 }
@@ -129,6 +136,7 @@ pub(crate) async fn key_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
 
     let pub_key = clean_html(&input.pub_key);
     let mut user: User = get_one(&DB, "users", claim.uid)?;
@@ -5,8 +5,9 @@ use crate::{DB, error::AppError, get_one};
 use askama::Template;
 use axum::{
     Form,
+    body::{Body, Bytes},
     extract::{FromRequest, Path, Request, rejection::FormRejection},
-    http::{HeaderMap, HeaderValue, Uri},
+    http::{HeaderMap, HeaderValue, Method, Uri},
     response::{Html, IntoResponse, Redirect, Response},
 };
 use axum_extra::{
@@ -15,7 +16,7 @@ use axum_extra::{
 };
 use http::{HeaderName, StatusCode, header};
 use include_dir::{Dir, include_dir};
-use serde::de::DeserializeOwned;
+use serde::{Deserialize, de::DeserializeOwned};
 use tracing::error;
 use validator::Validate;
 
@@ -27,9 +28,10 @@ struct PageError<'a> {
     error: String,
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let status = match self {
+impl AppError {
+    /// `NonLogin` is redirected to `/signin` by pages, it is `401` for the api
+    pub(super) fn status_code(&self) -> StatusCode {
+        match self {
             AppError::CaptchaError
             | AppError::NameExists
             | AppError::InnCreateLimit
@@ -44,11 +46,22 @@ impl IntoResponse for AppError {
             | AppError::AxumFormRejection(_) => StatusCode::BAD_REQUEST,
             AppError::NotFound => StatusCode::NOT_FOUND,
             AppError::WriteInterval => StatusCode::TOO_MANY_REQUESTS,
-            AppError::NonLogin => return Redirect::to("/signin").into_response(),
-            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
-            AppError::Banned => StatusCode::FORBIDDEN,
+            AppError::NonLogin | AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Banned
+            | AppError::AwaitingApproval
+            | AppError::Blocked
+            | AppError::InvalidCsrfToken => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if matches!(self, AppError::NonLogin) {
+            return Redirect::to("/signin").into_response();
+        }
+        let status = self.status_code();
 
         error!("{}, {}", status, self);
         let site_config = SiteConfig::get(&DB).unwrap_or_default();
@@ -169,6 +182,7 @@ pub(super) struct PageData<'a> {
     pub(super) lang: String,
     pub(super) custom_footer_code: Option<&'a str>,
     pub(super) login_captcha: bool,
+    pub(super) csrf_token: String,
 }
 
 impl<'a> PageData<'a> {
@@ -179,6 +193,10 @@ impl<'a> PageData<'a> {
         has_unread: bool,
     ) -> Self {
         let site_description = md2html(&site_config.description);
+        let csrf_token = claim
+            .as_ref()
+            .map(|claim| claim.csrf_token())
+            .unwrap_or_default();
         let lang = claim
             .as_ref()
             .and_then(|claim| claim.lang.as_ref())
@@ -196,6 +214,7 @@ impl<'a> PageData<'a> {
             site_name: &site_config.site_name,
             title,
             login_captcha: site_config.login_captcha,
+            csrf_token,
         }
     }
 }
@@ -219,6 +238,22 @@ pub(super) struct ParamsPage {
     pub(super) is_desc: bool,
 }
 
+/// Form data: the hidden `csrf_token` field of every state-changing form
+/// which carries no other data.
+#[derive(Deserialize)]
+pub(crate) struct FormCsrf {
+    pub(super) csrf_token: String,
+}
+
+/// Form data: a moderation action, the optional reason is recorded in the mod log.
+#[derive(Deserialize)]
+pub(crate) struct FormModAction {
+    pub(super) csrf_token: String,
+    pub(super) reason: Option<String>,
+    /// number of the inn rule to cite, 0 for none
+    pub(super) rule: Option<usize>,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub(crate) struct ValidatedForm<T>(pub T);
 
@@ -237,6 +272,39 @@ where
     }
 }
 
+/// A [ValidatedForm] whose `csrf_token` is read apart from the data, for forms whose data is
+/// stored as it is, like [SiteConfig] and drafts of posts. Check it by [Claim::check_csrf].
+pub(crate) struct CsrfForm<T>(pub String, pub T);
+
+impl<T, S> FromRequest<S> for CsrfForm<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req.headers().get(header::CONTENT_TYPE).cloned();
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| AppError::Custom(e.body_text()))?;
+        // the body is read twice, once for the token and once for the data
+        let form_request = || {
+            let mut req = Request::new(Body::from(bytes.clone()));
+            *req.method_mut() = Method::POST;
+            if let Some(content_type) = &content_type {
+                req.headers_mut()
+                    .insert(header::CONTENT_TYPE, content_type.clone());
+            }
+            req
+        };
+        let Form(FormCsrf { csrf_token }) = Form::from_request(form_request(), state).await?;
+        let Form(value) = Form::<T>::from_request(form_request(), state).await?;
+        value.validate()?;
+        Ok(CsrfForm(csrf_token, value))
+    }
+}
+
 /// Render a [`Template`] into a [`Response`], or render an error page.
 pub(crate) fn into_response<T: ?Sized + askama::Template>(tmpl: &T) -> Response {
     match tmpl.render() {
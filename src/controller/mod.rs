@@ -4,17 +4,23 @@
 //! involve data migration, otherwise data might be lost.
 //!
 //! ### user
-//! | tree             | key                  | value            |
-//! |------------------|----------------------|------------------|
-//! | default          | "users_count"        | N                |
-//! | "users"          | `uid`                | [`User`]         |
-//! | "usernames"      | `username`           | `uid`            |
-//! | "user_following" | `uid#uid`            | `[]`            |
-//! | "user_followers" | `uid#uid`            | `[]`            |
-//! | "user_stats"     | `timestamp#uid#type` | N                |
-//! | "user_uploads"   | `uid#img_id`         | `image_hash.ext` |
-//! | default          | "imgs_count"         | N                |
-//! | "tan"            | `ctype#id`           | `[]`            |
+//! | tree                | key                  | value            |
+//! |---------------------|----------------------|------------------|
+//! | default             | "users_count"        | N                |
+//! | "users"             | `uid`                | [`User`]         |
+//! | "usernames"         | `username`           | `uid`            |
+//! | "deleted_users"     | `uid`                | `&[0/1]`         |
+//! | "user_following"    | `uid#uid`            | `[]`             |
+//! | "user_followers"    | `uid#uid`            | `[]`             |
+//! | "user_stats"        | `timestamp#uid#type` | N                |
+//! | "user_uploads"      | `uid#img_id`         | `image_hash.ext` |
+//! | default             | "imgs_count"         | N                |
+//! | "tan"               | `ctype#id`           | `[]`             |
+//! | "tan_deleted_users" | `uid`                | `[]`             |
+//!
+//! Deleted accounts are kept in "users", "deleted_users" tells whether their content was purged.
+//! Docs of the uids in "tan_deleted_users" are deleted from the search index at startup, search
+//! leaves them out until then.
 //!
 //! ### notification
 //! | tree            | key                   | value             |
@@ -38,11 +44,74 @@
 //! | "solo_users_like"  | `sid#uid`     | `[]`            |
 //! | "solo_timeline"    | `sid`         | `uid#solo_type`  |
 //! | "hashtags"         | `hashtag#sid` | `[]`            |
+//! | "spam_solos"       | `sid`         | `solo_type`      |
 //!
+//! Solos held by the spam filter are private, "spam_solos" keeps the type their author chose.
 //! ### session
-//! | tree       | key                | value     |
-//! |------------|--------------------|-----------|
-//! | "sessions" | `timestamp_nanoid` | [`Claim`] |
+//! | tree           | key                | value              |
+//! |----------------|--------------------|--------------------|
+//! | "sessions"     | `timestamp_nanoid` | [`Claim`]          |
+//! | "user_totp"    | `uid`              | `secret#last_step` |
+//! | "totp_tickets" | `timestamp_nanoid` | `uid#remember`     |
+//!
+//! ### login throttling
+//! | tree             | key                                    | value |
+//! |------------------|----------------------------------------|-------|
+//! | "login_failures" | `timestamp_ip_{ip}`                    | N     |
+//! | "login_failures" | `timestamp_account_{uid or name}_{ip}` | N     |
+//! | "login_failures" | `timestamp_user_{uid or name}`         | N     |
+//!
+//! ### bans
+//! `id` is 0 for site bans, otherwise it is the iid of the inn.
+//!
+//! | tree   | key       | value     |
+//! |--------|-----------|-----------|
+//! | "bans" | `id#uid`  | [`Ban`]   |
+//!
+//! ### shadowbans
+//! | tree              | key   | value         |
+//! |-------------------|-------|---------------|
+//! | "shadowbans"      | `uid` | [`Shadowban`] |
+//! | "shadowban_posts" | `pid` | `uid`         |
+//!
+//! ### block and mute
+//! | tree          | key       | value |
+//! |---------------|-----------|-------|
+//! | "user_blocks" | `uid#uid` | `[]` |
+//! | "user_mutes"  | `uid#uid` | `[]` |
+//!
+//! ### registration
+//! | tree              | key                | value               |
+//! |-------------------|--------------------|---------------------|
+//! | "invites"         | `timestamp_nanoid` | `uid#max_uses#uses` |
+//! | "pending_signups" | `uid`              | `session_id`       |
+//!
+//! ### email
+//! | tree           | key                | value              |
+//! |----------------|--------------------|--------------------|
+//! | default        | "emails_count"     | N                  |
+//! | "email_queue"  | `eid`              | [`QueuedEmail`]    |
+//! | "user_emails"  | `uid`              | [`UserEmail`]      |
+//! | "email_uids"   | `address`          | `uid`              |
+//! | "email_tokens" | `timestamp_nanoid` | `uid#kind#address` |
+//!
+//! ### api tokens
+//! | tree         | key                | value        |
+//! |--------------|--------------------|--------------|
+//! | "api_tokens" | `timestamp_nanoid` | [`ApiToken`] |
+//!
+//! ### webhooks
+//! | tree                 | key                        | value                 |
+//! |----------------------|----------------------------|-----------------------|
+//! | default              | "webhooks_count"           | N                     |
+//! | default              | "webhook_deliveries_count" | N                     |
+//! | "webhooks"           | `whid`                     | [`Webhook`]           |
+//! | "inn_webhooks"       | `iid#whid`                 | `[]`                  |
+//! | "webhook_deliveries" | `did`                      | [`WebhookDelivery`]   |
+//! | "webhook_log"        | `whid#did`                 | `[]`                  |
+//! | "webhook_queue"      | `did`                      | `[]`                  |
+//!
+//! Site-wide webhooks have `iid` 0.
 //!
 //! ### site config
 //! | tree      | key           | value          |
@@ -60,9 +129,13 @@
 //! | "user_inns"     | `uid#iid`     | `[]`               |
 //! | "inn_apply"     | `iid#uid`     | `[]`               |
 //! | "inn_users"     | `iid#uid`     | `&[1/2/3/4/5/8/10]` |
-//! | "drafts"        | `uid`         | [`FormPost`]        |
+//! | "drafts"        | `uid#title`   | [`FormPost`]        |
 //! | "inn_feeds"     | `iid#feed_id` | `uid`               |
 //! | "inn_items"     | `iid#item_id` | `[]`               |
+//! | "inn_rules"     | `iid`         | [`InnRules`]        |
+//! | "inn_automod"   | `iid`         | `Vec<AutomodRule>`  |
+//! | "inn_premod"    | `iid`         | [`PreModeration`]   |
+//! | "inn_pending"   | `iid#pid`     | `uid#uid`           |
 //!
 //! ### post
 //! | tree                | key                 | value                |
@@ -78,6 +151,15 @@
 //! | "post_timeline"     | `timestamp#iid#pid` | `inn_type`           |
 //! | "post_pageviews"    | `pid`               | N                    |
 //! | "post_pins"         | `iid#pid`           | `[]`                |
+//! | "post_revisions"    | `pid#rev`           | [`PostRevision`]     |
+//! | "scheduled_posts"   | `pid`               | `publish_at#uid#uid` |
+//! | "post_redirects"    | `pid`               | `pid`                |
+//!
+//! Scheduled posts are saved in "posts" but indexed only when they are published.
+//! The uids in "scheduled_posts" are the mentioned users to be notified then.
+//! A post merged into another one is kept in "posts", and "post_redirects" points to the canonical post.
+//! Posts waiting for review are [`PostStatus::Pending`] and queued in "inn_pending", they are
+//! indexed only when mods approve them. The uids in "inn_pending" are the mentioned users.
 //!
 //! ### comment
 //! | tree                  | key                  | value       |
@@ -87,6 +169,56 @@
 //! | "user_comments"       | `uid#pid#cid`        | `[]`       |
 //! | "comment_upvotes"     | `pid#cid#uid`        | `[]`       |
 //! | "comment_downvotes"   | `pid#cid#uid`        | `[]`       |
+//! | "comment_revisions"   | `pid#cid#rev`        | [`CommentRevision`] |
+//! | "comment_replies"     | `pid#reply_to#cid`   | `[]`       |
+//!
+//! Top-level comments are kept in "comment_replies" as replies to cid 0. Deleted comments stay
+//! there, so their replies can still be found.
+//!
+//! ### report
+//! | tree          | key             | value       |
+//! |---------------|-----------------|-------------|
+//! | default       | "reports_count" | N           |
+//! | "reports"     | `rid`           | [`Report`]  |
+//! | "inn_reports" | `iid#rid`       | `[]`       |
+//!
+//! Open reports of solos are queued under iid 0, handled by admins.
+//!
+//! ### poll
+//! | tree              | key       | value       |
+//! |-------------------|-----------|-------------|
+//! | "post_polls"      | `pid`     | [`Poll`]    |
+//! | "post_poll_votes" | `pid#uid` | `&[option]` |
+//! | "solo_polls"      | `sid`     | [`Poll`]    |
+//! | "solo_poll_votes" | `sid#uid` | `&[option]` |
+//!
+//! ### bookmark
+//! | tree                    | key               | value        |
+//! |-------------------------|-------------------|--------------|
+//! | default                 | "bookmarks_count" | N            |
+//! | "bookmarks"             | `bid`             | [`Bookmark`] |
+//! | "user_bookmarks"        | `uid#bid`         | `collection` |
+//! | "user_bookmark_targets" | `uid#target`      | `bid`        |
+//! | "bookmark_tokens"       | `uid`             | `token`      |
+//!
+//! `target` is the encoded [`ReportTarget`].
+//!
+//! ### spam filter
+//! | tree          | key           | value             |
+//! |---------------|---------------|-------------------|
+//! | default       | "spam_docs"   | `spam#ham`        |
+//! | "spam_tokens" | `token`       | `spam#ham`        |
+//! | "spam_labels" | `ctype#id`    | `&[0/1]#tokens`   |
+//!
+//! `ctype#id` is the key used in "tan", like `post1` or `comt1/2`. `tokens` are the encoded tokens
+//! the target was trained with, so they can be taken back after the text has changed.
+//!
+//! ### mod log
+//! | tree          | key             | value       |
+//! |---------------|-----------------|-------------|
+//! | default       | "mod_log_count" | N           |
+//! | "mod_log"     | `lid`           | [`ModLog`]  |
+//! | "inn_mod_log" | `iid#lid`       | `[]`       |
 //!
 //! ### rss
 //! | tree                  | key                  | value       |
@@ -111,22 +243,38 @@
 //! | "user_messages"       | `uid#mid`        | `[]`              |
 
 pub(super) mod db_utils;
+pub(super) mod email;
 pub(super) mod feed;
 pub(super) mod meta_handler;
 pub(super) mod notification;
 pub(super) mod tantivy;
+pub(super) mod token;
 
+pub(super) mod account;
 pub(super) mod admin;
+pub(super) mod api;
+pub(super) mod automod;
+pub(super) mod block;
+pub(super) mod bookmark;
 pub(super) mod inn;
 pub(super) mod message;
+pub(super) mod mod_log;
+pub(super) mod poll;
+pub(super) mod premod;
+pub(super) mod registration;
+pub(super) mod report;
+pub(super) mod shadowban;
 pub(super) mod solo;
+pub(super) mod spam;
 pub(super) mod upload;
 pub(super) mod user;
+pub(super) mod webhook;
 
 mod fmt;
 
 use self::db_utils::{
-    get_ids_by_prefix, get_one, incr_id, ivec_to_u32, ks_incr_id, u8_slice_to_u32, u32_to_ivec,
+    get_ids_by_prefix, get_one, incr_id, ivec_to_u32, ks_incr_id, set_one, u8_slice_to_u32,
+    u32_to_ivec,
 };
 use self::fmt::md2html;
 use self::tantivy::{FIELDS, ToDoc};
@@ -140,6 +288,7 @@ use jiff::{Timestamp, ToSpan};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
+use utoipa::ToSchema;
 use validator::Validate;
 
 /// user
@@ -316,6 +465,67 @@ struct Inn {
     limit_edit_seconds: u32,
 }
 
+/// Numbered rules of an inn which reports and mod actions can cite, and the requirements to
+/// post in it. Inns without rules use the default, which requires nothing.
+#[derive(Encode, Decode, Debug, Default)]
+struct InnRules {
+    rules: Vec<String>,
+    /// minimum account age to post or comment
+    min_account_days: u32,
+    /// minimum [`InnRole`](user::InnRole) to start threads, 0 for anyone who can post
+    min_role_to_post: u8,
+    /// if not empty, every post needs at least one tag, and only these tags are allowed
+    tags: Vec<String>,
+    /// titles of posts must match it if not empty
+    title_regex: String,
+}
+
+/// New posts of these members wait in the review queue of the inn, see [`PostStatus::Pending`].
+/// Inns without it use the default, which reviews nothing.
+#[derive(Encode, Decode, Debug, Default)]
+struct PreModeration {
+    /// members up to this [`InnRole`](user::InnRole), 0 for none
+    max_role: u8,
+    /// accounts younger than these days, 0 for none
+    account_days: u32,
+}
+
+/// What automod does with a post or a comment matching an [`AutomodRule`]
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+enum AutomodAction {
+    Reject,
+    /// new posts wait in the review queue, others are hidden and reported
+    Hold,
+    Hide,
+    /// lock it, only for posts
+    Lock,
+    NotifyMods,
+}
+
+impl Display for AutomodAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A rule of the automod of an inn. Empty conditions are ignored, the others must all match.
+#[derive(Encode, Decode, Debug)]
+struct AutomodRule {
+    /// regex of the title, comments never match it
+    title: String,
+    /// regex of the content
+    body: String,
+    /// regex matching any tag
+    tags: String,
+    /// domains of links, subdomains included
+    domains: Vec<String>,
+    /// accounts younger than these days
+    account_days: u32,
+    /// members below this [`InnRole`](user::InnRole), 0 for all
+    below_role: u8,
+    action: AutomodAction,
+}
+
 impl Inn {
     fn is_open_access(&self) -> bool {
         InnType::from(self.inn_type) == InnType::Public
@@ -340,6 +550,8 @@ pub(super) enum PostStatus {
     HiddenByUser,
     LockedByMod,
     HiddenByMod,
+    /// waiting for the review of mods
+    Pending,
 }
 
 impl Display for PostStatus {
@@ -414,8 +626,19 @@ impl ToDoc for Post {
     }
 }
 
+/// A saved version of a post. `uid` is the user who made the edit, which can be a mod
+/// restoring an old revision.
+#[derive(Encode, Decode, Debug)]
+struct PostRevision {
+    uid: u32,
+    title: String,
+    tags: Vec<String>,
+    content: String,
+    created_at: i64,
+}
+
 /// Form data: `/inn/:iid/post/:pid` post create/edit page
-#[derive(Debug, Default, Deserialize, Validate, Encode, Decode)]
+#[derive(Debug, Default, Deserialize, Validate, Encode, Decode, ToSchema)]
 pub(super) struct FormPost {
     #[validate(skip)]
     iid: u32,
@@ -429,6 +652,19 @@ pub(super) struct FormPost {
     is_draft: Option<bool>,
     #[validate(skip)]
     delete_draft: Option<bool>,
+    /// `YYYY-MM-DDTHH:MM` in UTC, empty to publish at once
+    #[validate(skip)]
+    publish_at: Option<String>,
+}
+
+impl FormPost {
+    /// Drafts saved before `publish_at` was added lack the last field, which is decoded as `None`.
+    fn decode_draft(v: &[u8]) -> Result<Self, AppError> {
+        match bincode::decode_from_slice(v, standard()) {
+            Ok((draft, _)) => Ok(draft),
+            Err(_) => Ok(bincode::decode_from_slice(&[v, &[0]].concat(), standard())?.0),
+        }
+    }
 }
 
 #[derive(Encode, Decode, Serialize, Debug)]
@@ -442,6 +678,13 @@ struct Comment {
     is_hidden: bool,
 }
 
+/// A saved version of a comment, `content` is the markdown written by the user.
+#[derive(Encode, Decode, Debug)]
+struct CommentRevision {
+    content: String,
+    created_at: i64,
+}
+
 impl ToDoc for Comment {
     fn to_doc(&self, _id: Option<u32>) -> TantivyDocument {
         let mut doc = TantivyDocument::default();
@@ -489,6 +732,221 @@ impl ToDoc for Item {
     }
 }
 
+/// What is reported or bookmarked
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+enum ReportTarget {
+    Post(u32),
+    /// pid, cid
+    Comment(u32, u32),
+    Solo(u32),
+}
+
+#[derive(Encode, Decode, Debug)]
+struct Report {
+    rid: u32,
+    uid: u32,
+    iid: u32,
+    target: ReportTarget,
+    reason: String,
+    created_at: i64,
+}
+
+/// A bookmark of a user. `collection` is empty for unsorted bookmarks.
+#[derive(Encode, Decode, Debug)]
+struct Bookmark {
+    uid: u32,
+    target: ReportTarget,
+    collection: String,
+    created_at: i64,
+}
+
+/// What a mod acted on, see [`ModLog`]
+#[derive(Encode, Decode, Debug)]
+enum ModTarget {
+    Post(u32),
+    /// pid, cid
+    Comment(u32, u32),
+    Solo(u32),
+    User(u32),
+    /// uid, img_id
+    Image(u32, u32),
+}
+
+#[derive(Encode, Decode, Debug)]
+enum ModAction {
+    Lock,
+    Unlock,
+    Hide,
+    Unhide,
+    Pin,
+    Unpin,
+    Delete,
+    /// restore a post revision
+    Restore(u32),
+    /// set a site role or inn role
+    Role(String),
+    /// move a post to another inn
+    Move(u32),
+    /// merge a post into another post
+    Merge(u32),
+    /// approve a post pending review
+    Approve,
+    /// reject a post pending review
+    Reject,
+}
+
+impl Display for ModAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModAction::Restore(rev) => write!(f, "Restore #{rev}"),
+            ModAction::Role(role) => write!(f, "Role: {role}"),
+            ModAction::Move(iid) => write!(f, "Move to inn #{iid}"),
+            ModAction::Merge(pid) => write!(f, "Merge into post #{pid}"),
+            other => write!(f, "{other:?}"),
+        }
+    }
+}
+
+/// An entry of the append-only moderation log. `iid` is 0 for site-wide actions by admins.
+#[derive(Encode, Decode, Debug)]
+struct ModLog {
+    uid: u32,
+    iid: u32,
+    target: ModTarget,
+    action: ModAction,
+    reason: String,
+    created_at: i64,
+}
+
+impl ModLog {
+    fn add(
+        db: &TransactionalKeyspace,
+        uid: u32,
+        iid: u32,
+        target: ModTarget,
+        action: ModAction,
+        reason: Option<String>,
+    ) -> Result<(), AppError> {
+        let reason = reason
+            .map(|r| r.trim().chars().take(256).collect())
+            .unwrap_or_default();
+        let lid = incr_id(db, "mod_log_count")?;
+        let mod_log = ModLog {
+            uid,
+            iid,
+            target,
+            action,
+            reason,
+            created_at: Timestamp::now().as_second(),
+        };
+        set_one(db, "mod_log", lid, &mod_log)?;
+
+        if iid > 0 {
+            let k = [u32_to_ivec(iid), u32_to_ivec(lid)].concat();
+            db.open_partition("inn_mod_log", Default::default())?
+                .insert(k, [])?;
+        }
+        Ok(())
+    }
+}
+
+/// A poll attached to a post or a solo.
+#[derive(Encode, Decode, Debug)]
+struct Poll {
+    options: Vec<String>,
+    is_multiple: bool,
+    /// voters of each option are visible to everyone
+    is_public: bool,
+    /// 0 means the poll never closes
+    closes_at: i64,
+    created_at: i64,
+}
+
+/// A time-limited ban: site role `Banned`, or inn role `Rejected`/`Limited`.
+/// When it expires, the user gets `prev_role` back.
+#[derive(Encode, Decode, Debug)]
+struct Ban {
+    prev_role: Option<u8>,
+    reason: String,
+    expires_at: i64,
+    created_at: i64,
+}
+
+/// Content of a shadowbanned user is hidden from everyone but the user and admins.
+#[derive(Encode, Decode, Debug)]
+struct Shadowban {
+    reason: String,
+    created_at: i64,
+}
+
+/// Email address of a user. Only verified addresses are in "email_uids".
+#[derive(Encode, Decode, Debug)]
+struct UserEmail {
+    address: String,
+    verified: bool,
+    /// days between notification digests, 0 for none
+    digest_days: u8,
+    /// the last notification sent in a digest
+    digest_nid: u32,
+    digest_at: i64,
+}
+
+/// A personal API token, the key in "api_tokens": `{expire:x}_{nanoid}`
+#[derive(Encode, Decode, Debug)]
+struct ApiToken {
+    uid: u32,
+    name: String,
+    /// bits of the scopes, see [token]
+    scopes: u8,
+    /// PBKDF2 hash of the secret
+    hash: String,
+    /// the session backing the token
+    session_id: String,
+    created_at: i64,
+    last_used: i64,
+}
+
+/// An outgoing webhook of an inn, or of the site if `iid` is 0
+#[derive(Encode, Decode, Debug)]
+struct Webhook {
+    whid: u32,
+    iid: u32,
+    uid: u32,
+    url: String,
+    /// key of the HMAC-SHA256 signature of the payload
+    secret: String,
+    /// bits of the events, see [webhook]
+    events: u8,
+    created_at: i64,
+}
+
+/// An event sent, or to be sent, to a webhook
+#[derive(Encode, Decode, Debug)]
+struct WebhookDelivery {
+    whid: u32,
+    event: u8,
+    /// the JSON body
+    payload: String,
+    created_at: i64,
+    attempts: u8,
+    next_try: i64,
+    /// http status of the last attempt, 0 if there was no response
+    status: u16,
+    /// error of the last attempt
+    error: String,
+    is_delivered: bool,
+}
+
+/// An email waiting to be sent
+#[derive(Encode, Decode, Debug)]
+struct QueuedEmail {
+    to: String,
+    subject: String,
+    body: String,
+    attempts: u8,
+    next_try: i64,
+}
+
 /// Go to source code to see default value: [SiteConfig::default()]
 #[derive(Serialize, Deserialize, Encode, Decode, Validate, Debug)]
 pub(super) struct SiteConfig {
@@ -533,6 +991,15 @@ pub(super) struct SiteConfig {
     /// When enabled, requires users to complete a CAPTCHA challenge during login
     #[validate(skip)]
     login_captcha: bool,
+    /// New content with a spam score of at least this percent is held for review, 0 for off
+    #[validate(range(max = 100))]
+    spam_threshold: u8,
+    /// [`Registration`](registration::Registration) mode: 0 open, 1 invite code, 2 admin approval
+    #[validate(range(max = 2))]
+    registration: u8,
+    /// the lowest site role which can create invite codes
+    #[validate(skip)]
+    invite_role: u8,
 }
 
 impl SiteConfig {
@@ -541,13 +1008,31 @@ impl SiteConfig {
         let default_ks = db.open_partition("default", Default::default())?;
         let site_config = default_ks.get("site_config")?;
         if let Some(site_config) = site_config {
-            let (site_config, _): (SiteConfig, usize) =
-                bincode::decode_from_slice(&site_config, standard()).unwrap_or_default();
-            Ok(site_config)
+            Ok(SiteConfig::decode(&site_config))
         } else {
             Ok(SiteConfig::default())
         }
     }
+
+    /// Configs saved before the trailing fields were added lack some of them, the missing ones
+    /// are filled with their defaults. Only the right number of them decodes to the whole slice.
+    fn decode(v: &[u8]) -> SiteConfig {
+        let default = SiteConfig::default();
+        let tail = [
+            default.spam_threshold,
+            default.registration,
+            default.invite_role,
+        ];
+        for i in (0..=tail.len()).rev() {
+            let v = [v, &tail[i..]].concat();
+            if let Ok((site_config, len)) = bincode::decode_from_slice(&v, standard())
+                && len == v.len()
+            {
+                return site_config;
+            }
+        }
+        default
+    }
 }
 
 #[derive(Encode, Decode)]
@@ -0,0 +1,180 @@
+//! ## Mod log
+//!
+//! Every action of mods and admins on content of other users is recorded in the
+//! append-only [ModLog]. The log of an inn is public if the inn is open access,
+//! otherwise only its mods can read it. Admins can read the site-wide log.
+
+use super::{
+    Claim, Inn, ModLog, ModTarget, SiteConfig, User,
+    db_utils::{get_ids_by_prefix, get_one, u32_to_ivec},
+    filters,
+    fmt::ts_to_date,
+    meta_handler::{PageData, ParamsPage, into_response},
+    user::Role,
+};
+use crate::{DB, error::AppError};
+use askama::Template;
+use axum::{
+    extract::{Path, Query},
+    response::IntoResponse,
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Vec data: mod log
+struct OutModLog {
+    uid: u32,
+    username: String,
+    iid: u32,
+    target: String,
+    link: String,
+    action: String,
+    reason: String,
+    created_at: String,
+}
+
+/// Page data: `mod_log.html`
+#[derive(Template)]
+#[template(path = "mod_log.html")]
+struct PageModLog<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    inn_name: String,
+    logs: Vec<OutModLog>,
+    url: String,
+    anchor: usize,
+    n: usize,
+    is_desc: bool,
+}
+
+/// url params: `mod_log.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsModLog {
+    anchor: Option<usize>,
+    is_desc: Option<bool>,
+}
+
+fn out_mod_logs(lids: Vec<u32>) -> Result<Vec<OutModLog>, AppError> {
+    let mut logs = Vec::with_capacity(lids.len());
+    let mut username_map = HashMap::new();
+    for lid in lids {
+        let log: ModLog = get_one(&DB, "mod_log", lid)?;
+        if !username_map.contains_key(&log.uid) {
+            // actions of automod have uid 0
+            let username = if log.uid == 0 {
+                "automod".to_owned()
+            } else {
+                get_one::<User>(&DB, "users", log.uid)?.username
+            };
+            username_map.insert(log.uid, username);
+        }
+
+        let iid = log.iid;
+        let (target, link) = match log.target {
+            ModTarget::Post(pid) => (format!("post #{pid}"), format!("/post/{iid}/{pid}")),
+            ModTarget::Comment(pid, cid) => (
+                format!("comment #{pid}/{cid}"),
+                format!("/post/{iid}/{pid}#{cid}"),
+            ),
+            ModTarget::Solo(sid) => (format!("solo #{sid}"), format!("/solo/{sid}")),
+            ModTarget::User(uid) => (format!("user #{uid}"), format!("/user/{uid}")),
+            ModTarget::Image(uid, img_id) => {
+                (format!("image #{img_id}"), format!("/gallery/{uid}"))
+            }
+        };
+
+        logs.push(OutModLog {
+            uid: log.uid,
+            username: username_map[&log.uid].clone(),
+            iid,
+            target,
+            link,
+            action: log.action.to_string(),
+            reason: log.reason,
+            created_at: ts_to_date(log.created_at),
+        });
+    }
+    Ok(logs)
+}
+
+/// `GET /mod/:iid/log` mod log of an inn
+pub(crate) async fn inn_mod_log(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    Query(params): Query<ParamsModLog>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let inn: Inn = get_one(&DB, "inns", iid)?;
+    if !inn.is_open_access() {
+        let Some(ref claim) = claim else {
+            return Err(AppError::NonLogin);
+        };
+        if !User::is_mod(&DB, claim.uid, iid)? && Role::from(claim.role) != Role::Admin {
+            return Err(AppError::Unauthorized);
+        }
+    }
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let page_params = ParamsPage { anchor, n, is_desc };
+    let lids = get_ids_by_prefix(&DB, "inn_mod_log", u32_to_ivec(iid), Some(&page_params))?;
+    let logs = out_mod_logs(lids)?;
+
+    let has_unread = if let Some(ref claim) = claim {
+        User::has_unread(&DB, claim.uid)?
+    } else {
+        false
+    };
+    let page_data = PageData::new("mod log", &site_config, claim, has_unread);
+    let page_mod_log = PageModLog {
+        page_data,
+        iid,
+        inn_name: inn.inn_name,
+        logs,
+        url: format!("/mod/{iid}/log"),
+        anchor,
+        n,
+        is_desc,
+    };
+
+    Ok(into_response(&page_mod_log))
+}
+
+/// `GET /admin/mod_log` site-wide mod log
+pub(crate) async fn admin_mod_log(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsModLog>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let page_params = ParamsPage { anchor, n, is_desc };
+    let lids = get_ids_by_prefix(&DB, "mod_log", b"", Some(&page_params))?;
+    let logs = out_mod_logs(lids)?;
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("mod log", &site_config, Some(claim), has_unread);
+    let page_mod_log = PageModLog {
+        page_data,
+        iid: 0,
+        inn_name: String::new(),
+        logs,
+        url: "/admin/mod_log".to_owned(),
+        anchor,
+        n,
+        is_desc,
+    };
+
+    Ok(into_response(&page_mod_log))
+}
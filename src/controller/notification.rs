@@ -1,19 +1,28 @@
 use super::{
-    Claim, Comment, Inn, Post, SiteConfig, Solo, User, filters, get_ids_by_prefix, get_one,
-    incr_id,
+    Ban, Claim, Comment, Inn, Post, SiteConfig, Solo, User, filters,
+    fmt::ts_to_date,
+    get_ids_by_prefix, get_one, incr_id,
     meta_handler::{PageData, into_response},
     u8_slice_to_u32, u32_to_ivec,
     user::{InnRole, Role},
 };
 use crate::{DB, error::AppError};
-use askama::Template;
-use axum::{extract::Query, response::IntoResponse};
+use askama::{
+    Template,
+    filters::{Html, escape},
+};
+use axum::{
+    Form,
+    extract::Query,
+    response::{IntoResponse, Redirect},
+};
 use axum_extra::{TypedHeader, headers::Cookie};
 use bincode::config::standard;
 use fjall::TransactionalKeyspace;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use snailquote::unescape;
 use std::io::Read;
+use utoipa::ToSchema;
 
 /// notification.html
 #[derive(Template)]
@@ -28,7 +37,14 @@ struct NotificationPage<'a> {
 
 #[derive(Deserialize)]
 pub(crate) struct NotifyParams {
-    op_type: Option<String>,
+    anchor: Option<usize>,
+}
+
+/// Form data: `/notification` mark or delete
+#[derive(Deserialize)]
+pub(crate) struct FormNotify {
+    csrf_token: String,
+    op_type: String,
     nid: Option<u32>,
     anchor: Option<usize>,
 }
@@ -48,6 +64,9 @@ pub(super) enum NtType {
     PostHide = 11,
     CommentHide = 12,
     CommentMention = 13,
+    LoginThrottled = 14,
+    /// a post (cid is 0) or a comment matched a `NotifyMods` automod rule
+    Automod = 15,
 }
 
 impl From<u8> for NtType {
@@ -66,12 +85,15 @@ impl From<u8> for NtType {
             11 => Self::PostHide,
             12 => Self::CommentHide,
             13 => Self::CommentMention,
+            14 => Self::LoginThrottled,
+            15 => Self::Automod,
             _ => unreachable!(),
         }
     }
 }
 
-struct Notification {
+#[derive(Serialize, ToSchema)]
+pub(super) struct Notification {
     nid: u32,
     uid: u32,
     content1: String,
@@ -79,6 +101,19 @@ struct Notification {
     is_read: bool,
 }
 
+/// expiry and reason of a time-limited ban, empty if the ban is permanent
+fn ban_desc(id: u32, uid: u32) -> Result<String, AppError> {
+    let Some(ban) = Ban::get(&DB, id, uid)? else {
+        return Ok(String::new());
+    };
+    let mut desc = format!(" until {}", ts_to_date(ban.expires_at));
+    if !ban.reason.is_empty() {
+        let reason = escape(&ban.reason, Html).unwrap();
+        desc.push_str(&format!(". Reason: {reason}"));
+    }
+    Ok(desc)
+}
+
 /// update notification code to read.
 pub(super) fn mark_read(old: Option<&fjall::Slice>) -> Option<fjall::Slice> {
     old.map(|slice| {
@@ -88,76 +123,16 @@ pub(super) fn mark_read(old: Option<&fjall::Slice>) -> Option<fjall::Slice> {
     })
 }
 
-/// `GET /notification`
-///
-/// Batch mode:
-///
-/// 30 notifications in a batch and batch delete only if they has been marked read
-pub(crate) async fn notification(
-    cookie: Option<TypedHeader<Cookie>>,
-    Query(params): Query<NotifyParams>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie
-        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
-        .ok_or(AppError::NonLogin)?;
-
-    let prefix = u32_to_ivec(claim.uid);
+/// A page of the notifications of the user, newest first. Notifications whose target has
+/// been deleted are removed.
+pub(super) fn get_notifications(
+    current_uid: u32,
+    anchor: usize,
+    n: usize,
+) -> Result<Vec<Notification>, AppError> {
+    let prefix = u32_to_ivec(current_uid);
     let tree = DB.open_partition("notifications", Default::default())?;
 
-    let anchor = params.anchor.unwrap_or(0);
-    let n = site_config.per_page;
-    if let Some(op_type) = params.op_type {
-        match op_type.as_str() {
-            "mark_batch" => {
-                for (idx, i) in tree.inner().prefix(&prefix).rev().enumerate() {
-                    if idx < anchor {
-                        continue;
-                    }
-                    if idx >= n + anchor {
-                        break;
-                    }
-                    let (key, _) = i?;
-                    tree.update_fetch(key, mark_read)?;
-                }
-            }
-            "delete_batch" => {
-                for (idx, i) in tree.inner().prefix(&prefix).rev().enumerate() {
-                    if idx < anchor {
-                        continue;
-                    }
-                    if idx >= n + anchor {
-                        break;
-                    }
-                    let (key, value) = i?;
-                    // Delete notification if it is read
-                    if value[8] == 1 {
-                        tree.remove(key)?;
-                    }
-                }
-            }
-            "mark" => {
-                if let Some(nid) = params.nid {
-                    let prefix = [u32_to_ivec(claim.uid), u32_to_ivec(nid)].concat();
-                    for i in tree.inner().prefix(prefix) {
-                        let (k, _) = i?;
-                        tree.update_fetch(k, mark_read)?;
-                    }
-                }
-            }
-            "delete" => {
-                if let Some(nid) = params.nid {
-                    let prefix = [u32_to_ivec(claim.uid), u32_to_ivec(nid)].concat();
-                    for i in tree.inner().prefix(prefix) {
-                        let (k, _) = i?;
-                        tree.remove(k)?;
-                    }
-                }
-            }
-            _ => {}
-        }
-    }
-
     let mut notifications = Vec::with_capacity(n);
     for (idx, i) in tree.inner().prefix(&prefix).rev().enumerate() {
         if idx < anchor {
@@ -357,17 +332,24 @@ pub(crate) async fn notification(
                 notifications.push(notification);
             }
             NtType::InnNotification => {
-                let role = u8_slice_to_u32(&value[0..4]);
-                let role_desc = InnRole::from(role as u8).to_string();
+                let role = u8_slice_to_u32(&value[0..4]) as u8;
                 let iid = u8_slice_to_u32(&value[4..8]);
                 let inn: Inn = get_one(&DB, "inns", iid)?;
-                let content2 = format!(
-                    "Your role in {} (id:{}) has been changed to {role_desc}",
-                    inn.inn_name, iid
-                );
+                let mut content2 = if role == 0 {
+                    format!("Your ban in {} (id:{}) has been lifted", inn.inn_name, iid)
+                } else {
+                    let role_desc = InnRole::from(role).to_string();
+                    format!(
+                        "Your role in {} (id:{}) has been changed to {role_desc}",
+                        inn.inn_name, iid
+                    )
+                };
+                if role == InnRole::Rejected as u8 || role == InnRole::Limited as u8 {
+                    content2.push_str(&ban_desc(iid, current_uid)?);
+                }
                 let notification = Notification {
                     nid,
-                    uid: claim.uid,
+                    uid: current_uid,
                     content1: String::new(),
                     content2,
                     is_read,
@@ -377,10 +359,13 @@ pub(crate) async fn notification(
             NtType::SiteNotification => {
                 let role = u8_slice_to_u32(&value[0..4]);
                 let role_desc = Role::from(role as u8).to_string();
-                let content2 = format!("Your site role has been changed to {role_desc}");
+                let mut content2 = format!("Your site role has been changed to {role_desc}");
+                if role == Role::Banned as u32 {
+                    content2.push_str(&ban_desc(0, current_uid)?);
+                }
                 let notification = Notification {
                     nid,
-                    uid: claim.uid,
+                    uid: current_uid,
                     content1: String::new(),
                     content2,
                     is_read,
@@ -404,6 +389,46 @@ pub(crate) async fn notification(
                 };
                 notifications.push(notification);
             }
+            NtType::LoginThrottled => {
+                let failures = u8_slice_to_u32(&value[0..4]);
+                let content2 = format!(
+                    "There were {failures} failed sign in attempts on your account in the last hour, addresses with repeated failures are locked for one hour. \
+                    If it was not you, please consider enabling two-factor authentication."
+                );
+                let notification = Notification {
+                    nid,
+                    uid: current_uid,
+                    content1: String::new(),
+                    content2,
+                    is_read,
+                };
+                notifications.push(notification);
+            }
+            NtType::Automod => {
+                let pid = u8_slice_to_u32(&value[0..4]);
+                let cid = u8_slice_to_u32(&value[4..8]);
+                if let Ok(post) = get_one::<Post>(&DB, "posts", pid) {
+                    let (kind, anchor) = if cid > 0 {
+                        ("comment", format!("#{cid}"))
+                    } else {
+                        ("post", String::new())
+                    };
+                    let content2 = format!(
+                        "Automod flagged a {kind} in <a href='/post/{}/{}?nid={}{}'>{}</a>",
+                        post.iid, pid, nid, anchor, post.title
+                    );
+                    let notification = Notification {
+                        nid,
+                        uid: post.uid,
+                        content1: String::new(),
+                        content2,
+                        is_read,
+                    };
+                    notifications.push(notification);
+                } else {
+                    tree.remove(key)?;
+                };
+            }
             NtType::ImageDelete => {
                 let uid = u8_slice_to_u32(&value[0..4]);
                 let user: User = get_one(&DB, "users", uid)?;
@@ -421,6 +446,25 @@ pub(crate) async fn notification(
         }
     }
 
+    Ok(notifications)
+}
+
+/// `GET /notification`
+pub(crate) async fn notification(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<NotifyParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+
+    let prefix = u32_to_ivec(claim.uid);
+    let anchor = params.anchor.unwrap_or(0);
+    let n = site_config.per_page;
+
+    let notifications = get_notifications(claim.uid, anchor, n)?;
+
     let mut inn_notifications = Vec::new();
     let mod_inns = get_ids_by_prefix(&DB, "mod_inns", prefix, None)?;
     for i in mod_inns {
@@ -455,6 +499,78 @@ pub(crate) async fn notification(
     Ok(into_response(&notification_page))
 }
 
+/// `POST /notification`
+///
+/// Batch mode:
+///
+/// 30 notifications in a batch and batch delete only if they has been marked read
+pub(crate) async fn notification_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(form): Form<FormNotify>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    let prefix = u32_to_ivec(claim.uid);
+    let tree = DB.open_partition("notifications", Default::default())?;
+
+    let anchor = form.anchor.unwrap_or(0);
+    let n = site_config.per_page;
+    match form.op_type.as_str() {
+        "mark_batch" => {
+            for (idx, i) in tree.inner().prefix(&prefix).rev().enumerate() {
+                if idx < anchor {
+                    continue;
+                }
+                if idx >= n + anchor {
+                    break;
+                }
+                let (key, _) = i?;
+                tree.update_fetch(key, mark_read)?;
+            }
+        }
+        "delete_batch" => {
+            for (idx, i) in tree.inner().prefix(&prefix).rev().enumerate() {
+                if idx < anchor {
+                    continue;
+                }
+                if idx >= n + anchor {
+                    break;
+                }
+                let (key, value) = i?;
+                // Delete notification if it is read
+                if value[8] == 1 {
+                    tree.remove(key)?;
+                }
+            }
+        }
+        "mark" => {
+            if let Some(nid) = form.nid {
+                let prefix = [u32_to_ivec(claim.uid), u32_to_ivec(nid)].concat();
+                for i in tree.inner().prefix(prefix) {
+                    let (k, _) = i?;
+                    tree.update_fetch(k, mark_read)?;
+                }
+            }
+        }
+        "delete" => {
+            if let Some(nid) = form.nid {
+                let prefix = [u32_to_ivec(claim.uid), u32_to_ivec(nid)].concat();
+                for i in tree.inner().prefix(prefix) {
+                    let (k, _) = i?;
+                    tree.remove(k)?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(Redirect::to(&format!("/notification?anchor={anchor}")))
+}
+
 struct InnNotification {
     iid: u32,
     uid: u32,
@@ -0,0 +1,444 @@
+//! ## Poll
+//!
+//! The author of a post or a solo can attach one poll to it. Votes are stored like
+//! upvotes, keyed by `id#uid`, so every user has exactly one (changeable) vote.
+
+use super::{
+    Claim, Inn, InnType, Poll, Post, PostStatus, SiteConfig, Solo, User,
+    db_utils::{get_one, set_one, u8_slice_to_u32, u32_to_ivec},
+    filters,
+    fmt::ts_to_date,
+    meta_handler::{PageData, ValidatedForm, into_response},
+    solo::can_visit_solo_by,
+    user::InnRole,
+};
+use crate::{DB, error::AppError};
+use askama::{
+    Template,
+    filters::{Html, escape},
+};
+use axum::{
+    Form,
+    extract::Query,
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+use validator::Validate;
+
+const POLL_MAX_OPTIONS: usize = 20;
+
+#[derive(Clone, Copy)]
+pub(super) enum PollTarget {
+    Post(u32),
+    Solo(u32),
+}
+
+impl PollTarget {
+    fn new(pid: Option<u32>, sid: Option<u32>) -> Result<Self, AppError> {
+        match (pid, sid) {
+            (Some(pid), None) => Ok(Self::Post(pid)),
+            (None, Some(sid)) => Ok(Self::Solo(sid)),
+            _ => Err(AppError::NotFound),
+        }
+    }
+
+    /// partitions of polls and votes
+    const fn trees(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Post(_) => ("post_polls", "post_poll_votes"),
+            Self::Solo(_) => ("solo_polls", "solo_poll_votes"),
+        }
+    }
+
+    const fn id(&self) -> u32 {
+        match *self {
+            Self::Post(pid) => pid,
+            Self::Solo(sid) => sid,
+        }
+    }
+
+    const fn query(&self) -> (&'static str, u32) {
+        match *self {
+            Self::Post(pid) => ("pid", pid),
+            Self::Solo(sid) => ("sid", sid),
+        }
+    }
+
+    /// author, link and whether votes are accepted. Returns [AppError::NotFound]
+    /// if the user can not see the post or solo.
+    fn get_info(&self, uid: u32) -> Result<(u32, String, bool), AppError> {
+        match *self {
+            Self::Post(pid) => {
+                let post: Post = get_one(&DB, "posts", pid)?;
+                let inn: Inn = get_one(&DB, "inns", post.iid)?;
+                if inn.is_closed() {
+                    return Err(AppError::LockedOrHidden);
+                }
+                if InnType::from(inn.inn_type) == InnType::Private && !User::is_admin(&DB, uid)? {
+                    let k = [u32_to_ivec(uid), u32_to_ivec(post.iid)].concat();
+                    if !DB
+                        .open_partition("user_inns", Default::default())?
+                        .contains_key(k)?
+                    {
+                        return Err(AppError::NotFound);
+                    }
+                }
+                Ok((
+                    post.uid,
+                    format!("/post/{}/{pid}", post.iid),
+                    post.status == PostStatus::Normal,
+                ))
+            }
+            Self::Solo(sid) => {
+                let solo: Solo = get_one(&DB, "solos", sid)?;
+                if !can_visit_solo_by(&DB, &solo, Some(uid))? {
+                    return Err(AppError::NotFound);
+                }
+                Ok((solo.uid, format!("/solo/{sid}"), true))
+            }
+        }
+    }
+
+    fn get_poll(&self) -> Result<Option<Poll>, AppError> {
+        let Some(v) = DB
+            .open_partition(self.trees().0, Default::default())?
+            .get(u32_to_ivec(self.id()))?
+        else {
+            return Ok(None);
+        };
+        let (poll, _): (Poll, usize) = bincode::decode_from_slice(&v, standard())?;
+        Ok(Some(poll))
+    }
+}
+
+/// Vec data: poll option
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutPollOption {
+    pub(super) idx: usize,
+    pub(super) text: String,
+    pub(super) votes: usize,
+    pub(super) percent: usize,
+    pub(super) is_chosen: bool,
+    /// `(uid, username)`, only for public polls
+    #[schema(value_type = Vec<Vec<Value>>)]
+    pub(super) voters: Vec<(u32, String)>,
+}
+
+/// Page data: included by `post.html` and `solo.html`
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutPoll {
+    pub(super) vote_url: String,
+    pub(super) options: Vec<OutPollOption>,
+    pub(super) is_multiple: bool,
+    pub(super) is_public: bool,
+    pub(super) is_closed: bool,
+    pub(super) closes_at: String,
+    pub(super) voters: usize,
+    pub(super) has_voted: bool,
+}
+
+/// Load the poll and its results. `is_open` is false if the post does not accept
+/// votes any more, e.g. it has been locked.
+pub(super) fn get_out_poll(
+    target: PollTarget,
+    current_uid: Option<u32>,
+    is_open: bool,
+) -> Result<Option<OutPoll>, AppError> {
+    let Some(poll) = target.get_poll()? else {
+        return Ok(None);
+    };
+
+    let mut votes = vec![0; poll.options.len()];
+    let mut option_voters = vec![Vec::new(); poll.options.len()];
+    let mut chosen = Vec::new();
+    let mut voters = 0;
+    let mut has_voted = false;
+    let mut username_map = HashMap::new();
+    let votes_tree = DB.open_partition(target.trees().1, Default::default())?;
+    for i in votes_tree.inner().prefix(u32_to_ivec(target.id())) {
+        let (k, v) = i?;
+        let uid = u8_slice_to_u32(&k[4..8]);
+        voters += 1;
+        if Some(uid) == current_uid {
+            has_voted = true;
+            chosen = v.to_vec();
+        }
+
+        for idx in v.iter().map(|idx| *idx as usize) {
+            if idx >= votes.len() {
+                continue;
+            }
+            votes[idx] += 1;
+            if poll.is_public {
+                if !username_map.contains_key(&uid) {
+                    let user: User = get_one(&DB, "users", uid)?;
+                    username_map.insert(uid, user.username);
+                }
+                option_voters[idx].push((uid, username_map[&uid].clone()));
+            }
+        }
+    }
+
+    let mut options = Vec::with_capacity(poll.options.len());
+    for (idx, (text, voters_of_option)) in poll.options.iter().zip(option_voters).enumerate() {
+        let percent = if voters > 0 {
+            votes[idx] * 100 / voters
+        } else {
+            0
+        };
+        options.push(OutPollOption {
+            idx,
+            text: escape(text, Html).unwrap().to_string(),
+            votes: votes[idx],
+            percent,
+            is_chosen: chosen.contains(&(idx as u8)),
+            voters: voters_of_option,
+        });
+    }
+
+    let is_closed =
+        !is_open || (poll.closes_at > 0 && poll.closes_at <= Timestamp::now().as_second());
+    let closes_at = if poll.closes_at > 0 {
+        ts_to_date(poll.closes_at)
+    } else {
+        String::new()
+    };
+    let (key, id) = target.query();
+
+    Ok(Some(OutPoll {
+        vote_url: format!("/poll/vote?{key}={id}"),
+        options,
+        is_multiple: poll.is_multiple,
+        is_public: poll.is_public,
+        is_closed,
+        closes_at,
+        voters,
+        has_voted,
+    }))
+}
+
+/// Remove the poll and all its votes, called when the post or solo is deleted.
+pub(super) fn poll_delete(target: PollTarget) -> Result<(), AppError> {
+    let (polls_tree, votes_tree) = target.trees();
+    let id = u32_to_ivec(target.id());
+    DB.open_partition(polls_tree, Default::default())?
+        .remove(&id)?;
+    let votes_tree = DB.open_partition(votes_tree, Default::default())?;
+    for i in votes_tree.inner().prefix(&id) {
+        let (k, _) = i?;
+        votes_tree.remove(k)?;
+    }
+    Ok(())
+}
+
+/// Move the poll with its votes to a post that has none, or delete it.
+pub(super) fn poll_move(from: PollTarget, to: PollTarget) -> Result<(), AppError> {
+    let (polls_tree, votes_tree) = from.trees();
+    let polls_tree = DB.open_partition(polls_tree, Default::default())?;
+    let to_id = u32_to_ivec(to.id());
+    if polls_tree.contains_key(&to_id)? {
+        return poll_delete(from);
+    }
+    let Some(poll) = polls_tree.take(u32_to_ivec(from.id()))? else {
+        return Ok(());
+    };
+    polls_tree.insert(&*to_id, poll)?;
+
+    let votes_tree = DB.open_partition(votes_tree, Default::default())?;
+    for i in votes_tree.inner().prefix(u32_to_ivec(from.id())) {
+        let (k, v) = i?;
+        votes_tree.insert([&to_id[..], &k[4..]].concat(), v)?;
+        votes_tree.remove(k)?;
+    }
+    Ok(())
+}
+
+/// url params: `poll_create.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsPoll {
+    pid: Option<u32>,
+    sid: Option<u32>,
+}
+
+/// Page data: `poll_create.html`
+#[derive(Template)]
+#[template(path = "poll_create.html")]
+struct PagePollCreate<'a> {
+    page_data: PageData<'a>,
+    pid: Option<u32>,
+    sid: Option<u32>,
+    link: String,
+}
+
+/// `GET /poll/new` poll create page, for the author of the post or solo
+pub(crate) async fn poll_create(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsPoll>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let target = PollTarget::new(params.pid, params.sid)?;
+    let (uid, link, is_open) = target.get_info(claim.uid)?;
+    if uid != claim.uid {
+        return Err(AppError::Unauthorized);
+    }
+    if !is_open {
+        return Err(AppError::LockedOrHidden);
+    }
+    if target.get_poll()?.is_some() {
+        return Ok(Redirect::to(&link).into_response());
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("poll", &site_config, Some(claim), has_unread);
+    let page_poll_create = PagePollCreate {
+        page_data,
+        pid: params.pid,
+        sid: params.sid,
+        link,
+    };
+
+    Ok(into_response(&page_poll_create))
+}
+
+/// Form data: `/poll/new` poll create
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormPoll {
+    csrf_token: String,
+    pid: Option<u32>,
+    sid: Option<u32>,
+    /// one option per line
+    #[validate(length(min = 3, max = 2600))]
+    options: String,
+    is_multiple: Option<bool>,
+    is_public: Option<bool>,
+    /// 0 means the poll never closes
+    days: u32,
+}
+
+/// `POST /poll/new` poll create
+pub(crate) async fn poll_create_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    ValidatedForm(input): ValidatedForm<FormPoll>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    let target = PollTarget::new(input.pid, input.sid)?;
+    let (uid, link, is_open) = target.get_info(claim.uid)?;
+    if uid != claim.uid {
+        return Err(AppError::Unauthorized);
+    }
+    if !is_open {
+        return Err(AppError::LockedOrHidden);
+    }
+    if target.get_poll()?.is_some() {
+        return Ok(Redirect::to(&link));
+    }
+
+    let mut options: Vec<String> = Vec::new();
+    for line in input.options.lines() {
+        let option: String = line.trim().chars().take(128).collect();
+        if !option.is_empty() && !options.contains(&option) {
+            options.push(option);
+        }
+    }
+    if options.len() < 2 || options.len() > POLL_MAX_OPTIONS {
+        return Err(AppError::Custom(format!(
+            "A poll needs 2 to {POLL_MAX_OPTIONS} different options"
+        )));
+    }
+
+    let now = Timestamp::now().as_second();
+    let closes_at = if input.days > 0 {
+        now + i64::from(input.days.min(365)) * 24 * 3600
+    } else {
+        0
+    };
+    let poll = Poll {
+        options,
+        is_multiple: input.is_multiple.unwrap_or_default(),
+        is_public: input.is_public.unwrap_or_default(),
+        closes_at,
+        created_at: now,
+    };
+    set_one(&DB, target.trees().0, target.id(), &poll)?;
+
+    Ok(Redirect::to(&link))
+}
+
+/// `POST /poll/vote` poll vote
+///
+/// Form data: `csrf_token` and one `choice` per chosen option. Voting again replaces
+/// the old vote, `withdraw` takes it back.
+pub(crate) async fn poll_vote(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsPoll>,
+    Form(form): Form<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let mut csrf_token = String::new();
+    let mut choices: Vec<u8> = Vec::new();
+    let mut withdraw = false;
+    for (k, v) in form {
+        match k.as_str() {
+            "csrf_token" => csrf_token = v,
+            "withdraw" => withdraw = true,
+            "choice" => {
+                let idx = v.parse().map_err(|_| AppError::NotFound)?;
+                choices.push(idx);
+            }
+            _ => {}
+        }
+    }
+    claim.check_csrf(&csrf_token)?;
+
+    let target = PollTarget::new(params.pid, params.sid)?;
+    let (_, link, is_open) = target.get_info(claim.uid)?;
+    // who can not comment on the post can not vote on its poll either
+    if let PollTarget::Post(pid) = target {
+        let post: Post = get_one(&DB, "posts", pid)?;
+        let inn_role = InnRole::get(&DB, post.iid, claim.uid)?.ok_or(AppError::Unauthorized)?;
+        if inn_role < InnRole::Limited {
+            return Err(AppError::Unauthorized);
+        }
+    }
+    let poll = target.get_poll()?.ok_or(AppError::NotFound)?;
+    if !is_open || (poll.closes_at > 0 && poll.closes_at <= Timestamp::now().as_second()) {
+        return Err(AppError::LockedOrHidden);
+    }
+
+    choices.sort_unstable();
+    choices.dedup();
+    if choices
+        .iter()
+        .any(|idx| *idx as usize >= poll.options.len())
+        || (!poll.is_multiple && choices.len() > 1)
+    {
+        return Err(AppError::NotFound);
+    }
+
+    let k = [u32_to_ivec(target.id()), u32_to_ivec(claim.uid)].concat();
+    let votes_tree = DB.open_partition(target.trees().1, Default::default())?;
+    if withdraw {
+        votes_tree.remove(k)?;
+    } else if choices.is_empty() {
+        return Err(AppError::Custom("Please choose an option".into()));
+    } else {
+        votes_tree.insert(k, choices)?;
+    }
+
+    Ok(Redirect::to(&link))
+}
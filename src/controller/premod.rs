@@ -0,0 +1,247 @@
+//! ## Pre-moderation
+//!
+//! When an inn is under spam attack, mods can make new posts of low-trust members or young
+//! accounts wait in a review queue. Pending posts are only visible to the author and mods, and
+//! are indexed when they are approved.
+
+use super::{
+    Claim, Inn, InnRules, ModAction, ModLog, ModTarget, Post, PostContent, PostStatus,
+    PreModeration, SiteConfig, User,
+    db_utils::{get_ids_by_prefix, get_one, set_one, u8_slice_to_u32, u32_to_ivec},
+    filters,
+    fmt::ts_to_date,
+    inn::publish_post,
+    meta_handler::{PageData, ParamsPage, ValidatedForm, into_response},
+    notification::{NtType, add_notification},
+    spam::train_post,
+    user::{InnRole, Role},
+};
+use crate::{DB, error::AppError};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use fjall::TransactionalKeyspace;
+use serde::Deserialize;
+use validator::Validate;
+
+impl PreModeration {
+    pub(super) fn get(db: &TransactionalKeyspace, iid: u32) -> Result<Self, AppError> {
+        match db
+            .open_partition("inn_premod", Default::default())?
+            .get(u32_to_ivec(iid))?
+        {
+            Some(v) => {
+                let (premod, _): (PreModeration, usize) =
+                    bincode::decode_from_slice(&v, standard())?;
+                Ok(premod)
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// whether new posts of the user need review, mods never need it
+    pub(super) fn applies(&self, inn_role: &InnRole, user: &User, now: i64) -> bool {
+        if *inn_role >= InnRole::Mod {
+            return false;
+        }
+        (self.max_role > 0 && *inn_role <= InnRole::from(self.max_role))
+            || (self.account_days > 0
+                && now - user.created_at < self.account_days as i64 * 24 * 3600)
+    }
+}
+
+fn can_review(claim: &Claim, iid: u32) -> Result<bool, AppError> {
+    Ok(User::is_mod(&DB, claim.uid, iid)? || Role::from(claim.role) == Role::Admin)
+}
+
+/// Form data: `/mod/premod/:iid` pre-moderation of an inn
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormPreModeration {
+    csrf_token: String,
+    max_role: u8,
+    account_days: u32,
+}
+
+/// `POST /mod/premod/:iid` pre-moderation of an inn
+pub(crate) async fn mod_premod_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    ValidatedForm(input): ValidatedForm<FormPreModeration>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    if !can_review(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+    if !DB
+        .open_partition("inns", Default::default())?
+        .contains_key(u32_to_ivec(iid))?
+    {
+        return Err(AppError::NotFound);
+    }
+    if ![0, 4, 5].contains(&input.max_role) {
+        return Err(AppError::Custom("Invalid inn role".into()));
+    }
+
+    let premod = PreModeration {
+        max_role: input.max_role,
+        account_days: input.account_days,
+    };
+    set_one(&DB, "inn_premod", iid, &premod)?;
+
+    let target = format!("/mod/{iid}");
+    Ok(Redirect::to(&target))
+}
+
+/// Vec data: post pending review
+struct OutPending {
+    pid: u32,
+    uid: u32,
+    username: String,
+    title: String,
+    summary: String,
+    created_at: String,
+}
+
+/// Page data: `mod_queue.html`
+#[derive(Template)]
+#[template(path = "mod_queue.html")]
+struct PageModQueue<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    inn_name: String,
+    posts: Vec<OutPending>,
+    anchor: usize,
+    n: usize,
+    is_desc: bool,
+}
+
+/// url params: `mod_queue.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsModQueue {
+    anchor: Option<usize>,
+    is_desc: Option<bool>,
+}
+
+/// `GET /mod/:iid/queue` posts of an inn pending review
+pub(crate) async fn mod_queue(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    Query(params): Query<ParamsModQueue>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if !can_review(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+    let inn: Inn = get_one(&DB, "inns", iid)?;
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(false);
+    let page_params = ParamsPage { anchor, n, is_desc };
+
+    let mut posts = Vec::with_capacity(n);
+    for pid in get_ids_by_prefix(&DB, "inn_pending", u32_to_ivec(iid), Some(&page_params))? {
+        let post: Post = get_one(&DB, "posts", pid)?;
+        let user: User = get_one(&DB, "users", post.uid)?;
+        let summary = match post.content {
+            PostContent::Markdown(ref md) => md.chars().take(300).collect(),
+            PostContent::FeedItemId(_) => String::new(),
+        };
+        posts.push(OutPending {
+            pid,
+            uid: post.uid,
+            username: user.username,
+            title: post.title,
+            summary,
+            created_at: ts_to_date(post.created_at),
+        });
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("review queue", &site_config, Some(claim), has_unread);
+    let page_mod_queue = PageModQueue {
+        page_data,
+        iid,
+        inn_name: inn.inn_name,
+        posts,
+        anchor,
+        n,
+        is_desc,
+    };
+
+    Ok(into_response(&page_mod_queue))
+}
+
+/// Form data: `/mod/:iid/queue/:pid` approve or reject a post
+#[derive(Deserialize)]
+pub(crate) struct FormQueueAction {
+    csrf_token: String,
+    /// `approve` or `reject`
+    action: String,
+    reason: Option<String>,
+    /// number of the inn rule to cite, 0 for none
+    rule: Option<usize>,
+}
+
+/// `POST /mod/:iid/queue/:pid` approve or reject a post pending review
+///
+/// Approved posts are published like scheduled posts. Rejected posts are hidden by mod.
+pub(crate) async fn mod_queue_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, pid)): Path<(u32, u32)>,
+    Form(input): Form<FormQueueAction>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if !can_review(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let k = [u32_to_ivec(iid), u32_to_ivec(pid)].concat();
+    let pending_ks = DB.open_partition("inn_pending", Default::default())?;
+    let Some(mentions) = pending_ks.get(&k)? else {
+        return Err(AppError::NotFound);
+    };
+    let mut post: Post = get_one(&DB, "posts", pid)?;
+    if post.iid != iid || post.status != PostStatus::Pending {
+        return Err(AppError::NotFound);
+    }
+
+    let action = match input.action.as_str() {
+        "approve" => {
+            post.status = PostStatus::Normal;
+            set_one(&DB, "posts", pid, &post)?;
+            publish_post(&DB, &post, mentions.chunks_exact(4).map(u8_slice_to_u32))?;
+            train_post(&DB, &post, false)?;
+            ModAction::Approve
+        }
+        "reject" => {
+            post.status = PostStatus::HiddenByMod;
+            set_one(&DB, "posts", pid, &post)?;
+            add_notification(&DB, post.uid, NtType::PostHide, claim.uid, pid)?;
+            train_post(&DB, &post, true)?;
+            ModAction::Reject
+        }
+        _ => return Err(AppError::NotFound),
+    };
+    pending_ks.remove(k)?;
+
+    let reason = InnRules::cite(&DB, iid, input.rule, input.reason)?;
+    ModLog::add(&DB, claim.uid, iid, ModTarget::Post(pid), action, reason)?;
+
+    let target = format!("/mod/{iid}/queue");
+    Ok(Redirect::to(&target))
+}
@@ -0,0 +1,390 @@
+//! ## Registration
+//!
+//! Sign up is open by default. Admins can require an invite code, or hold new accounts until
+//! they approve them, see [`Registration`].
+//!
+//! Invite codes are created by users with a site role of at least `invite_role` and stored in a
+//! TTL partition, so they expire like sessions. Accounts waiting for approval can not sign in;
+//! the session handed out as a cookie at sign up is granted once an admin approves them.
+
+use super::{
+    Claim, ModAction, ModLog, ModTarget, SiteConfig, User,
+    db_utils::{
+        generate_nanoid_ttl, get_ids_by_prefix, get_one, set_one, u8_slice_to_u32, u32_to_ivec,
+    },
+    filters,
+    fmt::ts_to_date,
+    meta_handler::{PageData, ParamsPage, ValidatedForm, into_response},
+    user::Role,
+};
+use crate::{DB, error::AppError};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use serde::Deserialize;
+use validator::Validate;
+
+/// how many unexpired invite codes a user other than admins can have
+const INVITES_MAX: usize = 10;
+
+/// registration mode of the site
+#[repr(u8)]
+#[derive(PartialEq)]
+pub(super) enum Registration {
+    Open = 0,
+    Invite = 1,
+    Approval = 2,
+}
+
+impl From<u8> for Registration {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Registration::Invite,
+            2 => Registration::Approval,
+            _ => Registration::Open,
+        }
+    }
+}
+
+/// An invite code, the key in "invites": `{expire:x}_{nanoid}`
+struct Invite {
+    code: String,
+    uid: u32,
+    max_uses: u32,
+    uses: u32,
+    expires_at: i64,
+}
+
+impl Invite {
+    fn decode(k: &[u8], v: &[u8]) -> Option<Self> {
+        let code = String::from_utf8_lossy(k).to_string();
+        let expires_at = code
+            .split_once('_')
+            .and_then(|s| i64::from_str_radix(s.0, 16).ok())?;
+        Some(Invite {
+            code,
+            uid: u8_slice_to_u32(&v[0..4]),
+            max_uses: u8_slice_to_u32(&v[4..8]),
+            uses: u8_slice_to_u32(&v[8..12]),
+            expires_at,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        [
+            u32_to_ivec(self.uid),
+            u32_to_ivec(self.max_uses),
+            u32_to_ivec(self.uses),
+        ]
+        .concat()
+    }
+
+    fn is_valid(&self, now: i64) -> bool {
+        self.expires_at > now && self.uses < self.max_uses
+    }
+
+    fn get(db: &TransactionalKeyspace, code: &str) -> Result<Option<Self>, AppError> {
+        Ok(db
+            .open_partition("invites", Default::default())?
+            .get(code)?
+            .and_then(|v| Invite::decode(code.as_bytes(), &v)))
+    }
+}
+
+/// Check an invite code and count a use of it in one transaction, so concurrent sign ups can not
+/// use it more often than allowed
+pub(super) fn use_invite(db: &TransactionalKeyspace, code: &str) -> Result<(), AppError> {
+    let now = Timestamp::now().as_second();
+    let invites_ks = db.open_partition("invites", Default::default())?;
+    let mut tx = db.write_tx();
+    let invite = tx
+        .get(&invites_ks, code)?
+        .and_then(|v| Invite::decode(code.as_bytes(), &v));
+    let Some(mut invite) = invite.filter(|invite| invite.is_valid(now)) else {
+        return Err(AppError::Custom("Invalid or expired invite code".into()));
+    };
+    invite.uses += 1;
+    tx.insert(&invites_ks, code, invite.encode());
+    tx.commit()?;
+    Ok(())
+}
+
+/// Hold a new account until admins approve it. `session_id` is the session handed out as a
+/// cookie, it is granted on approval.
+pub(super) fn add_pending(
+    db: &TransactionalKeyspace,
+    uid: u32,
+    session_id: &str,
+) -> Result<(), AppError> {
+    db.open_partition("pending_signups", Default::default())?
+        .insert(u32_to_ivec(uid), session_id)?;
+    Ok(())
+}
+
+pub(super) fn is_pending(db: &TransactionalKeyspace, uid: u32) -> Result<bool, AppError> {
+    Ok(db
+        .open_partition("pending_signups", Default::default())?
+        .contains_key(u32_to_ivec(uid))?)
+}
+
+/// whether the user can create invite codes
+pub(super) fn can_invite(claim: &Claim, site_config: &SiteConfig) -> bool {
+    claim.role >= site_config.invite_role.max(Role::Standard as u8)
+}
+
+/// Vec data: invite code
+struct OutInvite {
+    code: String,
+    uid: u32,
+    max_uses: u32,
+    uses: u32,
+    expires_at: String,
+}
+
+/// Page data: `invite.html`
+#[derive(Template)]
+#[template(path = "invite.html")]
+struct PageInvite<'a> {
+    page_data: PageData<'a>,
+    invites: Vec<OutInvite>,
+    domain: String,
+}
+
+/// `GET /invite` invite codes of the user, admins see all of them
+pub(crate) async fn invite(
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if !can_invite(&claim, &site_config) {
+        return Err(AppError::Unauthorized);
+    }
+    let is_admin = Role::from(claim.role) == Role::Admin;
+
+    let now = Timestamp::now().as_second();
+    let mut invites = vec![];
+    for i in DB
+        .open_partition("invites", Default::default())?
+        .inner()
+        .iter()
+        .rev()
+    {
+        let (k, v) = i?;
+        if let Some(invite) = Invite::decode(&k, &v)
+            && invite.expires_at > now
+            && (is_admin || invite.uid == claim.uid)
+        {
+            invites.push(OutInvite {
+                code: invite.code,
+                uid: invite.uid,
+                max_uses: invite.max_uses,
+                uses: invite.uses,
+                expires_at: ts_to_date(invite.expires_at),
+            });
+        }
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("Invite", &site_config, Some(claim), has_unread);
+    let page_invite = PageInvite {
+        page_data,
+        invites,
+        domain: site_config.domain,
+    };
+    Ok(into_response(&page_invite))
+}
+
+/// Form data: `/invite` create or revoke an invite code
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormInvite {
+    csrf_token: String,
+    /// the code to revoke
+    revoke: Option<String>,
+    #[validate(range(min = 1, max = 100))]
+    max_uses: Option<u32>,
+    #[validate(range(min = 1, max = 90))]
+    days: Option<u32>,
+}
+
+/// `POST /invite` create or revoke an invite code
+pub(crate) async fn invite_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    ValidatedForm(input): ValidatedForm<FormInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if !can_invite(&claim, &site_config) {
+        return Err(AppError::Unauthorized);
+    }
+    let is_admin = Role::from(claim.role) == Role::Admin;
+    let invites_ks = DB.open_partition("invites", Default::default())?;
+
+    if let Some(code) = input.revoke {
+        let invite = Invite::get(&DB, &code)?.ok_or(AppError::NotFound)?;
+        if invite.uid != claim.uid && !is_admin {
+            return Err(AppError::Unauthorized);
+        }
+        invites_ks.remove(code)?;
+    } else {
+        let now = Timestamp::now().as_second();
+        if !is_admin {
+            let mut count = 0;
+            for i in invites_ks.inner().iter() {
+                let (k, v) = i?;
+                if Invite::decode(&k, &v).is_some_and(|i| i.uid == claim.uid && i.is_valid(now)) {
+                    count += 1;
+                }
+            }
+            if count >= INVITES_MAX {
+                return Err(AppError::Custom(format!(
+                    "You can have at most {INVITES_MAX} invite codes"
+                )));
+            }
+        }
+
+        let seconds = i64::from(input.days.unwrap_or(7)) * 24 * 3600;
+        let invite = Invite {
+            code: generate_nanoid_ttl(seconds),
+            uid: claim.uid,
+            max_uses: input.max_uses.unwrap_or(1),
+            uses: 0,
+            expires_at: now + seconds,
+        };
+        invites_ks.insert(&invite.code, invite.encode())?;
+    }
+
+    Ok(Redirect::to("/invite"))
+}
+
+/// Vec data: account waiting for approval
+struct OutSignup {
+    uid: u32,
+    username: String,
+    created_at: String,
+}
+
+/// Page data: `admin_signups.html`
+#[derive(Template)]
+#[template(path = "admin_signups.html")]
+struct PageAdminSignups<'a> {
+    page_data: PageData<'a>,
+    signups: Vec<OutSignup>,
+    anchor: usize,
+    n: usize,
+}
+
+/// url params: `admin_signups.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsAdminSignups {
+    anchor: Option<usize>,
+}
+
+/// `GET /admin/signups` accounts waiting for approval
+pub(crate) async fn admin_signups(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsAdminSignups>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let page_params = ParamsPage {
+        anchor,
+        n,
+        is_desc: false,
+    };
+
+    let mut signups = Vec::with_capacity(n);
+    for uid in get_ids_by_prefix(&DB, "pending_signups", b"", Some(&page_params))? {
+        let user: User = get_one(&DB, "users", uid)?;
+        signups.push(OutSignup {
+            uid,
+            username: user.username,
+            created_at: ts_to_date(user.created_at),
+        });
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("Sign ups", &site_config, Some(claim), has_unread);
+    let page_admin_signups = PageAdminSignups {
+        page_data,
+        signups,
+        anchor,
+        n,
+    };
+    Ok(into_response(&page_admin_signups))
+}
+
+/// Form data: `/admin/signups/:uid` approve or reject an account
+#[derive(Deserialize)]
+pub(crate) struct FormSignupAction {
+    csrf_token: String,
+    /// `approve` or `reject`
+    action: String,
+}
+
+/// `POST /admin/signups/:uid` approve or reject an account
+///
+/// Rejected accounts are banned, so the username stays taken.
+pub(crate) async fn admin_signups_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(uid): Path<u32>,
+    Form(input): Form<FormSignupAction>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+    let Some(session_id) = DB
+        .open_partition("pending_signups", Default::default())?
+        .get(u32_to_ivec(uid))?
+    else {
+        return Err(AppError::NotFound);
+    };
+
+    let action = match input.action.as_str() {
+        "approve" => {
+            // the cookie of the sign up may have expired in the meantime
+            let session_id = String::from_utf8_lossy(&session_id);
+            let now = Timestamp::now().as_second();
+            if session_id
+                .split_once('_')
+                .and_then(|(exp, _)| i64::from_str_radix(exp, 16).ok())
+                .is_some_and(|exp| exp > now)
+            {
+                let user: User = get_one(&DB, "users", uid)?;
+                Claim::grant(&DB, user, &session_id)?;
+            }
+            ModAction::Approve
+        }
+        "reject" => {
+            let mut user: User = get_one(&DB, "users", uid)?;
+            user.role = Role::Banned as u8;
+            set_one(&DB, "users", uid, &user)?;
+            ModAction::Reject
+        }
+        _ => return Err(AppError::NotFound),
+    };
+    DB.open_partition("pending_signups", Default::default())?
+        .remove(u32_to_ivec(uid))?;
+    ModLog::add(&DB, claim.uid, 0, ModTarget::User(uid), action, None)?;
+
+    Ok(Redirect::to("/admin/signups"))
+}
@@ -0,0 +1,528 @@
+//! ## Report
+//!
+//! Members can report posts, comments and solos. Reports of posts and comments go to the
+//! moderation queue of the inn, reports of solos go to admins.
+
+use super::{
+    Claim, Comment, Inn, InnRules, InnType, Post, PostStatus, Report, ReportTarget, SiteConfig,
+    Solo, User,
+    db_utils::{get_ids_by_prefix, get_one, incr_id, set_one, u32_to_ivec},
+    filters,
+    fmt::ts_to_date,
+    inn::{comment_hide, is_pending_for, is_scheduled_for, post_hide, post_lock},
+    meta_handler::{FormModAction, PageData, ParamsPage, ValidatedForm, into_response},
+    solo::{can_visit_solo_by, is_spam_held, solo_delete, solo_release},
+    user::Role,
+};
+use crate::{DB, error::AppError};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use serde::Deserialize;
+use std::collections::HashMap;
+use validator::Validate;
+
+/// url params: `report.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsReport {
+    pid: Option<u32>,
+    cid: Option<u32>,
+    sid: Option<u32>,
+}
+
+impl ReportTarget {
+    pub(super) fn new(
+        pid: Option<u32>,
+        cid: Option<u32>,
+        sid: Option<u32>,
+    ) -> Result<Self, AppError> {
+        match (pid, cid, sid) {
+            (Some(pid), None, None) => Ok(Self::Post(pid)),
+            (Some(pid), Some(cid), None) => Ok(Self::Comment(pid, cid)),
+            (None, None, Some(sid)) => Ok(Self::Solo(sid)),
+            _ => Err(AppError::NotFound),
+        }
+    }
+
+    /// the inn whose mods handle the report, 0 for admins, and a short description of the target
+    fn get_info(&self) -> Result<(u32, String, String), AppError> {
+        match *self {
+            Self::Post(pid) => {
+                let post: Post = get_one(&DB, "posts", pid)?;
+                Ok((post.iid, format!("/post/{}/{pid}", post.iid), post.title))
+            }
+            Self::Comment(pid, cid) => {
+                let post: Post = get_one(&DB, "posts", pid)?;
+                let comment = get_comment(pid, cid)?;
+                let content = ammonia::Builder::empty()
+                    .clean(&comment.content)
+                    .to_string();
+                Ok((
+                    post.iid,
+                    format!("/post/{}/{pid}#{cid}", post.iid),
+                    content.trim().chars().take(200).collect(),
+                ))
+            }
+            Self::Solo(sid) => {
+                let solo: Solo = get_one(&DB, "solos", sid)?;
+                let content = ammonia::Builder::empty().clean(&solo.content).to_string();
+                Ok((
+                    0,
+                    format!("/solo/{sid}"),
+                    content.trim().chars().take(200).collect(),
+                ))
+            }
+        }
+    }
+
+    /// `NotFound` if the reporter can not see the target, so the report page does not leak it
+    fn check_visible(&self, claim: &Claim) -> Result<(), AppError> {
+        let pid = match *self {
+            Self::Post(pid) | Self::Comment(pid, _) => pid,
+            Self::Solo(sid) => {
+                let solo: Solo = get_one(&DB, "solos", sid)?;
+                if !can_visit_solo_by(&DB, &solo, Some(claim.uid))? {
+                    return Err(AppError::NotFound);
+                }
+                return Ok(());
+            }
+        };
+
+        let post: Post = get_one(&DB, "posts", pid)?;
+        let inn: Inn = get_one(&DB, "inns", post.iid)?;
+        let is_mod =
+            User::is_mod(&DB, claim.uid, post.iid)? || Role::from(claim.role) == Role::Admin;
+        if inn.is_closed() && !is_mod {
+            return Err(AppError::NotFound);
+        }
+        if InnType::from(inn.inn_type) == InnType::Private && !is_mod {
+            let k = [u32_to_ivec(claim.uid), u32_to_ivec(post.iid)].concat();
+            if !DB
+                .open_partition("user_inns", Default::default())?
+                .contains_key(k)?
+            {
+                return Err(AppError::NotFound);
+            }
+        }
+        if is_scheduled_for(&DB, &post, Some(claim.uid))?
+            || is_pending_for(&DB, &post, Some(claim.uid))?
+        {
+            return Err(AppError::NotFound);
+        }
+        if !is_mod {
+            if matches!(
+                post.status,
+                PostStatus::HiddenByUser | PostStatus::HiddenByMod
+            ) {
+                return Err(AppError::NotFound);
+            }
+            if let Self::Comment(pid, cid) = *self
+                && get_comment(pid, cid)?.is_hidden
+            {
+                return Err(AppError::NotFound);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Report {
+    /// File a report, uid 0 is automod or the spam filter
+    pub(super) fn add(
+        db: &TransactionalKeyspace,
+        uid: u32,
+        iid: u32,
+        target: ReportTarget,
+        reason: String,
+    ) -> Result<(), AppError> {
+        let rid = incr_id(db, "reports_count")?;
+        let report = Report {
+            rid,
+            uid,
+            iid,
+            target,
+            reason,
+            created_at: Timestamp::now().as_second(),
+        };
+        set_one(db, "reports", rid, &report)?;
+        let k = [u32_to_ivec(iid), u32_to_ivec(rid)].concat();
+        db.open_partition("inn_reports", Default::default())?
+            .insert(k, [])?;
+        Ok(())
+    }
+}
+
+/// Point the reports on a merged post and its comments to where they live now in the
+/// canonical post, see `merge_post`. `cids` maps the old cids to the new ones, the body of the
+/// duplicate being cid 0.
+pub(super) fn retarget_reports(
+    dup: u32,
+    canonical: u32,
+    iid: u32,
+    cids: &HashMap<u32, u32>,
+) -> Result<(), AppError> {
+    let reports_tree = DB.open_partition("reports", Default::default())?;
+    let inn_reports_tree = DB.open_partition("inn_reports", Default::default())?;
+    for i in reports_tree.inner().iter() {
+        let (k, v) = i?;
+        let (mut report, _): (Report, usize) = bincode::decode_from_slice(&v, standard())?;
+        let cid = match report.target {
+            ReportTarget::Post(pid) if pid == dup => 0,
+            ReportTarget::Comment(pid, cid) if pid == dup => cid,
+            _ => continue,
+        };
+        let Some(&new_cid) = cids.get(&cid) else {
+            continue;
+        };
+
+        let old_k = [u32_to_ivec(report.iid), k.to_vec()].concat();
+        if inn_reports_tree.take(old_k)?.is_some() {
+            inn_reports_tree.insert([u32_to_ivec(iid), k.to_vec()].concat(), [])?;
+        }
+        report.target = ReportTarget::Comment(canonical, new_cid);
+        report.iid = iid;
+        reports_tree.insert(k, bincode::encode_to_vec(&report, standard())?)?;
+    }
+    Ok(())
+}
+
+pub(super) fn get_comment(pid: u32, cid: u32) -> Result<Comment, AppError> {
+    let k = [u32_to_ivec(pid), u32_to_ivec(cid)].concat();
+    let v = DB
+        .open_partition("post_comments", Default::default())?
+        .get(k)?
+        .ok_or(AppError::NotFound)?;
+    let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+    Ok(comment)
+}
+
+/// Page data: `report.html`
+#[derive(Template)]
+#[template(path = "report.html")]
+struct PageReport<'a> {
+    page_data: PageData<'a>,
+    pid: Option<u32>,
+    cid: Option<u32>,
+    sid: Option<u32>,
+    link: String,
+    summary: String,
+    rules: Vec<String>,
+}
+
+/// `GET /report` report page
+pub(crate) async fn report(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<ParamsReport>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let target = ReportTarget::new(params.pid, params.cid, params.sid)?;
+    target.check_visible(&claim)?;
+    let (iid, link, summary) = target.get_info()?;
+    let rules = if iid > 0 {
+        InnRules::get(&DB, iid)?.rules
+    } else {
+        vec![]
+    };
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("report", &site_config, Some(claim), has_unread);
+    let page_report = PageReport {
+        page_data,
+        pid: params.pid,
+        cid: params.cid,
+        sid: params.sid,
+        link,
+        summary,
+        rules,
+    };
+
+    Ok(into_response(&page_report))
+}
+
+/// Form data: `/report` report create
+#[derive(Deserialize, Validate)]
+pub(crate) struct FormReport {
+    csrf_token: String,
+    pid: Option<u32>,
+    cid: Option<u32>,
+    sid: Option<u32>,
+    #[validate(length(max = 500))]
+    reason: String,
+    /// number of the inn rule which is broken, 0 for none
+    rule: Option<usize>,
+}
+
+/// `POST /report` report create
+///
+/// Reporting the same target twice while the first report is still open is ignored.
+pub(crate) async fn report_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    ValidatedForm(input): ValidatedForm<FormReport>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    let created_at = Timestamp::now().as_second();
+    if created_at - claim.last_write < site_config.comment_interval {
+        return Err(AppError::WriteInterval);
+    }
+
+    let target = ReportTarget::new(input.pid, input.cid, input.sid)?;
+    target.check_visible(&claim)?;
+    let (iid, link, _) = target.get_info()?;
+
+    for rid in get_ids_by_prefix(&DB, "inn_reports", u32_to_ivec(iid), None)? {
+        let report: Report = get_one(&DB, "reports", rid)?;
+        if report.uid == claim.uid && report.target == target {
+            return Ok(Redirect::to(&link));
+        }
+    }
+
+    let reason = InnRules::cite(&DB, iid, input.rule, Some(input.reason))?.unwrap_or_default();
+    if reason.trim().is_empty() {
+        return Err(AppError::Custom(
+            "Please give a reason or cite a rule".into(),
+        ));
+    }
+
+    Report::add(&DB, claim.uid, iid, target, reason)?;
+    claim.update_last_write(&DB)?;
+
+    Ok(Redirect::to(&link))
+}
+
+/// mods of the inn and admins can handle reports of an inn, only admins of iid 0
+fn can_handle_reports(claim: &Claim, iid: u32) -> Result<bool, AppError> {
+    if Role::from(claim.role) == Role::Admin {
+        return Ok(true);
+    }
+    Ok(iid > 0 && User::is_mod(&DB, claim.uid, iid)?)
+}
+
+/// Vec data: report
+struct OutReport {
+    rid: u32,
+    uid: u32,
+    username: String,
+    reason: String,
+    created_at: String,
+    kind: &'static str,
+    link: String,
+    summary: String,
+    /// the target is already hidden or deleted
+    is_handled: bool,
+    is_locked: bool,
+    /// a solo held by the spam filter
+    is_held: bool,
+}
+
+/// Page data: `mod_reports.html`
+#[derive(Template)]
+#[template(path = "mod_reports.html")]
+struct PageModReports<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    inn_name: String,
+    reports: Vec<OutReport>,
+    anchor: usize,
+    n: usize,
+    is_desc: bool,
+}
+
+/// url params: `mod_reports.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsModReports {
+    anchor: Option<usize>,
+    is_desc: Option<bool>,
+}
+
+/// `GET /mod/:iid/reports` moderation queue of an inn, iid 0 is the admin queue for solos
+pub(crate) async fn mod_reports(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    Query(params): Query<ParamsModReports>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if !can_handle_reports(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let inn_name = if iid > 0 {
+        let inn: Inn = get_one(&DB, "inns", iid)?;
+        inn.inn_name
+    } else {
+        "Solo".to_owned()
+    };
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(false);
+    let page_params = ParamsPage { anchor, n, is_desc };
+
+    let mut reports = Vec::with_capacity(n);
+    let rids = get_ids_by_prefix(&DB, "inn_reports", u32_to_ivec(iid), Some(&page_params))?;
+    for rid in rids {
+        let report: Report = get_one(&DB, "reports", rid)?;
+        // reports filed by automod have uid 0
+        let username = if report.uid == 0 {
+            "automod".to_owned()
+        } else {
+            get_one::<User>(&DB, "users", report.uid)?.username
+        };
+
+        let mut is_handled = false;
+        let mut is_locked = false;
+        let mut is_held = false;
+        let (kind, link, summary) = match report.target.get_info() {
+            Ok((_, link, summary)) => {
+                match report.target {
+                    ReportTarget::Post(pid) => {
+                        let post: Post = get_one(&DB, "posts", pid)?;
+                        is_handled = post.status == PostStatus::HiddenByMod;
+                        is_locked = post.status == PostStatus::LockedByMod;
+                    }
+                    ReportTarget::Comment(pid, cid) => {
+                        is_handled = get_comment(pid, cid)?.is_hidden;
+                    }
+                    ReportTarget::Solo(sid) => is_held = is_spam_held(&DB, sid)?,
+                }
+                let kind = match report.target {
+                    ReportTarget::Post(_) => "post",
+                    ReportTarget::Comment(..) => "comment",
+                    ReportTarget::Solo(_) => "solo",
+                };
+                (kind, link, summary)
+            }
+            // deleted by the author in the meantime
+            Err(AppError::NotFound) => {
+                is_handled = true;
+                ("deleted", String::new(), String::new())
+            }
+            Err(e) => return Err(e),
+        };
+
+        reports.push(OutReport {
+            rid,
+            uid: report.uid,
+            username,
+            reason: report.reason,
+            created_at: ts_to_date(report.created_at),
+            kind,
+            link,
+            summary,
+            is_handled,
+            is_locked,
+            is_held,
+        });
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("reports", &site_config, Some(claim), has_unread);
+    let page_mod_reports = PageModReports {
+        page_data,
+        iid,
+        inn_name,
+        reports,
+        anchor,
+        n,
+        is_desc,
+    };
+
+    Ok(into_response(&page_mod_reports))
+}
+
+/// Form data: `/mod/:iid/reports/:rid` report action
+#[derive(Deserialize)]
+pub(crate) struct FormReportAction {
+    csrf_token: String,
+    action: String,
+}
+
+/// `POST /mod/:iid/reports/:rid` handle a report
+///
+/// `hide`, `lock` and `delete` act on the target through the same handlers as the buttons
+/// on the post and solo pages, `approve` releases a solo held by the spam filter, `dismiss` does
+/// nothing to it. Either way, all open reports of the target are closed.
+pub(crate) async fn mod_report_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, rid)): Path<(u32, u32)>,
+    Form(input): Form<FormReportAction>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie
+        .as_ref()
+        .and_then(|cookie| Claim::get(&DB, cookie, &site_config))
+        .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if !can_handle_reports(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let report: Report = get_one(&DB, "reports", rid)?;
+    if report.iid != iid {
+        return Err(AppError::NotFound);
+    }
+
+    let form = Form(FormModAction {
+        csrf_token: input.csrf_token,
+        reason: Some(format!("Report #{rid}")),
+        rule: None,
+    });
+    // post_hide, post_lock and comment_hide toggle, so only call them if not done yet
+    match (input.action.as_str(), report.target) {
+        ("dismiss", _) => {}
+        ("hide", ReportTarget::Post(pid)) => {
+            let post: Post = get_one(&DB, "posts", pid)?;
+            if post.status != PostStatus::HiddenByMod {
+                post_hide(cookie, Path((iid, pid)), form).await?;
+            }
+        }
+        ("lock", ReportTarget::Post(pid)) => {
+            let post: Post = get_one(&DB, "posts", pid)?;
+            if post.status != PostStatus::LockedByMod && post.status != PostStatus::HiddenByMod {
+                post_lock(cookie, Path((iid, pid)), form).await?;
+            }
+        }
+        ("hide", ReportTarget::Comment(pid, cid)) => {
+            if !get_comment(pid, cid)?.is_hidden {
+                comment_hide(cookie, Path((iid, pid, cid)), form).await?;
+            }
+        }
+        ("approve", ReportTarget::Solo(sid)) => solo_release(&DB, sid)?,
+        ("delete", ReportTarget::Solo(sid)) => {
+            if DB
+                .open_partition("solos", Default::default())?
+                .contains_key(u32_to_ivec(sid))?
+            {
+                solo_delete(cookie, Path(sid), form).await?;
+            }
+        }
+        _ => return Err(AppError::Custom("Invalid action".into())),
+    }
+
+    let inn_reports = DB.open_partition("inn_reports", Default::default())?;
+    for id in get_ids_by_prefix(&DB, "inn_reports", u32_to_ivec(iid), None)? {
+        let other: Report = get_one(&DB, "reports", id)?;
+        if other.target == report.target {
+            inn_reports.remove([u32_to_ivec(iid), u32_to_ivec(id)].concat())?;
+        }
+    }
+
+    let target = format!("/mod/{iid}/reports");
+    Ok(Redirect::to(&target))
+}
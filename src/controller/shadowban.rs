@@ -0,0 +1,149 @@
+//! ## Shadowban
+//!
+//! A shadowbanned user can go on as usual and still sees their own posts, comments and solos,
+//! but nobody else does: they are left out of timelines, post pages, search results and
+//! notifications. Admins see everything, and list shadowbanned users at
+//! `/user/list?filter=shadowbanned` to audit them.
+//!
+//! Shadowbans are not recorded in the mod log, which is public.
+
+use super::{
+    Claim, Shadowban, SiteConfig, User,
+    db_utils::{get_ids_by_prefix, get_one, set_one, u8_slice_to_u32, u32_to_ivec},
+    user::Role,
+};
+use crate::{DB, error::AppError};
+use axum::{
+    Form,
+    extract::Path,
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+impl Shadowban {
+    pub(super) fn get(db: &TransactionalKeyspace, uid: u32) -> Result<Option<Self>, AppError> {
+        let Some(v) = db
+            .open_partition("shadowbans", Default::default())?
+            .get(u32_to_ivec(uid))?
+        else {
+            return Ok(None);
+        };
+        let (shadowban, _): (Shadowban, _) = bincode::decode_from_slice(&v, standard())?;
+        Ok(Some(shadowban))
+    }
+
+    pub(super) fn is_on(db: &TransactionalKeyspace, uid: u32) -> Result<bool, AppError> {
+        Ok(db
+            .open_partition("shadowbans", Default::default())?
+            .contains_key(u32_to_ivec(uid))?)
+    }
+}
+
+/// Shadowbanned users whose content is hidden from the viewer: all of them but the viewer,
+/// none for admins.
+pub(super) fn hidden_uids(
+    db: &TransactionalKeyspace,
+    claim: Option<&Claim>,
+) -> Result<HashSet<u32>, AppError> {
+    if claim.is_some_and(|claim| Role::from(claim.role) == Role::Admin) {
+        return Ok(HashSet::new());
+    }
+    let mut uids: HashSet<u32> = get_ids_by_prefix(db, "shadowbans", b"", None)?
+        .into_iter()
+        .collect();
+    if let Some(claim) = claim {
+        uids.remove(&claim.uid);
+    }
+    Ok(uids)
+}
+
+/// pids of all posts of the users, read from the "shadowban_posts" index
+pub(super) fn hidden_pids(
+    db: &TransactionalKeyspace,
+    uids: &HashSet<u32>,
+) -> Result<HashSet<u32>, AppError> {
+    let mut pids = HashSet::new();
+    if uids.is_empty() {
+        return Ok(pids);
+    }
+    for i in db
+        .open_partition("shadowban_posts", Default::default())?
+        .inner()
+        .iter()
+    {
+        let (k, v) = i?;
+        if uids.contains(&u8_slice_to_u32(&v)) {
+            pids.insert(u8_slice_to_u32(&k));
+        }
+    }
+    Ok(pids)
+}
+
+/// Adds a new post to the "shadowban_posts" index if its author is shadowbanned.
+pub(super) fn index_post(db: &TransactionalKeyspace, uid: u32, pid: u32) -> Result<(), AppError> {
+    if Shadowban::is_on(db, uid)? {
+        db.open_partition("shadowban_posts", Default::default())?
+            .insert(u32_to_ivec(pid), u32_to_ivec(uid))?;
+    }
+    Ok(())
+}
+
+/// Form data: `/admin/shadowban/:uid` shadowban a user or lift it
+#[derive(Deserialize)]
+pub(crate) struct FormShadowban {
+    csrf_token: String,
+    reason: Option<String>,
+}
+
+/// `POST /admin/shadowban/:uid` shadowban a user or lift it
+pub(crate) async fn shadowban_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(uid): Path<u32>,
+    Form(input): Form<FormShadowban>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+    if Role::from(claim.role) != Role::Admin {
+        return Err(AppError::Unauthorized);
+    }
+
+    let user: User = get_one(&DB, "users", uid)?;
+    if Role::from(user.role) == Role::Admin {
+        return Err(AppError::Custom("Admins can not be shadowbanned".into()));
+    }
+
+    let pids = get_ids_by_prefix(&DB, "user_posts", u32_to_ivec(uid), None)?;
+    let shadowban_posts_ks = DB
+        .inner()
+        .open_partition("shadowban_posts", Default::default())?;
+    let mut batch = DB.inner().batch();
+    if Shadowban::is_on(&DB, uid)? {
+        DB.open_partition("shadowbans", Default::default())?
+            .remove(u32_to_ivec(uid))?;
+        for pid in pids {
+            batch.remove(&shadowban_posts_ks, u32_to_ivec(pid));
+        }
+    } else {
+        let shadowban = Shadowban {
+            reason: input
+                .reason
+                .map(|r| r.trim().chars().take(256).collect())
+                .unwrap_or_default(),
+            created_at: Timestamp::now().as_second(),
+        };
+        set_one(&DB, "shadowbans", uid, &shadowban)?;
+        for pid in pids {
+            batch.insert(&shadowban_posts_ks, u32_to_ivec(pid), u32_to_ivec(uid));
+        }
+    }
+    batch.commit()?;
+
+    Ok(Redirect::to("/user/list?filter=shadowbanned"))
+}
@@ -1,5 +1,7 @@
 use super::{
-    Claim, SiteConfig, Solo, SoloType, User,
+    Claim, ModAction, ModLog, ModTarget, ReportTarget, Shadowban, SiteConfig, Solo, SoloType, User,
+    automod::check_spam,
+    block::{is_blocked, muted_uids},
     db_utils::{
         IterType, extract_element, get_count_by_prefix, get_id_by_name, get_ids_by_tag, get_range,
         set_one,
@@ -7,14 +9,20 @@ use super::{
     filters,
     fmt::{md2html, ts_to_date},
     get_ids_by_prefix, get_one, incr_id, ivec_to_u32,
-    meta_handler::{PageData, ParamsPage, ValidatedForm, get_referer, into_response},
+    meta_handler::{
+        CsrfForm, FormCsrf, FormModAction, PageData, ParamsPage, get_referer, into_response,
+    },
     notification::{NtType, add_notification, mark_read},
+    poll::{OutPoll, PollTarget, get_out_poll, poll_delete},
+    shadowban::hidden_uids,
+    spam::{spam_check, spam_hold, train_solo},
     u8_slice_to_u32, u32_to_ivec,
     user::Role,
 };
 use crate::{DB, error::AppError};
 use askama::Template;
 use axum::{
+    Form,
     extract::{Path, Query},
     response::{IntoResponse, Redirect},
 };
@@ -24,12 +32,14 @@ use axum_extra::{
 };
 use fjall::TransactionalKeyspace;
 use jiff::Timestamp;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
 use validator::Validate;
 
 /// Form data: `/solo/user/:uid` solo create.
-#[derive(Deserialize, Validate)]
+#[derive(Deserialize, Validate, ToSchema)]
 pub(crate) struct FormSolo {
     #[validate(length(min = 1, max = 1000))]
     content: String,
@@ -56,7 +66,8 @@ struct PageSoloList<'a> {
 }
 
 /// Vec data: solo
-struct OutSolo {
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutSolo {
     uid: u32,
     sid: u32,
     username: String,
@@ -68,6 +79,8 @@ struct OutSolo {
     reply_to: Option<u32>,
     replies: Vec<u32>,
     can_delete: bool,
+    /// the author is muted by the viewer
+    is_muted: bool,
 }
 
 impl OutSolo {
@@ -80,25 +93,7 @@ impl OutSolo {
         let user: User = get_one(db, "users", solo.uid)?;
         let date = ts_to_date(solo.created_at);
 
-        let mut can_visit = false;
-        let solo_type = SoloType::from(solo.solo_type);
-        if solo_type == SoloType::Public {
-            can_visit = true;
-        } else if let Some(uid) = current_uid {
-            if uid == solo.uid || User::is_admin(db, uid)? {
-                can_visit = true;
-            } else if solo_type == SoloType::Following {
-                let k = [u32_to_ivec(solo.uid), u32_to_ivec(uid)].concat();
-                if db
-                    .open_partition("user_followers", Default::default())?
-                    .contains_key(k)?
-                {
-                    can_visit = true;
-                }
-            }
-        }
-
-        if !can_visit {
+        if !can_visit_solo_by(db, &solo, current_uid)? {
             return Ok(None);
         }
 
@@ -133,6 +128,7 @@ impl OutSolo {
             reply_to: solo.reply_to,
             replies: solo.replies,
             can_delete,
+            is_muted: false,
         };
 
         Ok(Some(out_solo))
@@ -148,47 +144,29 @@ fn can_visit_solo(solo_type: u32, followers: &[u32], solo_uid: u32, current_uid:
 }
 
 /// url params: `solo.html`
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub(crate) struct ParamsSolo {
-    anchor: Option<usize>,
-    is_desc: Option<bool>,
-    filter: Option<String>,
-    hashtag: Option<String>,
+    pub(super) anchor: Option<usize>,
+    pub(super) is_desc: Option<bool>,
+    pub(super) filter: Option<String>,
+    pub(super) hashtag: Option<String>,
     nid: Option<u32>,
 }
 
-/// `GET /solo/user/:uid` solo page
-pub(crate) async fn solo_list(
-    cookie: Option<TypedHeader<Cookie>>,
-    Path(u): Path<String>,
-    Query(params): Query<ParamsSolo>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
-
-    let uid = match u.parse::<u32>() {
-        Ok(uid) => uid,
-        Err(_) => get_id_by_name(&DB, "usernames", &u)?.ok_or(AppError::NotFound)?,
-    };
-
-    let n = site_config.per_page;
-    let anchor = params.anchor.unwrap_or(0);
-    let is_desc = params.is_desc.unwrap_or(true);
-    let page_params = ParamsPage { anchor, n, is_desc };
-
-    let mut is_following = false;
-    let mut index = Vec::with_capacity(n);
+/// Solos of the list page, all users if `uid` is 0. `filter` is `Following` or `Like`.
+pub(super) fn get_out_solos(
+    claim: Option<&Claim>,
+    uid: u32,
+    filter: Option<&str>,
+    hashtag: Option<&str>,
+    page_params: &ParamsPage,
+) -> Result<Vec<OutSolo>, AppError> {
+    let mut index = Vec::with_capacity(page_params.n);
     let mut followers = Vec::new();
     let mut current_uid = 0;
-    if let Some(ref claim) = claim {
-        let following_k = [u32_to_ivec(claim.uid), u32_to_ivec(uid)].concat();
-        if DB
-            .open_partition("user_following", Default::default())?
-            .contains_key(following_k)?
-        {
-            is_following = true;
-        }
-
+    let hidden_uids = hidden_uids(&DB, claim)?;
+    if let Some(claim) = claim {
         if let Ok(v) = get_ids_by_prefix(&DB, "user_followers", u32_to_ivec(claim.uid), None) {
             followers = v;
         }
@@ -196,47 +174,110 @@ pub(crate) async fn solo_list(
         followers.push(claim.uid);
     }
 
-    match params.filter.as_deref() {
+    match filter {
         Some("Following") => {
-            if let Some(ref claim) = claim
+            if let Some(claim) = claim
                 && let Ok(uids) =
                     get_ids_by_prefix(&DB, "user_following", u32_to_ivec(claim.uid), None)
             {
-                index = get_solos_by_uids(&DB, &uids, &followers, current_uid, &page_params)?;
+                index = get_solos_by_uids(
+                    &DB,
+                    &uids,
+                    &followers,
+                    current_uid,
+                    page_params,
+                    &hidden_uids,
+                )?;
             }
         }
         Some("Like") => {
-            if let Some(ref claim) = claim
+            if let Some(claim) = claim
                 && let Ok(sids) =
                     get_ids_by_prefix(&DB, "user_solos_like", u32_to_ivec(claim.uid), None)
             {
-                let (start, end) = get_range(sids.len(), &page_params);
+                let (start, end) = get_range(sids.len(), page_params);
                 index = sids[start - 1..end].to_vec();
-                if is_desc {
+                if page_params.is_desc {
                     index.reverse();
                 }
             }
         }
         _ => {
-            if let Some(ref hashtag) = params.hashtag {
-                index = get_ids_by_tag(&DB, "hashtags", hashtag, Some(&page_params))?;
+            if let Some(hashtag) = hashtag {
+                index = get_ids_by_tag(&DB, "hashtags", hashtag, Some(page_params))?;
             } else if uid == 0 {
-                index = get_all_solos(&DB, "solo_timeline", &followers, current_uid, &page_params)?;
+                index = get_all_solos(
+                    &DB,
+                    "solo_timeline",
+                    &followers,
+                    current_uid,
+                    page_params,
+                    &hidden_uids,
+                )?;
             } else {
-                index = get_solos_by_uids(&DB, &[uid], &followers, current_uid, &page_params)?;
+                index = get_solos_by_uids(
+                    &DB,
+                    &[uid],
+                    &followers,
+                    current_uid,
+                    page_params,
+                    &hidden_uids,
+                )?;
             }
         }
     }
 
+    let muted_uids = muted_uids(&DB, claim)?;
     let mut out_solos = Vec::with_capacity(index.len());
     for sid in index {
-        if let Some(out_solo) = OutSolo::get(&DB, sid, claim.as_ref().map(|c| c.uid))? {
+        if let Some(mut out_solo) = OutSolo::get(&DB, sid, claim.map(|c| c.uid))? {
+            if hidden_uids.contains(&out_solo.uid) {
+                continue;
+            }
+            out_solo.is_muted = muted_uids.contains(&out_solo.uid);
             out_solos.push(out_solo);
         } else {
             warn!("solo {} not found", sid);
         }
     }
 
+    Ok(out_solos)
+}
+
+/// `GET /solo/user/:uid` solo page
+pub(crate) async fn solo_list(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(u): Path<String>,
+    Query(params): Query<ParamsSolo>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let uid = match u.parse::<u32>() {
+        Ok(uid) => uid,
+        Err(_) => get_id_by_name(&DB, "usernames", &u)?.ok_or(AppError::NotFound)?,
+    };
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let page_params = ParamsPage { anchor, n, is_desc };
+
+    let mut is_following = false;
+    if let Some(ref claim) = claim {
+        let following_k = [u32_to_ivec(claim.uid), u32_to_ivec(uid)].concat();
+        is_following = DB
+            .open_partition("user_following", Default::default())?
+            .contains_key(following_k)?;
+    }
+    let out_solos = get_out_solos(
+        claim.as_ref(),
+        uid,
+        params.filter.as_deref(),
+        params.hashtag.as_deref(),
+        &page_params,
+    )?;
+
     let filter = if claim.is_none() { None } else { params.filter };
 
     let has_unread = if let Some(ref claim) = claim {
@@ -275,6 +316,44 @@ struct PageSolo<'a> {
     page_data: PageData<'a>,
     solo: OutSolo,
     reply_solos: Vec<OutSolo>,
+    poll: Option<OutPoll>,
+    is_bookmarked: bool,
+}
+
+/// A solo with its replies and poll
+#[derive(Serialize, ToSchema)]
+pub(super) struct SoloView {
+    solo: OutSolo,
+    replies: Vec<OutSolo>,
+    poll: Option<OutPoll>,
+}
+
+pub(super) fn get_solo_view(claim: Option<&Claim>, sid: u32) -> Result<SoloView, AppError> {
+    let current_uid = claim.map(|c| c.uid);
+    let solo = OutSolo::get(&DB, sid, current_uid)?.ok_or(AppError::NotFound)?;
+    let hidden_uids = hidden_uids(&DB, claim)?;
+    if hidden_uids.contains(&solo.uid) {
+        return Err(AppError::NotFound);
+    }
+
+    // TODO: Reply solos should be paginated
+    let muted_uids = muted_uids(&DB, claim)?;
+    let mut replies = Vec::with_capacity(solo.replies.len());
+    for i in &solo.replies {
+        if let Ok(Some(mut out_solo)) = OutSolo::get(&DB, *i, current_uid)
+            && !hidden_uids.contains(&out_solo.uid)
+        {
+            out_solo.is_muted = muted_uids.contains(&out_solo.uid);
+            replies.push(out_solo);
+        }
+    }
+    let poll = get_out_poll(PollTarget::Solo(sid), current_uid, true)?;
+
+    Ok(SoloView {
+        solo,
+        replies,
+        poll,
+    })
 }
 
 /// `GET /solo/:sid`
@@ -286,16 +365,7 @@ pub(crate) async fn solo(
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
 
-    let out_solo =
-        OutSolo::get(&DB, sid, claim.as_ref().map(|c| c.uid))?.ok_or(AppError::NotFound)?;
-
-    // TODO: Reply solos should be paginated
-    let mut reply_solos = Vec::with_capacity(out_solo.replies.len());
-    for i in &out_solo.replies {
-        if let Ok(Some(out_solo)) = OutSolo::get(&DB, *i, claim.as_ref().map(|c| c.uid)) {
-            reply_solos.push(out_solo);
-        }
-    }
+    let view = get_solo_view(claim.as_ref(), sid)?;
 
     if let Some(nid) = params.nid
         && let Some(ref claim) = claim
@@ -308,27 +378,58 @@ pub(crate) async fn solo(
         }
     }
 
-    let has_unread = if let Some(ref claim) = claim {
-        User::has_unread(&DB, claim.uid)?
-    } else {
-        false
-    };
+    let mut has_unread = false;
+    let mut is_bookmarked = false;
+    if let Some(ref claim) = claim {
+        has_unread = User::has_unread(&DB, claim.uid)?;
+        is_bookmarked = ReportTarget::Solo(sid).is_bookmarked(claim.uid)?;
+    }
+
     let page_data = PageData::new("Solo", &site_config, claim, has_unread);
     let page_solo = PageSolo {
         page_data,
-        solo: out_solo,
-        reply_solos,
+        solo: view.solo,
+        reply_solos: view.replies,
+        poll: view.poll,
+        is_bookmarked,
     };
 
     Ok(into_response(&page_solo))
 }
 
+/// whether the solo is visible to the user, `None` for anonymous visitors
+pub(super) fn can_visit_solo_by(
+    db: &TransactionalKeyspace,
+    solo: &Solo,
+    current_uid: Option<u32>,
+) -> Result<bool, AppError> {
+    let solo_type = SoloType::from(solo.solo_type);
+    if solo_type == SoloType::Public {
+        return Ok(true);
+    }
+
+    let Some(uid) = current_uid else {
+        return Ok(false);
+    };
+    if uid == solo.uid || User::is_admin(db, uid)? {
+        return Ok(true);
+    }
+    if solo_type == SoloType::Following {
+        let k = [u32_to_ivec(solo.uid), u32_to_ivec(uid)].concat();
+        return Ok(db
+            .open_partition("user_followers", Default::default())?
+            .contains_key(k)?);
+    }
+    Ok(false)
+}
+
 fn get_all_solos(
     db: &TransactionalKeyspace,
     timeline_tree: &str,
     followers: &[u32],
     current_uid: u32,
     page_params: &ParamsPage,
+    hidden_uids: &HashSet<u32>,
 ) -> Result<Vec<u32>, AppError> {
     let tree = db.open_partition(timeline_tree, Default::default())?;
     let mut count: usize = 0;
@@ -346,7 +447,9 @@ fn get_all_solos(
         let (k, v) = i?;
         let solo_uid = u8_slice_to_u32(&v[0..4]);
         let solo_type = u8_slice_to_u32(&v[4..8]);
-        if can_visit_solo(solo_type, followers, solo_uid, current_uid) {
+        if can_visit_solo(solo_type, followers, solo_uid, current_uid)
+            && !hidden_uids.contains(&solo_uid)
+        {
             if count < page_params.anchor {
                 count += 1;
                 continue;
@@ -367,10 +470,14 @@ fn get_solos_by_uids(
     followers: &[u32],
     current_uid: u32,
     page_params: &ParamsPage,
+    hidden_uids: &HashSet<u32>,
 ) -> Result<Vec<u32>, AppError> {
     let mut sids = Vec::with_capacity(page_params.n);
     let user_solos_tree = db.open_partition("user_solos", Default::default())?;
     for uid in uids {
+        if hidden_uids.contains(uid) {
+            continue;
+        }
         let prefix = u32_to_ivec(*uid);
         // kv_pair: uid#sid = solo_type
         for i in user_solos_tree.inner().prefix(prefix) {
@@ -393,33 +500,43 @@ fn get_solos_by_uids(
 /// `POST /solo/user/:uid` solo page
 pub(crate) async fn solo_post(
     cookie: Option<TypedHeader<Cookie>>,
-    ValidatedForm(input): ValidatedForm<FormSolo>,
+    CsrfForm(csrf_token, input): CsrfForm<FormSolo>,
 ) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
     let claim = cookie
         .and_then(|cookie| Claim::get(&DB, &cookie, &site_config))
         .ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&csrf_token)?;
 
-    if let Some(spam_regex) = &site_config.spam_regex {
-        let re = regex::Regex::new(spam_regex).unwrap();
-        if re.is_match(&input.content) {
-            return Err(AppError::Custom("Spam detected".into()));
-        }
-    }
+    check_spam(&site_config, &[&input.content])?;
 
     let created_at = Timestamp::now().as_second();
     if created_at - claim.last_write < site_config.solo_interval {
         return Err(AppError::WriteInterval);
     }
 
-    let solo_type = SoloType::from(input.solo_type);
+    // solos held by the spam filter stay private until admins look at them
+    let spam_score = spam_check(&DB, &site_config, &[&input.content])?;
+    let solo_type = if spam_score.is_some() {
+        SoloType::Private
+    } else {
+        SoloType::from(input.solo_type)
+    };
     let uid = claim.uid;
 
+    if input.reply_to != 0 {
+        let solo_replied: Solo = get_one(&DB, "solos", input.reply_to)?;
+        if is_blocked(&DB, solo_replied.uid, uid)? {
+            return Err(AppError::Blocked);
+        }
+    }
+
     let sid = incr_id(&DB, "solos_count")?;
     let sid_ivec = u32_to_ivec(sid);
     let mut content = input.content;
     let mut hashtags = Vec::new();
 
+    let is_shadowbanned = Shadowban::is_on(&DB, uid)?;
     let replied_user;
     let reply_to;
     if input.reply_to == 0 {
@@ -431,7 +548,7 @@ pub(crate) async fn solo_post(
         replied_user = Some(solo_replied.uid);
         set_one(&DB, "solos", input.reply_to, &solo_replied)?;
 
-        if solo_replied.uid != uid {
+        if solo_replied.uid != uid && !is_shadowbanned {
             add_notification(
                 &DB,
                 solo_replied.uid,
@@ -483,7 +600,11 @@ pub(crate) async fn solo_post(
             content = content.replace(&from, &to);
 
             // notify user to be mentioned in comment
-            if uid != claim.uid && replied_user != Some(uid) {
+            if uid != claim.uid
+                && replied_user != Some(uid)
+                && !is_shadowbanned
+                && !is_blocked(&DB, uid, claim.uid)?
+            {
                 add_notification(&DB, uid, NtType::SoloMention, sid, 0)?;
             }
         }
@@ -501,6 +622,12 @@ pub(crate) async fn solo_post(
     };
 
     set_one(&DB, "solos", sid, &solo)?;
+    if let Some(score) = spam_score {
+        let solo_type = SoloType::from(input.solo_type) as u32;
+        DB.open_partition("spam_solos", Default::default())?
+            .insert(&sid_ivec, u32_to_ivec(solo_type))?;
+        spam_hold(&DB, 0, ReportTarget::Solo(sid), score)?;
+    }
     let k = [u32_to_ivec(claim.uid), sid_ivec.clone()].concat();
     DB.open_partition("user_solos", Default::default())?
         .insert(k, u32_to_ivec(solo_type as u32))?;
@@ -526,15 +653,17 @@ pub(crate) async fn solo_post(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /solo/:sid/like` solo like
+/// `POST /solo/:sid/like` solo like
 pub(crate) async fn solo_like(
     referer: Option<TypedHeader<Referer>>,
     cookie: Option<TypedHeader<Cookie>>,
     Path(sid): Path<u32>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let solo: Solo = get_one(&DB, "solos", sid)?;
 
@@ -562,29 +691,55 @@ pub(crate) async fn solo_like(
     Ok(Redirect::to(&target))
 }
 
-/// `GET /solo/:sid/delete` solo delete
+/// `POST /solo/:sid/delete` solo delete
 pub(crate) async fn solo_delete(
     cookie: Option<TypedHeader<Cookie>>,
     Path(sid): Path<u32>,
+    Form(form): Form<FormModAction>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let solo: Solo = get_one(&DB, "solos", sid)?;
     if solo.uid != claim.uid && Role::from(claim.role) != Role::Admin {
         return Err(AppError::Unauthorized);
     }
+    if solo.uid != claim.uid {
+        train_solo(&DB, &solo, true)?;
+    }
+
+    solo_rm(&DB, &solo)?;
+
+    if solo.uid != claim.uid {
+        add_notification(&DB, solo.uid, NtType::SoloDelete, claim.uid, solo.sid)?;
+        ModLog::add(
+            &DB,
+            claim.uid,
+            0,
+            ModTarget::Solo(sid),
+            ModAction::Delete,
+            form.reason,
+        )?;
+    }
+
+    let target = format!("/solo/user/{}", solo.uid);
+    Ok(Redirect::to(&target))
+}
 
+/// Remove a solo with its likes, hashtags and poll
+pub(super) fn solo_rm(db: &TransactionalKeyspace, solo: &Solo) -> Result<(), AppError> {
+    let sid = solo.sid;
     let sid_ivec = u32_to_ivec(sid);
 
-    DB.open_partition("solos", Default::default())?
+    db.open_partition("solos", Default::default())?
         .remove(&sid_ivec)?;
-    DB.open_partition("solo_timeline", Default::default())?
+    db.open_partition("solo_timeline", Default::default())?
         .remove(&sid_ivec)?;
 
-    let solo_users_like_tree = DB.open_partition("solo_users_like", Default::default())?;
-    let user_solos_like_tree = DB.open_partition("user_solos_like", Default::default())?;
+    let solo_users_like_tree = db.open_partition("solo_users_like", Default::default())?;
+    let user_solos_like_tree = db.open_partition("user_solos_like", Default::default())?;
     for i in solo_users_like_tree.inner().prefix(&sid_ivec) {
         let (k, _) = i?;
         let uid = &k[4..8];
@@ -593,23 +748,54 @@ pub(crate) async fn solo_delete(
         solo_users_like_tree.remove(k)?;
     }
 
-    let hashtags_tree = DB.open_partition("hashtags", Default::default())?;
-    for hashtag in solo.hashtags {
+    let hashtags_tree = db.open_partition("hashtags", Default::default())?;
+    for hashtag in &solo.hashtags {
         let k = [hashtag.as_bytes(), &sid_ivec].concat();
         hashtags_tree.remove(k)?;
     }
 
-    let k = [u32_to_ivec(solo.uid), sid_ivec].concat();
-    DB.open_partition("user_solos", Default::default())?
+    let k = [u32_to_ivec(solo.uid), sid_ivec.clone()].concat();
+    db.open_partition("user_solos", Default::default())?
         .remove(k)?;
 
-    DB.open_partition("tan", Default::default())?
+    db.open_partition("tan", Default::default())?
         .remove(format!("solo{sid}"))?;
+    db.open_partition("spam_solos", Default::default())?
+        .remove(&sid_ivec)?;
 
-    if solo.uid != claim.uid {
-        add_notification(&DB, solo.uid, NtType::SoloDelete, claim.uid, solo.sid)?;
+    poll_delete(PollTarget::Solo(sid))
+}
+
+/// Whether the solo is held by the spam filter
+pub(super) fn is_spam_held(db: &TransactionalKeyspace, sid: u32) -> Result<bool, AppError> {
+    Ok(db
+        .open_partition("spam_solos", Default::default())?
+        .contains_key(u32_to_ivec(sid))?)
+}
+
+/// Give a solo held by the spam filter the type its author chose, and train it as ham
+pub(super) fn solo_release(db: &TransactionalKeyspace, sid: u32) -> Result<(), AppError> {
+    let sid_ivec = u32_to_ivec(sid);
+    let Some(v) = db
+        .open_partition("spam_solos", Default::default())?
+        .take(&sid_ivec)?
+    else {
+        return Ok(());
+    };
+    let mut solo: Solo = get_one(db, "solos", sid)?;
+    solo.solo_type = u8_slice_to_u32(&v);
+    set_one(db, "solos", sid, &solo)?;
+
+    let k = [u32_to_ivec(solo.uid), sid_ivec.clone()].concat();
+    db.open_partition("user_solos", Default::default())?
+        .insert(k, u32_to_ivec(solo.solo_type))?;
+    let v = [u32_to_ivec(solo.uid), u32_to_ivec(solo.solo_type)].concat();
+    db.open_partition("solo_timeline", Default::default())?
+        .insert(&sid_ivec, v)?;
+    if SoloType::from(solo.solo_type) == SoloType::Public {
+        db.open_partition("tan", Default::default())?
+            .insert(format!("solo{sid}"), [])?;
     }
 
-    let target = format!("/solo/user/{}", solo.uid);
-    Ok(Redirect::to(&target))
+    train_solo(db, &solo, false)
 }
@@ -0,0 +1,251 @@
+//! ## Spam filter
+//!
+//! A naive Bayes classifier trained from the decisions of mods: hiding, rejecting and deleting
+//! content or banning its author trains it as spam, approving or unhiding content trains it as
+//! ham. Texts are tokenized like the search index.
+//!
+//! New and edited content scoring at least `spam_threshold` percent is held for review: new posts
+//! go to the review queue, edited posts and comments are hidden and solos are made private, and
+//! all but new posts are reported. Admins approve held solos from the report queue.
+//! The filter stays silent until it has seen enough spam and ham.
+
+use super::{
+    Comment, ModAction, ModLog, ModTarget, Post, PostContent, Report, ReportTarget, SiteConfig,
+    Solo,
+    db_utils::{get_ids_by_prefix, get_one, u8_slice_to_u32, u32_to_ivec},
+    meta_handler::ParamsPage,
+    tantivy::pre_tokenize_text,
+};
+use crate::error::AppError;
+use bincode::config::standard;
+use fjall::{Slice, TransactionalKeyspace};
+use std::collections::HashSet;
+
+/// the classifier scores nothing before it has seen this many spam and ham
+const MIN_DOCS: u32 = 10;
+/// only the first tokens of long texts are used
+const MAX_TOKENS: usize = 1000;
+/// recent posts, comments and solos of a banned user trained as spam
+const BANNED_RECENT: usize = 20;
+
+fn tokenize(text: &str) -> HashSet<String> {
+    pre_tokenize_text(text)
+        .into_iter()
+        .take(MAX_TOKENS)
+        .map(|token| token.text)
+        .collect()
+}
+
+fn decode_counts(v: &[u8]) -> (u32, u32) {
+    (u8_slice_to_u32(&v[0..4]), u8_slice_to_u32(&v[4..8]))
+}
+
+/// add `spam` and `ham` to the counts `spam#ham`, removing the key when both are 0
+fn add_counts(old: Option<&Slice>, spam: i64, ham: i64) -> Option<Slice> {
+    let (s, h) = old.map(|v| decode_counts(v)).unwrap_or_default();
+    let s = (s as i64 + spam).max(0) as u32;
+    let h = (h as i64 + ham).max(0) as u32;
+    if s == 0 && h == 0 {
+        None
+    } else {
+        Some(Slice::from([u32_to_ivec(s), u32_to_ivec(h)].concat()))
+    }
+}
+
+fn update_model(
+    db: &TransactionalKeyspace,
+    tokens: &HashSet<String>,
+    spam: i64,
+    ham: i64,
+) -> Result<(), AppError> {
+    db.open_partition("default", Default::default())?
+        .update_fetch("spam_docs", |old| add_counts(old, spam, ham))?;
+    let tokens_ks = db.open_partition("spam_tokens", Default::default())?;
+    for token in tokens {
+        tokens_ks.update_fetch(token.as_str(), |old| add_counts(old, spam, ham))?;
+    }
+    Ok(())
+}
+
+/// Train the classifier with what mods decided. `target` is the key used in "tan", like
+/// `post1` or `comt1/2`. Training a target again with the other label moves it, using the
+/// tokens it was trained with.
+fn train(
+    db: &TransactionalKeyspace,
+    target: &str,
+    text: &str,
+    is_spam: bool,
+) -> Result<(), AppError> {
+    let labels_ks = db.open_partition("spam_labels", Default::default())?;
+    if let Some(v) = labels_ks.get(target)? {
+        let was_spam = v[0] == 1;
+        if was_spam == is_spam {
+            return Ok(());
+        }
+        // labels saved before the tokens were kept only have the flag
+        let old_tokens = if v.len() > 1 {
+            bincode::decode_from_slice(&v[1..], standard())?.0
+        } else {
+            tokenize(text)
+        };
+        if was_spam {
+            update_model(db, &old_tokens, -1, 0)?;
+        } else {
+            update_model(db, &old_tokens, 0, -1)?;
+        }
+    }
+
+    let tokens = tokenize(text);
+    if is_spam {
+        update_model(db, &tokens, 1, 0)?;
+    } else {
+        update_model(db, &tokens, 0, 1)?;
+    }
+    let v = [
+        vec![is_spam as u8],
+        bincode::encode_to_vec(&tokens, standard())?,
+    ]
+    .concat();
+    labels_ks.insert(target, v)?;
+    Ok(())
+}
+
+/// Posts generated from feeds are not trained.
+pub(super) fn train_post(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    is_spam: bool,
+) -> Result<(), AppError> {
+    if let PostContent::Markdown(ref content) = post.content {
+        let text = format!("{}\n{content}", post.title);
+        train(db, &format!("post{}", post.pid), &text, is_spam)?;
+    }
+    Ok(())
+}
+
+pub(super) fn train_comment(
+    db: &TransactionalKeyspace,
+    comment: &Comment,
+    is_spam: bool,
+) -> Result<(), AppError> {
+    let text = ammonia::Builder::empty()
+        .clean(&comment.content)
+        .to_string();
+    let target = format!("comt{}/{}", comment.pid, comment.cid);
+    train(db, &target, &text, is_spam)
+}
+
+pub(super) fn train_solo(
+    db: &TransactionalKeyspace,
+    solo: &Solo,
+    is_spam: bool,
+) -> Result<(), AppError> {
+    let text = ammonia::Builder::empty().clean(&solo.content).to_string();
+    train(db, &format!("solo{}", solo.sid), &text, is_spam)
+}
+
+/// Train the recent posts, comments and solos of a banned user as spam
+pub(super) fn train_banned(db: &TransactionalKeyspace, uid: u32) -> Result<(), AppError> {
+    let page_params = ParamsPage {
+        anchor: 0,
+        n: BANNED_RECENT,
+        is_desc: true,
+    };
+    let uid_ivec = u32_to_ivec(uid);
+
+    for pid in get_ids_by_prefix(db, "user_posts", &uid_ivec, Some(&page_params))? {
+        if let Ok(post) = get_one::<Post>(db, "posts", pid) {
+            train_post(db, &post, true)?;
+        }
+    }
+
+    let comments_ks = db.open_partition("post_comments", Default::default())?;
+    for i in db
+        .open_partition("user_comments", Default::default())?
+        .inner()
+        .prefix(&uid_ivec)
+        .rev()
+        .take(BANNED_RECENT)
+    {
+        let (k, _) = i?;
+        if let Some(v) = comments_ks.get(&k[4..12])? {
+            let (comment, _): (Comment, usize) = bincode::decode_from_slice(&v, standard())?;
+            train_comment(db, &comment, true)?;
+        }
+    }
+
+    for sid in get_ids_by_prefix(db, "user_solos", &uid_ivec, Some(&page_params))? {
+        if let Ok(solo) = get_one::<Solo>(db, "solos", sid) {
+            train_solo(db, &solo, true)?;
+        }
+    }
+    Ok(())
+}
+
+/// The probability in percent that the text is spam, 0 if the classifier is not trained enough
+fn spam_score(db: &TransactionalKeyspace, text: &str) -> Result<u8, AppError> {
+    let Some(v) = db
+        .open_partition("default", Default::default())?
+        .get("spam_docs")?
+    else {
+        return Ok(0);
+    };
+    let (n_spam, n_ham) = decode_counts(&v);
+    if n_spam < MIN_DOCS || n_ham < MIN_DOCS {
+        return Ok(0);
+    }
+    let (n_spam, n_ham) = (n_spam as f64, n_ham as f64);
+
+    let mut log_spam = n_spam.ln();
+    let mut log_ham = n_ham.ln();
+    let tokens_ks = db.open_partition("spam_tokens", Default::default())?;
+    for token in tokenize(text) {
+        if let Some(v) = tokens_ks.get(token)? {
+            let (s, h) = decode_counts(&v);
+            log_spam += ((s as f64 + 1.0) / (n_spam + 2.0)).ln();
+            log_ham += ((h as f64 + 1.0) / (n_ham + 2.0)).ln();
+        }
+    }
+    let p = 1.0 / (1.0 + (log_ham - log_spam).exp());
+    Ok((p * 100.0).round() as u8)
+}
+
+/// The spam score of the texts if it reaches the threshold of the site
+pub(super) fn spam_check(
+    db: &TransactionalKeyspace,
+    site_config: &SiteConfig,
+    texts: &[&str],
+) -> Result<Option<u8>, AppError> {
+    if site_config.spam_threshold == 0 {
+        return Ok(None);
+    }
+    let score = spam_score(db, &texts.join("\n"))?;
+    Ok((score >= site_config.spam_threshold).then_some(score))
+}
+
+/// Report an edited post, a comment (iid > 0) or a solo held by the spam filter. Posts and
+/// comments are also logged as hidden.
+pub(super) fn spam_hold(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    target: ReportTarget,
+    score: u8,
+) -> Result<(), AppError> {
+    let reason = format!("Spam score {score}%");
+    let mod_target = match target {
+        ReportTarget::Post(pid) => Some(ModTarget::Post(pid)),
+        ReportTarget::Comment(pid, cid) => Some(ModTarget::Comment(pid, cid)),
+        ReportTarget::Solo(_) => None,
+    };
+    if let Some(mod_target) = mod_target {
+        ModLog::add(
+            db,
+            0,
+            iid,
+            mod_target,
+            ModAction::Hide,
+            Some(reason.clone()),
+        )?;
+    }
+    Report::add(db, 0, iid, target, format!("{reason}: held for review"))
+}
@@ -8,9 +8,9 @@ use fjall::TransactionalKeyspace;
 use indexmap::IndexSet;
 use jieba_rs::{Jieba, TokenizeMode};
 use rust_stemmers::{Algorithm, Stemmer};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tantivy::{
-    Index, IndexReader, IndexWriter, TantivyDocument,
+    Index, IndexReader, IndexWriter, TantivyDocument, Term,
     collector::TopDocs,
     directory::MmapDirectory,
     query::QueryParser,
@@ -22,19 +22,22 @@ use tantivy::{
 };
 use tracing::{info, warn};
 use unicode_segmentation::UnicodeSegmentation;
+use utoipa::{IntoParams, ToSchema};
 use whichlang::detect_language;
 
 use crate::{DB, config::CONFIG, error::AppError};
 
 use super::{
-    Claim, Comment, InnType, Item, Post, PostStatus, SiteConfig, Solo, SoloType, User,
+    Claim, Comment, Inn, InnType, Item, Post, PostStatus, SiteConfig, Solo, SoloType, User,
     db_utils::{get_one, u8_slice_to_u32, u32_to_ivec},
     filters,
     fmt::ts_to_date,
     meta_handler::{PageData, into_response},
+    shadowban::hidden_uids,
 };
 
-struct OutSearch {
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutSearch {
     url: String,
     title: String,
     date: String,
@@ -54,7 +57,8 @@ struct PageSearch<'a> {
     uid: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub(crate) struct ParamsSearch {
     search: String,
     offset: Option<usize>,
@@ -62,13 +66,11 @@ pub(crate) struct ParamsSearch {
     ctype: Option<String>,
 }
 
-pub(crate) async fn search(
-    Query(input): Query<ParamsSearch>,
-    cookie: Option<TypedHeader<Cookie>>,
-) -> Result<impl IntoResponse, AppError> {
-    let site_config = SiteConfig::get(&DB)?;
-    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
-
+/// Up to 20 results from `offset`, leaving out content of shadowbanned users
+pub(super) fn get_out_searches(
+    claim: Option<&Claim>,
+    input: &ParamsSearch,
+) -> Result<Vec<OutSearch>, AppError> {
     let offset = input.offset.unwrap_or_default();
     let search = input.search.trim();
 
@@ -108,13 +110,36 @@ pub(crate) async fn search(
         }
     }
 
+    let hidden_uids = hidden_uids(&DB, claim)?;
+    // docs of deleted users stay in the index until the next restart
+    let deleted_ks = DB.open_partition("deleted_users", Default::default())?;
     let mut out_searches = Vec::with_capacity(20);
     for id in ids {
-        if let Some(out) = OutSearch::get(&id, &DB) {
+        if let Some(out) = OutSearch::get(&id, &DB)
+            && !out.uid.is_some_and(|uid| hidden_uids.contains(&uid))
+            && !out.uid.is_some_and(|uid| {
+                deleted_ks
+                    .contains_key(u32_to_ivec(uid))
+                    .unwrap_or_default()
+            })
+        {
             out_searches.push(out);
         }
     }
 
+    Ok(out_searches)
+}
+
+pub(crate) async fn search(
+    Query(input): Query<ParamsSearch>,
+    cookie: Option<TypedHeader<Cookie>>,
+) -> Result<impl IntoResponse, AppError> {
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = cookie.and_then(|cookie| Claim::get(&DB, &cookie, &site_config));
+
+    let offset = input.offset.unwrap_or_default();
+    let out_searches = get_out_searches(claim.as_ref(), &input)?;
+
     let has_unread = if let Some(ref claim) = claim {
         User::has_unread(&DB, claim.uid)?
     } else {
@@ -175,6 +200,12 @@ impl Tan {
         Ok(())
     }
 
+    /// Delete all docs of a user, not commit.
+    pub fn delete_user(&mut self, uid: u32) {
+        self.writer
+            .delete_term(Term::from_field_u64(FIELDS.uid, uid as u64));
+    }
+
     pub fn commit(&mut self) -> tantivy::Result<()> {
         self.writer.commit()?;
         Ok(())
@@ -332,6 +363,25 @@ fn extract_id(id: &str, db: &TransactionalKeyspace) -> Result<TantivyDocument, A
     }
 }
 
+/// The index keeps the docs of posts moved out of open inns, merged or hidden since, as docs
+/// can not be deleted by id. Check the post as it is now.
+fn is_searchable(db: &TransactionalKeyspace, post: &Post) -> bool {
+    if matches!(
+        post.status,
+        PostStatus::HiddenByMod | PostStatus::HiddenByUser | PostStatus::Pending
+    ) {
+        return false;
+    }
+    let Ok(inn) = get_one::<Inn>(db, "inns", post.iid) else {
+        return false;
+    };
+    let is_redirected = db
+        .open_partition("post_redirects", Default::default())
+        .and_then(|ks| ks.contains_key(u32_to_ivec(post.pid)))
+        .unwrap_or(true);
+    inn.is_open_access() && !is_redirected
+}
+
 impl OutSearch {
     fn get(id: &str, db: &TransactionalKeyspace) -> Option<Self> {
         let ctype = &id[0..4];
@@ -341,6 +391,9 @@ impl OutSearch {
         match ctype {
             "post" => {
                 let post: Post = get_one(db, "posts", id1).ok()?;
+                if !is_searchable(db, &post) {
+                    return None;
+                }
                 Some(Self {
                     url: format!("/post/{}/{}", post.iid, post.pid),
                     title: post.title,
@@ -360,9 +413,12 @@ impl OutSearch {
                 let (comment, _): (Comment, usize) =
                     bincode::decode_from_slice(&v, standard()).ok()?;
                 let post: Post = get_one(db, "posts", id1).ok()?;
+                if comment.is_hidden || !is_searchable(db, &post) {
+                    return None;
+                }
                 Some(Self {
                     url: format!(
-                        "/post/{}/{}?anchor={}&is_desc=false#{}",
+                        "/post/{}/{}?anchor={}&is_desc=false&view=flat#{}",
                         post.iid,
                         comment.pid,
                         comment.cid - 1,
@@ -446,7 +502,7 @@ impl TokenStream for MultiLingoTokenStream {
 static JIEBA: LazyLock<Jieba> = LazyLock::new(Jieba::new);
 static STEMMER_ENG: LazyLock<Stemmer> = LazyLock::new(|| Stemmer::create(Algorithm::English));
 
-fn pre_tokenize_text(text: &str) -> Vec<Token> {
+pub(super) fn pre_tokenize_text(text: &str) -> Vec<Token> {
     let mut tokens = Vec::with_capacity(text.len() / 4);
     match detect_language(text) {
         whichlang::Lang::Eng => {
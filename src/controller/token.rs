@@ -0,0 +1,450 @@
+//! ## Personal API tokens
+//!
+//! Users create named tokens with a set of scopes and an expiry at `/user/setting`, and send
+//! them as `Authorization: Bearer <token>` instead of the session cookie. Only a PBKDF2 hash of
+//! the secret is stored, the token is shown once when it is created.
+//!
+//! Each token is backed by a session of its own, so handlers see a usual [Claim]. The session is
+//! never handed out as a cookie, and requests carrying it skip the CSRF check, since browsers do
+//! not attach bearer tokens by themselves.
+//!
+//! Tokens are meant for the JSON api at `/api/v1`, see [super::api], but work for the pages too.
+//!
+//! Every request is checked against the scopes of the token, see [required_scope]. Account
+//! settings, messages and the token pages themselves can not be reached with a token.
+
+use super::{
+    ApiToken, Claim, SiteConfig, Solo, User,
+    api::ApiError,
+    db_utils::{get_one, set_one_with_key},
+    fmt::ts_to_date,
+    meta_handler::{FormCsrf, PageData, into_response},
+    user::{COOKIE_NAME, Role, check_password, generate_password_hash},
+};
+use crate::{DB, error::AppError};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Path, Request},
+    http::{
+        HeaderValue, Method,
+        header::{AUTHORIZATION, COOKIE},
+    },
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use bincode::config::standard;
+use cached::cached;
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use nanoid::nanoid;
+use tokio::task;
+
+/// how many unexpired tokens a user can have
+const TOKENS_MAX: usize = 10;
+
+/// `last_used` of a token is written at most once in this many seconds
+const LAST_USED_INTERVAL: i64 = 300;
+
+/// Sessions of tokens are marked by this char after the expiry, `{exp:x}_~{nanoid}`.
+/// nanoid never generates it.
+pub(super) const TOKEN_SESSION_MARK: char = '~';
+
+/// scopes of a token, stored as bits in [ApiToken::scopes]
+const SCOPES: [(&str, u8); 6] = [
+    ("read", 1),
+    ("post", 1 << 1),
+    ("comment", 1 << 2),
+    ("solo", 1 << 3),
+    ("feed", 1 << 4),
+    ("mod", 1 << 5),
+];
+
+pub(super) fn scope_bit(name: &str) -> u8 {
+    SCOPES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, bit)| *bit)
+        .unwrap_or_default()
+}
+
+fn scope_names(scopes: u8) -> Vec<&'static str> {
+    SCOPES
+        .iter()
+        .filter(|(_, bit)| scopes & bit != 0)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// The scope a token needs for the request, `None` if tokens can not be used for it.
+///
+/// The api under `/api/v1` needs the same scopes as the pages it mirrors.
+pub(super) fn required_scope(method: &Method, path: &str) -> Option<u8> {
+    let path = path.strip_prefix("/api/v1").unwrap_or(path);
+    const DENIED: [&str; 15] = [
+        "/signin",
+        "/signup",
+        "/signout",
+        "/user/setting",
+        "/user/avatar",
+        "/user/password",
+        "/user/recovery",
+        "/user/totp",
+        "/user/reset",
+        "/user/email",
+        "/user/export",
+        "/user/delete",
+        "/user/remove",
+        "/user/tokens",
+        "/user/bookmarks/token",
+    ];
+    if DENIED.iter().any(|p| path.starts_with(p))
+        || path.starts_with("/message/")
+        || path.starts_with("/inbox/")
+        || path == "/key"
+        || path == "/invite"
+    {
+        return None;
+    }
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let name = match segments.as_slice() {
+        ["mod", ..] | ["admin", ..] | ["role", ..] => "mod",
+        ["post", _, _, _, "hide"] => "mod",
+        _ if method == Method::GET || method == Method::HEAD => "read",
+        ["feed", "update"] => "read",
+        ["post", "edit", _] | ["post", _, _, "delete" | "upvote" | "downvote"] => "post",
+        ["poll", ..] | ["upload"] | ["preview"] => "post",
+        ["post", _, _] | ["post", _, _, _, ..] => "comment",
+        ["solo", ..] => "solo",
+        ["feed", ..] => "feed",
+        _ => return None,
+    };
+    Some(scope_bit(name))
+}
+
+impl ApiToken {
+    fn get(db: &TransactionalKeyspace, tid: &str) -> Result<Option<Self>, AppError> {
+        let Some(v) = db
+            .open_partition("api_tokens", Default::default())?
+            .get(tid)?
+        else {
+            return Ok(None);
+        };
+        let (token, _): (ApiToken, _) = bincode::decode_from_slice(&v, standard())?;
+        Ok(Some(token))
+    }
+}
+
+/// the expiry of a token or session id `{exp:x}_{nanoid}`
+fn expires_at(id: &str) -> Option<i64> {
+    id.split_once('_')
+        .and_then(|(exp, _)| i64::from_str_radix(exp, 16).ok())
+}
+
+/// Admins delete the solos of others by the url authors use, which needs the `mod` scope then.
+fn deletes_solo_of_others(
+    db: &TransactionalKeyspace,
+    method: &Method,
+    path: &str,
+    uid: u32,
+) -> Result<bool, AppError> {
+    if method != Method::POST {
+        return Ok(false);
+    }
+    let path = path.strip_prefix("/api/v1").unwrap_or(path);
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let ["solo", sid, "delete"] = segments.as_slice() else {
+        return Ok(false);
+    };
+    let Ok(sid) = sid.parse() else {
+        return Ok(false);
+    };
+    let solo: Solo = get_one(db, "solos", sid)?;
+    Ok(solo.uid != uid)
+}
+
+/// PBKDF2 is slow by design, so verified secrets are kept for a while.
+/// Revoked tokens are gone from "api_tokens" and fail before this.
+#[cached(size = 1024, time = 600)]
+fn verify_secret(secret: String, hash: String) -> bool {
+    check_password(&secret, &hash)
+}
+
+/// Check a bearer token `{tid}.{secret}` and return the id of its session
+async fn token_session(
+    db: &TransactionalKeyspace,
+    bearer: &str,
+    method: &Method,
+    path: &str,
+) -> Result<String, AppError> {
+    let (tid, secret) = bearer.split_once('.').ok_or(AppError::Unauthorized)?;
+    let mut token = ApiToken::get(db, tid)?.ok_or(AppError::Unauthorized)?;
+    let now = Timestamp::now().as_second();
+    if expires_at(tid).is_none_or(|exp| exp < now) {
+        return Err(AppError::Unauthorized);
+    }
+    let (secret, hash) = (secret.to_owned(), token.hash.clone());
+    if !task::spawn_blocking(move || verify_secret(secret, hash))
+        .await
+        .unwrap_or_default()
+    {
+        return Err(AppError::Unauthorized);
+    }
+    let mut scope = required_scope(method, path).ok_or(AppError::Unauthorized)?;
+    if deletes_solo_of_others(db, method, path, token.uid)? {
+        scope = scope_bit("mod");
+    }
+    if token.scopes & scope == 0 {
+        return Err(AppError::Unauthorized);
+    }
+
+    if !db
+        .open_partition("sessions", Default::default())?
+        .contains_key(&token.session_id)?
+    {
+        let user: User = get_one(db, "users", token.uid)?;
+        if Role::from(user.role) == Role::Banned {
+            return Err(AppError::Banned);
+        }
+        let claim = Claim {
+            uid: user.uid,
+            username: user.username,
+            role: user.role,
+            last_write: token.last_used,
+            session_id: token.session_id.clone(),
+            lang: user.lang,
+        };
+        set_one_with_key(db, "sessions", &token.session_id, &claim)?;
+    }
+
+    if now - token.last_used >= LAST_USED_INTERVAL {
+        token.last_used = now;
+        set_one_with_key(db, "api_tokens", tid, &token)?;
+    }
+    Ok(token.session_id)
+}
+
+/// Middleware: sign in requests with an `Authorization: Bearer` header by the session of the
+/// token. The cookie sent along is dropped.
+pub(crate) async fn bearer_auth(mut req: Request, next: Next) -> Response {
+    let Some(bearer) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return next.run(req).await;
+    };
+
+    let bearer = bearer.trim().to_owned();
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let is_api = path.starts_with("/api/");
+    let session_id = match token_session(&DB, &bearer, &method, &path).await {
+        Ok(session_id) => session_id,
+        Err(e) if is_api => return ApiError::from(e).into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let cookie = format!("{COOKIE_NAME}={session_id}");
+    let Ok(cookie) = HeaderValue::from_str(&cookie) else {
+        return AppError::Unauthorized.into_response();
+    };
+    req.headers_mut().insert(COOKIE, cookie);
+    next.run(req).await
+}
+
+/// Vec data: api token
+pub(super) struct OutApiToken {
+    pub(super) tid: String,
+    pub(super) name: String,
+    pub(super) scopes: String,
+    pub(super) created_at: String,
+    pub(super) expires_at: String,
+    pub(super) last_used: String,
+}
+
+/// unexpired tokens of the user
+pub(super) fn user_tokens(
+    db: &TransactionalKeyspace,
+    uid: u32,
+) -> Result<Vec<OutApiToken>, AppError> {
+    let now = Timestamp::now().as_second();
+    let mut tokens = vec![];
+    for i in db
+        .open_partition("api_tokens", Default::default())?
+        .inner()
+        .iter()
+    {
+        let (k, v) = i?;
+        let tid = String::from_utf8_lossy(&k).to_string();
+        let Some(exp) = expires_at(&tid).filter(|exp| *exp > now) else {
+            continue;
+        };
+        let (token, _): (ApiToken, _) = bincode::decode_from_slice(&v, standard())?;
+        if token.uid != uid {
+            continue;
+        }
+        tokens.push(OutApiToken {
+            tid,
+            name: token.name,
+            scopes: scope_names(token.scopes).join(", "),
+            created_at: ts_to_date(token.created_at),
+            expires_at: ts_to_date(exp),
+            last_used: if token.last_used > 0 {
+                ts_to_date(token.last_used)
+            } else {
+                "-".to_owned()
+            },
+        });
+    }
+    Ok(tokens)
+}
+
+/// Remove a token together with its session
+pub(super) fn token_rm(db: &TransactionalKeyspace, tid: &str) -> Result<(), AppError> {
+    if let Some(token) = ApiToken::get(db, tid)? {
+        db.open_partition("sessions", Default::default())?
+            .remove(&token.session_id)?;
+        db.open_partition("api_tokens", Default::default())?
+            .remove(tid)?;
+    }
+    Ok(())
+}
+
+/// Page data: `api_token.html`
+#[derive(Template)]
+#[template(path = "api_token.html")]
+struct PageApiToken<'a> {
+    page_data: PageData<'a>,
+    name: String,
+    token: String,
+    scopes: String,
+}
+
+/// `POST /user/tokens` create a token, it is shown once
+///
+/// Form data: `csrf_token`, `name`, `days` and one `scope` per chosen scope
+pub(crate) async fn token_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(form): Form<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let mut csrf_token = String::new();
+    let mut name = String::new();
+    let mut days: i64 = 30;
+    let mut scopes = 0;
+    for (k, v) in form {
+        match k.as_str() {
+            "csrf_token" => csrf_token = v,
+            "name" => name = v.trim().chars().take(64).collect(),
+            "days" => days = v.parse().map_err(|_| AppError::NotFound)?,
+            "scope" => scopes |= scope_bit(&v),
+            _ => {}
+        }
+    }
+    claim.check_csrf(&csrf_token)?;
+
+    if name.is_empty() || scopes == 0 || !(1..=365).contains(&days) {
+        return Err(AppError::Custom(
+            "A token needs a name, at least one scope and 1 to 365 days".into(),
+        ));
+    }
+    if user_tokens(&DB, claim.uid)?.len() >= TOKENS_MAX {
+        return Err(AppError::Custom(format!(
+            "You can have at most {TOKENS_MAX} tokens"
+        )));
+    }
+
+    let now = Timestamp::now();
+    let exp = now.as_second() + days * 24 * 3600;
+    let tid = format!("{exp:x}_{}", nanoid!());
+    let secret = nanoid!(32);
+    let token = ApiToken {
+        uid: claim.uid,
+        name,
+        scopes,
+        hash: generate_password_hash(&secret),
+        session_id: format!("{exp:x}_{TOKEN_SESSION_MARK}{}", nanoid!()),
+        created_at: now.as_second(),
+        last_used: 0,
+    };
+    set_one_with_key(&DB, "api_tokens", &tid, &token)?;
+
+    let page_api_token = PageApiToken {
+        page_data: PageData::new("API token", &site_config, Some(claim), false),
+        name: token.name,
+        token: format!("{tid}.{secret}"),
+        scopes: scope_names(scopes).join(", "),
+    };
+    Ok(into_response(&page_api_token))
+}
+
+/// `POST /user/tokens/:tid/revoke` revoke a token
+pub(crate) async fn token_revoke(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(tid): Path<String>,
+    Form(form): Form<FormCsrf>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+
+    let token = ApiToken::get(&DB, &tid)?.ok_or(AppError::NotFound)?;
+    if token.uid != claim.uid {
+        return Err(AppError::Unauthorized);
+    }
+    token_rm(&DB, &tid)?;
+    Ok(Redirect::to("/user/setting"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_scope() {
+        let get = Method::GET;
+        let post = Method::POST;
+        let cases = [
+            (&get, "/", Some("read")),
+            (&get, "/post/1/2", Some("read")),
+            (&get, "/api/v1/inn/1", Some("read")),
+            (&post, "/post/edit/0", Some("post")),
+            (&post, "/api/v1/post/edit/0", Some("post")),
+            (&post, "/post/1/2/delete", Some("post")),
+            (&post, "/post/1/2/upvote", Some("post")),
+            (&post, "/poll/new", Some("post")),
+            (&post, "/upload", Some("post")),
+            (&post, "/post/1/2", Some("comment")),
+            (&post, "/post/1/2/3/delete", Some("comment")),
+            (&post, "/post/1/2/3/hide", Some("mod")),
+            (&post, "/solo/user/1", Some("solo")),
+            (&post, "/solo/1/delete", Some("solo")),
+            (&post, "/feed/add", Some("feed")),
+            (&post, "/feed/update", Some("read")),
+            (&get, "/mod/1", Some("mod")),
+            (&post, "/admin", Some("mod")),
+            (&post, "/role/1/2", Some("mod")),
+            (&get, "/user/setting", None),
+            (&post, "/user/tokens", None),
+            (&post, "/signout", None),
+            (&get, "/message/1", None),
+            (&get, "/inbox/1", None),
+            (&get, "/key", None),
+            (&post, "/unknown", None),
+        ];
+        for (method, path, scope) in cases {
+            assert_eq!(
+                required_scope(method, path),
+                scope.map(scope_bit),
+                "{method} {path}"
+            );
+        }
+    }
+}
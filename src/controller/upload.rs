@@ -1,9 +1,9 @@
 use super::{
-    Claim, SiteConfig, User,
+    Claim, ModAction, ModLog, ModTarget, SiteConfig, User,
     db_utils::{IterType, u8_slice_to_u32},
     filters, incr_id,
     inn::ParamsTag,
-    meta_handler::{PageData, get_referer, into_response},
+    meta_handler::{FormModAction, PageData, get_referer, into_response},
     notification::{NtType, add_notification},
     u32_to_ivec,
     user::{InnRole, Role},
@@ -11,6 +11,7 @@ use super::{
 use crate::{DB, config::CONFIG, error::AppError};
 use askama::Template;
 use axum::{
+    Form,
     extract::{Multipart, Path, Query},
     response::{IntoResponse, Redirect},
 };
@@ -32,6 +33,23 @@ pub(crate) struct UploadPicParams {
     iid: Option<u32>,
 }
 
+/// Multipart forms carry the `csrf_token` as their first field, before any file
+async fn check_multipart_csrf(claim: &Claim, multipart: &mut Multipart) -> Result<(), AppError> {
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::Custom(e.to_string()))?
+        .ok_or(AppError::InvalidCsrfToken)?;
+    if field.name() != Some("csrf_token") {
+        return Err(AppError::InvalidCsrfToken);
+    }
+    let token = field
+        .text()
+        .await
+        .map_err(|e| AppError::Custom(e.to_string()))?;
+    claim.check_csrf(&token)
+}
+
 /// `POST /mod/inn_icon` && `/user/avatar`
 pub(crate) async fn upload_pic_post(
     cookie: Option<TypedHeader<Cookie>>,
@@ -41,6 +59,7 @@ pub(crate) async fn upload_pic_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    check_multipart_csrf(&claim, &mut multipart).await?;
 
     let target;
     let fname = match params.page_type.as_str() {
@@ -147,15 +166,17 @@ pub(crate) async fn gallery(
     Ok(into_response(&page_gallery))
 }
 
-/// `GET /image/delete/:uid/:img_id`
+/// `POST /image/delete/:uid/:img_id`
 pub(crate) async fn image_delete(
     cookie: Option<TypedHeader<Cookie>>,
     Path((uid, img_id)): Path<(u32, u32)>,
     referer: Option<TypedHeader<Referer>>,
+    Form(form): Form<FormModAction>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     if claim.uid != uid && Role::from(claim.role) != Role::Admin {
         return Err(AppError::Unauthorized);
@@ -185,6 +206,14 @@ pub(crate) async fn image_delete(
 
     if uid != claim.uid {
         add_notification(&DB, uid, NtType::ImageDelete, claim.uid, img_id)?;
+        ModLog::add(
+            &DB,
+            claim.uid,
+            0,
+            ModTarget::Image(uid, img_id),
+            ModAction::Delete,
+            form.reason,
+        )?;
     }
 
     let target = if let Some(referer) = get_referer(referer) {
@@ -231,6 +260,7 @@ pub(crate) async fn upload_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    check_multipart_csrf(&claim, &mut multipart).await?;
 
     let mut imgs = Vec::with_capacity(10);
     let user_uploads = DB
@@ -1,23 +1,29 @@
 //! ## [User] sign up/in/out, user profile/list controller
 
 use super::{
-    Claim, Inn, InnType, SiteConfig, User,
+    Ban, Claim, Inn, InnType, ModAction, ModLog, ModTarget, Shadowban, SiteConfig, User, UserEmail,
+    block::{is_blocked, is_muted},
     db_utils::{
         IterType, generate_nanoid_ttl, get_count, get_count_by_prefix, get_id_by_name, get_range,
-        is_valid_name, ivec_to_u32, set_one, set_one_with_key,
+        i64_to_ivec, is_valid_name, ivec_to_u32, ks_incr_id, set_one, set_one_with_key,
+        u8_slice_to_i64,
     },
-    filters,
+    email, filters,
     fmt::{clean_html, ts_to_date},
     get_ids_by_prefix, get_one, incr_id,
-    meta_handler::{PageData, ParamsPage, ValidatedForm, into_response},
+    meta_handler::{FormCsrf, PageData, ParamsPage, ValidatedForm, into_response},
     notification::{NtType, add_notification},
+    registration::{Registration, add_pending, can_invite, is_pending, use_invite},
+    spam::train_banned,
+    token::{OutApiToken, TOKEN_SESSION_MARK, user_tokens},
     u8_slice_to_u32, u32_to_ivec,
+    webhook::{WebhookEvent, emit_user},
 };
 use crate::{DB, config::CONFIG, error::AppError};
 use ::rand::{RngExt, rng};
 use askama::Template;
 use axum::{
-    extract::{Form, Path, Query},
+    extract::{ConnectInfo, Form, Path, Query},
     http::{HeaderMap, header::SET_COOKIE},
     response::{IntoResponse, Redirect},
 };
@@ -27,17 +33,26 @@ use captcha::{
     Captcha, CaptchaName, Difficulty, Geometry,
     filters::{Cow, Noise, Wave},
 };
-use data_encoding::BASE64;
+use data_encoding::{BASE32_NOPAD, BASE64, HEXLOWER};
 use fjall::TransactionalKeyspace;
 use identicon::Identicon;
+use image::{ImageFormat, Luma};
 use jiff::Timestamp;
+use nanoid::nanoid;
+use qrcode::QrCode;
 use ring::{
-    pbkdf2,
+    digest::{Context, SHA256},
+    hmac, pbkdf2,
     rand::{self, SecureRandom},
 };
-use serde::Deserialize;
-use std::{cmp::Ordering, fmt::Display, num::NonZeroU32, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering, fmt::Display, io::Cursor, net::SocketAddr, num::NonZeroU32, sync::LazyLock,
+    time::Duration,
+};
 use tokio::time::sleep;
+use tracing::{error, warn};
+use utoipa::ToSchema;
 use validator::Validate;
 
 /// Page data: `user.html`
@@ -53,10 +68,13 @@ struct PageUser<'a> {
     user_followers_count: usize,
     has_followed: Option<bool>,
     has_recovery_code: bool,
+    is_blocked: bool,
+    is_muted: bool,
 }
 
 /// Vec data: user
-struct OutUser {
+#[derive(Serialize, ToSchema)]
+pub(super) struct OutUser {
     uid: u32,
     username: String,
     about: String,
@@ -65,6 +83,19 @@ struct OutUser {
     created_at: String,
 }
 
+impl From<User> for OutUser {
+    fn from(user: User) -> Self {
+        OutUser {
+            uid: user.uid,
+            username: user.username,
+            about: user.about,
+            role_desc: Role::from(user.role).to_string(),
+            url: user.url,
+            created_at: ts_to_date(user.created_at),
+        }
+    }
+}
+
 /// `GET /user/:uid`
 pub(crate) async fn user(
     cookie: Option<TypedHeader<Cookie>>,
@@ -79,14 +110,8 @@ pub(crate) async fn user(
     };
 
     let user: User = get_one(&DB, "users", uid)?;
-    let out_user = OutUser {
-        uid: user.uid,
-        username: user.username,
-        about: user.about,
-        role_desc: Role::from(user.role).to_string(),
-        url: user.url,
-        created_at: ts_to_date(user.created_at),
-    };
+    let has_recovery_hash = user.recovery_hash.is_some();
+    let out_user = OutUser::from(user);
     let uid_ivec = u32_to_ivec(uid);
 
     let mut user_solos_count = 0;
@@ -132,15 +157,19 @@ pub(crate) async fn user(
     let user_followers_count = get_count_by_prefix(&DB, "user_followers", &uid_ivec)?;
 
     let mut has_recovery_code = true;
+    let mut is_blocked_by_me = false;
+    let mut is_muted_by_me = false;
     let has_followed = if let Some(ref claim) = claim {
         if claim.uid != uid {
+            is_blocked_by_me = is_blocked(&DB, claim.uid, uid)?;
+            is_muted_by_me = is_muted(&DB, claim.uid, uid)?;
             let following_k = [u32_to_ivec(claim.uid), uid_ivec].concat();
             Some(
                 DB.open_partition("user_following", Default::default())?
                     .contains_key(following_k)?,
             )
         } else {
-            has_recovery_code = user.recovery_hash.is_some();
+            has_recovery_code = has_recovery_hash;
             None
         }
     } else {
@@ -165,19 +194,23 @@ pub(crate) async fn user(
         user_followers_count,
         has_followed,
         has_recovery_code,
+        is_blocked: is_blocked_by_me,
+        is_muted: is_muted_by_me,
     };
 
     Ok(into_response(&page_user))
 }
 
-/// `GET /user/:uid/follow` follow user
+/// `POST /user/:uid/follow` follow user
 pub(crate) async fn user_follow(
     cookie: Option<TypedHeader<Cookie>>,
     Path(u): Path<String>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let uid = match u.parse::<u32>() {
         Ok(uid) => uid,
@@ -224,6 +257,10 @@ struct OutUserList {
     about: String,
     role: u8,
     role_desc: String,
+    /// expiry date and reason of a time-limited ban
+    ban: Option<(String, String)>,
+    /// date and reason of a shadowban, only for admins
+    shadowban: Option<(String, String)>,
 }
 
 #[repr(u8)]
@@ -308,6 +345,8 @@ impl OutUserList {
             about,
             role,
             role_desc,
+            ban: None,
+            shadowban: None,
         }
     }
 
@@ -398,7 +437,6 @@ pub(crate) async fn user_list(
     let page_params = ParamsPage { anchor, n, is_desc };
 
     let mut index;
-    let count;
     let info;
 
     let mut is_admin = false;
@@ -438,8 +476,27 @@ pub(crate) async fn user_list(
             }
             _ => return Ok(Redirect::to("/user/list").into_response()),
         }
+    } else if let Some(tree) = match params.filter.as_deref() {
+        Some("blocked") => Some("user_blocks"),
+        Some("muted") => Some("user_mutes"),
+        _ => None,
+    } {
+        // only the user sees their own lists
+        let claim = claim.as_ref().ok_or(AppError::NonLogin)?;
+        info = (claim.uid, "all".to_owned(), false);
+        index = get_ids_by_prefix(&DB, tree, u32_to_ivec(claim.uid), Some(&page_params))?;
+        users = OutUserList::get_from_uids(&DB, index, n)?;
+    } else if params.filter.as_deref() == Some("shadowbanned") {
+        if !is_admin {
+            return Err(AppError::Unauthorized);
+        }
+        info = (0, "all".to_owned(), false);
+        index = get_ids_by_prefix(&DB, "shadowbans", b"", Some(&page_params))?;
+        users = OutUserList::get_from_uids(&DB, index, n)?;
     } else {
         info = (0, "all".to_owned(), false);
+        // deleted accounts are left out, a page may come up short
+        let deleted_ks = DB.open_partition("deleted_users", Default::default())?;
         if let Some(role) = params.role {
             let keyspace = DB.open_partition("users", Default::default())?;
             let iter = keyspace.inner().iter();
@@ -454,9 +511,9 @@ pub(crate) async fn user_list(
                     continue;
                 }
 
-                let (_, v) = i?;
+                let (k, v) = i?;
                 let (user, _): (User, usize) = bincode::decode_from_slice(&v, standard())?;
-                if user.role == role {
+                if user.role == role && !deleted_ks.contains_key(&k)? {
                     let user_desc = Role::from(user.role).to_string();
                     let out_user_list =
                         OutUserList::new(user.uid, user.username, user.about, role, user_desc);
@@ -468,16 +525,36 @@ pub(crate) async fn user_list(
                 }
             }
         } else {
-            count = get_count(&DB, "default", "users_count")?;
+            let count = get_count(&DB, "default", "users_count")?;
             let (start, end) = get_range(count, &page_params);
             index = (start..=end).map(|x| x as u32).collect();
             if is_desc {
                 index.reverse();
             }
+            index.retain(|uid| {
+                !deleted_ks
+                    .contains_key(u32_to_ivec(*uid))
+                    .unwrap_or_default()
+            });
             users = OutUserList::get_from_uids(&DB, index, n)?;
         }
     }
 
+    if is_admin && matches!(params.filter.as_deref(), None | Some("inn")) {
+        for user in users.iter_mut() {
+            if let Some(ban) = Ban::get(&DB, info.0, user.uid)? {
+                user.ban = Some((ts_to_date(ban.expires_at), ban.reason));
+            }
+        }
+    }
+    if is_admin && matches!(params.filter.as_deref(), None | Some("shadowbanned")) {
+        for user in users.iter_mut() {
+            if let Some(shadowban) = Shadowban::get(&DB, user.uid)? {
+                user.shadowban = Some((ts_to_date(shadowban.created_at), shadowban.reason));
+            }
+        }
+    }
+
     let has_unread = if let Some(ref claim) = claim {
         User::has_unread(&DB, claim.uid)?
     } else {
@@ -502,7 +579,11 @@ pub(crate) async fn user_list(
 /// Form data: `/role/:id/:uid`
 #[derive(Deserialize)]
 pub(crate) struct FormRole {
+    csrf_token: String,
     role: String,
+    reason: Option<String>,
+    /// ban duration in days for `Banned`, `Rejected` and `Limited`, 0 means permanent
+    days: Option<u32>,
 }
 
 /// `POST /role/:id/:uid`
@@ -514,6 +595,7 @@ pub(crate) async fn role_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
     let target;
     match id.cmp(&0) {
@@ -560,28 +642,31 @@ pub(crate) async fn role_post(
                 _ => return Err(AppError::Unauthorized),
             };
 
-            if old_inn_role != Some(inn_role.into()) {
-                DB.open_partition("inn_users", Default::default())?
-                    .insert(&inn_users_k, [inn_role])?;
-
-                let user_inns_k = [u32_to_ivec(uid), u32_to_ivec(id)].concat();
-                if inn_role >= 3 {
-                    DB.open_partition("user_inns", Default::default())?
-                        .insert(&user_inns_k, [])?;
-                } else {
-                    DB.open_partition("user_inns", Default::default())?
-                        .remove(&user_inns_k)?;
-                }
-
-                if inn_role >= 7 {
-                    DB.open_partition("mod_inns", Default::default())?
-                        .insert(&user_inns_k, [])?;
-                } else {
-                    DB.open_partition("mod_inns", Default::default())?
-                        .remove(&user_inns_k)?;
-                }
+            let old_inn_role = old_inn_role.map(|role| role as u8);
+            let prev_role = old_inn_role.filter(|role| *role > inn_role);
+            let is_ban = inn_role == 2 || inn_role == 3;
+            Ban::update(
+                &DB,
+                id,
+                uid,
+                is_ban,
+                prev_role,
+                form.days,
+                form.reason.as_deref(),
+            )?;
+
+            if old_inn_role != Some(inn_role) {
+                set_inn_role(&DB, id, uid, Some(inn_role))?;
 
                 add_notification(&DB, uid, NtType::InnNotification, inn_role as u32, id)?;
+                ModLog::add(
+                    &DB,
+                    claim.uid,
+                    id,
+                    ModTarget::User(uid),
+                    ModAction::Role(form.role),
+                    form.reason,
+                )?;
             }
 
             target = format!("/user/list?filter=inn&id={id}");
@@ -600,12 +685,34 @@ pub(crate) async fn role_post(
                 _ => return Err(AppError::Unauthorized),
             };
 
+            let prev_role = Some(user.role).filter(|role| *role > 0);
+            Ban::update(
+                &DB,
+                0,
+                uid,
+                role == 0,
+                prev_role,
+                form.days,
+                form.reason.as_deref(),
+            )?;
+
             if user.role != role {
                 user.role = role;
                 set_one(&DB, "users", uid, &user)?;
                 Claim::update_role(&DB, uid)?;
+                if role == 0 {
+                    train_banned(&DB, uid)?;
+                }
 
                 add_notification(&DB, uid, NtType::SiteNotification, role as u32, 0)?;
+                ModLog::add(
+                    &DB,
+                    claim.uid,
+                    0,
+                    ModTarget::User(uid),
+                    ModAction::Role(form.role),
+                    form.reason,
+                )?;
             }
             target = "/user/list".to_string();
         }
@@ -615,9 +722,148 @@ pub(crate) async fn role_post(
     Ok(Redirect::to(&target))
 }
 
+/// Store the inn role of a user and keep `user_inns` and `mod_inns` in sync.
+/// `None` removes the user from the inn.
+fn set_inn_role(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    uid: u32,
+    inn_role: Option<u8>,
+) -> Result<(), AppError> {
+    let inn_users_k = [u32_to_ivec(iid), u32_to_ivec(uid)].concat();
+    let user_inns_k = [u32_to_ivec(uid), u32_to_ivec(iid)].concat();
+    let inn_role = inn_role.unwrap_or_default();
+
+    if inn_role > 0 {
+        db.open_partition("inn_users", Default::default())?
+            .insert(&inn_users_k, [inn_role])?;
+    } else {
+        db.open_partition("inn_users", Default::default())?
+            .remove(&inn_users_k)?;
+    }
+
+    if inn_role >= 3 {
+        db.open_partition("user_inns", Default::default())?
+            .insert(&user_inns_k, [])?;
+    } else {
+        db.open_partition("user_inns", Default::default())?
+            .remove(&user_inns_k)?;
+    }
+
+    if inn_role >= 7 {
+        db.open_partition("mod_inns", Default::default())?
+            .insert(&user_inns_k, [])?;
+    } else {
+        db.open_partition("mod_inns", Default::default())?
+            .remove(&user_inns_k)?;
+    }
+
+    Ok(())
+}
+
+impl Ban {
+    /// `id` is 0 for site bans, otherwise it is the iid.
+    pub(super) fn get(
+        db: &TransactionalKeyspace,
+        id: u32,
+        uid: u32,
+    ) -> Result<Option<Self>, AppError> {
+        let k = [u32_to_ivec(id), u32_to_ivec(uid)].concat();
+        let Some(v) = db.open_partition("bans", Default::default())?.get(k)? else {
+            return Ok(None);
+        };
+        let (ban, _): (Ban, _) = bincode::decode_from_slice(&v, standard())?;
+        Ok(Some(ban))
+    }
+
+    /// Set the expiry of a ban. A permanent ban or any other role clears it.
+    fn update(
+        db: &TransactionalKeyspace,
+        id: u32,
+        uid: u32,
+        is_ban: bool,
+        prev_role: Option<u8>,
+        days: Option<u32>,
+        reason: Option<&str>,
+    ) -> Result<(), AppError> {
+        let k = [u32_to_ivec(id), u32_to_ivec(uid)].concat();
+        let days = days.unwrap_or_default();
+        if !is_ban || days == 0 {
+            db.open_partition("bans", Default::default())?.remove(k)?;
+            return Ok(());
+        }
+
+        // extending a ban keeps the role from before the first one
+        let prev_role = match Ban::get(db, id, uid)? {
+            Some(ban) => ban.prev_role,
+            None => prev_role,
+        };
+        let now = Timestamp::now().as_second();
+        let ban = Ban {
+            prev_role,
+            reason: reason
+                .map(|r| r.trim().chars().take(256).collect())
+                .unwrap_or_default(),
+            expires_at: now + i64::from(days) * 24 * 3600,
+            created_at: now,
+        };
+        set_one_with_key(db, "bans", k, &ban)
+    }
+}
+
+/// Lift expired bans and notify the users.
+pub async fn cron_lift_bans(db: &TransactionalKeyspace) -> Result<(), AppError> {
+    let now = Timestamp::now().as_second();
+    let bans = db.open_partition("bans", Default::default())?;
+    for i in bans.inner().iter() {
+        let (k, v) = i?;
+        let ban: Ban = match bincode::decode_from_slice(&v, standard()) {
+            Ok((ban, _)) => ban,
+            Err(e) => {
+                error!("invalid ban {k:?}: {e}");
+                continue;
+            }
+        };
+        if ban.expires_at > now {
+            continue;
+        }
+
+        // one broken ban must not keep the others from being lifted
+        if let Err(e) = lift_ban(db, &k, &ban) {
+            error!("lift ban {k:?} failed: {e}");
+            continue;
+        }
+        bans.remove(k)?;
+    }
+    Ok(())
+}
+
+fn lift_ban(db: &TransactionalKeyspace, k: &[u8], ban: &Ban) -> Result<(), AppError> {
+    let id = u8_slice_to_u32(&k[0..4]);
+    let uid = u8_slice_to_u32(&k[4..8]);
+    if id == 0 {
+        let mut user: User = get_one(db, "users", uid)?;
+        if Role::from(user.role) == Role::Banned {
+            user.role = ban.prev_role.unwrap_or(Role::Standard as u8);
+            set_one(db, "users", uid, &user)?;
+            Claim::update_role(db, uid)?;
+            add_notification(db, uid, NtType::SiteNotification, user.role as u32, 0)?;
+        }
+    } else if matches!(
+        InnRole::get(db, id, uid)?,
+        Some(InnRole::Rejected | InnRole::Limited)
+    ) {
+        set_inn_role(db, id, uid, ban.prev_role)?;
+        let role = ban.prev_role.unwrap_or_default();
+        add_notification(db, uid, NtType::InnNotification, role as u32, id)?;
+    }
+    Ok(())
+}
+
 /// Form data: `/user/setting`
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormUser {
+    csrf_token: String,
     #[validate(length(min = 1, max = 32))]
     username: String,
     #[validate(length(max = 1024))]
@@ -641,6 +887,14 @@ struct PageUserSetting<'a> {
     about: String,
     sessions: Vec<String>,
     home_page: u8,
+    has_totp: bool,
+    totp_secret: String,
+    totp_qr: String,
+    can_invite: bool,
+    email_enabled: bool,
+    email: Option<UserEmail>,
+    digest_days: u8,
+    tokens: Vec<OutApiToken>,
 }
 
 /// `GET /user/setting`
@@ -665,12 +919,28 @@ pub(crate) async fn user_setting(
                 .remove(k)?;
             continue;
         };
-        if claim2.uid == claim.uid {
+        if claim2.uid == claim.uid && !claim2.is_token_session() {
             sessions.push(claim2.session_id);
         }
     }
 
+    let has_totp = DB
+        .open_partition("user_totp", Default::default())?
+        .contains_key(u32_to_ivec(claim.uid))?;
+    let mut totp_secret = String::new();
+    let mut totp_qr = String::new();
+    if !has_totp {
+        totp_secret = BASE32_NOPAD.encode(&generate_totp_secret());
+        let uri = totp_uri(&site_config.site_name, &user.username, &totp_secret);
+        totp_qr = qr_code_base64(&uri)?;
+    }
+
     let has_unread = User::has_unread(&DB, claim.uid)?;
+    let can_invite = Registration::from(site_config.registration) == Registration::Invite
+        && can_invite(&claim, &site_config);
+    let email = UserEmail::get(&DB, claim.uid)?;
+    let digest_days = email.as_ref().map(|e| e.digest_days).unwrap_or_default();
+    let tokens = user_tokens(&DB, claim.uid)?;
     let page_user_setting = PageUserSetting {
         uid: claim.uid,
         page_data: PageData::new("setting", &site_config, Some(claim), has_unread),
@@ -679,6 +949,14 @@ pub(crate) async fn user_setting(
         url: user.url,
         sessions,
         home_page: user.home_page,
+        has_totp,
+        totp_secret,
+        totp_qr,
+        can_invite,
+        email_enabled: email::is_enabled(),
+        email,
+        digest_days,
+        tokens,
     };
 
     Ok(into_response(&page_user_setting))
@@ -691,6 +969,7 @@ struct PageReset<'a> {
     captcha_id: String,
     captcha_image: String,
     page_data: PageData<'a>,
+    email_enabled: bool,
 }
 
 /// `GET /user/reset`
@@ -714,6 +993,7 @@ pub(crate) async fn reset(
         page_data,
         captcha_id: captcha_session.id,
         captcha_image: captcha_session.image,
+        email_enabled: email::is_enabled(),
     };
 
     Ok(into_response(&page_reset))
@@ -775,17 +1055,25 @@ pub(crate) async fn reset_post(
     Err(AppError::NotFound)
 }
 
-/// `GET /user/remove/:session_id`
+/// `POST /user/remove/:session_id`
 pub(crate) async fn remove_session(
     cookie: Option<TypedHeader<Cookie>>,
     Path(session_id): Path<String>,
+    Form(form): Form<FormCsrf>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
-    Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
 
-    DB.open_partition("sessions", Default::default())?
-        .remove(session_id)?;
+    let tree = DB.open_partition("sessions", Default::default())?;
+    if let Some(v) = tree.get(&session_id)? {
+        let (session, _): (Claim, usize) = bincode::decode_from_slice(&v, standard())?;
+        if session.uid != claim.uid {
+            return Err(AppError::Unauthorized);
+        }
+        tree.remove(session_id)?;
+    }
     Ok(Redirect::to("/user/setting"))
 }
 
@@ -797,6 +1085,7 @@ pub(crate) async fn user_setting_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let mut claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
     let mut user: User = get_one(&DB, "users", claim.uid)?;
 
     let username = clean_html(&input.username);
@@ -841,6 +1130,7 @@ pub(crate) async fn user_setting_post(
 /// Form data: `/user/setting`
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormPassword {
+    csrf_token: String,
     #[validate(skip)]
     old_password: String,
     password: String,
@@ -859,19 +1149,80 @@ pub(crate) async fn user_password_post(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
     let mut user: User = get_one(&DB, "users", claim.uid)?;
 
     if check_password(&input.old_password, &user.password_hash) {
         let password_hash = generate_password_hash(&input.password);
         user.password_hash = password_hash;
         set_one(&DB, "users", claim.uid, &user)?;
-        Ok(Redirect::to("/signout"))
+        Ok(sign_out(&claim.session_id)?)
     } else {
         sleep(Duration::from_secs(1)).await;
         Err(AppError::WrongPassword)
     }
 }
 
+/// Form data: `/user/totp`
+#[derive(Deserialize)]
+pub(crate) struct FormTotp {
+    csrf_token: String,
+    secret: String,
+    code: String,
+}
+
+/// `POST /user/totp` enable two-factor authentication
+pub(crate) async fn user_totp_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(input): Form<FormTotp>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    let secret = BASE32_NOPAD
+        .decode(input.secret.as_bytes())
+        .map_err(|_| AppError::Custom("Invalid secret".to_string()))?;
+    let Some(step) = check_totp_code(&secret, input.code.trim(), i64::MIN) else {
+        sleep(Duration::from_secs(1)).await;
+        return Err(AppError::WrongPassword);
+    };
+
+    DB.open_partition("user_totp", Default::default())?
+        .insert(u32_to_ivec(claim.uid), [secret, i64_to_ivec(step)].concat())?;
+
+    Ok(Redirect::to("/user/setting"))
+}
+
+/// Form data: `/user/totp/disable`
+#[derive(Deserialize)]
+pub(crate) struct FormTotpDisable {
+    csrf_token: String,
+    code: String,
+}
+
+/// `POST /user/totp/disable` disable two-factor authentication
+pub(crate) async fn user_totp_disable_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(input): Form<FormTotpDisable>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
+
+    if !verify_totp(&DB, claim.uid, input.code.trim())? {
+        sleep(Duration::from_secs(1)).await;
+        return Err(AppError::WrongPassword);
+    }
+
+    DB.open_partition("user_totp", Default::default())?
+        .remove(u32_to_ivec(claim.uid))?;
+
+    Ok(Redirect::to("/user/setting"))
+}
+
 pub(crate) const COOKIE_NAME: &str = "id";
 
 /// Form data: `/signin`
@@ -923,8 +1274,28 @@ pub(crate) async fn signin(
     Ok(into_response(&page_signin))
 }
 
+/// Page data: `signin_totp.html`
+#[derive(Template)]
+#[template(path = "signin_totp.html")]
+struct PageSigninTotp<'a> {
+    page_data: PageData<'a>,
+    ticket: String,
+}
+
 /// `POST /signin`
-pub(crate) async fn signin_post(Form(input): Form<FormSignin>) -> impl IntoResponse {
+///
+/// If the user has enabled two-factor authentication, no session is issued here. A short lived
+/// ticket is stored in `totp_tickets` instead, and the sign in is finished by [signin_totp_post].
+pub(crate) async fn signin_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req_headers: HeaderMap,
+    Form(input): Form<FormSignin>,
+) -> impl IntoResponse {
+    let ip = client_ip(&req_headers, addr);
+    if login_failures(&DB, "ip", &ip)? >= THROTTLE_IP_LIMIT {
+        return Err(AppError::WriteInterval);
+    }
+
     let site_config = SiteConfig::get(&DB)?;
     if site_config.login_captcha {
         let captcha_id = input.captcha_id.ok_or(AppError::CaptchaError)?;
@@ -939,16 +1310,47 @@ pub(crate) async fn signin_post(Form(input): Form<FormSignin>) -> impl IntoRespo
     }
 
     let uid = match input.username.parse::<u32>() {
-        Ok(uid) => uid,
-        Err(_) => {
-            get_id_by_name(&DB, "usernames", &input.username)?.ok_or(AppError::WrongPassword)?
-        }
+        Ok(uid) => Some(uid),
+        Err(_) => get_id_by_name(&DB, "usernames", &input.username)?,
     };
-    let user: User = get_one(&DB, "users", uid)?;
+    let user = uid.and_then(|uid| get_one::<User>(&DB, "users", uid).ok());
+    // unknown names are throttled and checked like accounts, so they can not be told apart
+    let name = match user {
+        Some(ref user) => user.uid.to_string(),
+        None => input.username.trim().to_lowercase(),
+    };
+    if login_failures(&DB, "account", &account_key(&name, &ip))? >= THROTTLE_ACCOUNT_LIMIT {
+        return Err(AppError::WriteInterval);
+    }
+    let Some(user) = user else {
+        check_password(&input.password, &DUMMY_HASH);
+        login_failed(&DB, &ip, &name, None).await?;
+        return Err(AppError::WrongPassword);
+    };
+    let uid = user.uid;
+
     if check_password(&input.password, &user.password_hash) {
         if site_config.read_only && Role::from(user.role) != Role::Admin {
             return Err(AppError::ReadOnly);
         }
+        if is_pending(&DB, uid)? {
+            return Err(AppError::AwaitingApproval);
+        }
+
+        if DB
+            .open_partition("user_totp", Default::default())?
+            .contains_key(u32_to_ivec(uid))?
+        {
+            let ticket = generate_nanoid_ttl(300);
+            DB.open_partition("totp_tickets", Default::default())?
+                .insert(
+                    &ticket,
+                    [&u32_to_ivec(uid)[..], input.remember.as_bytes()].concat(),
+                )?;
+            let page_data = PageData::new("Sign in", &site_config, None, false);
+            let page_signin_totp = PageSigninTotp { page_data, ticket };
+            return Ok(into_response(&page_signin_totp));
+        }
 
         let mut headers = HeaderMap::new();
         let cookie = Claim::generate_cookie(&DB, user, &input.remember)?;
@@ -958,13 +1360,71 @@ pub(crate) async fn signin_post(Form(input): Form<FormSignin>) -> impl IntoRespo
             return Err(AppError::WrongPassword);
         }
 
-        Ok((headers, Redirect::to("/")))
+        Ok((headers, Redirect::to("/")).into_response())
     } else {
-        sleep(Duration::from_secs(1)).await;
+        login_failed(&DB, &ip, &name, Some(uid)).await?;
         Err(AppError::WrongPassword)
     }
 }
 
+/// Form data: `/signin/totp`
+#[derive(Deserialize)]
+pub(crate) struct FormSigninTotp {
+    ticket: String,
+    code: String,
+}
+
+/// `POST /signin/totp` second step of sign in
+///
+/// Accepts either a TOTP code or the recovery code. A recovery code can only be used once
+/// in this way, the user has to generate a new one afterwards.
+pub(crate) async fn signin_totp_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req_headers: HeaderMap,
+    Form(input): Form<FormSigninTotp>,
+) -> Result<impl IntoResponse, AppError> {
+    let ip = client_ip(&req_headers, addr);
+    if login_failures(&DB, "ip", &ip)? >= THROTTLE_IP_LIMIT {
+        return Err(AppError::WriteInterval);
+    }
+
+    let v = DB
+        .open_partition("totp_tickets", Default::default())?
+        .take(&input.ticket)?
+        .ok_or(AppError::NonLogin)?;
+    let timestamp = input
+        .ticket
+        .split_once('_')
+        .and_then(|(timestamp, _)| i64::from_str_radix(timestamp, 16).ok())
+        .ok_or(AppError::NonLogin)?;
+    if timestamp < Timestamp::now().as_second() {
+        return Err(AppError::NonLogin);
+    }
+
+    let uid = u8_slice_to_u32(&v[0..4]);
+    let remember = String::from_utf8_lossy(&v[4..]).to_string();
+    let mut user: User = get_one(&DB, "users", uid)?;
+    let code = input.code.trim();
+
+    if !verify_totp(&DB, uid, code)? {
+        match user.recovery_hash {
+            Some(ref recovery_hash) if check_password(code, recovery_hash) => {
+                user.recovery_hash = None;
+                set_one(&DB, "users", uid, &user)?;
+            }
+            _ => {
+                login_failed(&DB, &ip, &uid.to_string(), Some(uid)).await?;
+                return Err(AppError::WrongPassword);
+            }
+        }
+    }
+
+    let mut headers = HeaderMap::new();
+    let cookie = Claim::generate_cookie(&DB, user, &remember)?;
+    headers.insert(SET_COOKIE, cookie.parse().unwrap());
+    Ok((headers, Redirect::to("/")))
+}
+
 /// Form data: `/signup`
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormSignup {
@@ -975,6 +1435,7 @@ pub(crate) struct FormSignup {
     password2: String,
     captcha_id: String,
     captcha_value: String,
+    invite: Option<String>,
 }
 
 /// Page data: `signup.html`
@@ -985,10 +1446,20 @@ struct PageSignup<'a> {
     captcha_image: String,
     page_data: PageData<'a>,
     tos_link: &'a str,
+    registration: u8,
+    invite: String,
+}
+
+/// url params: `signup.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsSignup {
+    invite: Option<String>,
 }
 
 /// `GET /signup`
-pub(crate) async fn signup() -> Result<impl IntoResponse, AppError> {
+pub(crate) async fn signup(
+    Query(params): Query<ParamsSignup>,
+) -> Result<impl IntoResponse, AppError> {
     let site_config = SiteConfig::get(&DB)?;
 
     if site_config.read_only {
@@ -1004,6 +1475,8 @@ pub(crate) async fn signup() -> Result<impl IntoResponse, AppError> {
         captcha_image: captcha_session.image,
         page_data,
         tos_link: &site_config.tos_link,
+        registration: site_config.registration,
+        invite: params.invite.unwrap_or_default(),
     };
     Ok(into_response(&page_signup))
 }
@@ -1028,6 +1501,9 @@ fn captcha_digits() -> Captcha {
     c
 }
 
+/// how long the cookie handed out to accounts waiting for approval lasts
+const PENDING_SESSION_EXPIRY: &str = "2weeks";
+
 /// `POST /signup`
 pub(crate) async fn signup_post(
     ValidatedForm(input): ValidatedForm<FormSignup>,
@@ -1057,6 +1533,19 @@ pub(crate) async fn signup_post(
         return Err(AppError::NameExists);
     }
 
+    // the first user is the admin, who sets up registration
+    let site_config = SiteConfig::get(&DB)?;
+    let is_first = get_count(&DB, "default", "users_count")? == 0;
+    let registration = if is_first {
+        Registration::Open
+    } else {
+        Registration::from(site_config.registration)
+    };
+    let invite = input.invite.as_deref().map(str::trim).unwrap_or_default();
+    if registration == Registration::Invite {
+        use_invite(&DB, invite)?;
+    }
+
     let password_hash = generate_password_hash(&input.password);
     let uid = incr_id(&DB, "users_count")?;
 
@@ -1080,23 +1569,30 @@ pub(crate) async fn signup_post(
 
     set_one(&DB, "users", uid, &user)?;
     usernames_tree.insert(username_key, u32_to_ivec(uid))?;
+    emit_user(&DB, 0, WebhookEvent::UserSignup, uid, &user.username);
 
-    let cookie = Claim::generate_cookie(&DB, user, "4h")?;
     let mut headers = HeaderMap::new();
+    if registration == Registration::Approval {
+        // the session is not valid before admins approve the account
+        let seconds = expire_seconds(PENDING_SESSION_EXPIRY);
+        let session_id = generate_nanoid_ttl(seconds);
+        add_pending(&DB, uid, &session_id)?;
+        let cookie = format!(
+            "{COOKIE_NAME}={session_id}; SameSite=Strict; Path=/; Secure; HttpOnly; Max-Age={seconds}"
+        );
+        headers.insert(SET_COOKIE, cookie.parse().unwrap());
+        return Ok((headers, AppError::AwaitingApproval).into_response());
+    }
+
+    let cookie = Claim::generate_cookie(&DB, user, "4h")?;
     headers.insert(SET_COOKIE, cookie.parse().unwrap());
-    Ok((headers, Redirect::to("/")))
+    Ok((headers, Redirect::to("/")).into_response())
 }
 
-/// `GET /signout`
-pub(crate) async fn signout(
-    cookie: Option<TypedHeader<Cookie>>,
-) -> Result<impl IntoResponse, AppError> {
-    if let Some(cookie) = cookie
-        && let Some(session) = cookie.get(COOKIE_NAME)
-    {
-        DB.open_partition("sessions", Default::default())?
-            .remove(session)?;
-    }
+/// Remove the session and clear the cookie
+fn sign_out(session_id: &str) -> Result<(HeaderMap, Redirect), AppError> {
+    DB.open_partition("sessions", Default::default())?
+        .remove(session_id)?;
 
     let cookie = format!(
         "{COOKIE_NAME}=deleted; SameSite=Strict; Path=/; Secure; HttpOnly; expires=Thu, 01 Jan 1970 00:00:00 GMT"
@@ -1106,6 +1602,18 @@ pub(crate) async fn signout(
     Ok((headers, Redirect::to("/")))
 }
 
+/// `POST /signout`
+pub(crate) async fn signout(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(form): Form<FormCsrf>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+    sign_out(&claim.session_id)
+}
+
 #[derive(Template)]
 #[template(path = "show_recovery.html")]
 struct PageShowRecovery<'a> {
@@ -1116,6 +1624,7 @@ struct PageShowRecovery<'a> {
 /// Form data: `/user/recovery`
 #[derive(Deserialize, Validate)]
 pub(crate) struct FormRecoverySet {
+    csrf_token: String,
     #[validate(length(min = 7))]
     password: String,
 }
@@ -1128,6 +1637,7 @@ pub(crate) async fn user_recovery_code(
     let cookie = cookie.ok_or(AppError::NonLogin)?;
     let site_config = SiteConfig::get(&DB)?;
     let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&input.csrf_token)?;
     let mut user: User = get_one(&DB, "users", claim.uid)?;
 
     if check_password(&input.password, &user.password_hash) {
@@ -1166,7 +1676,7 @@ fn gen_password() -> String {
 /// generate salt
 ///
 /// <https://rust-lang-nursery.github.io/rust-cookbook/cryptography/encryption.html>
-fn generate_salt() -> [u8; 64] {
+pub(super) fn generate_salt() -> [u8; 64] {
     let rng = rand::SystemRandom::new();
     let mut salt = [0_u8; 64];
     rng.fill(&mut salt).unwrap();
@@ -1176,7 +1686,7 @@ fn generate_salt() -> [u8; 64] {
 const N_ITER: Option<std::num::NonZeroU32> = NonZeroU32::new(100_000);
 
 /// return hashed password and salt
-fn generate_password_hash(password: &str) -> String {
+pub(super) fn generate_password_hash(password: &str) -> String {
     let n = N_ITER.unwrap();
     let salt = generate_salt();
     let mut pbkdf2_hash = [0_u8; 64];
@@ -1192,7 +1702,7 @@ fn generate_password_hash(password: &str) -> String {
 }
 
 /// check password
-fn check_password(password: &str, password_hash: &str) -> bool {
+pub(super) fn check_password(password: &str, password_hash: &str) -> bool {
     let n = N_ITER.unwrap();
     let decoded = BASE64.decode(password_hash.as_bytes()).unwrap();
 
@@ -1206,15 +1716,169 @@ fn check_password(password: &str, password_hash: &str) -> bool {
     .is_ok()
 }
 
+const TOTP_STEP: i64 = 30;
+
+/// generate a 160 bits TOTP secret, as recommended by RFC 4226
+fn generate_totp_secret() -> [u8; 20] {
+    let rng = rand::SystemRandom::new();
+    let mut secret = [0_u8; 20];
+    rng.fill(&mut secret).unwrap();
+    secret
+}
+
+/// [RFC 6238](https://www.rfc-editor.org/rfc/rfc6238) TOTP with HMAC-SHA1 and 6 digits,
+/// which is what most authenticator apps support.
+fn totp_code(secret: &[u8], step: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!("{:06}", bin % 1_000_000)
+}
+
+/// Check the code against the previous, current and next time step. Steps not greater than
+/// `last_step` are rejected, so a code can not be replayed. Return the matched step.
+fn check_totp_code(secret: &[u8], code: &str, last_step: i64) -> Option<i64> {
+    if code.len() != 6 {
+        return None;
+    }
+    let now = Timestamp::now().as_second() / TOTP_STEP;
+    (now - 1..=now + 1).find(|step| *step > last_step && totp_code(secret, *step) == code)
+}
+
+/// verify the TOTP code of a user who has enabled two-factor authentication
+fn verify_totp(db: &TransactionalKeyspace, uid: u32, code: &str) -> Result<bool, AppError> {
+    let tree = db.open_partition("user_totp", Default::default())?;
+    let Some(v) = tree.get(u32_to_ivec(uid))? else {
+        return Ok(false);
+    };
+    let (secret, last_step) = v.split_at(v.len() - 8);
+    if let Some(step) = check_totp_code(secret, code, u8_slice_to_i64(last_step)) {
+        tree.insert(u32_to_ivec(uid), [secret, &i64_to_ivec(step)].concat())?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// `otpauth://` uri for authenticator apps
+///
+/// <https://github.com/google/google-authenticator/wiki/Key-Uri-Format>
+fn totp_uri(issuer: &str, username: &str, secret: &str) -> String {
+    let issuer = percent_encode(issuer);
+    let username = percent_encode(username);
+    format!("otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}")
+}
+
+fn percent_encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for b in input.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            output.push(b as char);
+        } else {
+            output.push_str(&format!("%{b:02X}"));
+        }
+    }
+    output
+}
+
+/// render a QR code as base64 png, shown the same way as the captcha image
+fn qr_code_base64(data: &str) -> Result<String, AppError> {
+    let code = QrCode::new(data.as_bytes()).map_err(|e| AppError::Custom(e.to_string()))?;
+    let image = code.render::<Luma<u8>>().min_dimensions(200, 200).build();
+    let mut buf = Cursor::new(Vec::new());
+    image.write_to(&mut buf, ImageFormat::Png)?;
+    Ok(BASE64.encode(buf.get_ref()))
+}
+
+/// Failed sign in attempts are counted in buckets of 5 minutes, and summed over a sliding
+/// window of one hour.
+const THROTTLE_BUCKET: i64 = 300;
+const THROTTLE_WINDOW: i64 = 3600;
+/// An ip is locked out of signing in after so many failures in the window.
+const THROTTLE_IP_LIMIT: u32 = 30;
+/// An account is locked out of signing in from an ip after so many failures in the window.
+/// Others can still sign in to it, so it can not be locked by anyone. The owner is notified
+/// when the account reaches this many failures from all ips.
+const THROTTLE_ACCOUNT_LIMIT: u32 = 10;
+
+/// checked against the password of unknown names, so they take as long as accounts
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| generate_password_hash(&nanoid!()));
+
+/// the id of the failures of an account, or an unknown name, from an ip
+fn account_key(account: &str, ip: &str) -> String {
+    format!("{account}_{ip}")
+}
+
+/// client ip, from the header set by reverse proxy if `real_ip_header` is configured
+fn client_ip(headers: &HeaderMap, addr: SocketAddr) -> String {
+    if let Some(ref name) = CONFIG.real_ip_header
+        && let Some(value) = headers.get(name.as_str()).and_then(|v| v.to_str().ok())
+        && let Some(ip) = value.split(',').next()
+        && !ip.trim().is_empty()
+    {
+        return ip.trim().to_owned();
+    }
+    addr.ip().to_string()
+}
+
+/// `{expire:x}_{scope}_{id}`, so the counters can be removed by [clear_invalid](super::db_utils::clear_invalid)
+fn throttle_key(bucket: i64, scope: &str, id: &str) -> String {
+    let expire = bucket * THROTTLE_BUCKET + THROTTLE_WINDOW;
+    format!("{expire:x}_{scope}_{id}")
+}
+
+/// count failed sign in attempts in the sliding window
+fn login_failures(db: &TransactionalKeyspace, scope: &str, id: &str) -> Result<u32, AppError> {
+    let tree = db.open_partition("login_failures", Default::default())?;
+    let now = Timestamp::now().as_second() / THROTTLE_BUCKET;
+    let mut count = 0;
+    for bucket in (now - THROTTLE_WINDOW / THROTTLE_BUCKET + 1)..=now {
+        if let Some(v) = tree.get(throttle_key(bucket, scope, id))? {
+            count += ivec_to_u32(&v);
+        }
+    }
+    Ok(count)
+}
+
+/// Record a failed sign in attempt of `name`, the uid or an unknown name, and sleep. The delay is
+/// doubled every three failures of the ip or the account from all ips, up to 16 seconds.
+/// When an account reaches [THROTTLE_ACCOUNT_LIMIT], the owner is notified.
+async fn login_failed(
+    db: &TransactionalKeyspace,
+    ip: &str,
+    name: &str,
+    uid: Option<u32>,
+) -> Result<(), AppError> {
+    let tree = db.open_partition("login_failures", Default::default())?;
+    let now = Timestamp::now().as_second() / THROTTLE_BUCKET;
+
+    ks_incr_id(&tree, throttle_key(now, "ip", ip))?;
+    ks_incr_id(&tree, throttle_key(now, "account", &account_key(name, ip)))?;
+    ks_incr_id(&tree, throttle_key(now, "user", name))?;
+    let user_failures = login_failures(db, "user", name)?;
+    if let Some(uid) = uid
+        && user_failures == THROTTLE_ACCOUNT_LIMIT
+    {
+        warn!("{user_failures} failed sign in attempts of uid {uid}, last from {ip}");
+        add_notification(db, uid, NtType::LoginThrottled, user_failures, 0)?;
+    }
+    let failures = login_failures(db, "ip", ip)?.max(user_failures);
+
+    let delay = 1 << (failures / 3).min(4);
+    sleep(Duration::from_secs(delay)).await;
+    Ok(())
+}
+
 /// Captcha session members
-struct CaptchaSession {
-    id: String,
-    image: String,
+pub(super) struct CaptchaSession {
+    pub(super) id: String,
+    pub(super) image: String,
 }
 
 impl CaptchaSession {
     /// Generate new captcha
-    fn create(site_config: &SiteConfig) -> Result<Self, AppError> {
+    pub(super) fn create(site_config: &SiteConfig) -> Result<Self, AppError> {
         let captcha_difficulty = match site_config.captcha_difficulty.as_str() {
             "Easy" => Difficulty::Easy,
             "Medium" => Difficulty::Medium,
@@ -1303,6 +1967,19 @@ impl Claim {
         Ok(())
     }
 
+    /// Sign the user out everywhere. Sessions of api tokens come back with the next request.
+    pub(super) fn revoke_all(db: &TransactionalKeyspace, uid: u32) -> Result<(), AppError> {
+        let session_tree = db.open_partition("sessions", Default::default())?;
+        for i in session_tree.inner().iter() {
+            let (k, v) = i?;
+            let (claim, _): (Claim, _) = bincode::decode_from_slice(&v, standard())?;
+            if claim.uid == uid {
+                session_tree.remove(k)?;
+            }
+        }
+        Ok(())
+    }
+
     fn update_role(db: &TransactionalKeyspace, uid: u32) -> Result<(), AppError> {
         let user: User = get_one(db, "users", uid)?;
 
@@ -1319,6 +1996,56 @@ impl Claim {
         Ok(())
     }
 
+    /// CSRF token bound to this session. It is derived from the session id, so it
+    /// is rotated on every sign in and dropped together with the session.
+    pub(super) fn csrf_token(&self) -> String {
+        let mut context = Context::new(&SHA256);
+        context.update(b"csrf#");
+        context.update(self.session_id.as_bytes());
+        HEXLOWER.encode(context.finish().as_ref())
+    }
+
+    /// whether the session backs an API token, see [super::token]
+    pub(super) fn is_token_session(&self) -> bool {
+        self.session_id
+            .split_once('_')
+            .is_some_and(|(_, id)| id.starts_with(TOKEN_SESSION_MARK))
+    }
+
+    /// Check the token submitted by a form against [Claim::csrf_token] in constant time.
+    /// Requests signed in by API tokens are not checked.
+    pub(super) fn check_csrf(&self, token: &str) -> Result<(), AppError> {
+        if self.is_token_session() {
+            return Ok(());
+        }
+        let expected = self.csrf_token();
+        let diff = expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if expected.len() != token.len() || diff != 0 {
+            return Err(AppError::InvalidCsrfToken);
+        }
+        Ok(())
+    }
+
+    /// generate a Claim from user and store it in session tree under `session_id`
+    pub(super) fn grant(
+        db: &TransactionalKeyspace,
+        user: User,
+        session_id: &str,
+    ) -> Result<(), AppError> {
+        let claim = Claim {
+            uid: user.uid,
+            username: user.username,
+            role: user.role,
+            last_write: Timestamp::now().as_second(),
+            session_id: session_id.to_owned(),
+            lang: user.lang,
+        };
+        set_one_with_key(db, "sessions", session_id, &claim)
+    }
+
     /// generate a Claim from user and store it in session tree, then return a cookie with a session id.
     fn generate_cookie(
         db: &TransactionalKeyspace,
@@ -1329,19 +2056,8 @@ impl Claim {
             return Err(AppError::Banned);
         }
         let seconds = expire_seconds(expiry);
-        let now = Timestamp::now().as_second();
         let session_id = generate_nanoid_ttl(seconds);
-
-        let claim = Claim {
-            uid: user.uid,
-            username: user.username,
-            role: user.role,
-            last_write: now,
-            session_id: session_id.clone(),
-            lang: user.lang,
-        };
-
-        set_one_with_key(db, "sessions", &session_id, &claim)?;
+        Self::grant(db, user, &session_id)?;
 
         let cookie = format!(
             "{COOKIE_NAME}={session_id}; SameSite=Strict; Path=/; Secure; HttpOnly; Max-Age={seconds}"
@@ -1391,4 +2107,36 @@ mod tests {
         // must generate different password_hash and salt with the same password
         assert_ne!(password_hash, password_hash2);
     }
+
+    #[test]
+    fn test_totp_code() {
+        // RFC 6238 Appendix B, truncated to 6 digits
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, 59 / TOTP_STEP), "287082");
+        assert_eq!(totp_code(secret, 1111111109 / TOTP_STEP), "081804");
+        assert_eq!(totp_code(secret, 1234567890 / TOTP_STEP), "005924");
+        assert_eq!(totp_code(secret, 2000000000 / TOTP_STEP), "279037");
+    }
+
+    #[test]
+    fn test_check_csrf() {
+        let claim = Claim {
+            uid: 1,
+            username: "test".into(),
+            role: 10,
+            last_write: 0,
+            session_id: generate_nanoid_ttl(60),
+            lang: None,
+        };
+        let token = claim.csrf_token();
+        assert!(claim.check_csrf(&token).is_ok());
+        assert!(claim.check_csrf("").is_err());
+        assert!(claim.check_csrf(&token[1..]).is_err());
+
+        let other = Claim {
+            session_id: generate_nanoid_ttl(60),
+            ..claim
+        };
+        assert!(other.check_csrf(&token).is_err());
+    }
 }
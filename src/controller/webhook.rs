@@ -0,0 +1,809 @@
+//! ## Webhooks
+//!
+//! Mods can add webhooks to their inns at `/mod/:iid/webhooks`, admins add site-wide ones at
+//! `/mod/0/webhooks`. Site-wide webhooks receive the events of all inns, and new sign ups.
+//!
+//! Events are not sent during requests. [emit] puts a [WebhookDelivery] in the
+//! "webhook_queue" partition, and [cron_webhooks] POSTs it with the JSON payload:
+//!
+//! ```json
+//! {"event": "new_post", "iid": 1, "created_at": 1700000000, "data": {...}}
+//! ```
+//!
+//! Each request carries the headers `X-Freedit-Event`, `X-Freedit-Delivery` and
+//! `X-Freedit-Signature: sha256=<hex>`, the HMAC-SHA256 of the body keyed by the secret of the
+//! webhook. Failed deliveries are retried with exponential backoff. The latest deliveries of a
+//! webhook are kept for its delivery log.
+
+use super::{
+    Claim, Inn, Post, SiteConfig, User, Webhook, WebhookDelivery,
+    db_utils::{
+        get_count_by_prefix, get_ids_by_prefix, get_one, incr_id, ivec_to_u32, set_one, u32_to_ivec,
+    },
+    feed::client_builder,
+    fmt::ts_to_date,
+    meta_handler::{FormCsrf, PageData, ParamsPage, into_response},
+    user::Role,
+};
+use crate::{DB, error::AppError};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::{TypedHeader, headers::Cookie};
+use data_encoding::HEXLOWER;
+use fjall::TransactionalKeyspace;
+use jiff::Timestamp;
+use nanoid::nanoid;
+use reqwest::{
+    Client, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::CONTENT_TYPE,
+    redirect,
+};
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::task::JoinSet;
+use tracing::{error, warn};
+
+/// how many webhooks an inn can have
+const WEBHOOKS_MAX: usize = 10;
+/// failed deliveries are given up after this many attempts
+const MAX_ATTEMPTS: u8 = 8;
+/// how many deliveries are kept for the log of a webhook
+const LOG_MAX: usize = 100;
+/// how many deliveries are sent at once by [cron_webhooks]
+const CONCURRENT_SENDS: usize = 8;
+
+/// events a webhook subscribes to, stored as bits in [Webhook::events]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum WebhookEvent {
+    NewPost = 1,
+    NewComment = 1 << 1,
+    PostLocked = 1 << 2,
+    PostHidden = 1 << 3,
+    MemberJoined = 1 << 4,
+    MemberApplied = 1 << 5,
+    /// only sent to site-wide webhooks
+    UserSignup = 1 << 6,
+}
+
+const EVENTS: [WebhookEvent; 7] = [
+    WebhookEvent::NewPost,
+    WebhookEvent::NewComment,
+    WebhookEvent::PostLocked,
+    WebhookEvent::PostHidden,
+    WebhookEvent::MemberJoined,
+    WebhookEvent::MemberApplied,
+    WebhookEvent::UserSignup,
+];
+
+impl WebhookEvent {
+    const fn name(self) -> &'static str {
+        match self {
+            WebhookEvent::NewPost => "new_post",
+            WebhookEvent::NewComment => "new_comment",
+            WebhookEvent::PostLocked => "post_locked",
+            WebhookEvent::PostHidden => "post_hidden",
+            WebhookEvent::MemberJoined => "member_joined",
+            WebhookEvent::MemberApplied => "member_applied",
+            WebhookEvent::UserSignup => "user_signup",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        EVENTS.into_iter().find(|e| e.name() == name)
+    }
+
+    fn from_bit(bit: u8) -> Option<Self> {
+        EVENTS.into_iter().find(|e| *e as u8 == bit)
+    }
+}
+
+fn event_names(events: u8) -> Vec<&'static str> {
+    EVENTS
+        .into_iter()
+        .filter(|e| events & *e as u8 != 0)
+        .map(WebhookEvent::name)
+        .collect()
+}
+
+/// Queue `event` for the webhooks of the inn and the site-wide ones, `iid` is 0 for site events.
+fn emit(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    event: WebhookEvent,
+    data: Value,
+) -> Result<(), AppError> {
+    let mut whids = get_ids_by_prefix(db, "inn_webhooks", u32_to_ivec(iid), None)?;
+    if iid > 0 {
+        whids.extend(get_ids_by_prefix(db, "inn_webhooks", u32_to_ivec(0), None)?);
+    }
+    if whids.is_empty() {
+        return Ok(());
+    }
+
+    let now = Timestamp::now().as_second();
+    let payload = json!({
+        "event": event.name(),
+        "iid": iid,
+        "created_at": now,
+        "data": data,
+    })
+    .to_string();
+    for whid in whids {
+        let webhook: Webhook = get_one(db, "webhooks", whid)?;
+        if webhook.events & event as u8 == 0 {
+            continue;
+        }
+        let did = incr_id(db, "webhook_deliveries_count")?;
+        let delivery = WebhookDelivery {
+            whid,
+            event: event as u8,
+            payload: payload.clone(),
+            created_at: now,
+            attempts: 0,
+            next_try: 0,
+            status: 0,
+            error: String::new(),
+            is_delivered: false,
+        };
+        set_one(db, "webhook_deliveries", did, &delivery)?;
+        let did_ivec = u32_to_ivec(did);
+        db.open_partition("webhook_queue", Default::default())?
+            .insert(&did_ivec, [])?;
+        db.open_partition("webhook_log", Default::default())?
+            .insert([u32_to_ivec(whid), did_ivec].concat(), [])?;
+        prune_log(db, whid)?;
+    }
+    Ok(())
+}
+
+/// Webhooks never fail the request which fired the event, errors are only logged
+fn log_err(event: WebhookEvent, res: Result<(), AppError>) {
+    if let Err(e) = res {
+        error!("failed to queue webhook event {}: {e}", event.name());
+    }
+}
+
+/// [emit] an event of a post
+pub(super) fn emit_post(db: &TransactionalKeyspace, event: WebhookEvent, post: &Post, by: u32) {
+    log_err(event, post_event(db, event, post, by));
+}
+
+fn post_event(
+    db: &TransactionalKeyspace,
+    event: WebhookEvent,
+    post: &Post,
+    by: u32,
+) -> Result<(), AppError> {
+    let site_config = SiteConfig::get(db)?;
+    let user: User = get_one(db, "users", post.uid)?;
+    let data = json!({
+        "pid": post.pid,
+        "uid": post.uid,
+        "username": user.username,
+        "title": post.title,
+        "tags": post.tags,
+        "by": by,
+        "url": format!("{}/post/{}/{}", site_config.domain, post.iid, post.pid),
+    });
+    emit(db, post.iid, event, data)
+}
+
+/// [emit] a new comment
+pub(super) fn emit_comment(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    cid: u32,
+    uid: u32,
+    username: &str,
+) {
+    let res = comment_event(db, post, cid, uid, username);
+    log_err(WebhookEvent::NewComment, res);
+}
+
+fn comment_event(
+    db: &TransactionalKeyspace,
+    post: &Post,
+    cid: u32,
+    uid: u32,
+    username: &str,
+) -> Result<(), AppError> {
+    let site_config = SiteConfig::get(db)?;
+    let data = json!({
+        "pid": post.pid,
+        "cid": cid,
+        "uid": uid,
+        "username": username,
+        "title": post.title,
+        "url": format!("{}/post/{}/{}#{cid}", site_config.domain, post.iid, post.pid),
+    });
+    emit(db, post.iid, WebhookEvent::NewComment, data)
+}
+
+/// [emit] an event of a user, like joining an inn
+pub(super) fn emit_user(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    event: WebhookEvent,
+    uid: u32,
+    username: &str,
+) {
+    log_err(event, user_event(db, iid, event, uid, username));
+}
+
+fn user_event(
+    db: &TransactionalKeyspace,
+    iid: u32,
+    event: WebhookEvent,
+    uid: u32,
+    username: &str,
+) -> Result<(), AppError> {
+    let site_config = SiteConfig::get(db)?;
+    let data = json!({
+        "uid": uid,
+        "username": username,
+        "url": format!("{}/user/{uid}", site_config.domain),
+    });
+    emit(db, iid, event, data)
+}
+
+/// keep the latest [LOG_MAX] deliveries of a webhook
+fn prune_log(db: &TransactionalKeyspace, whid: u32) -> Result<(), AppError> {
+    let prefix = u32_to_ivec(whid);
+    let count = get_count_by_prefix(db, "webhook_log", &prefix)?;
+    if count <= LOG_MAX {
+        return Ok(());
+    }
+    for did in get_ids_by_prefix(db, "webhook_log", &prefix, None)?
+        .into_iter()
+        .take(count - LOG_MAX)
+    {
+        delivery_rm(db, whid, did)?;
+    }
+    Ok(())
+}
+
+fn delivery_rm(db: &TransactionalKeyspace, whid: u32, did: u32) -> Result<(), AppError> {
+    let did_ivec = u32_to_ivec(did);
+    db.open_partition("webhook_log", Default::default())?
+        .remove([u32_to_ivec(whid), did_ivec.clone()].concat())?;
+    db.open_partition("webhook_queue", Default::default())?
+        .remove(&did_ivec)?;
+    db.open_partition("webhook_deliveries", Default::default())?
+        .remove(did_ivec)?;
+    Ok(())
+}
+
+/// Addresses webhooks must not reach: loopback, private, link-local, shared (CGNAT) and other
+/// special-purpose ranges, also when they are written as IPv4-mapped IPv6 addresses.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(ip));
+            }
+            let [s0, s1, ..] = ip.segments();
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.is_multicast()
+                // NAT64 and IPv4-compatible addresses
+                || (s0 == 0x64 && s1 == 0xff9b)
+                || ip.octets()[..12].iter().all(|b| *b == 0)
+        }
+    }
+}
+
+/// Resolves the hosts of webhooks and refuses internal addresses. Names are only checked when
+/// a delivery is sent, since they can point elsewhere by then.
+struct PublicResolver;
+
+async fn resolve_public(host: String) -> Result<Addrs, Box<dyn Error + Send + Sync>> {
+    let addrs: Vec<SocketAddr> =
+        tokio::task::spawn_blocking(move || (host.as_str(), 0).to_socket_addrs())
+            .await??
+            .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err("host resolves to an internal address".into());
+    }
+    Ok(Box::new(addrs.into_iter()))
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(resolve_public(name.as_str().to_owned()))
+    }
+}
+
+/// Webhooks do not follow redirects or use the proxy of the feeds, so every connection goes
+/// through [PublicResolver].
+static WEBHOOK_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    client_builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(redirect::Policy::none())
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .unwrap()
+});
+
+/// POST the payload, returns the http status
+///
+/// Errors are kept vague, the delivery log must not tell what is listening behind a url.
+async fn send(webhook: &Webhook, did: u32, delivery: &WebhookDelivery) -> Result<u16, String> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, webhook.secret.as_bytes());
+    let signature = hmac::sign(&key, delivery.payload.as_bytes());
+    let event = WebhookEvent::from_bit(delivery.event).map_or("", WebhookEvent::name);
+    let res = WEBHOOK_CLIENT
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Freedit-Event", event)
+        .header("X-Freedit-Delivery", did)
+        .header(
+            "X-Freedit-Signature",
+            format!("sha256={}", HEXLOWER.encode(signature.as_ref())),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                "timeout".to_owned()
+            } else {
+                "request failed".to_owned()
+            }
+        })?;
+    Ok(res.status().as_u16())
+}
+
+/// Send queued deliveries which are due. Failed ones are retried after 30s, 1m, 2m, ... and
+/// given up after [MAX_ATTEMPTS].
+///
+/// Up to [CONCURRENT_SENDS] deliveries are sent at once, so a slow endpoint does not hold up the
+/// webhooks of other inns.
+pub async fn cron_webhooks(db: &TransactionalKeyspace) -> Result<(), AppError> {
+    let now = Timestamp::now().as_second();
+    let queue = db.open_partition("webhook_queue", Default::default())?;
+    let mut due = vec![];
+    for i in queue.inner().iter() {
+        let (k, _) = i?;
+        let did = ivec_to_u32(&k);
+        let Ok(delivery) = get_one::<WebhookDelivery>(db, "webhook_deliveries", did) else {
+            queue.remove(k)?;
+            continue;
+        };
+        if delivery.next_try > now {
+            continue;
+        }
+        let Ok(webhook) = get_one::<Webhook>(db, "webhooks", delivery.whid) else {
+            queue.remove(k)?;
+            continue;
+        };
+        due.push((did, webhook, delivery));
+    }
+
+    let mut sending = JoinSet::new();
+    for (did, webhook, delivery) in due {
+        while sending.len() >= CONCURRENT_SENDS {
+            match sending.join_next().await {
+                Some(Ok(sent)) => record(db, now, sent),
+                Some(Err(e)) => error!("webhook delivery task failed: {e}"),
+                None => break,
+            }
+        }
+        sending.spawn(async move {
+            let res = send(&webhook, did, &delivery).await;
+            (did, webhook, delivery, res)
+        });
+    }
+    while let Some(sent) = sending.join_next().await {
+        match sent {
+            Ok(sent) => record(db, now, sent),
+            Err(e) => error!("webhook delivery task failed: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Store the result of a delivery, errors are logged so the other deliveries are still stored
+fn record(
+    db: &TransactionalKeyspace,
+    now: i64,
+    (did, webhook, mut delivery, res): (u32, Webhook, WebhookDelivery, Result<u16, String>),
+) {
+    delivery.attempts += 1;
+    match res {
+        Ok(status) => {
+            delivery.status = status;
+            delivery.error = String::new();
+            delivery.is_delivered = (200..300).contains(&status);
+        }
+        Err(e) => {
+            delivery.status = 0;
+            delivery.error = e;
+        }
+    }
+
+    let is_done = if delivery.is_delivered {
+        true
+    } else if delivery.attempts >= MAX_ATTEMPTS {
+        warn!(
+            "giving up webhook delivery {did} to {} after {} attempts",
+            webhook.url, delivery.attempts
+        );
+        true
+    } else {
+        delivery.next_try = now + (30 << (delivery.attempts - 1));
+        false
+    };
+
+    let res = set_one(db, "webhook_deliveries", did, &delivery).and_then(|_| {
+        if is_done {
+            db.open_partition("webhook_queue", Default::default())?
+                .remove(u32_to_ivec(did))?;
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        error!("failed to store webhook delivery {did}: {e}");
+    }
+}
+
+/// Admins manage site-wide webhooks, mods those of their inns
+fn can_manage(claim: &Claim, iid: u32) -> Result<bool, AppError> {
+    if Role::from(claim.role) == Role::Admin {
+        return Ok(true);
+    }
+    Ok(iid > 0 && User::is_mod(&DB, claim.uid, iid)?)
+}
+
+fn inn_name(iid: u32) -> Result<String, AppError> {
+    if iid > 0 {
+        let inn: Inn = get_one(&DB, "inns", iid)?;
+        Ok(inn.inn_name)
+    } else {
+        Ok("Site".to_owned())
+    }
+}
+
+/// Webhooks must not point into the network of the server. Hostnames are checked again by
+/// [PublicResolver] when deliveries are sent.
+fn check_url(url: &str) -> Result<(), AppError> {
+    let err = || AppError::Custom("Invalid webhook url".into());
+    let url = Url::parse(url).map_err(|_| err())?;
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return Err(err());
+    }
+    let host = url.host_str().ok_or_else(err)?;
+    if host == "localhost" || host.ends_with(".localhost") {
+        return Err(err());
+    }
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>()
+        && is_internal(ip)
+    {
+        return Err(err());
+    }
+    Ok(())
+}
+
+/// Vec data: webhook
+struct OutWebhook {
+    whid: u32,
+    url: String,
+    secret: String,
+    events: String,
+    created_at: String,
+    /// deliveries waiting for a retry
+    failing: usize,
+}
+
+/// Page data: `webhooks.html`
+#[derive(Template)]
+#[template(path = "webhooks.html")]
+struct PageWebhooks<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    inn_name: String,
+    webhooks: Vec<OutWebhook>,
+    events: Vec<&'static str>,
+}
+
+/// `GET /mod/:iid/webhooks` webhooks of an inn, or of the site if iid is 0
+pub(crate) async fn mod_webhooks(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if !can_manage(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let queue = DB.open_partition("webhook_queue", Default::default())?;
+    let mut webhooks = vec![];
+    for whid in get_ids_by_prefix(&DB, "inn_webhooks", u32_to_ivec(iid), None)? {
+        let webhook: Webhook = get_one(&DB, "webhooks", whid)?;
+        let mut failing = 0;
+        for did in get_ids_by_prefix(&DB, "webhook_log", u32_to_ivec(whid), None)? {
+            if queue.contains_key(u32_to_ivec(did))?
+                && get_one::<WebhookDelivery>(&DB, "webhook_deliveries", did)?.attempts > 0
+            {
+                failing += 1;
+            }
+        }
+        webhooks.push(OutWebhook {
+            whid,
+            url: webhook.url,
+            secret: webhook.secret,
+            events: event_names(webhook.events).join(", "),
+            created_at: ts_to_date(webhook.created_at),
+            failing,
+        });
+    }
+
+    // sign ups are not events of inns
+    let events = EVENTS
+        .into_iter()
+        .filter(|e| iid == 0 || *e != WebhookEvent::UserSignup)
+        .map(WebhookEvent::name)
+        .collect();
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("webhooks", &site_config, Some(claim), has_unread);
+    let page_webhooks = PageWebhooks {
+        page_data,
+        iid,
+        inn_name: inn_name(iid)?,
+        webhooks,
+        events,
+    };
+    Ok(into_response(&page_webhooks))
+}
+
+/// `POST /mod/:iid/webhooks` add a webhook
+///
+/// Form data: `csrf_token`, `url` and one `event` per chosen event
+pub(crate) async fn mod_webhooks_post(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(iid): Path<u32>,
+    Form(form): Form<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+
+    let mut csrf_token = String::new();
+    let mut url = String::new();
+    let mut events = 0;
+    for (k, v) in form {
+        match k.as_str() {
+            "csrf_token" => csrf_token = v,
+            "url" => url = v.trim().to_owned(),
+            "event" => {
+                if let Some(event) = WebhookEvent::from_name(&v)
+                    && (iid == 0 || event != WebhookEvent::UserSignup)
+                {
+                    events |= event as u8;
+                }
+            }
+            _ => {}
+        }
+    }
+    claim.check_csrf(&csrf_token)?;
+    if !can_manage(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+
+    if url.len() > 512 || events == 0 {
+        return Err(AppError::Custom(
+            "A webhook needs an url and at least one event".into(),
+        ));
+    }
+    check_url(&url)?;
+    if get_count_by_prefix(&DB, "inn_webhooks", &u32_to_ivec(iid))? >= WEBHOOKS_MAX {
+        return Err(AppError::Custom(format!(
+            "An inn can have at most {WEBHOOKS_MAX} webhooks"
+        )));
+    }
+
+    let whid = incr_id(&DB, "webhooks_count")?;
+    let webhook = Webhook {
+        whid,
+        iid,
+        uid: claim.uid,
+        url,
+        secret: nanoid!(32),
+        events,
+        created_at: Timestamp::now().as_second(),
+    };
+    set_one(&DB, "webhooks", whid, &webhook)?;
+    DB.open_partition("inn_webhooks", Default::default())?
+        .insert([u32_to_ivec(iid), u32_to_ivec(whid)].concat(), [])?;
+
+    Ok(Redirect::to(&format!("/mod/{iid}/webhooks")))
+}
+
+/// `POST /mod/:iid/webhooks/:whid/delete` delete a webhook with its deliveries
+pub(crate) async fn mod_webhook_delete(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, whid)): Path<(u32, u32)>,
+    Form(form): Form<FormCsrf>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    claim.check_csrf(&form.csrf_token)?;
+    if !can_manage(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let webhook: Webhook = get_one(&DB, "webhooks", whid)?;
+    if webhook.iid != iid {
+        return Err(AppError::NotFound);
+    }
+    for did in get_ids_by_prefix(&DB, "webhook_log", u32_to_ivec(whid), None)? {
+        delivery_rm(&DB, whid, did)?;
+    }
+    DB.open_partition("inn_webhooks", Default::default())?
+        .remove([u32_to_ivec(iid), u32_to_ivec(whid)].concat())?;
+    DB.open_partition("webhooks", Default::default())?
+        .remove(u32_to_ivec(whid))?;
+
+    Ok(Redirect::to(&format!("/mod/{iid}/webhooks")))
+}
+
+/// Vec data: webhook delivery
+struct OutDelivery {
+    did: u32,
+    event: &'static str,
+    payload: String,
+    created_at: String,
+    attempts: u8,
+    status: u16,
+    error: String,
+    is_delivered: bool,
+    /// when it is tried again, empty if it is not queued
+    next_try: String,
+}
+
+/// Page data: `webhook_log.html`
+#[derive(Template)]
+#[template(path = "webhook_log.html")]
+struct PageWebhookLog<'a> {
+    page_data: PageData<'a>,
+    iid: u32,
+    inn_name: String,
+    whid: u32,
+    url: String,
+    deliveries: Vec<OutDelivery>,
+    anchor: usize,
+    n: usize,
+    is_desc: bool,
+}
+
+/// url params: `webhook_log.html`
+#[derive(Deserialize)]
+pub(crate) struct ParamsWebhookLog {
+    anchor: Option<usize>,
+    is_desc: Option<bool>,
+}
+
+/// `GET /mod/:iid/webhooks/:whid` delivery log of a webhook
+pub(crate) async fn mod_webhook_log(
+    cookie: Option<TypedHeader<Cookie>>,
+    Path((iid, whid)): Path<(u32, u32)>,
+    Query(params): Query<ParamsWebhookLog>,
+) -> Result<impl IntoResponse, AppError> {
+    let cookie = cookie.ok_or(AppError::NonLogin)?;
+    let site_config = SiteConfig::get(&DB)?;
+    let claim = Claim::get(&DB, &cookie, &site_config).ok_or(AppError::NonLogin)?;
+    if !can_manage(&claim, iid)? {
+        return Err(AppError::Unauthorized);
+    }
+    let webhook: Webhook = get_one(&DB, "webhooks", whid)?;
+    if webhook.iid != iid {
+        return Err(AppError::NotFound);
+    }
+
+    let n = site_config.per_page;
+    let anchor = params.anchor.unwrap_or(0);
+    let is_desc = params.is_desc.unwrap_or(true);
+    let page_params = ParamsPage { anchor, n, is_desc };
+
+    let queue = DB.open_partition("webhook_queue", Default::default())?;
+    let mut deliveries = Vec::with_capacity(n);
+    for did in get_ids_by_prefix(&DB, "webhook_log", u32_to_ivec(whid), Some(&page_params))? {
+        let delivery: WebhookDelivery = get_one(&DB, "webhook_deliveries", did)?;
+        let next_try = if queue.contains_key(u32_to_ivec(did))? {
+            ts_to_date(delivery.next_try.max(delivery.created_at))
+        } else {
+            String::new()
+        };
+        deliveries.push(OutDelivery {
+            did,
+            event: WebhookEvent::from_bit(delivery.event).map_or("", WebhookEvent::name),
+            payload: delivery.payload,
+            created_at: ts_to_date(delivery.created_at),
+            attempts: delivery.attempts,
+            status: delivery.status,
+            error: delivery.error,
+            is_delivered: delivery.is_delivered,
+            next_try,
+        });
+    }
+
+    let has_unread = User::has_unread(&DB, claim.uid)?;
+    let page_data = PageData::new("webhooks", &site_config, Some(claim), has_unread);
+    let page_webhook_log = PageWebhookLog {
+        page_data,
+        iid,
+        inn_name: inn_name(iid)?,
+        whid,
+        url: webhook.url,
+        deliveries,
+        anchor,
+        n,
+        is_desc,
+    };
+    Ok(into_response(&page_webhook_log))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("https://example.com/hook").is_ok());
+        assert!(check_url("http://93.184.216.34:8080/hook").is_ok());
+        assert!(check_url("https://[2606:2800:220:1::]/hook").is_ok());
+
+        for url in [
+            "ftp://example.com/hook",
+            "not a url",
+            "http://localhost/hook",
+            "http://api.localhost/hook",
+            "http://127.0.0.1/hook",
+            "http://10.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://[::ffff:a9fe:a9fe]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[64:ff9b::a00:1]/hook",
+        ] {
+            assert!(check_url(url).is_err(), "{url}");
+        }
+    }
+}
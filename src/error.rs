@@ -41,6 +41,12 @@ pub enum AppError {
     NonLogin,
     #[error("You have been banned")]
     Banned,
+    #[error("Your account is waiting for approval by admins")]
+    AwaitingApproval,
+    #[error("You have been blocked by this user")]
+    Blocked,
+    #[error("Invalid CSRF token, please refresh the page and try again")]
+    InvalidCsrfToken,
     #[error("It has been locked or hidden")]
     LockedOrHidden,
     #[error(transparent)]
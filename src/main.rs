@@ -4,7 +4,8 @@
 // #![warn(clippy::unwrap_used)]
 
 use freedit::{
-    AppError, CONFIG, DB, Tan, VERSION, clear_invalid, cron_download_audio, cron_feed, router,
+    AppError, CONFIG, DB, Tan, VERSION, clear_invalid, cron_download_audio, cron_feed,
+    cron_lift_bans, cron_publish_posts, cron_send_emails, cron_webhooks, ivec_to_u32, router,
 };
 use jiff::Timestamp;
 use std::{fs, net::SocketAddr, path::PathBuf};
@@ -35,6 +36,27 @@ async fn main() -> Result<(), AppError> {
             if let Err(e) = clear_invalid(&DB, "sessions").await {
                 error!(%e);
             }
+            if let Err(e) = clear_invalid(&DB, "totp_tickets").await {
+                error!(%e);
+            }
+            if let Err(e) = clear_invalid(&DB, "login_failures").await {
+                error!(%e);
+            }
+            if let Err(e) = clear_invalid(&DB, "invites").await {
+                error!(%e);
+            }
+            if let Err(e) = clear_invalid(&DB, "email_tokens").await {
+                error!(%e);
+            }
+            if let Err(e) = clear_invalid(&DB, "api_tokens").await {
+                error!(%e);
+            }
+            if let Err(e) = cron_lift_bans(&DB).await {
+                error!(%e);
+            }
+            if let Err(e) = cron_publish_posts(&DB).await {
+                error!(%e);
+            }
             if let Err(e) = cron_download_audio(&DB).await {
                 error!(%e);
             }
@@ -42,6 +64,26 @@ async fn main() -> Result<(), AppError> {
         }
     });
 
+    // webhooks are expected to arrive soon, so they are not sent with the jobs above
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = cron_webhooks(&DB).await {
+                error!(%e);
+            }
+            sleep_seconds(15).await;
+        }
+    });
+
+    // a slow mail server only holds up the emails, and reset links go out soon
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = cron_send_emails(&DB).await {
+                error!(%e);
+            }
+            sleep_seconds(60).await;
+        }
+    });
+
     tokio::spawn(async move {
         loop {
             sleep_seconds(600).await;
@@ -67,6 +109,15 @@ async fn main() -> Result<(), AppError> {
             tan.add_doc(&id, &DB).unwrap();
             tan_ks.take(k).unwrap();
         }
+        // after adding docs, so the pending docs of deleted users go too
+        let deleted_ks = DB
+            .open_partition("tan_deleted_users", Default::default())
+            .unwrap();
+        for item in deleted_ks.inner().iter() {
+            let (k, _) = item.unwrap();
+            tan.delete_user(ivec_to_u32(&k));
+            deleted_ks.take(k).unwrap();
+        }
         tan.commit().unwrap();
         info!("tantivy indexer finished");
     });
@@ -76,7 +127,12 @@ async fn main() -> Result<(), AppError> {
     let listener = TcpListener::bind(addr).await.unwrap();
 
     info!("listening on http://{}", addr);
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 
     Ok(())
 }